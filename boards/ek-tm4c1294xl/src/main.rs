@@ -28,6 +28,10 @@ const NUM_PROCS: usize = 4;
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// How much of each process's memory is reserved for grants. Processes cannot
+// `brk` into this region, so capsules can always allocate at least this much.
+const MIN_GRANT_SIZE: usize = 0;
+
 // RAM to be shared by all application processes.
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 10240] = [0; 10240];
@@ -114,15 +118,20 @@ pub unsafe fn reset_handler() {
     // Create virtual device for kernel debug.
     let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
     debugger_uart.setup();
+    let debug_sink = static_init!(
+        kernel::debug::UartSink<'static>,
+        kernel::debug::UartSink::new(debugger_uart)
+    );
+    hil::uart::UART::set_client(debugger_uart, debug_sink);
     let debugger = static_init!(
         kernel::debug::DebugWriter,
         kernel::debug::DebugWriter::new(
-            debugger_uart,
+            debug_sink,
             &mut kernel::debug::OUTPUT_BUF,
             &mut kernel::debug::INTERNAL_BUF,
         )
     );
-    hil::uart::UART::set_client(debugger_uart, debugger);
+    kernel::debug::DebugSink::set_client(debug_sink, debugger);
 
     let debug_wrapper = static_init!(
         kernel::debug::DebugWriterWrapper,
@@ -244,6 +253,7 @@ pub unsafe fn reset_handler() {
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
+        MIN_GRANT_SIZE,
     );
-    board_kernel.kernel_loop(&tm4c1294, &mut chip, Some(&tm4c1294.ipc));
+    board_kernel.kernel_loop(&tm4c1294, &mut chip, Some(&tm4c1294.ipc), None);
 }
@@ -20,6 +20,8 @@ use capsules::virtual_i2c::{I2CDevice, MuxI2C};
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
 use capsules::virtual_uart::{UartDevice, UartMux};
 use kernel::hil;
+use kernel::hil::digest::Digest;
+use kernel::hil::rng::Random;
 use kernel::hil::spi::SpiMaster;
 use kernel::hil::Controller;
 use kernel::Platform;
@@ -35,6 +37,11 @@ mod test_take_map_cell;
 static mut SPI_READ_BUF: [u8; 64] = [0; 64];
 static mut SPI_WRITE_BUF: [u8; 64] = [0; 64];
 
+// Buffers the digest driver copies process data and results through.
+static mut DIGEST_DATA_BUF: [u8; 128] = [0; 128];
+static mut DIGEST_BUF: [u8; kernel::hil::digest::SHA256_DIGEST_SIZE] =
+    [0; kernel::hil::digest::SHA256_DIGEST_SIZE];
+
 // State for loading and holding applications.
 
 // Number of concurrent processes this platform supports.
@@ -43,6 +50,10 @@ const NUM_PROCS: usize = 20;
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// How much of each process's memory is reserved for grants. Processes cannot
+// `brk` into this region, so capsules can always allocate at least this much.
+const MIN_GRANT_SIZE: usize = 0;
+
 // RAM to be shared by all application processes.
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 49152] = [0; 49152];
@@ -67,6 +78,7 @@ struct Hail {
         'static,
         VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
     >,
+    rtc: &'static capsules::rtc::RtcDriver<'static>,
     ambient_light: &'static capsules::ambient_light::AmbientLight<'static>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
@@ -76,13 +88,23 @@ struct Hail {
         'static,
         sam4l::usart::USART,
     >,
-    adc: &'static capsules::adc::Adc<'static, sam4l::adc::Adc>,
+    adc: &'static capsules::adc::Adc<
+        'static,
+        capsules::virtual_adc::AdcDevice<'static, sam4l::adc::Adc>,
+    >,
     led: &'static capsules::led::LED<'static, sam4l::gpio::GPIOPin>,
     button: &'static capsules::button::Button<'static, sam4l::gpio::GPIOPin>,
-    rng: &'static capsules::rng::SimpleRng<'static, sam4l::trng::Trng<'static>>,
+    rng: &'static capsules::rng::SimpleRng<
+        'static,
+        capsules::hmac_drbg::HmacDrbg<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
+    >,
     ipc: kernel::ipc::IPC,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
+    digest: &'static capsules::digest::Digest<
+        capsules::sha256::Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
+    >,
     dac: &'static capsules::dac::Dac<'static>,
+    process_info: &'static capsules::process_info::ProcessInfo,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -96,6 +118,7 @@ impl Platform for Hail {
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
 
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules::rtc::DRIVER_NUM => f(Some(self.rtc)),
             capsules::spi::DRIVER_NUM => f(Some(self.spi)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::ambient_light::DRIVER_NUM => f(Some(self.ambient_light)),
@@ -109,9 +132,12 @@ impl Platform for Hail {
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
 
             capsules::crc::DRIVER_NUM => f(Some(self.crc)),
+            capsules::digest::DRIVER_NUM => f(Some(self.digest)),
 
             capsules::dac::DRIVER_NUM => f(Some(self.dac)),
 
+            capsules::process_info::DRIVER_NUM => f(Some(self.process_info)),
+
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -324,6 +350,40 @@ pub unsafe fn reset_handler() {
     );
     virtual_alarm1.set_client(alarm);
 
+    // 64-bit monotonic clock on the AST, which applications can read through
+    // the alarm driver.
+    let clock_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let clock = static_init!(
+        kernel::common::monotonic::MonotonicClock<
+            'static,
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        >,
+        kernel::common::monotonic::MonotonicClock::new(clock_virtual_alarm)
+    );
+    clock_virtual_alarm.set_client(clock);
+    clock.start();
+    alarm.set_monotonic(clock);
+
+    // Calendar time, kept in software on the monotonic clock. Processes set
+    // it, as Hail has no battery-backed RTC.
+    let rtc_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let software_rtc = static_init!(
+        capsules::software_rtc::SoftwareRtc<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        capsules::software_rtc::SoftwareRtc::new(rtc_virtual_alarm, clock)
+    );
+    rtc_virtual_alarm.set_client(software_rtc);
+    let rtc = static_init!(
+        capsules::rtc::RtcDriver<'static>,
+        capsules::rtc::RtcDriver::new(software_rtc, board_kernel.create_grant())
+    );
+    hil::rtc::Rtc::set_client(software_rtc, rtc);
+
     // FXOS8700CQ accelerometer, device address 0x1e
     let fxos8700_i2c = static_init!(I2CDevice, I2CDevice::new(sensors_i2c, 0x1e));
     let fxos8700 = static_init!(
@@ -420,24 +480,49 @@ pub unsafe fn reset_handler() {
             &sam4l::adc::CHANNEL_AD6, // A5
         ]
     );
+    let mux_adc = static_init!(
+        capsules::virtual_adc::MuxAdc<'static, sam4l::adc::Adc>,
+        capsules::virtual_adc::MuxAdc::new(&sam4l::adc::ADC0)
+    );
+    sam4l::adc::ADC0.set_client(mux_adc);
+    let adc_device = static_init!(
+        capsules::virtual_adc::AdcDevice<'static, sam4l::adc::Adc>,
+        capsules::virtual_adc::AdcDevice::new(mux_adc)
+    );
     let adc = static_init!(
-        capsules::adc::Adc<'static, sam4l::adc::Adc>,
+        capsules::adc::Adc<'static, capsules::virtual_adc::AdcDevice<'static, sam4l::adc::Adc>>,
         capsules::adc::Adc::new(
-            &mut sam4l::adc::ADC0,
+            adc_device,
             adc_channels,
             &mut capsules::adc::ADC_BUFFER1,
             &mut capsules::adc::ADC_BUFFER2,
-            &mut capsules::adc::ADC_BUFFER3
+            &mut capsules::adc::ADC_BUFFER3,
+            board_kernel.create_grant()
         )
     );
-    sam4l::adc::ADC0.set_client(adc);
+    adc_device.set_client(adc);
+    adc_device.set_highspeed_client(adc);
 
-    // Setup RNG
+    // Setup RNG: the TRNG seeds a DRBG, which serves processes
+    let drbg_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let drbg = static_init!(
+        capsules::hmac_drbg::HmacDrbg<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        capsules::hmac_drbg::HmacDrbg::new(Some(&sam4l::trng::TRNG), drbg_alarm)
+    );
+    sam4l::trng::TRNG.set_client(drbg);
+    drbg_alarm.set_client(drbg);
+    drbg.initialize();
     let rng = static_init!(
-        capsules::rng::SimpleRng<'static, sam4l::trng::Trng>,
-        capsules::rng::SimpleRng::new(&sam4l::trng::TRNG, board_kernel.create_grant())
+        capsules::rng::SimpleRng<
+            'static,
+            capsules::hmac_drbg::HmacDrbg<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        >,
+        capsules::rng::SimpleRng::new(drbg, board_kernel.create_grant())
     );
-    sam4l::trng::TRNG.set_client(rng);
+    drbg.set_client(rng);
 
     // set GPIO driver controlling remaining GPIO pins
     let gpio_pins = static_init!(
@@ -464,16 +549,46 @@ pub unsafe fn reset_handler() {
     );
     sam4l::crccu::CRCCU.set_client(crc);
 
+    // SHA-256 and HMAC-SHA256, computed in software
+    let sha_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let sha256 = static_init!(
+        capsules::sha256::Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        capsules::sha256::Sha256Software::new(sha_alarm)
+    );
+    sha_alarm.set_client(sha256);
+    let digest = static_init!(
+        capsules::digest::Digest<
+            capsules::sha256::Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        >,
+        capsules::digest::Digest::new(
+            sha256,
+            &mut DIGEST_DATA_BUF,
+            &mut DIGEST_BUF,
+            board_kernel.create_grant()
+        )
+    );
+    sha256.set_client(digest);
+
     // DAC
     let dac = static_init!(
         capsules::dac::Dac<'static>,
         capsules::dac::Dac::new(&mut sam4l::dac::DAC)
     );
 
+    // Process information
+    let process_info = static_init!(
+        capsules::process_info::ProcessInfo,
+        capsules::process_info::ProcessInfo::new(board_kernel, board_kernel.create_grant())
+    );
+
     let hail = Hail {
         console: console,
         gpio: gpio,
         alarm: alarm,
+        rtc: rtc,
         ambient_light: ambient_light,
         temp: temp,
         humidity: humidity,
@@ -486,7 +601,9 @@ pub unsafe fn reset_handler() {
         rng: rng,
         ipc: kernel::ipc::IPC::new(board_kernel),
         crc: crc,
+        digest: digest,
         dac: dac,
+        process_info: process_info,
     };
 
     hail.console.initialize();
@@ -494,21 +611,31 @@ pub unsafe fn reset_handler() {
     // Create virtual device for kernel debug.
     let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
     debugger_uart.setup();
+    let debug_sink = static_init!(
+        kernel::debug::UartSink<'static>,
+        kernel::debug::UartSink::new(debugger_uart)
+    );
+    hil::uart::UART::set_client(debugger_uart, debug_sink);
     let debugger = static_init!(
         kernel::debug::DebugWriter,
         kernel::debug::DebugWriter::new(
-            debugger_uart,
+            debug_sink,
             &mut kernel::debug::OUTPUT_BUF,
             &mut kernel::debug::INTERNAL_BUF,
         )
     );
-    hil::uart::UART::set_client(debugger_uart, debugger);
+    kernel::debug::DebugSink::set_client(debug_sink, debugger);
 
     let debug_wrapper = static_init!(
         kernel::debug::DebugWriterWrapper,
         kernel::debug::DebugWriterWrapper::new(debugger)
     );
     kernel::debug::set_debug_writer_wrapper(debug_wrapper);
+    // Timestamp kernel log records with the monotonic clock.
+    kernel::debug::set_log_clock(clock);
+
+    // Report what the kernel was doing if the watchdog reset the board.
+    kernel::debug::report_crash_log();
 
     // Reset the nRF and setup the UART bus.
     hail.nrf51822.reset();
@@ -532,6 +659,15 @@ pub unsafe fn reset_handler() {
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
+        MIN_GRANT_SIZE,
     );
-    board_kernel.kernel_loop(&hail, &mut chip, Some(&hail.ipc));
+
+    // Reset the board if the kernel loop stops making progress for about 16
+    // seconds. The SAM4L watchdog interrupts one period before resetting, so
+    // a long sleep only wakes the kernel.
+    let wdt = &sam4l::wdt::WDT;
+    hil::watchdog::Watchdog::set_client(wdt, board_kernel);
+    hil::watchdog::Watchdog::start(wdt, 10000);
+
+    board_kernel.kernel_loop(&hail, &mut chip, Some(&hail.ipc), Some(wdt));
 }
@@ -2,12 +2,13 @@
 //!
 //! This provides one Component, AdcComponent, which implements
 //! a userspace syscall interface to the SAM4L ADC. It provides
-//! 6 ADC channels, AD0-AD5.
+//! 6 ADC channels, AD0-AD5. The syscall driver sits on a virtual
+//! ADC device, so kernel clients can share the ADC with it.
 //!
 //! Usage
 //! -----
 //! ```rust
-//! let adc = AdcComponent::new().finalize();
+//! let adc = AdcComponent::new(board_kernel).finalize();
 //! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
@@ -16,19 +17,25 @@
 #![allow(dead_code)] // Components are intended to be conditionally included
 
 use capsules::adc;
+use capsules::virtual_adc::{AdcDevice, MuxAdc};
+use kernel;
 use kernel::component::Component;
 use sam4l;
 
-pub struct AdcComponent {}
+pub struct AdcComponent {
+    board_kernel: &'static kernel::Kernel,
+}
 
 impl AdcComponent {
-    pub fn new() -> AdcComponent {
-        AdcComponent {}
+    pub fn new(board_kernel: &'static kernel::Kernel) -> AdcComponent {
+        AdcComponent {
+            board_kernel: board_kernel,
+        }
     }
 }
 
 impl Component for AdcComponent {
-    type Output = &'static adc::Adc<'static, sam4l::adc::Adc>;
+    type Output = &'static adc::Adc<'static, AdcDevice<'static, sam4l::adc::Adc>>;
 
     unsafe fn finalize(&mut self) -> Self::Output {
         let adc_channels = static_init!(
@@ -42,17 +49,28 @@ impl Component for AdcComponent {
                 &sam4l::adc::CHANNEL_AD6, // AD5
             ]
         );
+        let mux_adc = static_init!(
+            MuxAdc<'static, sam4l::adc::Adc>,
+            MuxAdc::new(&sam4l::adc::ADC0)
+        );
+        sam4l::adc::ADC0.set_client(mux_adc);
+        let adc_device = static_init!(
+            AdcDevice<'static, sam4l::adc::Adc>,
+            AdcDevice::new(mux_adc)
+        );
         let adc = static_init!(
-            adc::Adc<'static, sam4l::adc::Adc>,
+            adc::Adc<'static, AdcDevice<'static, sam4l::adc::Adc>>,
             adc::Adc::new(
-                &mut sam4l::adc::ADC0,
+                adc_device,
                 adc_channels,
                 &mut adc::ADC_BUFFER1,
                 &mut adc::ADC_BUFFER2,
-                &mut adc::ADC_BUFFER3
+                &mut adc::ADC_BUFFER3,
+                self.board_kernel.create_grant()
             )
         );
-        sam4l::adc::ADC0.set_client(adc);
+        adc_device.set_client(adc);
+        adc_device.set_highspeed_client(adc);
 
         adc
     }
@@ -0,0 +1,81 @@
+//! Component for the AES syscall interface on imix board.
+//!
+//! This provides one Component, AesComponent, which implements a
+//! userspace syscall interface to AES-128 in CTR, CBC and CCM* mode,
+//! sharing the SAM4L's AESA with the kernel through a `MuxAES128`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let aes = AesComponent::new(board_kernel, mux_aes).finalize();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::aes::Aes;
+use capsules::aes_ccm::AES128CCM;
+use capsules::virtual_aes::{MuxAES128, VirtualAES128Device};
+use kernel;
+use kernel::component::Component;
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{AES128, AES128CCM as AES128CCMTrait};
+use sam4l;
+
+type AesDevice = VirtualAES128Device<'static, sam4l::aes::Aes<'static>>;
+
+pub struct AesComponent {
+    board_kernel: &'static kernel::Kernel,
+    mux_aes: &'static MuxAES128<'static, sam4l::aes::Aes<'static>>,
+}
+
+impl AesComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux_aes: &'static MuxAES128<'static, sam4l::aes::Aes<'static>>,
+    ) -> AesComponent {
+        AesComponent {
+            board_kernel: board_kernel,
+            mux_aes: mux_aes,
+        }
+    }
+}
+
+// Kernel buffer that CTR and CBC requests are processed through, and that
+// holds a whole CCM* message.
+const AES_BUF_SIZE: usize = 128;
+static mut AES_BUF: [u8; AES_BUF_SIZE] = [0x00; AES_BUF_SIZE];
+
+// Intermediate buffer for AES CCM encryption, which needs up to
+// 3 * BLOCK_SIZE more than the largest message.
+const CRYPT_SIZE: usize = 3 * symmetric_encryption::AES128_BLOCK_SIZE + AES_BUF_SIZE;
+static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
+
+impl Component for AesComponent {
+    type Output = &'static Aes<AesDevice, AES128CCM<'static, AesDevice>>;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        let aes_device = static_init!(AesDevice, VirtualAES128Device::new(self.mux_aes));
+        let ccm_device = static_init!(AesDevice, VirtualAES128Device::new(self.mux_aes));
+        let aes_ccm = static_init!(
+            AES128CCM<'static, AesDevice>,
+            AES128CCM::new(ccm_device, &mut CRYPT_BUF)
+        );
+        ccm_device.set_client(aes_ccm);
+        ccm_device.enable();
+
+        let aes = static_init!(
+            Aes<AesDevice, AES128CCM<'static, AesDevice>>,
+            Aes::new(
+                aes_device,
+                aes_ccm,
+                &mut AES_BUF,
+                self.board_kernel.create_grant()
+            )
+        );
+        aes_device.set_client(aes);
+        aes_device.enable();
+        aes_ccm.set_client(aes);
+
+        aes
+    }
+}
@@ -0,0 +1,162 @@
+//! Component for a SLIP border router on imix board.
+//!
+//! This provides one Component, BorderRouterComponent, which forwards IPv6
+//! packets between the 6LoWPAN mesh on the radio and a host on a SLIP link.
+//! The SLIP link shares the console UART: a `SlipRouter` on the UART mux
+//! passes SLIP frames to the link and all other bytes to the console. Frames
+//! are sent in chunks, so console or debug output sent in between corrupts
+//! the frame, and the host drops it. The board therefore does not build the
+//! border router by default; enable it in `main.rs` on an image whose apps
+//! and kernel do not print.
+//!
+//! On the host, bridge the serial line to a tun interface and route the mesh
+//! prefix through it:
+//!
+//! ```text
+//! sudo tunslip6 -s /dev/ttyUSB0 -B 115200 fd00::1/64
+//! sudo ip -6 route add fd00:0:0:1::/64 dev tun0
+//! ```
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (radio_driver, mux_mac) =
+//!     RadioComponent::new(board_kernel, rf233, mux_aes, 0xABCD, 0x1008).finalize();
+//! let border_router = BorderRouterComponent::new(mux_mac, uart_mux).finalize();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::virtual_mac::{MacUser, MuxMac};
+use capsules::net::border_router::BorderRouter;
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::ipv6::ipv6_recv::{IP6RecvStruct, IP6Receiver};
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::sixlowpan::sixlowpan_compression;
+use capsules::net::sixlowpan::sixlowpan_state::{RxState, Sixlowpan, SixlowpanState, TxState};
+use capsules::net::slip::{self, Slip, SlipRouter};
+use capsules::net::udp::udp::UDPHeader;
+use capsules::virtual_uart::{UartDevice, UartMux};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::radio;
+use sam4l;
+
+/// Prefix of the mesh. Packets to other addresses are sent to the host.
+const MESH_PREFIX: IPAddr = IPAddr([
+    0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0,
+]);
+const MESH_PREFIX_LEN: u8 = 64;
+const ROUTER_ADDR: IPAddr = IPAddr([
+    0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0x01,
+]);
+
+const DEFAULT_CTX_PREFIX_LEN: u8 = 8;
+static DEFAULT_CTX_PREFIX: [u8; 16] = [0x0 as u8; 16];
+
+// Reassembles packets from the radio.
+static mut RX_STATE_BUF: [u8; slip::MTU] = [0x0; slip::MTU];
+// Frames sent on the radio.
+static mut RADIO_TX_BUF: [u8; radio::MAX_BUF_SIZE] = [0x0; radio::MAX_BUF_SIZE];
+// Payload of the packet being sent on the radio.
+static mut RADIO_PAYLOAD: [u8; slip::MTU] = [0x0; slip::MTU];
+
+pub struct BorderRouterComponent {
+    mux_mac: &'static MuxMac<'static>,
+    uart_mux: &'static UartMux<'static>,
+}
+
+impl BorderRouterComponent {
+    pub fn new(
+        mux_mac: &'static MuxMac<'static>,
+        uart_mux: &'static UartMux<'static>,
+    ) -> BorderRouterComponent {
+        BorderRouterComponent {
+            mux_mac: mux_mac,
+            uart_mux: uart_mux,
+        }
+    }
+}
+
+impl Component for BorderRouterComponent {
+    type Output = &'static BorderRouter<'static>;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        // Radio interface: 6LoWPAN on a MAC user of its own
+        let radio_mac = static_init!(MacUser<'static>, MacUser::new(self.mux_mac));
+        self.mux_mac.add_user(radio_mac);
+
+        let sixlowpan = static_init!(
+            Sixlowpan<'static, sam4l::ast::Ast<'static>, sixlowpan_compression::Context>,
+            Sixlowpan::new(
+                sixlowpan_compression::Context {
+                    prefix: DEFAULT_CTX_PREFIX,
+                    prefix_len: DEFAULT_CTX_PREFIX_LEN,
+                    id: 0,
+                    compress: false,
+                },
+                &sam4l::ast::AST
+            )
+        );
+        let sixlowpan_state = sixlowpan as &SixlowpanState;
+        let rx_state = static_init!(RxState<'static>, RxState::new(&mut RX_STATE_BUF));
+        sixlowpan_state.add_rx_state(rx_state);
+        radio_mac.set_receive_client(sixlowpan);
+
+        let radio_packet = static_init!(
+            IP6Packet<'static>,
+            IP6Packet::new(IPPayload::new(
+                TransportHeader::UDP(UDPHeader::new()),
+                &mut RADIO_PAYLOAD
+            ))
+        );
+        let ip6_send_radio = static_init!(
+            IP6SendStruct<'static>,
+            IP6SendStruct::new(
+                radio_packet,
+                &mut RADIO_TX_BUF,
+                TxState::new(sixlowpan_state),
+                radio_mac
+            )
+        );
+        radio_mac.set_transmit_client(ip6_send_radio);
+        ip6_send_radio.set_addr(ROUTER_ADDR);
+
+        let ip6_recv_radio = static_init!(IP6RecvStruct<'static>, IP6RecvStruct::new());
+        sixlowpan_state.set_rx_client(ip6_recv_radio);
+
+        // Serial interface: SLIP on a channel of the console UART
+        let slip_uart = static_init!(UartDevice<'static>, UartDevice::new(self.uart_mux, true));
+        slip_uart.setup();
+        slip_uart.set_channel(slip::SLIP_CHANNEL);
+        let slip_router = static_init!(SlipRouter, SlipRouter::new(slip::SLIP_CHANNEL));
+        self.uart_mux.set_router(slip_router);
+
+        let slip = static_init!(
+            Slip<'static>,
+            Slip::new(
+                slip_uart,
+                &mut slip::TX_PACKET,
+                &mut slip::TX_BUF,
+                &mut slip::RX_BUF,
+                &mut slip::RX_PACKET
+            )
+        );
+        hil::uart::UART::set_client(slip_uart, slip);
+
+        let border_router = static_init!(
+            BorderRouter<'static>,
+            BorderRouter::new(ip6_send_radio, slip, MESH_PREFIX, MESH_PREFIX_LEN)
+        );
+        border_router.set_addr(ROUTER_ADDR);
+        ip6_send_radio.set_client(border_router);
+        IP6Sender::set_client(slip, border_router);
+        ip6_recv_radio.set_client(border_router);
+        IP6Receiver::set_client(slip, border_router);
+        slip.start();
+
+        border_router
+    }
+}
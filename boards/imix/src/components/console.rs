@@ -66,15 +66,20 @@ impl Component for ConsoleComponent {
         // Create virtual device for kernel debug.
         let debugger_uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, false));
         debugger_uart.setup();
+        let debug_sink = static_init!(
+            kernel::debug::UartSink<'static>,
+            kernel::debug::UartSink::new(debugger_uart)
+        );
+        hil::uart::UART::set_client(debugger_uart, debug_sink);
         let debugger = static_init!(
             kernel::debug::DebugWriter,
             kernel::debug::DebugWriter::new(
-                debugger_uart,
+                debug_sink,
                 &mut kernel::debug::OUTPUT_BUF,
                 &mut kernel::debug::INTERNAL_BUF,
             )
         );
-        hil::uart::UART::set_client(debugger_uart, debugger);
+        kernel::debug::DebugSink::set_client(debug_sink, debugger);
 
         let debug_wrapper = static_init!(
             kernel::debug::DebugWriterWrapper,
@@ -0,0 +1,70 @@
+//! Component for the digest syscall interface on imix board.
+//!
+//! This provides one Component, DigestComponent, which implements a
+//! userspace syscall interface to SHA-256 and HMAC-SHA256, computed in
+//! software.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let digest = DigestComponent::new(board_kernel, mux_alarm).finalize();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::digest::Digest;
+use capsules::sha256::Sha256Software;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel;
+use kernel::component::Component;
+use kernel::hil::digest::{Digest as DigestTrait, SHA256_DIGEST_SIZE};
+use sam4l;
+
+type Sha256 = Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+pub struct DigestComponent {
+    board_kernel: &'static kernel::Kernel,
+    alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+}
+
+impl DigestComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux: &'static MuxAlarm<'static, sam4l::ast::Ast>,
+    ) -> DigestComponent {
+        DigestComponent {
+            board_kernel: board_kernel,
+            alarm_mux: mux,
+        }
+    }
+}
+
+// Kernel buffer that process data is copied through into the digest engine.
+static mut DIGEST_DATA_BUF: [u8; 128] = [0; 128];
+static mut DIGEST_BUF: [u8; SHA256_DIGEST_SIZE] = [0; SHA256_DIGEST_SIZE];
+
+impl Component for DigestComponent {
+    type Output = &'static Digest<Sha256>;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        let sha_alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let sha256 = static_init!(Sha256, Sha256Software::new(sha_alarm));
+        sha_alarm.set_client(sha256);
+
+        let digest = static_init!(
+            Digest<Sha256>,
+            Digest::new(
+                sha256,
+                &mut DIGEST_DATA_BUF,
+                &mut DIGEST_BUF,
+                self.board_kernel.create_grant()
+            )
+        );
+        sha256.set_client(digest);
+
+        digest
+    }
+}
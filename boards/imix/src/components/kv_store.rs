@@ -0,0 +1,84 @@
+//! Component for the key-value store on the imix board.
+//!
+//! This provides one Component, KVStoreComponent, which keeps a
+//! wear-levelled key-value store in the last 4 kB of on-chip flash and
+//! provides a system call interface to it.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let kv_store = KVStoreComponent::new(board_kernel, mux_flash).finalize();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules;
+use capsules::kv_store::{KVStore, KVStoreUser};
+use capsules::kv_store_driver::KVStoreDriver;
+use capsules::virtual_flash::{FlashUser, MuxFlash};
+use kernel;
+use kernel::component::Component;
+use kernel::hil;
+use sam4l;
+
+type KVFlash = FlashUser<'static, sam4l::flashcalw::FLASHCALW>;
+
+/// Start address of the store, after the userspace region of
+/// `NonvolatileStorageComponent`.
+const KV_STORE_START: usize = 0x7f000;
+
+/// Number of 512 byte pages in the store.
+const KV_STORE_PAGES: usize = 8;
+
+pub struct KVStoreComponent {
+    board_kernel: &'static kernel::Kernel,
+    mux_flash: &'static MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+}
+
+impl KVStoreComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux_flash: &'static MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+    ) -> KVStoreComponent {
+        KVStoreComponent {
+            board_kernel: board_kernel,
+            mux_flash: mux_flash,
+        }
+    }
+}
+
+impl Component for KVStoreComponent {
+    type Output = &'static KVStoreDriver<'static, KVFlash>;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        pub static mut KV_PAGEBUFFER: sam4l::flashcalw::Sam4lPage =
+            sam4l::flashcalw::Sam4lPage::new();
+        let kv_flash = static_init!(KVFlash, FlashUser::new(self.mux_flash));
+        let kv_store = static_init!(
+            KVStore<'static, KVFlash>,
+            KVStore::new(
+                kv_flash,
+                KV_STORE_START / 512,
+                KV_STORE_PAGES,
+                &mut capsules::kv_store::PAGES,
+                &mut capsules::kv_store::INDEX,
+                &mut KV_PAGEBUFFER
+            )
+        );
+        hil::flash::HasClient::set_client(kv_flash, kv_store);
+
+        let kv_user = static_init!(KVStoreUser<'static, KVFlash>, KVStoreUser::new(kv_store, 0));
+        let kv_store_driver = static_init!(
+            KVStoreDriver<'static, KVFlash>,
+            KVStoreDriver::new(
+                kv_user,
+                &mut capsules::kv_store_driver::BUFFER,
+                self.board_kernel.create_grant()
+            )
+        );
+        hil::kv_store::KVStore::set_client(kv_user, kv_store_driver);
+        kv_store.initialize();
+
+        kv_store_driver
+    }
+}
@@ -1,14 +1,19 @@
 pub mod adc;
+pub mod aes;
 pub mod alarm;
+pub mod border_router;
 pub mod button;
 pub mod console;
 pub mod crc;
+pub mod digest;
 pub mod fxos8700;
 pub mod gpio;
 pub mod isl29035;
+pub mod kv_store;
 pub mod led;
 pub mod nonvolatile_storage;
 pub mod nrf51822;
+pub mod process_info;
 pub mod radio;
 pub mod rf233;
 pub mod si7021;
@@ -16,16 +21,21 @@ pub mod spi;
 pub mod usb;
 
 pub use self::adc::AdcComponent;
+pub use self::aes::AesComponent;
 pub use self::alarm::AlarmDriverComponent;
+pub use self::border_router::BorderRouterComponent;
 pub use self::button::ButtonComponent;
 pub use self::console::ConsoleComponent;
 pub use self::crc::CrcComponent;
+pub use self::digest::DigestComponent;
 pub use self::fxos8700::NineDofComponent;
 pub use self::gpio::GpioComponent;
 pub use self::isl29035::Isl29035Component;
+pub use self::kv_store::KVStoreComponent;
 pub use self::led::LedComponent;
 pub use self::nonvolatile_storage::NonvolatileStorageComponent;
 pub use self::nrf51822::Nrf51822Component;
+pub use self::process_info::ProcessInfoComponent;
 pub use self::radio::RadioComponent;
 pub use self::rf233::RF233Component;
 pub use self::si7021::{HumidityComponent, SI7021Component, TemperatureComponent};
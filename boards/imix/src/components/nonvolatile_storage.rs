@@ -7,7 +7,7 @@
 //! Usage
 //! -----
 //! ```rust
-//! let nonvolatile_storage = NonvolatileStorageComponent::new().finalize();
+//! let nonvolatile_storage = NonvolatileStorageComponent::new(board_kernel, mux_flash).finalize();
 //! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
@@ -18,6 +18,7 @@
 use capsules;
 use capsules::nonvolatile_storage_driver::NonvolatileStorage;
 use capsules::nonvolatile_to_pages::NonvolatileToPages;
+use capsules::virtual_flash::{FlashUser, MuxFlash};
 use kernel;
 use kernel::component::Component;
 use kernel::hil;
@@ -25,12 +26,17 @@ use sam4l;
 
 pub struct NonvolatileStorageComponent {
     board_kernel: &'static kernel::Kernel,
+    mux_flash: &'static MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
 }
 
 impl NonvolatileStorageComponent {
-    pub fn new(board_kernel: &'static kernel::Kernel) -> Self {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux_flash: &'static MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+    ) -> Self {
         NonvolatileStorageComponent {
             board_kernel: board_kernel,
+            mux_flash: mux_flash,
         }
     }
 }
@@ -39,17 +45,17 @@ impl Component for NonvolatileStorageComponent {
     type Output = &'static NonvolatileStorage<'static>;
 
     unsafe fn finalize(&mut self) -> Self::Output {
-        sam4l::flashcalw::FLASH_CONTROLLER.configure();
         pub static mut FLASH_PAGEBUFFER: sam4l::flashcalw::Sam4lPage =
             sam4l::flashcalw::Sam4lPage::new();
+        let nv_flash = static_init!(
+            FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+            FlashUser::new(self.mux_flash)
+        );
         let nv_to_page = static_init!(
-            NonvolatileToPages<'static, sam4l::flashcalw::FLASHCALW>,
-            NonvolatileToPages::new(
-                &mut sam4l::flashcalw::FLASH_CONTROLLER,
-                &mut FLASH_PAGEBUFFER
-            )
+            NonvolatileToPages<'static, FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+            NonvolatileToPages::new(nv_flash, &mut FLASH_PAGEBUFFER)
         );
-        hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, nv_to_page);
+        hil::flash::HasClient::set_client(nv_flash, nv_to_page);
 
         extern "C" {
             /// Beginning on the ROM region containing app images.
@@ -69,13 +75,16 @@ impl Component for NonvolatileStorageComponent {
                 nv_to_page,
                 self.board_kernel.create_grant(),
                 0x60000,      // Start address for userspace accessible region
-                0x20000,      // Length of userspace accessible region
+                0x1f000,      // Length of userspace accessible region
+                0x2000,       // Default length of each app's region
                 kernel_start, // Start address of kernel region
                 kernel_len,   // Length of kernel region
-                &mut capsules::nonvolatile_storage_driver::BUFFER
+                &mut capsules::nonvolatile_storage_driver::BUFFER,
+                &mut capsules::nonvolatile_storage_driver::REGION_TABLE
             )
         );
         hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, nonvolatile_storage);
+        nonvolatile_storage.initialize();
         nonvolatile_storage
     }
 }
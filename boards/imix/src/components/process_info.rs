@@ -0,0 +1,42 @@
+//! Component for the process information syscall interface on imix board.
+//!
+//! This provides one Component, ProcessInfoComponent, which lets
+//! processes query read-only information about themselves and the other
+//! processes on the board.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let process_info = ProcessInfoComponent::new(board_kernel).finalize();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::process_info;
+use kernel;
+use kernel::component::Component;
+
+pub struct ProcessInfoComponent {
+    board_kernel: &'static kernel::Kernel,
+}
+
+impl ProcessInfoComponent {
+    pub fn new(board_kernel: &'static kernel::Kernel) -> ProcessInfoComponent {
+        ProcessInfoComponent {
+            board_kernel: board_kernel,
+        }
+    }
+}
+
+impl Component for ProcessInfoComponent {
+    type Output = &'static process_info::ProcessInfo;
+
+    unsafe fn finalize(&mut self) -> Self::Output {
+        let process_info = static_init!(
+            process_info::ProcessInfo,
+            process_info::ProcessInfo::new(self.board_kernel, self.board_kernel.create_grant())
+        );
+
+        process_info
+    }
+}
@@ -2,12 +2,13 @@
 //!
 //! This provides one Component, RadioComponent, which implements a
 //! userspace syscall interface to a full 802.15.4 stack with a
-//! always-on MAC implementation.
+//! always-on MAC implementation. It also returns the MAC mux, so that other
+//! users such as the border router can share the radio.
 //!
 //! Usage
 //! -----
 //! ```rust
-//! let radio = RadioComponetn::new().finalize();
+//! let (radio, mux_mac) = RadioComponent::new(board_kernel, rf233, mux_aes, pan_id, addr).finalize();
 //! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
@@ -18,6 +19,7 @@
 use capsules;
 use capsules::ieee802154::device::MacDevice;
 use capsules::ieee802154::mac::{AwakeMac, Mac};
+use capsules::virtual_aes::{MuxAES128, VirtualAES128Device};
 use capsules::virtual_spi::VirtualSpiMasterDevice;
 
 use kernel;
@@ -31,10 +33,12 @@ use sam4l;
 // Save some deep nesting
 type RF233Device =
     capsules::rf233::RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>;
+type AesDevice = VirtualAES128Device<'static, sam4l::aes::Aes<'static>>;
 
 pub struct RadioComponent {
     board_kernel: &'static kernel::Kernel,
     rf233: &'static RF233Device,
+    mux_aes: &'static MuxAES128<'static, sam4l::aes::Aes<'static>>,
     pan_id: capsules::net::ieee802154::PanID,
     short_addr: u16,
 }
@@ -43,12 +47,14 @@ impl RadioComponent {
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         rf233: &'static RF233Device,
+        mux_aes: &'static MuxAES128<'static, sam4l::aes::Aes<'static>>,
         pan_id: capsules::net::ieee802154::PanID,
         addr: u16,
     ) -> RadioComponent {
         RadioComponent {
             board_kernel: board_kernel,
             rf233: rf233,
+            mux_aes: mux_aes,
             pan_id: pan_id,
             short_addr: addr,
         }
@@ -68,15 +74,20 @@ const CRYPT_SIZE: usize = 3 * symmetric_encryption::AES128_BLOCK_SIZE + radio::M
 static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
 
 impl Component for RadioComponent {
-    type Output = &'static capsules::ieee802154::RadioDriver<'static>;
+    type Output = (
+        &'static capsules::ieee802154::RadioDriver<'static>,
+        &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+    );
 
     unsafe fn finalize(&mut self) -> Self::Output {
+        // The framer shares the AES engine with the AES syscall driver
+        let aes_device = static_init!(AesDevice, VirtualAES128Device::new(self.mux_aes));
         let aes_ccm = static_init!(
-            capsules::aes_ccm::AES128CCM<'static, sam4l::aes::Aes<'static>>,
-            capsules::aes_ccm::AES128CCM::new(&sam4l::aes::AES, &mut CRYPT_BUF)
+            capsules::aes_ccm::AES128CCM<'static, AesDevice>,
+            capsules::aes_ccm::AES128CCM::new(aes_device, &mut CRYPT_BUF)
         );
-        sam4l::aes::AES.set_client(aes_ccm);
-        sam4l::aes::AES.enable();
+        aes_device.set_client(aes_ccm);
+        aes_device.enable();
 
         // Keeps the radio on permanently; pass-through layer
         let awake_mac: &AwakeMac<RF233Device> =
@@ -88,7 +99,7 @@ impl Component for RadioComponent {
             capsules::ieee802154::framer::Framer<
                 'static,
                 AwakeMac<'static, RF233Device>,
-                capsules::aes_ccm::AES128CCM<'static, sam4l::aes::Aes<'static>>,
+                capsules::aes_ccm::AES128CCM<'static, AesDevice>,
             >,
             capsules::ieee802154::framer::Framer::new(awake_mac, aes_ccm)
         );
@@ -126,6 +137,6 @@ impl Component for RadioComponent {
         radio_mac.set_pan(self.pan_id);
         radio_mac.set_address(self.short_addr);
 
-        radio_driver
+        (radio_driver, mux_mac)
     }
 }
@@ -29,19 +29,24 @@ use kernel::hil::radio;
 #[allow(unused_imports)]
 use kernel::hil::radio::{RadioConfig, RadioData};
 use kernel::hil::spi::SpiMaster;
+use kernel::hil::symmetric_encryption::AES128;
 use kernel::hil::Controller;
 
 use components::adc::AdcComponent;
+use components::aes::AesComponent;
 use components::alarm::AlarmDriverComponent;
 use components::button::ButtonComponent;
 use components::console::ConsoleComponent;
 use components::crc::CrcComponent;
+use components::digest::DigestComponent;
 use components::fxos8700::NineDofComponent;
 use components::gpio::GpioComponent;
 use components::isl29035::AmbientLightComponent;
+use components::kv_store::KVStoreComponent;
 use components::led::LedComponent;
 use components::nonvolatile_storage::NonvolatileStorageComponent;
 use components::nrf51822::Nrf51822Component;
+use components::process_info::ProcessInfoComponent;
 use components::radio::RadioComponent;
 use components::rf233::RF233Component;
 use components::si7021::{HumidityComponent, SI7021Component, TemperatureComponent};
@@ -85,6 +90,10 @@ const NUM_PROCS: usize = 2;
 // how should the kernel respond when a process faults
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// How much of each process's memory is reserved for grants. Processes cannot
+// `brk` into this region, so capsules can always allocate at least this much.
+const MIN_GRANT_SIZE: usize = 0;
+
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 16384] = [0; 16384];
 
@@ -102,7 +111,10 @@ struct Imix {
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
     humidity: &'static capsules::humidity::HumiditySensor<'static>,
     ambient_light: &'static capsules::ambient_light::AmbientLight<'static>,
-    adc: &'static capsules::adc::Adc<'static, sam4l::adc::Adc>,
+    adc: &'static capsules::adc::Adc<
+        'static,
+        capsules::virtual_adc::AdcDevice<'static, sam4l::adc::Adc>,
+    >,
     led: &'static capsules::led::LED<'static, sam4l::gpio::GPIOPin>,
     button: &'static capsules::button::Button<'static, sam4l::gpio::GPIOPin>,
     spi: &'static capsules::spi::Spi<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>,
@@ -110,6 +122,16 @@ struct Imix {
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
+    aes: &'static capsules::aes::Aes<
+        capsules::virtual_aes::VirtualAES128Device<'static, sam4l::aes::Aes<'static>>,
+        capsules::aes_ccm::AES128CCM<
+            'static,
+            capsules::virtual_aes::VirtualAES128Device<'static, sam4l::aes::Aes<'static>>,
+        >,
+    >,
+    digest: &'static capsules::digest::Digest<
+        capsules::sha256::Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
+    >,
     usb_driver: &'static capsules::usb_user::UsbSyscallDriver<
         'static,
         capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
@@ -119,6 +141,11 @@ struct Imix {
         sam4l::usart::USART,
     >,
     nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    process_info: &'static capsules::process_info::ProcessInfo,
+    kv_store: &'static capsules::kv_store_driver::KVStoreDriver<
+        'static,
+        capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+    >,
 }
 
 // The RF233 radio stack requires our buffers for its SPI operations:
@@ -152,10 +179,14 @@ impl kernel::Platform for Imix {
             capsules::humidity::DRIVER_NUM => f(Some(self.humidity)),
             capsules::ninedof::DRIVER_NUM => f(Some(self.ninedof)),
             capsules::crc::DRIVER_NUM => f(Some(self.crc)),
+            capsules::aes::DRIVER_NUM => f(Some(self.aes)),
+            capsules::digest::DRIVER_NUM => f(Some(self.digest)),
             capsules::usb_user::DRIVER_NUM => f(Some(self.usb_driver)),
             capsules::ieee802154::DRIVER_NUM => f(Some(self.radio_driver)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
+            capsules::process_info::DRIVER_NUM => f(Some(self.process_info)),
+            capsules::kv_store_driver::DRIVER_NUM => f(Some(self.kv_store)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -273,6 +304,9 @@ pub unsafe fn reset_handler() {
 
     let console = ConsoleComponent::new(board_kernel, uart_mux, 115200).finalize();
 
+    // Report what the kernel was doing if the watchdog reset the board.
+    kernel::debug::report_crash_log();
+
     // Allow processes to communicate over BLE through the nRF51822
     let nrf_serialization =
         Nrf51822Component::new(&sam4l::usart::USART2, &sam4l::gpio::PB[07]).finalize();
@@ -318,18 +352,43 @@ pub unsafe fn reset_handler() {
     // sam4l::gpio::PC[16].enable_output();
     // sam4l::gpio::PC[16].clear();
 
-    let adc = AdcComponent::new().finalize();
+    let adc = AdcComponent::new(board_kernel).finalize();
     let gpio = GpioComponent::new().finalize();
     let led = LedComponent::new().finalize();
     let button = ButtonComponent::new(board_kernel).finalize();
     let crc = CrcComponent::new(board_kernel).finalize();
 
+    // AES engine, shared by the 802.15.4 framer and the AES syscall driver
+    let mux_aes = static_init!(
+        capsules::virtual_aes::MuxAES128<'static, sam4l::aes::Aes<'static>>,
+        capsules::virtual_aes::MuxAES128::new(&sam4l::aes::AES)
+    );
+    sam4l::aes::AES.set_client(mux_aes);
+    let aes = AesComponent::new(board_kernel, mux_aes).finalize();
+    let digest = DigestComponent::new(board_kernel, mux_alarm).finalize();
+
     // Can this initialize be pushed earlier, or into component? -pal
     rf233.initialize(&mut RF233_BUF, &mut RF233_REG_WRITE, &mut RF233_REG_READ);
-    let radio_driver = RadioComponent::new(board_kernel, rf233, 0xABCD, 0x1008).finalize();
+    let (radio_driver, _mux_mac) =
+        RadioComponent::new(board_kernel, rf233, mux_aes, 0xABCD, 0x1008).finalize();
+    // To forward IPv6 between the radio and a host on the console UART, build
+    // the border router. It shares the UART with the console, so keep console
+    // and debug output quiet while it runs.
+    //    let _border_router =
+    //        components::BorderRouterComponent::new(_mux_mac, uart_mux).finalize();
 
     let usb_driver = UsbComponent::new(board_kernel).finalize();
-    let nonvolatile_storage = NonvolatileStorageComponent::new(board_kernel).finalize();
+
+    // On-chip flash, shared by nonvolatile storage and the key-value store
+    sam4l::flashcalw::FLASH_CONTROLLER.configure();
+    let mux_flash = static_init!(
+        capsules::virtual_flash::MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::virtual_flash::MuxFlash::new(&sam4l::flashcalw::FLASH_CONTROLLER)
+    );
+    hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, mux_flash);
+    let nonvolatile_storage = NonvolatileStorageComponent::new(board_kernel, mux_flash).finalize();
+    let kv_store = KVStoreComponent::new(board_kernel, mux_flash).finalize();
+    let process_info = ProcessInfoComponent::new(board_kernel).finalize();
 
     let imix = Imix {
         console: console,
@@ -342,6 +401,8 @@ pub unsafe fn reset_handler() {
         led: led,
         button: button,
         crc: crc,
+        aes: aes,
+        digest: digest,
         spi: spi_syscalls,
         ipc: kernel::ipc::IPC::new(board_kernel),
         ninedof: ninedof,
@@ -349,6 +410,8 @@ pub unsafe fn reset_handler() {
         usb_driver: usb_driver,
         nrf51822: nrf_serialization,
         nonvolatile_storage: nonvolatile_storage,
+        process_info: process_info,
+        kv_store: kv_store,
     };
 
     let mut chip = sam4l::chip::Sam4l::new();
@@ -376,7 +439,8 @@ pub unsafe fn reset_handler() {
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
+        MIN_GRANT_SIZE,
     );
 
-    board_kernel.kernel_loop(&imix, &mut chip, Some(&imix.ipc));
+    board_kernel.kernel_loop(&imix, &mut chip, Some(&imix.ipc), None);
 }
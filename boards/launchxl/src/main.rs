@@ -16,6 +16,7 @@ use capsules::virtual_uart::{UartDevice, UartMux};
 use cc26x2::aon;
 use cc26x2::prcm;
 use kernel::hil;
+use kernel::hil::rng::Random;
 
 #[macro_use]
 pub mod io;
@@ -23,6 +24,10 @@ pub mod io;
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// How much of each process's memory is reserved for grants. Processes cannot
+// `brk` into this region, so capsules can always allocate at least this much.
+const MIN_GRANT_SIZE: usize = 0;
+
 // Number of concurrent processes this platform supports.
 const NUM_PROCS: usize = 2;
 static mut PROCESSES: [Option<&'static kernel::procs::Process<'static>>; NUM_PROCS] = [None, None];
@@ -45,7 +50,13 @@ pub struct Platform {
         'static,
         capsules::virtual_alarm::VirtualMuxAlarm<'static, cc26x2::rtc::Rtc>,
     >,
-    rng: &'static capsules::rng::SimpleRng<'static, cc26xx::trng::Trng>,
+    rng: &'static capsules::rng::SimpleRng<
+        'static,
+        capsules::hmac_drbg::HmacDrbg<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, cc26x2::rtc::Rtc>,
+        >,
+    >,
 }
 
 impl kernel::Platform for Platform {
@@ -162,15 +173,20 @@ pub unsafe fn reset_handler() {
     // Create virtual device for kernel debug.
     let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
     debugger_uart.setup();
+    let debug_sink = static_init!(
+        kernel::debug::UartSink<'static>,
+        kernel::debug::UartSink::new(debugger_uart)
+    );
+    hil::uart::UART::set_client(debugger_uart, debug_sink);
     let debugger = static_init!(
         kernel::debug::DebugWriter,
         kernel::debug::DebugWriter::new(
-            debugger_uart,
+            debug_sink,
             &mut kernel::debug::OUTPUT_BUF,
             &mut kernel::debug::INTERNAL_BUF,
         )
     );
-    hil::uart::UART::set_client(debugger_uart, debugger);
+    kernel::debug::DebugSink::set_client(debug_sink, debugger);
 
     let debug_wrapper = static_init!(
         kernel::debug::DebugWriterWrapper,
@@ -236,11 +252,32 @@ pub unsafe fn reset_handler() {
     );
     virtual_alarm1.set_client(alarm);
 
+    let drbg_alarm = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, cc26x2::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let drbg = static_init!(
+        capsules::hmac_drbg::HmacDrbg<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, cc26x2::rtc::Rtc>,
+        >,
+        capsules::hmac_drbg::HmacDrbg::new(Some(&cc26xx::trng::TRNG), drbg_alarm)
+    );
+    cc26xx::trng::TRNG.set_client(drbg);
+    drbg_alarm.set_client(drbg);
+    drbg.initialize();
+
     let rng = static_init!(
-        capsules::rng::SimpleRng<'static, cc26xx::trng::Trng>,
-        capsules::rng::SimpleRng::new(&cc26xx::trng::TRNG, board_kernel.create_grant())
+        capsules::rng::SimpleRng<
+            'static,
+            capsules::hmac_drbg::HmacDrbg<
+                'static,
+                capsules::virtual_alarm::VirtualMuxAlarm<'static, cc26x2::rtc::Rtc>,
+            >,
+        >,
+        capsules::rng::SimpleRng::new(drbg, board_kernel.create_grant())
     );
-    cc26xx::trng::TRNG.set_client(rng);
+    drbg.set_client(rng);
 
     let launchxl = Platform {
         console,
@@ -264,11 +301,13 @@ pub unsafe fn reset_handler() {
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
+        MIN_GRANT_SIZE,
     );
 
     board_kernel.kernel_loop(
         &launchxl,
         &mut chip,
         Some(&kernel::ipc::IPC::new(board_kernel)),
+        None,
     );
 }
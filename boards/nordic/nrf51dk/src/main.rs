@@ -87,6 +87,10 @@ const BUTTON4_PIN: usize = 20;
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// How much of each process's memory is reserved for grants. Processes cannot
+// `brk` into this region, so capsules can always allocate at least this much.
+const MIN_GRANT_SIZE: usize = 0;
+
 // Number of concurrent processes this platform supports.
 const NUM_PROCS: usize = 1;
 
@@ -271,15 +275,20 @@ pub unsafe fn reset_handler() {
     // Create virtual device for kernel debug.
     let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
     debugger_uart.setup();
+    let debug_sink = static_init!(
+        kernel::debug::UartSink<'static>,
+        kernel::debug::UartSink::new(debugger_uart)
+    );
+    hil::uart::UART::set_client(debugger_uart, debug_sink);
     let debugger = static_init!(
         kernel::debug::DebugWriter,
         kernel::debug::DebugWriter::new(
-            debugger_uart,
+            debug_sink,
             &mut kernel::debug::OUTPUT_BUF,
             &mut kernel::debug::INTERNAL_BUF,
         )
     );
-    hil::uart::UART::set_client(debugger_uart, debugger);
+    kernel::debug::DebugSink::set_client(debug_sink, debugger);
 
     let debug_wrapper = static_init!(
         kernel::debug::DebugWriterWrapper,
@@ -386,11 +395,13 @@ pub unsafe fn reset_handler() {
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
+        MIN_GRANT_SIZE,
     );
 
     board_kernel.kernel_loop(
         &platform,
         &mut chip,
         Some(&kernel::ipc::IPC::new(board_kernel)),
+        None,
     );
 }
@@ -83,6 +83,7 @@ pub struct Platform {
     // The nRF52dk does not have the flash chip on it, so we make this optional.
     nonvolatile_storage:
         Option<&'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>>,
+    log: Option<&'static capsules::log_driver::LogDriver<'static>>,
 }
 
 impl kernel::Platform for Platform {
@@ -102,6 +103,7 @@ impl kernel::Platform for Platform {
             capsules::nonvolatile_storage_driver::DRIVER_NUM => {
                 f(self.nonvolatile_storage.map_or(None, |nv| Some(nv)))
             }
+            capsules::log_driver::DRIVER_NUM => f(self.log.map_or(None, |log| Some(log))),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -127,6 +129,7 @@ pub unsafe fn setup_board(
         &'static kernel::procs::Process<'static>,
     >],
     app_fault_response: kernel::procs::FaultResponse,
+    app_min_grant_size: usize,
 ) {
     // Make non-volatile memory writable and activate the reset button
     let uicr = nrf52::uicr::Uicr::new();
@@ -231,15 +234,20 @@ pub unsafe fn setup_board(
     // Create virtual device for kernel debug.
     let debugger_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
     debugger_uart.setup();
+    let debug_sink = static_init!(
+        kernel::debug::UartSink<'static>,
+        kernel::debug::UartSink::new(debugger_uart)
+    );
+    hil::uart::UART::set_client(debugger_uart, debug_sink);
     let debugger = static_init!(
         kernel::debug::DebugWriter,
         kernel::debug::DebugWriter::new(
-            debugger_uart,
+            debug_sink,
             &mut kernel::debug::OUTPUT_BUF,
             &mut kernel::debug::INTERNAL_BUF,
         )
     );
-    hil::uart::UART::set_client(debugger_uart, debugger);
+    kernel::debug::DebugSink::set_client(debug_sink, debugger);
 
     let debug_wrapper = static_init!(
         kernel::debug::DebugWriterWrapper,
@@ -247,6 +255,9 @@ pub unsafe fn setup_board(
     );
     kernel::debug::set_debug_writer_wrapper(debug_wrapper);
 
+    // Report what the kernel was doing if the watchdog reset the board.
+    kernel::debug::report_crash_log();
+
     let ble_radio = static_init!(
         capsules::ble_advertising_driver::BLE<
             'static,
@@ -298,9 +309,10 @@ pub unsafe fn setup_board(
         nrf5x::pinmux::Pinmux::new(spi_pins.clk as u32),
     );
 
-    let nonvolatile_storage: Option<
-        &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
-    > = if let Some(driver) = mx25r6435f {
+    let (nonvolatile_storage, log): (
+        Option<&'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>>,
+        Option<&'static capsules::log_driver::LogDriver<'static>>,
+    ) = if let Some(driver) = mx25r6435f {
         // Create a SPI device for the mx25r6435f flash chip.
         let mx25r6435f_spi = static_init!(
             capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52::spi::SPIM>,
@@ -334,24 +346,35 @@ pub unsafe fn setup_board(
         mx25r6435f_spi.set_client(mx25r6435f);
         mx25r6435f_virtual_alarm.set_client(mx25r6435f);
 
+        type Mx25r6435f = capsules::mx25r6435f::MX25R6435F<
+            'static,
+            capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52::spi::SPIM>,
+            nrf5x::gpio::GPIOPin,
+            VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        >;
+        let mux_flash = static_init!(
+            capsules::virtual_flash::MuxFlash<'static, Mx25r6435f>,
+            capsules::virtual_flash::MuxFlash::new(mx25r6435f)
+        );
+        hil::flash::HasClient::set_client(mx25r6435f, mux_flash);
+
         pub static mut FLASH_PAGEBUFFER: capsules::mx25r6435f::Mx25r6435fSector =
             capsules::mx25r6435f::Mx25r6435fSector::new();
+        let nv_flash = static_init!(
+            capsules::virtual_flash::FlashUser<'static, Mx25r6435f>,
+            capsules::virtual_flash::FlashUser::new(mux_flash)
+        );
         let nv_to_page = static_init!(
             capsules::nonvolatile_to_pages::NonvolatileToPages<
                 'static,
-                capsules::mx25r6435f::MX25R6435F<
-                    'static,
-                    capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52::spi::SPIM>,
-                    nrf5x::gpio::GPIOPin,
-                    VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
-                >,
+                capsules::virtual_flash::FlashUser<'static, Mx25r6435f>,
             >,
             capsules::nonvolatile_to_pages::NonvolatileToPages::new(
-                mx25r6435f,
+                nv_flash,
                 &mut FLASH_PAGEBUFFER
             )
         );
-        hil::flash::HasClient::set_client(mx25r6435f, nv_to_page);
+        hil::flash::HasClient::set_client(nv_flash, nv_to_page);
 
         let nonvolatile_storage = static_init!(
             capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
@@ -360,15 +383,49 @@ pub unsafe fn setup_board(
                 board_kernel.create_grant(),
                 0x60000, // Start address for userspace accessible region
                 0x20000, // Length of userspace accessible region
+                0x2000,  // Default length of each app's region
                 0,       // Start address of kernel accessible region
                 0x60000, // Length of kernel accessible region
-                &mut capsules::nonvolatile_storage_driver::BUFFER
+                &mut capsules::nonvolatile_storage_driver::BUFFER,
+                &mut capsules::nonvolatile_storage_driver::REGION_TABLE
             )
         );
         hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, nonvolatile_storage);
-        Some(nonvolatile_storage)
+        nonvolatile_storage.initialize();
+
+        // Keep a log for userspace in the 128 kB after the nonvolatile
+        // storage regions.
+        pub static mut LOG_PAGEBUFFER: capsules::mx25r6435f::Mx25r6435fSector =
+            capsules::mx25r6435f::Mx25r6435fSector::new();
+        let log_flash = static_init!(
+            capsules::virtual_flash::FlashUser<'static, Mx25r6435f>,
+            capsules::virtual_flash::FlashUser::new(mux_flash)
+        );
+        let log = static_init!(
+            capsules::log::Log<'static, capsules::virtual_flash::FlashUser<'static, Mx25r6435f>>,
+            capsules::log::Log::new(
+                log_flash,
+                0x80000 / 4096, // First sector of the log
+                32,             // Number of sectors in the log
+                &mut capsules::log::PAGES,
+                &mut LOG_PAGEBUFFER
+            )
+        );
+        hil::flash::HasClient::set_client(log_flash, log);
+        let log_driver = static_init!(
+            capsules::log_driver::LogDriver<'static>,
+            capsules::log_driver::LogDriver::new(
+                log,
+                &mut capsules::log_driver::BUFFER,
+                board_kernel.create_grant()
+            )
+        );
+        hil::log::Log::set_client(log, log_driver);
+        log.initialize();
+
+        (Some(nonvolatile_storage), Some(log_driver))
     } else {
-        None
+        (None, None)
     };
 
     // Start all of the clocks. Low power operation will require a better
@@ -393,6 +450,7 @@ pub unsafe fn setup_board(
         temp: temp,
         alarm: alarm,
         nonvolatile_storage: nonvolatile_storage,
+        log: log,
         ipc: kernel::ipc::IPC::new(board_kernel),
     };
 
@@ -411,7 +469,8 @@ pub unsafe fn setup_board(
         app_memory,
         process_pointers,
         app_fault_response,
+        app_min_grant_size,
     );
 
-    board_kernel.kernel_loop(&platform, &mut chip, Some(&platform.ipc));
+    board_kernel.kernel_loop(&platform, &mut chip, Some(&platform.ipc), None);
 }
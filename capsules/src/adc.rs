@@ -1,6 +1,15 @@
 //! Provides userspace applications with the ability to sample
 //! analog signals.
 //!
+//! Each process has its own buffers and callback. Single samples requested
+//! by several processes are queued and taken one after another. Continuous
+//! and buffered sampling need the ADC to themselves, so they fail with
+//! `EBUSY` while any other sampling is in progress, and while they run other
+//! processes' requests fail with `EBUSY`.
+//!
+//! To share the ADC with kernel clients, instantiate this driver on top of a
+//! `capsules::virtual_adc::AdcDevice` instead of the ADC itself.
+//!
 //! Usage
 //! -----
 //!
@@ -23,7 +32,8 @@
 //!         adc_channels,
 //!         &mut capsules::adc::ADC_BUFFER1,
 //!         &mut capsules::adc::ADC_BUFFER2,
-//!         &mut capsules::adc::ADC_BUFFER3
+//!         &mut capsules::adc::ADC_BUFFER3,
+//!         kernel::Grant::create()
 //!     )
 //! );
 //! sam4l::adc::ADC0.set_client(adc);
@@ -31,15 +41,14 @@
 
 use core::cell::Cell;
 use core::cmp;
-use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
-use kernel::{AppId, AppSlice, Callback, Driver, ReturnCode, Shared};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x00000005;
 
 /// ADC application driver, used by applications to interact with ADC.
-/// Not currently virtualized, only one application can use it at a time.
 pub struct Adc<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> {
     // ADC driver
     adc: &'a A,
@@ -50,9 +59,11 @@ pub struct Adc<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> {
     mode: Cell<AdcMode>,
 
     // App state
-    app: MapCell<App>,
+    apps: Grant<App>,
+    appid: OptionalCell<AppId>,
+    // Application whose single sample was started last
+    last_served: OptionalCell<AppId>,
     channel: Cell<usize>,
-    callback: OptionalCell<Callback>,
     app_buf_offset: Cell<usize>,
     samples_remaining: Cell<usize>,
     samples_outstanding: Cell<usize>,
@@ -76,11 +87,14 @@ enum AdcMode {
     ContinuousBuffer = 3,
 }
 
-/// Holds buffers that the application has passed us
+/// Holds the callback and buffers that an application has passed us
 #[derive(Default)]
 pub struct App {
+    callback: Option<Callback>,
     app_buf1: Option<AppSlice<Shared, u8>>,
     app_buf2: Option<AppSlice<Shared, u8>>,
+    // channel of a single sample waiting for the ADC
+    pending_sample: Option<usize>,
 }
 
 /// Buffers to use for DMA transfers
@@ -99,12 +113,14 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
     /// channels - list of ADC channels usable by applications
     /// adc_buf1 - buffer used to hold ADC samples
     /// adc_buf2 - second buffer used when continuously sampling ADC
+    /// grant - per-application state
     pub fn new(
         adc: &'a A,
         channels: &'a [&'a <A as hil::adc::Adc>::Channel],
         adc_buf1: &'static mut [u16; 128],
         adc_buf2: &'static mut [u16; 128],
         adc_buf3: &'static mut [u16; 128],
+        grant: Grant<App>,
     ) -> Adc<'a, A> {
         Adc {
             // ADC driver
@@ -116,9 +132,10 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
             mode: Cell::new(AdcMode::NoMode),
 
             // App state
-            app: MapCell::new(App::default()),
+            apps: grant,
+            appid: OptionalCell::empty(),
+            last_served: OptionalCell::empty(),
             channel: Cell::new(0),
-            callback: OptionalCell::empty(),
             app_buf_offset: Cell::new(0),
             samples_remaining: Cell::new(0),
             samples_outstanding: Cell::new(0),
@@ -169,25 +186,56 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
         }
     }
 
+    /// Whether `appid` owns the current sampling operation
+    fn is_owner(&self, appid: AppId) -> bool {
+        self.appid.map_or(false, |owner| *owner == appid)
+    }
+
     /// Collect a single analog sample on a channel
+    /// If another application's single sample is in progress, the request is
+    /// queued behind it
     ///
     /// channel - index into `channels` array, which channel to sample
-    fn sample(&self, channel: usize) -> ReturnCode {
-        // only one sample at a time
-        if self.active.get() {
-            return ReturnCode::EBUSY;
-        }
-
+    /// appid - application requesting the sample
+    fn sample(&self, channel: usize, appid: AppId) -> ReturnCode {
         // convert channel index
         if channel >= self.channels.len() {
             return ReturnCode::EINVAL;
         }
+
+        if self.active.get() {
+            // only single samples from other applications can wait
+            if self.mode.get() != AdcMode::SingleSample || self.is_owner(appid) {
+                return ReturnCode::EBUSY;
+            }
+            return self
+                .apps
+                .enter(appid, |app, _| {
+                    if app.pending_sample.is_some() {
+                        ReturnCode::EBUSY
+                    } else {
+                        app.pending_sample = Some(channel);
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into());
+        }
+
+        self.start_sample(channel, appid)
+    }
+
+    /// Start a single sample for an application
+    ///
+    /// channel - index into `channels` array, must be valid
+    /// appid - application requesting the sample
+    fn start_sample(&self, channel: usize, appid: AppId) -> ReturnCode {
         let chan = self.channels[channel];
 
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::SingleSample);
         self.channel.set(channel);
+        self.appid.set(appid);
 
         // start a single sample
         let res = self.adc.sample(chan);
@@ -195,18 +243,58 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
             // failure, clear state
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
+            self.appid.clear();
 
             return res;
         }
 
+        self.last_served.set(appid);
         ReturnCode::SUCCESS
     }
 
+    /// Start the next queued single sample, if any
+    /// Applications are served in turn, starting after the one served last
+    /// Requests that fail to start are dropped
+    fn serve_pending_samples(&self) {
+        let start = self.last_served.map_or(0, |last| {
+            self.apps
+                .iter()
+                .position(|cntr| cntr.enter(|app, _| app.appid() == *last))
+                .map_or(0, |pos| pos + 1)
+        });
+        for cntr in self.apps.iter().skip(start).chain(self.apps.iter().take(start)) {
+            let started = cntr.enter(|app, _| {
+                app.pending_sample.take().map_or(false, |channel| {
+                    self.start_sample(channel, app.appid()) == ReturnCode::SUCCESS
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Schedule the callback of the application that owns the current
+    /// operation
+    /// Returns false if that application no longer exists
+    fn schedule_callback(&self, r0: usize, r1: usize, r2: usize) -> bool {
+        self.appid.map_or(false, |appid| {
+            self.apps
+                .enter(*appid, |app, _| {
+                    app.callback.map(|mut callback| {
+                        callback.schedule(r0, r1, r2);
+                    });
+                })
+                .is_ok()
+        })
+    }
+
     /// Collected repeated single analog samples on a channel
     ///
     /// channel - index into `channels` array, which channel to sample
     /// frequency - number of samples per second to collect
-    fn sample_continuous(&self, channel: usize, frequency: u32) -> ReturnCode {
+    /// appid - application requesting the samples
+    fn sample_continuous(&self, channel: usize, frequency: u32, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -222,6 +310,7 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
         self.active.set(true);
         self.mode.set(AdcMode::ContinuousSample);
         self.channel.set(channel);
+        self.appid.set(appid);
 
         // start a single sample
         let res = self.adc.sample_continuous(chan, frequency);
@@ -229,10 +318,12 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
             // failure, clear state
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
+            self.appid.clear();
 
             return res;
         }
 
+        self.last_served.set(appid);
         ReturnCode::SUCCESS
     }
 
@@ -242,7 +333,8 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
     ///
     /// channel - index into `channels` array, which channel to sample
     /// frequency - number of samples per second to collect
-    fn sample_buffer(&self, channel: usize, frequency: u32) -> ReturnCode {
+    /// appid - application requesting the samples
+    fn sample_buffer(&self, channel: usize, frequency: u32, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -255,20 +347,21 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
         let chan = self.channels[channel];
 
         // cannot sample a buffer without a buffer to sample into
-        let mut app_buf_length = 0;
-        let exists = self.app.map_or(false, |state| {
-            app_buf_length = state.app_buf1.as_mut().map_or(0, |buf| buf.len());
-            state.app_buf1.is_some()
-        });
-        if !exists {
-            return ReturnCode::ENOMEM;
-        }
+        let app_buf_length = self
+            .apps
+            .enter(appid, |state, _| state.app_buf1.as_ref().map(|buf| buf.len()))
+            .unwrap_or(None);
+        let app_buf_length = match app_buf_length {
+            Some(length) => length,
+            None => return ReturnCode::ENOMEM,
+        };
 
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::SingleBuffer);
         self.app_buf_offset.set(0);
         self.channel.set(channel);
+        self.appid.set(appid);
 
         // start a continuous sample
         let res = self.adc_buf1.take().map_or(ReturnCode::EBUSY, |buf1| {
@@ -311,12 +404,14 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
             // failure, clear state
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
+            self.appid.clear();
             self.samples_remaining.set(0);
             self.samples_outstanding.set(0);
 
             return res;
         }
 
+        self.last_served.set(appid);
         ReturnCode::SUCCESS
     }
 
@@ -327,7 +422,8 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
     ///
     /// channel - index into `channels` array, which channel to sample
     /// frequency - number of samples per second to collect
-    fn sample_buffer_continuous(&self, channel: usize, frequency: u32) -> ReturnCode {
+    /// appid - application requesting the samples
+    fn sample_buffer_continuous(&self, channel: usize, frequency: u32, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -340,22 +436,24 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
         let chan = self.channels[channel];
 
         // cannot continuously sample without two buffers
-        let mut app_buf_length = 0;
-        let mut next_app_buf_length = 0;
-        let exists = self.app.map_or(false, |state| {
-            app_buf_length = state.app_buf1.as_mut().map_or(0, |buf| buf.len());
-            next_app_buf_length = state.app_buf2.as_mut().map_or(0, |buf| buf.len());
-            state.app_buf1.is_some() && state.app_buf2.is_some()
-        });
-        if !exists {
-            return ReturnCode::ENOMEM;
-        }
+        let app_buf_lengths = self
+            .apps
+            .enter(appid, |state, _| match (&state.app_buf1, &state.app_buf2) {
+                (Some(buf1), Some(buf2)) => Some((buf1.len(), buf2.len())),
+                _ => None,
+            })
+            .unwrap_or(None);
+        let (app_buf_length, next_app_buf_length) = match app_buf_lengths {
+            Some(lengths) => lengths,
+            None => return ReturnCode::ENOMEM,
+        };
 
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::ContinuousBuffer);
         self.app_buf_offset.set(0);
         self.channel.set(channel);
+        self.appid.set(appid);
 
         // start a continuous sample
         let res = self.adc_buf1.take().map_or(ReturnCode::EBUSY, |buf1| {
@@ -412,19 +510,42 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
             // failure, clear state
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
+            self.appid.clear();
             self.samples_remaining.set(0);
             self.samples_outstanding.set(0);
 
             return res;
         }
 
+        self.last_served.set(appid);
         ReturnCode::SUCCESS
     }
 
-    /// Stops sampling the ADC
+    /// Stops sampling the ADC for an application
+    /// Cancels the application's queued sample or active operation. No
+    /// additional callbacks will occur. Operations of other applications are
+    /// not affected
+    ///
+    /// appid - application to stop sampling for
+    fn stop_sampling(&self, appid: AppId) -> ReturnCode {
+        let _ = self.apps.enter(appid, |app, _| {
+            app.pending_sample = None;
+        });
+
+        if !self.is_owner(appid) {
+            // this application is not sampling
+            return ReturnCode::SUCCESS;
+        }
+
+        let rc = self.stop_active_operation();
+        self.serve_pending_samples();
+        rc
+    }
+
+    /// Stops the active operation of the ADC
     /// Any active operation by the ADC is canceled. No additional callbacks
     /// will occur. Also retrieves buffers from the ADC (if any)
-    fn stop_sampling(&self) -> ReturnCode {
+    fn stop_active_operation(&self) -> ReturnCode {
         if !self.active.get() || self.mode.get() == AdcMode::NoMode {
             // already inactive!
             return ReturnCode::SUCCESS;
@@ -434,6 +555,7 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
         self.active.set(false);
         self.mode.set(AdcMode::NoMode);
         self.app_buf_offset.set(0);
+        self.appid.clear();
 
         // actually cancel the operation
         let rc = self.adc.stop_sampling();
@@ -455,6 +577,245 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Adc<'a, A> {
         // return result
         rc
     }
+
+    /// Copy samples from a filled internal buffer into the current
+    /// application buffer, requesting more samples from the ADC as needed
+    /// and performing a callback when the application buffer is full.
+    ///
+    /// state - state of the application that owns the operation
+    /// buf - internal buffer filled with analog samples, taken when the
+    ///       samples are copied
+    /// length - number of valid samples in the buffer
+    fn copy_samples(&self, state: &mut App, buf: &mut Option<&'static mut [u16]>, length: usize) {
+        let callback = &mut state.callback;
+
+        // determine which app buffer to copy data into and which is
+        // next up if we're in continuous mode
+        let app_buf;
+        let next_app_buf;
+        if self.using_app_buf1.get() {
+            app_buf = state.app_buf1.as_mut();
+            next_app_buf = state.app_buf2.as_ref();
+        } else {
+            app_buf = state.app_buf2.as_mut();
+            next_app_buf = state.app_buf1.as_ref();
+        }
+
+        // update count of outstanding sample requests
+        self.samples_outstanding
+            .set(self.samples_outstanding.get() - length);
+
+        // provide a new buffer and length request to the ADC if
+        // necessary. If we haven't received enough samples for the
+        // current app_buffer, we may need to place more requests. If we
+        // have received enough, but are in continuous mode, we should
+        // place a request for the next app_buffer. This is all
+        // unfortunately made more complicated by the fact that there is
+        // always one outstanding request to the ADC.
+        let perform_callback;
+        if self.samples_remaining.get() == 0 {
+            // we have already placed outstanding requests for all the
+            // samples needed to fill the current app_buffer
+
+            if self.samples_outstanding.get() == 0 {
+                // and the samples we just received are the last ones
+                // we need
+                perform_callback = true;
+
+                if self.mode.get() == AdcMode::ContinuousBuffer {
+                    // it's time to switch to the next app_buffer, but
+                    // there's already an outstanding request to the ADC
+                    // for the next app_buffer that was placed last
+                    // time, so we need to account for that
+                    let samples_needed = next_app_buf.map_or(0, |buf| buf.len() / 2);
+                    self.samples_remaining
+                        .set(samples_needed - self.next_samples_outstanding.get());
+                    self.samples_outstanding
+                        .set(self.next_samples_outstanding.get());
+                    self.using_app_buf1.set(!self.using_app_buf1.get());
+
+                    // we also need to place our next request, however
+                    // the outstanding request already placed for the
+                    // next app_buffer might have completed it! So we
+                    // have to account for that case
+                    if self.samples_remaining.get() == 0 {
+                        // oh boy. We actually need to place a request
+                        // for the next next app_buffer (which is
+                        // actually the current app_buf, but try not to
+                        // think about that...). In practice, this
+                        // should be a pretty uncommon case to hit, only
+                        // occurring if the length of the app buffers
+                        // are smaller than the length of the adc
+                        // buffers, which is unsustainable at high
+                        // sampling frequencies
+                        let next_next_app_buf = &app_buf;
+
+                        // provide a new buffer. However, we cannot
+                        // currently update state since the next
+                        // app_buffer still has a request outstanding.
+                        // We'll just make a request and handle the
+                        // state updating on next callback
+                        self.take_and_map_buffer(|adc_buf| {
+                            let samples_needed =
+                                next_next_app_buf.as_ref().map_or(0, |buf| buf.len() / 2);
+                            let request_len = cmp::min(samples_needed, adc_buf.len());
+                            self.next_samples_outstanding.set(request_len);
+                            let (res, retbuf) =
+                                self.adc.provide_buffer(adc_buf, request_len);
+                            if res != ReturnCode::SUCCESS {
+                                retbuf.map(|buf| {
+                                    self.replace_buffer(buf);
+                                });
+                            }
+                        });
+                    } else {
+                        // okay, we still need more samples for the next
+                        // app_buffer
+
+                        // provide a new buffer and update state
+                        self.take_and_map_buffer(|adc_buf| {
+                            let request_len =
+                                cmp::min(self.samples_remaining.get(), adc_buf.len());
+                            self.samples_remaining
+                                .set(self.samples_remaining.get() - request_len);
+                            self.samples_outstanding
+                                .set(self.samples_outstanding.get() + request_len);
+                            let (res, retbuf) =
+                                self.adc.provide_buffer(adc_buf, request_len);
+                            if res != ReturnCode::SUCCESS {
+                                retbuf.map(|buf| {
+                                    self.replace_buffer(buf);
+                                });
+                            }
+                        });
+                    }
+                }
+            } else {
+                // but there are still outstanding samples for the
+                // current app_buffer (actually exactly one request, the
+                // one the ADC is currently acting on)
+                perform_callback = false;
+
+                if self.mode.get() == AdcMode::ContinuousBuffer {
+                    // we're in continuous mode, so we need to start the
+                    // first request for the next app_buffer
+
+                    // provide a new buffer. However, we cannot
+                    // currently update state since the current
+                    // app_buffer still has a request outstanding. We'll
+                    // just make a request and handle the state updating
+                    // on next callback
+                    self.take_and_map_buffer(|adc_buf| {
+                        let samples_needed = next_app_buf.map_or(0, |buf| buf.len() / 2);
+                        let request_len = cmp::min(samples_needed, adc_buf.len());
+                        self.next_samples_outstanding.set(request_len);
+                        let (res, retbuf) = self.adc.provide_buffer(adc_buf, request_len);
+                        if res != ReturnCode::SUCCESS {
+                            retbuf.map(|buf| {
+                                self.replace_buffer(buf);
+                            });
+                        }
+                    });
+                }
+            }
+        } else {
+            // we need to get more samples from the current app_buffer
+            perform_callback = false;
+
+            // provide a new buffer and update state
+            self.take_and_map_buffer(|adc_buf| {
+                let request_len = cmp::min(self.samples_remaining.get(), adc_buf.len());
+                self.samples_remaining
+                    .set(self.samples_remaining.get() - request_len);
+                self.samples_outstanding
+                    .set(self.samples_outstanding.get() + request_len);
+                let (res, retbuf) = self.adc.provide_buffer(adc_buf, request_len);
+                if res != ReturnCode::SUCCESS {
+                    retbuf.map(|buf| {
+                        self.replace_buffer(buf);
+                    });
+                }
+            });
+        }
+
+        // next we should copy bytes to the app buffer
+        app_buf.map(|app_buf| {
+            // copy bytes to app buffer
+            // first, regain ownership of the buffer and then iterate
+            // over the data
+            buf.take().map(|buf| {
+                self.replace_buffer(buf).map(|adc_buf| {
+                    // The `for` commands:
+                    //  * `chunks_mut`: get sets of two bytes from the app
+                    //                  buffer
+                    //  * `skip`: skips the already written bytes from the
+                    //            app buffer
+                    //  * `zip`: ties that iterator to an iterator on the
+                    //           adc buffer, limiting iteration length to
+                    //           the minimum of each of their lengths
+                    //  * `take`: limits us to the minimum of buffer lengths
+                    //            or sample length
+                    // We then split each sample into its two bytes and copy
+                    // them to the app buffer
+                    for (chunk, &sample) in app_buf
+                        .chunks_mut(2)
+                        .skip(self.app_buf_offset.get() / 2)
+                        .zip(adc_buf.iter())
+                        .take(length)
+                    {
+                        let mut val = sample;
+                        for byte in chunk.iter_mut() {
+                            *byte = (val & 0xFF) as u8;
+                            val = val >> 8;
+                        }
+                    }
+
+                    // update our byte offset based on how many samples we
+                    // copied
+                    self.app_buf_offset
+                        .set(self.app_buf_offset.get() + length * 2);
+                });
+            });
+
+            // if the app_buffer is filled, perform callback
+            if perform_callback {
+                // actually schedule the callback
+                callback.as_mut().map(|callback| {
+                    let len_chan = ((app_buf.len() / 2) << 8) | (self.channel.get() & 0xFF);
+                    callback.schedule(
+                        self.mode.get() as usize,
+                        len_chan,
+                        app_buf.ptr() as usize,
+                    );
+                });
+
+                // if the mode is SingleBuffer, the operation is
+                // complete. Clean up state
+                if self.mode.get() == AdcMode::SingleBuffer {
+                    self.active.set(false);
+                    self.mode.set(AdcMode::NoMode);
+                    self.app_buf_offset.set(0);
+                    self.appid.clear();
+
+                    // need to actually stop sampling
+                    self.adc.stop_sampling();
+
+                    // reclaim buffers and store them
+                    let (_, buf1, buf2) = self.adc.retrieve_buffers();
+                    buf1.map(|buf| {
+                        self.replace_buffer(buf);
+                    });
+                    buf2.map(|buf| {
+                        self.replace_buffer(buf);
+                    });
+                } else {
+                    // if the mode is ContinuousBuffer, we've just
+                    // switched app buffers. Reset our offset to zero
+                    self.app_buf_offset.set(0);
+                }
+            }
+        });
+    }
 }
 
 /// Callbacks from the ADC driver
@@ -465,34 +826,38 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for Adc<'a, A>
     /// sample - analog sample value
     fn sample_ready(&self, sample: u16) {
         if self.active.get() && self.mode.get() == AdcMode::SingleSample {
-            // single sample complete, clean up state
+            // perform callback
+            self.schedule_callback(
+                AdcMode::SingleSample as usize,
+                self.channel.get(),
+                sample as usize,
+            );
+
+            // single sample complete, clean up state and move on to the
+            // next application waiting for a sample
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
-
-            // perform callback
-            self.callback.map(|callback| {
-                callback.schedule(
-                    AdcMode::SingleSample as usize,
-                    self.channel.get(),
-                    sample as usize,
-                );
-            });
+            self.appid.clear();
+            self.serve_pending_samples();
         } else if self.active.get() && self.mode.get() == AdcMode::ContinuousSample {
             // sample ready in continuous sampling operation, keep state
 
-            // perform callback
-            self.callback.map(|callback| {
-                callback.schedule(
-                    AdcMode::ContinuousSample as usize,
-                    self.channel.get(),
-                    sample as usize,
-                );
-            });
+            // perform callback, and stop sampling if the application is gone
+            let delivered = self.schedule_callback(
+                AdcMode::ContinuousSample as usize,
+                self.channel.get(),
+                sample as usize,
+            );
+            if !delivered {
+                self.stop_active_operation();
+                self.serve_pending_samples();
+            }
         } else {
             // operation probably canceled. Make sure state is consistent. No
             // callback
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
+            self.appid.clear();
         }
     }
 }
@@ -515,232 +880,26 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for Ad
             && (self.mode.get() == AdcMode::SingleBuffer
                 || self.mode.get() == AdcMode::ContinuousBuffer)
         {
-            // we did expect a buffer. Determine the current application state
-            self.app.map(|state| {
-                // determine which app buffer to copy data into and which is
-                // next up if we're in continuous mode
-                let app_buf;
-                let next_app_buf;
-                if self.using_app_buf1.get() {
-                    app_buf = state.app_buf1.as_mut();
-                    next_app_buf = state.app_buf2.as_ref();
-                } else {
-                    app_buf = state.app_buf2.as_mut();
-                    next_app_buf = state.app_buf1.as_ref();
-                }
-
-                // update count of outstanding sample requests
-                self.samples_outstanding
-                    .set(self.samples_outstanding.get() - length);
-
-                // provide a new buffer and length request to the ADC if
-                // necessary. If we haven't received enough samples for the
-                // current app_buffer, we may need to place more requests. If we
-                // have received enough, but are in continuous mode, we should
-                // place a request for the next app_buffer. This is all
-                // unfortunately made more complicated by the fact that there is
-                // always one outstanding request to the ADC.
-                let perform_callback;
-                if self.samples_remaining.get() == 0 {
-                    // we have already placed outstanding requests for all the
-                    // samples needed to fill the current app_buffer
-
-                    if self.samples_outstanding.get() == 0 {
-                        // and the samples we just received are the last ones
-                        // we need
-                        perform_callback = true;
-
-                        if self.mode.get() == AdcMode::ContinuousBuffer {
-                            // it's time to switch to the next app_buffer, but
-                            // there's already an outstanding request to the ADC
-                            // for the next app_buffer that was placed last
-                            // time, so we need to account for that
-                            let samples_needed = next_app_buf.map_or(0, |buf| buf.len() / 2);
-                            self.samples_remaining
-                                .set(samples_needed - self.next_samples_outstanding.get());
-                            self.samples_outstanding
-                                .set(self.next_samples_outstanding.get());
-                            self.using_app_buf1.set(!self.using_app_buf1.get());
-
-                            // we also need to place our next request, however
-                            // the outstanding request already placed for the
-                            // next app_buffer might have completed it! So we
-                            // have to account for that case
-                            if self.samples_remaining.get() == 0 {
-                                // oh boy. We actually need to place a request
-                                // for the next next app_buffer (which is
-                                // actually the current app_buf, but try not to
-                                // think about that...). In practice, this
-                                // should be a pretty uncommon case to hit, only
-                                // occurring if the length of the app buffers
-                                // are smaller than the length of the adc
-                                // buffers, which is unsustainable at high
-                                // sampling frequencies
-                                let next_next_app_buf = &app_buf;
-
-                                // provide a new buffer. However, we cannot
-                                // currently update state since the next
-                                // app_buffer still has a request outstanding.
-                                // We'll just make a request and handle the
-                                // state updating on next callback
-                                self.take_and_map_buffer(|adc_buf| {
-                                    let samples_needed =
-                                        next_next_app_buf.as_ref().map_or(0, |buf| buf.len() / 2);
-                                    let request_len = cmp::min(samples_needed, adc_buf.len());
-                                    self.next_samples_outstanding.set(request_len);
-                                    let (res, retbuf) =
-                                        self.adc.provide_buffer(adc_buf, request_len);
-                                    if res != ReturnCode::SUCCESS {
-                                        retbuf.map(|buf| {
-                                            self.replace_buffer(buf);
-                                        });
-                                    }
-                                });
-                            } else {
-                                // okay, we still need more samples for the next
-                                // app_buffer
-
-                                // provide a new buffer and update state
-                                self.take_and_map_buffer(|adc_buf| {
-                                    let request_len =
-                                        cmp::min(self.samples_remaining.get(), adc_buf.len());
-                                    self.samples_remaining
-                                        .set(self.samples_remaining.get() - request_len);
-                                    self.samples_outstanding
-                                        .set(self.samples_outstanding.get() + request_len);
-                                    let (res, retbuf) =
-                                        self.adc.provide_buffer(adc_buf, request_len);
-                                    if res != ReturnCode::SUCCESS {
-                                        retbuf.map(|buf| {
-                                            self.replace_buffer(buf);
-                                        });
-                                    }
-                                });
-                            }
-                        }
-                    } else {
-                        // but there are still outstanding samples for the
-                        // current app_buffer (actually exactly one request, the
-                        // one the ADC is currently acting on)
-                        perform_callback = false;
-
-                        if self.mode.get() == AdcMode::ContinuousBuffer {
-                            // we're in continuous mode, so we need to start the
-                            // first request for the next app_buffer
-
-                            // provide a new buffer. However, we cannot
-                            // currently update state since the current
-                            // app_buffer still has a request outstanding. We'll
-                            // just make a request and handle the state updating
-                            // on next callback
-                            self.take_and_map_buffer(|adc_buf| {
-                                let samples_needed = next_app_buf.map_or(0, |buf| buf.len() / 2);
-                                let request_len = cmp::min(samples_needed, adc_buf.len());
-                                self.next_samples_outstanding.set(request_len);
-                                let (res, retbuf) = self.adc.provide_buffer(adc_buf, request_len);
-                                if res != ReturnCode::SUCCESS {
-                                    retbuf.map(|buf| {
-                                        self.replace_buffer(buf);
-                                    });
-                                }
-                            });
-                        }
-                    }
-                } else {
-                    // we need to get more samples from the current app_buffer
-                    perform_callback = false;
-
-                    // provide a new buffer and update state
-                    self.take_and_map_buffer(|adc_buf| {
-                        let request_len = cmp::min(self.samples_remaining.get(), adc_buf.len());
-                        self.samples_remaining
-                            .set(self.samples_remaining.get() - request_len);
-                        self.samples_outstanding
-                            .set(self.samples_outstanding.get() + request_len);
-                        let (res, retbuf) = self.adc.provide_buffer(adc_buf, request_len);
-                        if res != ReturnCode::SUCCESS {
-                            retbuf.map(|buf| {
-                                self.replace_buffer(buf);
-                            });
-                        }
-                    });
-                }
-
-                // next we should copy bytes to the app buffer
-                app_buf.map(move |app_buf| {
-                    // copy bytes to app buffer
-                    // first, regain ownership of the buffer and then iterate
-                    // over the data
-                    self.replace_buffer(buf).map(|adc_buf| {
-                        // The `for` commands:
-                        //  * `chunks_mut`: get sets of two bytes from the app
-                        //                  buffer
-                        //  * `skip`: skips the already written bytes from the
-                        //            app buffer
-                        //  * `zip`: ties that iterator to an iterator on the
-                        //           adc buffer, limiting iteration length to
-                        //           the minimum of each of their lengths
-                        //  * `take`: limits us to the minimum of buffer lengths
-                        //            or sample length
-                        // We then split each sample into its two bytes and copy
-                        // them to the app buffer
-                        for (chunk, &sample) in app_buf
-                            .chunks_mut(2)
-                            .skip(self.app_buf_offset.get() / 2)
-                            .zip(adc_buf.iter())
-                            .take(length)
-                        {
-                            let mut val = sample;
-                            for byte in chunk.iter_mut() {
-                                *byte = (val & 0xFF) as u8;
-                                val = val >> 8;
-                            }
-                        }
-
-                        // update our byte offset based on how many samples we
-                        // copied
-                        self.app_buf_offset
-                            .set(self.app_buf_offset.get() + length * 2);
-                    });
-
-                    // if the app_buffer is filled, perform callback
-                    if perform_callback {
-                        // actually schedule the callback
-                        self.callback.map(|callback| {
-                            let len_chan = ((app_buf.len() / 2) << 8) | (self.channel.get() & 0xFF);
-                            callback.schedule(
-                                self.mode.get() as usize,
-                                len_chan,
-                                app_buf.ptr() as usize,
-                            );
-                        });
-
-                        // if the mode is SingleBuffer, the operation is
-                        // complete. Clean up state
-                        if self.mode.get() == AdcMode::SingleBuffer {
-                            self.active.set(false);
-                            self.mode.set(AdcMode::NoMode);
-                            self.app_buf_offset.set(0);
-
-                            // need to actually stop sampling
-                            self.adc.stop_sampling();
+            // we did expect a buffer. Copy it to the current application
+            let mut buf = Some(buf);
+            let app_exists = self.appid.map_or(false, |appid| {
+                self.apps
+                    .enter(*appid, |state, _| {
+                        self.copy_samples(&mut **state, &mut buf, length);
+                    })
+                    .is_ok()
+            });
 
-                            // reclaim buffers and store them
-                            let (_, buf1, buf2) = self.adc.retrieve_buffers();
-                            buf1.map(|buf| {
-                                self.replace_buffer(buf);
-                            });
-                            buf2.map(|buf| {
-                                self.replace_buffer(buf);
-                            });
-                        } else {
-                            // if the mode is ContinuousBuffer, we've just
-                            // switched app buffers. Reset our offset to zero
-                            self.app_buf_offset.set(0);
-                        }
-                    }
-                });
+            // reclaim the buffer if no application buffer took the samples
+            buf.take().map(|buf| {
+                self.replace_buffer(buf);
             });
+
+            // stop sampling if the application is gone
+            if !app_exists {
+                self.stop_active_operation();
+                self.serve_pending_samples();
+            }
         } else {
             // operation was likely canceled. Make sure state is consistent. No
             // callback
@@ -759,12 +918,12 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for Adc<'a, A> {
     /// Provides access to a buffer from the application to store data in or
     /// read data from
     ///
-    /// _appid - application identifier, unused
+    /// appid - application identifier
     /// allow_num - which allow call this is
     /// slice - representation of application memory to copy data into
     fn allow(
         &self,
-        _appid: AppId,
+        appid: AppId,
         allow_num: usize,
         slice: Option<AppSlice<Shared, u8>>,
     ) -> ReturnCode {
@@ -772,21 +931,23 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for Adc<'a, A> {
             // Pass buffer for samples to go into
             0 => {
                 // set first buffer
-                self.app.map(|state| {
-                    state.app_buf1 = slice;
-                });
-
-                ReturnCode::SUCCESS
+                self.apps
+                    .enter(appid, |state, _| {
+                        state.app_buf1 = slice;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
             }
 
             // Pass a second buffer to be used for double-buffered continuous sampling
             1 => {
                 // set second buffer
-                self.app.map(|state| {
-                    state.app_buf2 = slice;
-                });
-
-                ReturnCode::SUCCESS
+                self.apps
+                    .enter(appid, |state, _| {
+                        state.app_buf2 = slice;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
             }
 
             // default
@@ -803,14 +964,18 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for Adc<'a, A> {
         &self,
         subscribe_num: usize,
         callback: Option<Callback>,
-        _app_id: AppId,
+        app_id: AppId,
     ) -> ReturnCode {
         match subscribe_num {
             // subscribe to ADC sample done (from all types of sampling)
             0 => {
                 // set callback
-                self.callback.insert(callback);
-                ReturnCode::SUCCESS
+                self.apps
+                    .enter(app_id, |state, _| {
+                        state.callback = callback;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
             }
 
             // default
@@ -822,13 +987,13 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for Adc<'a, A> {
     ///
     /// command_num - which command call this is
     /// data - value sent by the application, varying uses
-    /// _appid - application identifier, unused
+    /// appid - application identifier
     fn command(
         &self,
         command_num: usize,
         channel: usize,
         frequency: usize,
-        _appid: AppId,
+        appid: AppId,
     ) -> ReturnCode {
         match command_num {
             // check if present
@@ -837,19 +1002,19 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> Driver for Adc<'a, A> {
             },
 
             // Single sample on channel
-            1 => self.sample(channel),
+            1 => self.sample(channel, appid),
 
             // Repeated single samples on a channel
-            2 => self.sample_continuous(channel, frequency as u32),
+            2 => self.sample_continuous(channel, frequency as u32, appid),
 
             // Multiple sample on a channel
-            3 => self.sample_buffer(channel, frequency as u32),
+            3 => self.sample_buffer(channel, frequency as u32, appid),
 
             // Continuous buffered sampling on a channel
-            4 => self.sample_buffer_continuous(channel, frequency as u32),
+            4 => self.sample_buffer_continuous(channel, frequency as u32, appid),
 
             // Stop sampling
-            5 => self.stop_sampling(),
+            5 => self.stop_sampling(appid),
 
             // default
             _ => ReturnCode::ENOSUPPORT,
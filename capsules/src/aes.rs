@@ -0,0 +1,518 @@
+//! Provides userspace with AES-128 encryption and decryption in CTR, CBC and
+//! CCM* mode.
+//!
+//! Each process allows the driver a key, an IV (or CCM* nonce), and source
+//! and destination buffers, then requests an operation with a command. The
+//! driver serves one process at a time and queues requests from the others.
+//! CTR and CBC requests are processed in chunks the size of the driver's
+//! kernel buffer, so they can be as long as the process's buffers. CCM*
+//! requests are handed to `capsules::aes_ccm` whole, so they must fit in the
+//! kernel buffer.
+//!
+//! The driver is meant to run on `capsules::virtual_aes` devices, so that
+//! processes share the AES engine with kernel users such as the 802.15.4
+//! framer.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let aes_device = static_init!(
+//!     capsules::virtual_aes::VirtualAES128Device<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::virtual_aes::VirtualAES128Device::new(mux_aes)
+//! );
+//! let ccm_device = static_init!(
+//!     capsules::virtual_aes::VirtualAES128Device<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::virtual_aes::VirtualAES128Device::new(mux_aes)
+//! );
+//! let aes_ccm = static_init!(
+//!     capsules::aes_ccm::AES128CCM<'static, VirtualAES128Device<'static, sam4l::aes::Aes<'static>>>,
+//!     capsules::aes_ccm::AES128CCM::new(ccm_device, &mut CCM_CRYPT_BUF)
+//! );
+//! ccm_device.set_client(aes_ccm);
+//! let aes = static_init!(
+//!     capsules::aes::Aes<
+//!         VirtualAES128Device<'static, sam4l::aes::Aes<'static>>,
+//!         capsules::aes_ccm::AES128CCM<'static, VirtualAES128Device<'static, sam4l::aes::Aes<'static>>>,
+//!     >,
+//!     capsules::aes::Aes::new(aes_device, aes_ccm, &mut AES_BUF, kernel::Grant::create())
+//! );
+//! aes_device.set_client(aes);
+//! aes_device.enable();
+//! aes_ccm.set_client(aes);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::TakeCell;
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128, AES128CBC, AES128CCM, AES128Ctr, AES128_BLOCK_SIZE, AES128_KEY_SIZE, CCM_NONCE_LENGTH,
+};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use util::{AppQueue, QueuedApp};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x40000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request {
+    Ctr {
+        len: usize,
+    },
+    Cbc {
+        len: usize,
+        encrypting: bool,
+    },
+    Ccm {
+        a_len: usize,
+        m_len: usize,
+        encrypting: bool,
+    },
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    iv: Option<AppSlice<Shared, u8>>,
+    source: Option<AppSlice<Shared, u8>>,
+    dest: Option<AppSlice<Shared, u8>>,
+    ccm_mic_len: usize,
+    ccm_confidential: bool,
+
+    // If Some, the process is waiting for the engine or being served.
+    waiting: Option<Request>,
+}
+
+impl QueuedApp for App {
+    type Request = Request;
+
+    fn waiting(&mut self) -> &mut Option<Request> {
+        &mut self.waiting
+    }
+
+    fn callback(&mut self) -> &mut Option<Callback> {
+        &mut self.callback
+    }
+}
+
+pub struct Aes<
+    A: AES128<'static> + AES128Ctr + AES128CBC + 'static,
+    C: AES128CCM<'static> + 'static,
+> {
+    aes: &'static A,
+    ccm: &'static C,
+    apps: Grant<App>,
+    queue: AppQueue,
+    buffer: TakeCell<'static, [u8]>,
+
+    // Progress through the CTR or CBC request being served.
+    offset: Cell<usize>,
+    chunk_len: Cell<usize>,
+    iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    next_iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC, C: AES128CCM<'static>> Aes<A, C> {
+    pub fn new(
+        aes: &'static A,
+        ccm: &'static C,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> Aes<A, C> {
+        Aes {
+            aes: aes,
+            ccm: ccm,
+            apps: grant,
+            queue: AppQueue::new(),
+            buffer: TakeCell::new(buffer),
+            offset: Cell::new(0),
+            chunk_len: Cell::new(0),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            next_iv: Cell::new([0; AES128_BLOCK_SIZE]),
+        }
+    }
+
+    fn serve_waiting_apps(&self) {
+        self.queue.serve_next(&self.apps, |app, request| self.start_request(app, request));
+    }
+
+    fn start_request(&self, app: &mut App, request: Request) -> ReturnCode {
+        let key_ok = app
+            .key
+            .as_ref()
+            .map_or(false, |key| key.len() >= AES128_KEY_SIZE);
+        if !key_ok {
+            return ReturnCode::EINVAL;
+        }
+
+        match request {
+            Request::Ctr { len } | Request::Cbc { len, .. } => {
+                let source_len = app.source.as_ref().map_or(0, |source| source.len());
+                let dest_len = app.dest.as_ref().map_or(0, |dest| dest.len());
+                if len > source_len || len > dest_len {
+                    return ReturnCode::EINVAL;
+                }
+                match app.iv {
+                    Some(ref iv) if iv.len() >= AES128_BLOCK_SIZE => {
+                        let mut first_iv = [0; AES128_BLOCK_SIZE];
+                        first_iv.copy_from_slice(&iv.as_ref()[..AES128_BLOCK_SIZE]);
+                        self.iv.set(first_iv);
+                    }
+                    _ => return ReturnCode::EINVAL,
+                }
+                self.offset.set(0);
+
+                let res = app.key.as_ref().map_or(ReturnCode::EINVAL, |key| {
+                    self.aes.set_key(&key.as_ref()[..AES128_KEY_SIZE])
+                });
+                if res != ReturnCode::SUCCESS {
+                    return res;
+                }
+                self.crypt_next_chunk(app, request)
+            }
+            Request::Ccm {
+                a_len,
+                m_len,
+                encrypting,
+            } => self.start_ccm(app, a_len, m_len, encrypting),
+        }
+    }
+
+    /// Copy the next chunk of the process's source buffer into the kernel
+    /// buffer and start on it.
+    fn crypt_next_chunk(&self, app: &mut App, request: Request) -> ReturnCode {
+        let (len, cbc_encrypting) = match request {
+            Request::Ctr { len } => (len, None),
+            Request::Cbc { len, encrypting } => (len, Some(encrypting)),
+            Request::Ccm { .. } => return ReturnCode::FAIL,
+        };
+        let offset = self.offset.get();
+
+        self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            let max_chunk = (buffer.len() / AES128_BLOCK_SIZE) * AES128_BLOCK_SIZE;
+            let chunk_len = cmp::min(len - offset, max_chunk);
+            if chunk_len == 0 {
+                self.buffer.replace(buffer);
+                return ReturnCode::ENOMEM;
+            }
+            // The process may have allowed a shorter buffer since the
+            // request started.
+            let source_len = app.source.as_ref().map_or(0, |source| source.len());
+            if offset + chunk_len > source_len {
+                self.buffer.replace(buffer);
+                return ReturnCode::ESIZE;
+            }
+            app.source.as_ref().map(|source| {
+                buffer[..chunk_len].copy_from_slice(&source.as_ref()[offset..offset + chunk_len]);
+            });
+            if cbc_encrypting == Some(false) {
+                // Decrypting in place overwrites the ciphertext, whose last
+                // block is the IV of the next chunk.
+                let mut next_iv = [0; AES128_BLOCK_SIZE];
+                next_iv.copy_from_slice(&buffer[chunk_len - AES128_BLOCK_SIZE..chunk_len]);
+                self.next_iv.set(next_iv);
+            }
+            self.chunk_len.set(chunk_len);
+
+            let res = self.aes.set_iv(&self.iv.get());
+            if res != ReturnCode::SUCCESS {
+                self.buffer.replace(buffer);
+                return res;
+            }
+            match cbc_encrypting {
+                None => self.aes.set_mode_aes128ctr(true),
+                Some(encrypting) => self.aes.set_mode_aes128cbc(encrypting),
+            }
+            self.aes.start_message();
+            match self.aes.crypt(None, buffer, 0, chunk_len) {
+                None => ReturnCode::SUCCESS,
+                Some((res, _, buffer)) => {
+                    self.buffer.replace(buffer);
+                    res
+                }
+            }
+        })
+    }
+
+    /// Copy the process's CCM* message into the kernel buffer and hand it to
+    /// `aes_ccm`.
+    fn start_ccm(&self, app: &mut App, a_len: usize, m_len: usize, encrypting: bool) -> ReturnCode {
+        let nonce_ok = app
+            .iv
+            .as_ref()
+            .map_or(false, |iv| iv.len() >= CCM_NONCE_LENGTH);
+        if !nonce_ok {
+            return ReturnCode::EINVAL;
+        }
+        let total = a_len + m_len + app.ccm_mic_len;
+        if total > app.dest.as_ref().map_or(0, |dest| dest.len()) {
+            return ReturnCode::EINVAL;
+        }
+
+        let res = app.key.as_ref().map_or(ReturnCode::EINVAL, |key| {
+            self.ccm.set_key(&key.as_ref()[..AES128_KEY_SIZE])
+        });
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+        let res = app.iv.as_ref().map_or(ReturnCode::EINVAL, |iv| {
+            self.ccm.set_nonce(&iv.as_ref()[..CCM_NONCE_LENGTH])
+        });
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+
+        self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            if total > buffer.len() {
+                self.buffer.replace(buffer);
+                return ReturnCode::ESIZE;
+            }
+            app.dest.as_ref().map(|dest| {
+                buffer[..total].copy_from_slice(&dest.as_ref()[..total]);
+            });
+            let (res, buffer) = self.ccm.crypt(
+                buffer,
+                0,
+                a_len,
+                m_len,
+                app.ccm_mic_len,
+                app.ccm_confidential,
+                encrypting,
+            );
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            res
+        })
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC, C: AES128CCM<'static>> Driver for Aes<A, C> {
+    /// Share buffers with the driver.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The 16 byte key.
+    /// - `1`: The 16 byte IV or initial counter, or for CCM* the 13 byte
+    ///        nonce.
+    /// - `2`: Source buffer for CTR and CBC.
+    /// - `3`: Destination buffer for CTR and CBC, and the buffer CCM*
+    ///        operates on in place.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.key = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.iv = slice;
+                    ReturnCode::SUCCESS
+                }
+                2 => {
+                    app.source = slice;
+                    ReturnCode::SUCCESS
+                }
+                3 => {
+                    app.dest = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to operation completion.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when an operation finishes, with the status of the
+    ///        operation, the number of message bytes processed, and for
+    ///        CCM* decryption whether the authentication tag was valid.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Configure and start operations.
+    ///
+    /// The key, IV and buffers are read when the operation starts, which may
+    /// be after other processes' queued operations. A process may only have
+    /// one operation outstanding and gets `EBUSY` otherwise. If the process
+    /// allows a buffer that is too short while its operation is in progress,
+    /// the operation ends with `ESIZE`.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: CTR encrypt or decrypt `data` bytes from the source buffer into
+    ///        the destination buffer. `data` must be a multiple of 16.
+    /// - `2`: CBC encrypt `data` bytes from the source buffer into the
+    ///        destination buffer. `data` must be a multiple of 16.
+    /// - `3`: CBC decrypt, as command 2.
+    /// - `4`: Configure CCM*: `data` is the length of the authentication tag
+    ///        (0, 4, 6, 8, 10, 12, 14 or 16) and `data2` is non-zero if the
+    ///        message should be encrypted as well as authenticated.
+    /// - `5`: CCM* encrypt the destination buffer in place. It holds `data`
+    ///        bytes of additional data, followed by `data2` bytes of message,
+    ///        followed by room for the tag.
+    /// - `6`: CCM* decrypt the destination buffer in place, laid out as for
+    ///        command 5 with the received tag at the end.
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let request = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Request::Ctr { len: data },
+            2 | 3 => Request::Cbc {
+                len: data,
+                encrypting: command_num == 2,
+            },
+            4 => {
+                return match data {
+                    0 | 4 | 6 | 8 | 10 | 12 | 14 | 16 => self
+                        .apps
+                        .enter(appid, |app, _| {
+                            app.ccm_mic_len = data;
+                            app.ccm_confidential = data2 != 0;
+                            ReturnCode::SUCCESS
+                        })
+                        .unwrap_or_else(|err| err.into()),
+                    _ => ReturnCode::EINVAL,
+                }
+            }
+            5 | 6 => Request::Ccm {
+                a_len: data,
+                m_len: data2,
+                encrypting: command_num == 5,
+            },
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        match request {
+            Request::Ctr { len } | Request::Cbc { len, .. } => {
+                if len == 0 || len % AES128_BLOCK_SIZE != 0 {
+                    return ReturnCode::EINVAL;
+                }
+            }
+            Request::Ccm { .. } => {}
+        }
+
+        let res = self.queue.enqueue(&self.apps, appid, request);
+        if res == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        res
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC, C: AES128CCM<'static>>
+    symmetric_encryption::Client<'static> for Aes<A, C>
+{
+    fn crypt_done(&self, _: Option<&'static mut [u8]>, buffer: &'static mut [u8]) {
+        let offset = self.offset.get();
+        let chunk_len = self.chunk_len.get();
+
+        // The IV for the next chunk: CTR advances the counter by the number
+        // of blocks processed, CBC encryption continues from the last block
+        // of ciphertext it produced.
+        let mut ctr_iv = self.iv.get();
+        let mut carry = chunk_len / AES128_BLOCK_SIZE;
+        for byte in ctr_iv.iter_mut().rev() {
+            let sum = *byte as usize + (carry & 0xff);
+            *byte = sum as u8;
+            carry = (carry >> 8) + (sum >> 8);
+        }
+        let mut cbc_iv = [0; AES128_BLOCK_SIZE];
+        cbc_iv.copy_from_slice(&buffer[chunk_len - AES128_BLOCK_SIZE..chunk_len]);
+        self.buffer.replace(buffer);
+
+        // The status and length to end the request with, if it is over.
+        let result = self
+            .queue
+            .with_serving(&self.apps, |app, request| {
+                let (len, next_iv) = match request {
+                    Request::Ctr { len } => (len, ctr_iv),
+                    Request::Cbc { len, encrypting } => (
+                        len,
+                        if encrypting {
+                            cbc_iv
+                        } else {
+                            self.next_iv.get()
+                        },
+                    ),
+                    Request::Ccm { .. } => return Some((ReturnCode::FAIL, 0)),
+                };
+                let processed = offset + chunk_len;
+                if app.dest.as_ref().map_or(0, |dest| dest.len()) < processed {
+                    return Some((ReturnCode::ESIZE, offset));
+                }
+                self.buffer.map(|buffer| {
+                    app.dest.as_mut().map(|dest| {
+                        dest.as_mut()[offset..processed].copy_from_slice(&buffer[..chunk_len]);
+                    });
+                });
+
+                if processed < len {
+                    self.iv.set(next_iv);
+                    self.offset.set(processed);
+                    let res = self.crypt_next_chunk(app, request);
+                    if res == ReturnCode::SUCCESS {
+                        return None;
+                    }
+                    Some((res, processed))
+                } else {
+                    Some((ReturnCode::SUCCESS, len))
+                }
+            })
+            .unwrap_or(Some((ReturnCode::FAIL, 0)));
+
+        if let Some((res, len)) = result {
+            self.queue.finish(&self.apps, |_, _| (res, len, 0));
+            self.serve_waiting_apps();
+        }
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC, C: AES128CCM<'static>>
+    symmetric_encryption::CCMClient for Aes<A, C>
+{
+    fn crypt_done(&self, buffer: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        self.queue.finish(&self.apps, |app, request| match request {
+            Request::Ccm { a_len, m_len, .. } => {
+                let end = a_len + m_len + app.ccm_mic_len;
+                let mut res = res;
+                if res == ReturnCode::SUCCESS
+                    && app.dest.as_ref().map_or(0, |dest| dest.len()) < end
+                {
+                    res = ReturnCode::ESIZE;
+                }
+                if res == ReturnCode::SUCCESS {
+                    app.dest.as_mut().map(|dest| {
+                        dest.as_mut()[a_len..end].copy_from_slice(&buffer[a_len..end]);
+                    });
+                }
+                (res, m_len, tag_is_valid as usize)
+            }
+            _ => (ReturnCode::FAIL, 0, 0),
+        });
+        self.buffer.replace(buffer);
+        self.serve_waiting_apps();
+    }
+}
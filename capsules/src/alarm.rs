@@ -1,12 +1,27 @@
 //! Provides userspace applications with a alarm API.
+//!
+//! Each process has `NUM_ALARMS` independent alarms, each with its own
+//! expiration and, optionally, its own callback. Alarms are either one-shot or
+//! periodic. Periodic alarms are re-armed in the kernel one period after their
+//! previous expiration, not after the time the callback ran, so they do not
+//! drift. Commands 3 and 4 are the original single-alarm interface, and use
+//! alarm 0.
+//!
+//! Besides the wrapping 32-bit counter of the alarm, applications can read the
+//! upper 32 bits of a 64-bit monotonic clock on the same counter, if the board
+//! gives the driver one with `set_monotonic`.
 
 use core::cell::Cell;
-use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, Frequency, Monotonic};
 use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
 
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x00000000;
 
+/// Number of alarms each process can have armed at once.
+pub const NUM_ALARMS: usize = 4;
+
 #[derive(Copy, Clone, Debug)]
 enum Expiration {
     Disabled,
@@ -14,16 +29,30 @@ enum Expiration {
 }
 
 #[derive(Copy, Clone)]
-pub struct AlarmData {
+struct AppAlarm {
     expiration: Expiration,
+    // Ticks between expirations of a periodic alarm, or 0 for a one-shot
+    // alarm.
+    period: u32,
     callback: Option<Callback>,
 }
 
+#[derive(Copy, Clone)]
+pub struct AlarmData {
+    // Callback for alarms that do not have their own.
+    callback: Option<Callback>,
+    alarms: [AppAlarm; NUM_ALARMS],
+}
+
 impl Default for AlarmData {
     fn default() -> AlarmData {
         AlarmData {
-            expiration: Expiration::Disabled,
             callback: None,
+            alarms: [AppAlarm {
+                expiration: Expiration::Disabled,
+                period: 0,
+                callback: None,
+            }; NUM_ALARMS],
         }
     }
 }
@@ -33,6 +62,7 @@ pub struct AlarmDriver<'a, A: Alarm> {
     num_armed: Cell<usize>,
     app_alarm: Grant<AlarmData>,
     prev: Cell<u32>,
+    monotonic: OptionalCell<&'a Monotonic<Frequency = A::Frequency>>,
 }
 
 impl<A: Alarm> AlarmDriver<'a, A> {
@@ -42,6 +72,37 @@ impl<A: Alarm> AlarmDriver<'a, A> {
             num_armed: Cell::new(0),
             app_alarm: grant,
             prev: Cell::new(0),
+            monotonic: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the 64-bit clock that applications can read. Its lower 32 bits
+    /// must be the counter of the alarm, which is the case for a
+    /// `MonotonicClock` on a virtual alarm of the same `MuxAlarm`.
+    pub fn set_monotonic(&self, monotonic: &'a Monotonic<Frequency = A::Frequency>) {
+        self.monotonic.set(monotonic);
+    }
+
+    /// Arm alarm `id` of `app` to expire at `expiration`, every `period`
+    /// ticks if `period` is not 0.
+    fn arm(&self, app: &mut AlarmData, id: usize, expiration: u32, period: u32) {
+        // if previously unarmed, but now will become armed
+        if let Expiration::Disabled = app.alarms[id].expiration {
+            self.num_armed.set(self.num_armed.get() + 1);
+        }
+        app.alarms[id].expiration = Expiration::Abs(expiration);
+        app.alarms[id].period = period;
+    }
+
+    /// Disarm alarm `id` of `app`. Returns `EALREADY` if it is not armed.
+    fn disarm(&self, app: &mut AlarmData, id: usize) -> ReturnCode {
+        match app.alarms[id].expiration {
+            Expiration::Disabled => ReturnCode::EALREADY,
+            Expiration::Abs(_) => {
+                app.alarms[id].expiration = Expiration::Disabled;
+                self.num_armed.set(self.num_armed.get() - 1);
+                ReturnCode::SUCCESS
+            }
         }
     }
 
@@ -50,15 +111,19 @@ impl<A: Alarm> AlarmDriver<'a, A> {
         let mut next_alarm = u32::max_value();
         let mut next_dist = u32::max_value();
         for alarm in self.app_alarm.iter() {
-            alarm.enter(|alarm, _| match alarm.expiration {
-                Expiration::Abs(exp) => {
-                    let t_dist = exp.wrapping_sub(now);
-                    if next_dist > t_dist {
-                        next_alarm = exp;
-                        next_dist = t_dist;
+            alarm.enter(|alarm, _| {
+                for app_alarm in alarm.alarms.iter() {
+                    match app_alarm.expiration {
+                        Expiration::Abs(exp) => {
+                            let t_dist = exp.wrapping_sub(now);
+                            if next_dist > t_dist {
+                                next_alarm = exp;
+                                next_dist = t_dist;
+                            }
+                        }
+                        Expiration::Disabled => {}
                     }
                 }
-                Expiration::Disabled => {}
             });
         }
         if next_alarm != u32::max_value() {
@@ -73,18 +138,28 @@ impl<A: Alarm> AlarmDriver<'a, A> {
 impl<A: Alarm> Driver for AlarmDriver<'a, A> {
     /// Subscribe to alarm expiration
     ///
-    /// ### `_subscribe_num`
+    /// ### `subscribe_num`
     ///
-    /// - `0`: Subscribe to alarm expiration
+    /// - `0`: Subscribe to expiration of all alarms that have no callback of
+    ///   their own.
+    /// - `1` to `NUM_ALARMS`: Subscribe to expiration of alarm
+    ///   `subscribe_num - 1` only.
     fn subscribe(
         &self,
-        _subscribe_num: usize,
+        subscribe_num: usize,
         callback: Option<Callback>,
         app_id: AppId,
     ) -> ReturnCode {
+        if subscribe_num > NUM_ALARMS {
+            return ReturnCode::ENOSUPPORT;
+        }
         self.app_alarm
             .enter(app_id, |td, _allocator| {
-                td.callback = callback;
+                if subscribe_num == 0 {
+                    td.callback = callback;
+                } else {
+                    td.alarms[subscribe_num - 1].callback = callback;
+                }
                 ReturnCode::SUCCESS
             })
             .unwrap_or_else(|err| err.into())
@@ -94,22 +169,33 @@ impl<A: Alarm> Driver for AlarmDriver<'a, A> {
     ///
     /// ### `command_num`
     ///
-    /// - `0`: Driver check.
+    /// - `0`: Driver check. Returns the number of alarms per process.
     /// - `1`: Return the clock frequency in Hz.
     /// - `2`: Read the the current clock value
-    /// - `3`: Stop the alarm if it is outstanding
-    /// - `4`: Set an alarm to fire at a given clock value `time`.
-    fn command(&self, cmd_type: usize, data: usize, _: usize, caller_id: AppId) -> ReturnCode {
+    /// - `3`: Stop alarm 0 if it is outstanding and set to expire at `data`.
+    /// - `4`: Set alarm 0 to fire at a given clock value `time`.
+    /// - `5`: Read the upper 32 bits of the 64-bit clock. Read them again after
+    ///   reading the current clock value, and retry if they changed.
+    /// - `6`: Set alarm `data` to fire once at clock value `data2`.
+    /// - `7`: Set alarm `data` to fire every `data2` ticks, starting `data2`
+    ///   ticks from now. Periods must be at least 1 ms long.
+    /// - `8`: Stop alarm `data`.
+    fn command(&self, cmd_type: usize, data: usize, data2: usize, caller_id: AppId) -> ReturnCode {
         // Returns the error code to return to the user and whether we need to
         // reset which is the next active alarm. We only _don't_ reset if we're
         // disabling the underlying alarm anyway, if the underlying alarm is
         // currently disabled and we're enabling the first alarm, or on an error
         // (i.e. no change to the alarms).
+        if cmd_type >= 6 && data >= NUM_ALARMS {
+            return ReturnCode::EINVAL;
+        }
         self.app_alarm
             .enter(caller_id, |td, _alloc| {
                 let now = self.alarm.now();
                 let (return_code, reset) = match cmd_type {
-                    0 /* check if present */ => (ReturnCode::SuccessWithValue { value: 1 }, false),
+                    0 /* check if present */ => {
+                        (ReturnCode::SuccessWithValue { value: NUM_ALARMS }, false)
+                    },
                     1 /* Get clock frequency */ => {
                         let freq = <A::Frequency>::frequency() as usize;
                         (ReturnCode::SuccessWithValue { value: freq }, false)
@@ -120,32 +206,46 @@ impl<A: Alarm> Driver for AlarmDriver<'a, A> {
                     },
                     3 /* Stop */ => {
                         let alarm_id = data as u32;
-                        match td.expiration {
-                            Expiration::Disabled => {
-                                // Request to stop when already stopped
-                                (ReturnCode::EALREADY, false)
-                            },
+                        match td.alarms[0].expiration {
                             Expiration::Abs(exp) if exp != alarm_id => {
                                 // Request to stop invalid alarm id
                                 (ReturnCode::EINVAL, false)
                             },
                             _ => {
-                                td.expiration = Expiration::Disabled;
-                                let new_num_armed = self.num_armed.get() - 1;
-                                self.num_armed.set(new_num_armed);
-                                (ReturnCode::SUCCESS, true)
+                                let rc = self.disarm(td, 0);
+                                (rc, rc == ReturnCode::SUCCESS)
                             }
                         }
                     },
                     4 /* Set absolute expiration */ => {
                         let time = data;
-                        // if previously unarmed, but now will become armed
-                        if let Expiration::Disabled = td.expiration {
-                            self.num_armed.set(self.num_armed.get() + 1);
-                        }
-                        td.expiration = Expiration::Abs(time as u32);
+                        self.arm(td, 0, time as u32, 0);
                         (ReturnCode::SuccessWithValue { value: time }, true)
                     },
+                    5 /* capture upper bits of 64-bit time */ => {
+                        let rc = self.monotonic.map_or(ReturnCode::ENOSUPPORT, |monotonic| {
+                            let high = (monotonic.now().ticks() >> 32) as usize;
+                            ReturnCode::SuccessWithValue { value: high }
+                        });
+                        (rc, false)
+                    },
+                    6 /* Set absolute expiration of one alarm */ => {
+                        self.arm(td, data, data2 as u32, 0);
+                        (ReturnCode::SUCCESS, true)
+                    },
+                    7 /* Set period of one alarm */ => {
+                        let period = data2 as u32;
+                        if period < <A::Frequency>::frequency() / 1000 || period == 0 {
+                            (ReturnCode::EINVAL, false)
+                        } else {
+                            self.arm(td, data, now.wrapping_add(period), period);
+                            (ReturnCode::SUCCESS, true)
+                        }
+                    },
+                    8 /* Stop one alarm */ => {
+                        let rc = self.disarm(td, data);
+                        (rc, rc == ReturnCode::SUCCESS)
+                    },
                     _ => (ReturnCode::ENOSUPPORT, false)
                 };
                 if reset {
@@ -157,22 +257,30 @@ impl<A: Alarm> Driver for AlarmDriver<'a, A> {
     }
 }
 
-fn has_expired(alarm: u32, now: u32, prev: u32) -> bool {
-    now.wrapping_sub(prev) >= alarm.wrapping_sub(prev)
-}
-
 impl<A: Alarm> time::Client for AlarmDriver<'a, A> {
     fn fired(&self) {
         let now = self.alarm.now();
         self.app_alarm.each(|alarm| {
-            if let Expiration::Abs(exp) = alarm.expiration {
-                let expired = has_expired(exp, now, self.prev.get());
-                if expired {
-                    alarm.expiration = Expiration::Disabled;
-                    self.num_armed.set(self.num_armed.get() - 1);
-                    alarm
-                        .callback
-                        .map(|mut cb| cb.schedule(now as usize, exp as usize, 0));
+            let default_callback = alarm.callback;
+            for (id, app_alarm) in alarm.alarms.iter_mut().enumerate() {
+                if let Expiration::Abs(exp) = app_alarm.expiration {
+                    let expired = time::has_expired(exp, now, self.prev.get());
+                    if expired {
+                        if app_alarm.period == 0 {
+                            app_alarm.expiration = Expiration::Disabled;
+                            self.num_armed.set(self.num_armed.get() - 1);
+                        } else {
+                            // Re-arm one period after this expiration, skipping
+                            // any periods that have passed already.
+                            let periods = now.wrapping_sub(exp) / app_alarm.period + 1;
+                            let next = exp.wrapping_add(app_alarm.period.wrapping_mul(periods));
+                            app_alarm.expiration = Expiration::Abs(next);
+                        }
+                        app_alarm
+                            .callback
+                            .or(default_callback)
+                            .map(|mut cb| cb.schedule(now as usize, exp as usize, id));
+                    }
                 }
             }
         });
@@ -183,7 +291,7 @@ impl<A: Alarm> time::Client for AlarmDriver<'a, A> {
             self.alarm.disable();
         } else if let Some(next_alarm) = self.reset_active_alarm(now) {
             let new_now = self.alarm.now();
-            if has_expired(next_alarm, new_now, now) {
+            if time::has_expired(next_alarm, new_now, now) {
                 self.fired();
             }
         } else {
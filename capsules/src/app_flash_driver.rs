@@ -3,7 +3,12 @@
 //! All write requests from userland are checked to ensure that they are only
 //! trying to write their own flash space, and not the TBF header either.
 //!
-//! This driver can handle non page aligned writes.
+//! This driver can handle non page aligned writes. Writes longer than the
+//! internal buffer are split into chunks that end on multiples of the buffer
+//! length, so with a buffer as long as a flash page every chunk writes a
+//! single page. Writes can optionally be read back and compared with the
+//! app's buffer after each chunk. Ranges can also be erased, which sets them
+//! to `0xFF`.
 //!
 //! Userland apps should allocate buffers in flash when they are compiled to
 //! ensure that there is room to write to. This should be accomplished by
@@ -20,6 +25,7 @@
 //!         kernel::Grant::create(), &mut APP_FLASH_BUFFER));
 //! ```
 
+use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
@@ -28,12 +34,28 @@ use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x50000;
 
+/// Value of erased flash.
+const ERASED: u8 = 0xff;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Write,
+    Erase,
+}
+
+#[derive(Clone, Copy)]
+pub struct Request {
+    command: Command,
+    flash_address: usize,
+    length: usize,
+    verify: bool,
+}
+
 #[derive(Default)]
 pub struct App {
     callback: Option<Callback>,
     buffer: Option<AppSlice<Shared, u8>>,
-    pending_command: bool,
-    flash_address: usize,
+    pending_command: Option<Request>,
 }
 
 pub struct AppFlash<'a> {
@@ -41,6 +63,11 @@ pub struct AppFlash<'a> {
     apps: Grant<App>,
     current_app: OptionalCell<AppId>,
     buffer: TakeCell<'static, [u8]>,
+    // Request of the current app, bytes of it written so far, and length of
+    // the chunk being written.
+    current_request: Cell<Option<Request>>,
+    done: Cell<usize>,
+    chunk: Cell<usize>,
 }
 
 impl AppFlash<'a> {
@@ -54,105 +81,226 @@ impl AppFlash<'a> {
             apps: grant,
             current_app: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
+            current_request: Cell::new(None),
+            done: Cell::new(0),
+            chunk: Cell::new(0),
         }
     }
 
     // Check to see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending command
     // completes.
-    fn enqueue_write(&self, flash_address: usize, appid: AppId) -> ReturnCode {
-        self.apps
+    fn enqueue(&self, mut request: Request, appid: AppId) -> ReturnCode {
+        let res = self
+            .apps
             .enter(appid, |app, _| {
+                // Writes default to the whole buffer.
+                let buffer_length = app.buffer.as_ref().map_or(0, |app_buffer| app_buffer.len());
+                if request.command == Command::Write {
+                    if request.length == 0 {
+                        request.length = buffer_length;
+                    }
+                    if request.length > buffer_length {
+                        return ReturnCode::ESIZE;
+                    }
+                }
+
                 // Check that this is a valid range in the app's flash.
-                let flash_length = app.buffer.as_mut().map_or(0, |app_buffer| app_buffer.len());
                 let (app_flash_start, app_flash_end) = appid.get_editable_flash_range();
-                if flash_address < app_flash_start
-                    || flash_address >= app_flash_end
-                    || flash_address + flash_length >= app_flash_end
+                if request.length == 0
+                    || request.flash_address < app_flash_start
+                    || request.flash_address >= app_flash_end
+                    || request.length > app_flash_end - request.flash_address
                 {
                     return ReturnCode::EINVAL;
                 }
 
-                if self.current_app.is_none() {
-                    self.current_app.set(appid);
-
-                    app.buffer
-                        .as_mut()
-                        .map_or(ReturnCode::ERESERVE, |app_buffer| {
-                            // Copy contents to internal buffer and write it.
-                            self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
-                                let length = cmp::min(buffer.len(), app_buffer.len());
-                                let d = &mut app_buffer.as_mut()[0..length];
-                                for (i, c) in buffer.as_mut()[0..length].iter_mut().enumerate() {
-                                    *c = d[i];
-                                }
+                if app.pending_command.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                app.pending_command = Some(request);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+
+        if res == ReturnCode::SUCCESS {
+            self.start_next();
+        }
+        res
+    }
+
+    /// Start the command of the next waiting app, if none is running. Apps
+    /// whose command cannot be started are told so through their callback.
+    fn start_next(&self) {
+        if self.current_app.is_some() {
+            return;
+        }
+        for cntr in self.apps.iter() {
+            let (appid, request) = cntr.enter(|app, _| (app.appid(), app.pending_command));
+            if let Some(request) = request {
+                self.current_app.set(appid);
+                self.current_request.set(Some(request));
+                self.done.set(0);
+                let res = self.write_chunk();
+                if res == ReturnCode::SUCCESS {
+                    break;
+                }
+                self.finish(res);
+            }
+        }
+    }
+
+    /// Write the next chunk of the current request.
+    fn write_chunk(&self) -> ReturnCode {
+        let request = match self.current_request.get() {
+            Some(request) => request,
+            None => return ReturnCode::FAIL,
+        };
+        let done = self.done.get();
+        let address = request.flash_address + done;
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            let length = cmp::min(request.length - done, buffer.len() - address % buffer.len());
+            let res = self.fill_expected(&mut buffer[..length], request);
+            if res != ReturnCode::SUCCESS {
+                self.buffer.replace(buffer);
+                return res;
+            }
+            self.chunk.set(length);
+            self.driver.write(buffer, address, length)
+        })
+    }
 
-                                self.driver.write(buffer, flash_address, length)
+    /// Fill `buffer` with what the current chunk should contain once written.
+    fn fill_expected(&self, buffer: &mut [u8], request: Request) -> ReturnCode {
+        match request.command {
+            Command::Erase => {
+                for c in buffer.iter_mut() {
+                    *c = ERASED;
+                }
+                ReturnCode::SUCCESS
+            }
+            Command::Write => self.current_app.map_or(ReturnCode::FAIL, |appid| {
+                let done = self.done.get();
+                self.apps
+                    .enter(*appid, |app, _| {
+                        app.buffer
+                            .as_ref()
+                            .map_or(ReturnCode::ERESERVE, |app_buffer| {
+                                let app_buffer = app_buffer.as_ref();
+                                let length = buffer.len();
+                                if done + length > app_buffer.len() {
+                                    return ReturnCode::ESIZE;
+                                }
+                                buffer.copy_from_slice(&app_buffer[done..done + length]);
+                                ReturnCode::SUCCESS
                             })
-                        })
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }),
+        }
+    }
+
+    /// Check a chunk read back after writing it.
+    fn verify_chunk(&self, buffer: &[u8]) -> ReturnCode {
+        let request = match self.current_request.get() {
+            Some(request) => request,
+            None => return ReturnCode::FAIL,
+        };
+        let done = self.done.get();
+        match request.command {
+            Command::Erase => {
+                if buffer.iter().all(|&c| c == ERASED) {
+                    ReturnCode::SUCCESS
                 } else {
-                    // Queue this request for later.
-                    if app.pending_command == true {
-                        ReturnCode::ENOMEM
-                    } else {
-                        app.pending_command = true;
-                        app.flash_address = flash_address;
-                        ReturnCode::SUCCESS
-                    }
+                    ReturnCode::FAIL
                 }
-            })
-            .unwrap_or_else(|err| err.into())
+            }
+            Command::Write => self.current_app.map_or(ReturnCode::FAIL, |appid| {
+                self.apps
+                    .enter(*appid, |app, _| {
+                        app.buffer.as_ref().map_or(ReturnCode::FAIL, |app_buffer| {
+                            let app_buffer = app_buffer.as_ref();
+                            if done + buffer.len() <= app_buffer.len()
+                                && &app_buffer[done..done + buffer.len()] == buffer
+                            {
+                                ReturnCode::SUCCESS
+                            } else {
+                                ReturnCode::FAIL
+                            }
+                        })
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }),
+        }
     }
-}
 
-impl hil::nonvolatile_storage::NonvolatileStorageClient for AppFlash<'a> {
-    fn read_done(&self, _buffer: &'static mut [u8], _length: usize) {}
-
-    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
-        // Put our write buffer back.
-        self.buffer.replace(buffer);
+    /// Move on to the next chunk after one was written and, if asked for,
+    /// verified.
+    fn chunk_done(&self) {
+        self.done.set(self.done.get() + self.chunk.get());
+        let finished = self
+            .current_request
+            .get()
+            .map_or(true, |request| self.done.get() >= request.length);
+        if finished {
+            self.finish(ReturnCode::SUCCESS);
+            self.start_next();
+        } else {
+            let res = self.write_chunk();
+            if res != ReturnCode::SUCCESS {
+                self.finish(res);
+                self.start_next();
+            }
+        }
+    }
 
-        // Notify the current application that the command finished.
+    /// Notify the current application that its command finished.
+    fn finish(&self, result: ReturnCode) {
+        self.current_request.set(None);
         self.current_app.take().map(|appid| {
+            let done = self.done.get();
             let _ = self.apps.enter(appid, |app, _| {
+                app.pending_command = None;
                 app.callback.map(|mut cb| {
-                    cb.schedule(0, 0, 0);
+                    cb.schedule(From::from(result), done, 0);
                 });
             });
         });
+    }
+}
 
-        // Check if there are any pending events.
-        for cntr in self.apps.iter() {
-            let started_command = cntr.enter(|app, _| {
-                if app.pending_command {
-                    app.pending_command = false;
-                    self.current_app.set(app.appid());
-                    let flash_address = app.flash_address;
-
-                    app.buffer.as_mut().map_or(false, |app_buffer| {
-                        self.buffer.take().map_or(false, |buffer| {
-                            if app_buffer.len() != 512 {
-                                false
-                            } else {
-                                // Copy contents to internal buffer and write it.
-                                let length = cmp::min(buffer.len(), app_buffer.len());
-                                let d = &mut app_buffer.as_mut()[0..length];
-                                for (i, c) in buffer.as_mut()[0..length].iter_mut().enumerate() {
-                                    *c = d[i];
-                                }
+impl hil::nonvolatile_storage::NonvolatileStorageClient for AppFlash<'a> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        let res = self.verify_chunk(&buffer[..length]);
+        self.buffer.replace(buffer);
+        if res == ReturnCode::SUCCESS {
+            self.chunk_done();
+        } else {
+            self.finish(res);
+            self.start_next();
+        }
+    }
 
-                                self.driver.write(buffer, flash_address, length)
-                                    == ReturnCode::SUCCESS
-                            }
-                        })
-                    })
-                } else {
-                    false
-                }
-            });
-            if started_command {
-                break;
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        let verify = self
+            .current_request
+            .get()
+            .map_or(false, |request| request.verify);
+        if verify {
+            // Read the chunk back to check it.
+            let address = self
+                .current_request
+                .get()
+                .map_or(0, |request| request.flash_address + self.done.get());
+            let res = self.driver.read(buffer, address, length);
+            if res != ReturnCode::SUCCESS {
+                self.finish(res);
+                self.start_next();
             }
+        } else {
+            // Put our write buffer back.
+            self.buffer.replace(buffer);
+            self.chunk_done();
         }
     }
 }
@@ -162,7 +310,7 @@ impl Driver for AppFlash<'a> {
     ///
     /// ### `allow_num`
     ///
-    /// - `0`: Set write buffer. This entire buffer will be written to flash.
+    /// - `0`: Set write buffer. Writes copy it to flash, from its start.
     fn allow(
         &self,
         appid: AppId,
@@ -185,7 +333,10 @@ impl Driver for AppFlash<'a> {
     ///
     /// ### `subscribe_num`
     ///
-    /// - `0`: Set a write_done callback.
+    /// - `0`: Set a callback for when a command finishes. It is passed the
+    ///   status of the command and the number of bytes written or erased.
+    ///   The status is `FAIL` if verification found flash that differs from
+    ///   what was written.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -209,18 +360,34 @@ impl Driver for AppFlash<'a> {
     /// ### `command_num`
     ///
     /// - `0`: Driver check.
-    /// - `1`: Write the memory from the `allow` buffer to the address in flash.
-    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
-        match command_num {
-            0 => /* This driver exists. */ ReturnCode::SUCCESS,
+    /// - `1`: Write the first `arg2` bytes of the `allow` buffer to the
+    ///   address `arg1` in flash, or the whole buffer if `arg2` is 0.
+    /// - `2`: Like `1`, and read every chunk back after writing it to check
+    ///   that flash holds what was written.
+    /// - `3`: Erase `arg2` bytes of flash starting at address `arg1`.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        let (command, verify) = match command_num {
+            // This driver exists.
+            0 => return ReturnCode::SUCCESS,
 
             // Write to flash from the allowed buffer.
-            1 => {
-                let flash_address = arg1;
-                self.enqueue_write(flash_address, appid)
-            }
+            1 => (Command::Write, false),
+            2 => (Command::Write, true),
 
-            _ => ReturnCode::ENOSUPPORT,
-        }
+            // Set a range of flash to its erased value.
+            3 => (Command::Erase, false),
+
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        self.enqueue(
+            Request {
+                command: command,
+                flash_address: arg1,
+                length: arg2,
+                verify: verify,
+            },
+            appid,
+        )
     }
 }
@@ -101,7 +101,7 @@ use kernel;
 use kernel::common::cells::OptionalCell;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{self, Frequency};
 use kernel::ReturnCode;
 
 /// Syscall Number
@@ -387,8 +387,7 @@ where
 
         self.app.each(|app| {
             if let Expiration::Abs(exp) = app.alarm_data.expiration {
-                let expired =
-                    now.wrapping_sub(app.alarm_data.t0) >= exp.wrapping_sub(app.alarm_data.t0);
+                let expired = time::has_expired(exp, now, app.alarm_data.t0);
                 if expired {
                     if self.busy.get() {
                         // The radio is currently busy, so we won't be able to start the
@@ -33,7 +33,29 @@
 //! When the buffer has been written successfully, the buffer is released from
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
+//!
+//! Input
+//! -----
+//!
+//! By default reads are raw: a read receives the next bytes from the UART,
+//! and only one process can read at a time. Other processes get `EBUSY` until
+//! the read completes.
+//!
+//! A board can instead enable a line discipline with `set_line_discipline`,
+//! before calling `initialize`. The console then echoes input and supports
+//! editing it with backspace and delete, and a read receives a whole line,
+//! without the line ending. Input goes to the process with input focus, which
+//! is the first process to read. Other processes can start reads too, which
+//! complete once they have focus. Typing Ctrl-A and then a digit `n` moves the
+//! focus to the `n`th process that uses the console. Ctrl-C discards the line
+//! and sends a signal to the process with focus.
+//!
+//! ```rust
+//! console.set_line_discipline(&mut console::LINE_BUF, &mut console::ECHO_BUF);
+//! console.initialize();
+//! ```
 
+use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil::uart::{self, Client, UART};
@@ -53,10 +75,22 @@ pub struct App {
     read_callback: Option<Callback>,
     read_buffer: Option<AppSlice<Shared, u8>>,
     read_len: usize,
+    // With the line discipline, whether the app is waiting for a line.
+    read_pending: bool,
+
+    signal_callback: Option<Callback>,
 }
 
 pub static mut WRITE_BUF: [u8; 64] = [0; 64];
 pub static mut READ_BUF: [u8; 64] = [0; 64];
+pub static mut LINE_BUF: [u8; 64] = [0; 64];
+pub static mut ECHO_BUF: [u8; 32] = [0; 32];
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const BELL: u8 = 0x07;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
 
 pub struct Console<'a, U: UART> {
     uart: &'a U,
@@ -66,6 +100,22 @@ pub struct Console<'a, U: UART> {
     rx_in_progress: OptionalCell<AppId>,
     rx_buffer: TakeCell<'static, [u8]>,
     baud_rate: u32,
+
+    // Line discipline state. The line is being edited until it is complete,
+    // and then waits until the process with focus reads it.
+    line: TakeCell<'static, [u8]>,
+    line_len: Cell<usize>,
+    line_complete: Cell<bool>,
+    // Whether the last byte was a carriage return, or the Ctrl-A that starts
+    // a focus switch.
+    after_cr: Cell<bool>,
+    after_escape: Cell<bool>,
+    focus: OptionalCell<AppId>,
+    // Output waiting to be echoed. It is sent through `tx_buffer` whenever
+    // no process is writing.
+    echo_buffer: TakeCell<'static, [u8]>,
+    echo_len: Cell<usize>,
+    echo_in_progress: Cell<bool>,
 }
 
 impl<U: UART> Console<'a, U> {
@@ -84,9 +134,30 @@ impl<U: UART> Console<'a, U> {
             rx_in_progress: OptionalCell::empty(),
             rx_buffer: TakeCell::new(rx_buffer),
             baud_rate: baud_rate,
+            line: TakeCell::empty(),
+            line_len: Cell::new(0),
+            line_complete: Cell::new(false),
+            after_cr: Cell::new(false),
+            after_escape: Cell::new(false),
+            focus: OptionalCell::empty(),
+            echo_buffer: TakeCell::empty(),
+            echo_len: Cell::new(0),
+            echo_in_progress: Cell::new(false),
         }
     }
 
+    /// Enable the line discipline, which edits input in `line_buffer` and
+    /// queues echoed output in `echo_buffer`. Must be called before
+    /// `initialize`.
+    pub fn set_line_discipline(
+        &self,
+        line_buffer: &'static mut [u8],
+        echo_buffer: &'static mut [u8],
+    ) {
+        self.line.replace(line_buffer);
+        self.echo_buffer.replace(echo_buffer);
+    }
+
     pub fn initialize(&self) {
         self.uart.configure(uart::UARTParameters {
             baud_rate: self.baud_rate,
@@ -94,6 +165,14 @@ impl<U: UART> Console<'a, U> {
             parity: uart::Parity::None,
             hw_flow_control: false,
         });
+
+        // With the line discipline, the console receives all the time, one
+        // byte at a time.
+        if self.line.is_some() {
+            self.rx_buffer
+                .take()
+                .map(|buffer| self.uart.receive(buffer, 1));
+        }
     }
 
     /// Internal helper function for setting up a new send transaction
@@ -127,7 +206,7 @@ impl<U: UART> Console<'a, U> {
     /// Internal helper function for sending data for an existing transaction.
     /// Cannot fail. If can't send now, it will schedule for sending later.
     fn send(&self, app_id: AppId, app: &mut App, slice: AppSlice<Shared, u8>) {
-        if self.tx_in_progress.is_none() {
+        if self.tx_in_progress.is_none() && !self.echo_in_progress.get() {
             self.tx_in_progress.set(app_id);
             self.tx_buffer.take().map(|buffer| {
                 let mut transaction_len = app.write_remaining;
@@ -192,6 +271,190 @@ impl<U: UART> Console<'a, U> {
     }
 }
 
+/// Line discipline.
+impl<U: UART> Console<'a, U> {
+    /// Queue `bytes` to be echoed, dropping what does not fit.
+    fn echo(&self, bytes: &[u8]) {
+        self.echo_buffer.map(|echo_buffer| {
+            let start = self.echo_len.get();
+            let len = cmp::min(bytes.len(), echo_buffer.len() - start);
+            echo_buffer[start..start + len].copy_from_slice(&bytes[..len]);
+            self.echo_len.set(start + len);
+        });
+        self.send_echo();
+    }
+
+    /// Send queued echo output if the UART is free. Returns whether a
+    /// transmission was started.
+    fn send_echo(&self) -> bool {
+        if self.echo_len.get() == 0 || self.tx_in_progress.is_some() || self.echo_in_progress.get()
+        {
+            return false;
+        }
+        self.tx_buffer.take().map_or(false, |buffer| {
+            let len = self.echo_buffer.map_or(0, |echo_buffer| {
+                let echo_len = self.echo_len.get();
+                let len = cmp::min(echo_len, buffer.len());
+                buffer[..len].copy_from_slice(&echo_buffer[..len]);
+                for i in len..echo_len {
+                    echo_buffer[i - len] = echo_buffer[i];
+                }
+                self.echo_len.set(echo_len - len);
+                len
+            });
+            self.echo_in_progress.set(true);
+            self.uart.transmit(buffer, len);
+            true
+        })
+    }
+
+    /// Returns whether `appid` has input focus, giving it focus if no live
+    /// process has it.
+    fn take_focus(&self, appid: AppId) -> bool {
+        let focus_alive = self.focus.map_or(false, |focus| {
+            *focus == appid || self.apps.enter(*focus, |_, _| ()).is_ok()
+        });
+        if !focus_alive {
+            self.focus.set(appid);
+        }
+        self.focus.map_or(false, |focus| *focus == appid)
+    }
+
+    /// Start waiting for a line for a process.
+    fn receive_line(&self, appid: AppId, app: &mut App, len: usize) -> ReturnCode {
+        if app.read_pending {
+            return ReturnCode::EBUSY;
+        }
+        let read_len = match app.read_buffer {
+            Some(ref slice) => cmp::min(len, slice.len()),
+            None => return ReturnCode::EINVAL,
+        };
+        app.read_len = read_len;
+        app.read_pending = true;
+        if self.take_focus(appid) {
+            self.deliver_line(app);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Pass the line to the process with focus, if it is complete and the
+    /// process is waiting for it.
+    fn deliver_line(&self, app: &mut App) {
+        if !self.line_complete.get() || !app.read_pending {
+            return;
+        }
+        app.read_pending = false;
+        let len = self.line.map_or(0, |line| {
+            app.read_buffer.take().map_or(0, |mut slice| {
+                // The process may have allowed a smaller buffer while it
+                // was waiting for the line.
+                let len = cmp::min(
+                    self.line_len.get(),
+                    cmp::min(app.read_len, slice.len()),
+                );
+                slice.as_mut()[..len].copy_from_slice(&line[..len]);
+                len
+            })
+        });
+        self.line_len.set(0);
+        self.line_complete.set(false);
+        app.read_callback
+            .map(|mut cb| cb.schedule(From::from(ReturnCode::SUCCESS), len, 0));
+    }
+
+    fn deliver_line_to_focus(&self) {
+        self.focus.map(|focus| {
+            let _ = self.apps.enter(*focus, |app, _| self.deliver_line(app));
+        });
+    }
+
+    /// Move input focus to the `n`th process that uses the console, and show
+    /// its name.
+    fn switch_focus(&self, n: usize) {
+        let mut found = false;
+        for (i, cntr) in self.apps.iter().enumerate() {
+            if i == n {
+                cntr.enter(|app, _| {
+                    let appid = app.appid();
+                    self.focus.set(appid);
+                    self.echo(b"\r\n[");
+                    self.echo(appid.get_package_name().as_bytes());
+                    self.echo(b"]\r\n");
+                    found = true;
+                });
+                break;
+            }
+        }
+        if found {
+            self.deliver_line_to_focus();
+        } else {
+            self.echo(&[BELL]);
+        }
+    }
+
+    /// Handle one byte of input.
+    fn receive_byte(&self, byte: u8) {
+        let after_cr = self.after_cr.get();
+        self.after_cr.set(byte == b'\r');
+        if self.after_escape.get() {
+            self.after_escape.set(false);
+            if byte >= b'0' && byte <= b'9' {
+                self.switch_focus((byte - b'0') as usize);
+            } else {
+                self.echo(&[BELL]);
+            }
+            return;
+        }
+
+        match byte {
+            CTRL_A => self.after_escape.set(true),
+            CTRL_C => {
+                self.line_len.set(0);
+                self.line_complete.set(false);
+                self.echo(b"^C\r\n");
+                self.focus.map(|focus| {
+                    let _ = self.apps.enter(*focus, |app, _| {
+                        app.signal_callback.map(|mut cb| cb.schedule(0, 0, 0));
+                    });
+                });
+            }
+            BACKSPACE | DELETE => {
+                if !self.line_complete.get() && self.line_len.get() > 0 {
+                    self.line_len.set(self.line_len.get() - 1);
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                if !self.line_complete.get() {
+                    self.line_complete.set(true);
+                    self.echo(b"\r\n");
+                    self.deliver_line_to_focus();
+                }
+            }
+            0x20...0x7e => {
+                let len = self.line_len.get();
+                let stored = !self.line_complete.get()
+                    && self.line.map_or(false, |line| {
+                        if len < line.len() {
+                            line[len] = byte;
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                if stored {
+                    self.line_len.set(len + 1);
+                    self.echo(&[byte]);
+                } else {
+                    self.echo(&[BELL]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 impl<U: UART> Driver for Console<'a, U> {
     /// Setup shared buffers.
     ///
@@ -229,6 +492,9 @@ impl<U: UART> Driver for Console<'a, U> {
     /// ### `subscribe_num`
     ///
     /// - `1`: Write buffer completed callback
+    /// - `2`: Read buffer completed callback
+    /// - `3`: Ctrl-C was typed while this process had input focus. Only
+    ///        used with the line discipline.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -248,6 +514,12 @@ impl<U: UART> Driver for Console<'a, U> {
                     ReturnCode::SUCCESS
                 }).unwrap_or_else(|err| err.into())
             },
+            3 /* signal */ => {
+                self.apps.enter(app_id, |app, _| {
+                    app.signal_callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into())
+            },
             _ => ReturnCode::ENOSUPPORT
         }
     }
@@ -260,9 +532,11 @@ impl<U: UART> Driver for Console<'a, U> {
     /// - `1`: Transmits a buffer passed via `allow`, up to the length
     ///        passed in `arg1`
     /// - `2`: Receives into a buffer passed via `allow`, up to the length
-    ///        passed in `arg1`
+    ///        passed in `arg1`. With the line discipline, receives the next
+    ///        line once this process has input focus.
     /// - `3`: Cancel any in progress receives and return (via callback)
-    ///        what has been received so far.
+    ///        what has been received so far. With the line discipline, the
+    ///        line being edited stays for the next read.
     fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         match cmd_num {
             0 /* check if present */ => ReturnCode::SUCCESS,
@@ -275,12 +549,28 @@ impl<U: UART> Driver for Console<'a, U> {
             2 /* getnstr */ => {
                 let len = arg1;
                 self.apps.enter(appid, |app, _| {
-                    self.receive_new(appid, app, len)
+                    if self.line.is_some() {
+                        self.receive_line(appid, app, len)
+                    } else {
+                        self.receive_new(appid, app, len)
+                    }
                 }).unwrap_or_else(|err| err.into())
             },
             3 /* abort rx */ => {
-                self.uart.abort_receive();
-                ReturnCode::SUCCESS
+                if self.line.is_some() {
+                    self.apps.enter(appid, |app, _| {
+                        if app.read_pending {
+                            app.read_pending = false;
+                            app.read_callback.map(|mut cb| {
+                                cb.schedule(From::from(ReturnCode::ECANCEL), 0, 0)
+                            });
+                        }
+                        ReturnCode::SUCCESS
+                    }).unwrap_or_else(|err| err.into())
+                } else {
+                    self.uart.abort_receive();
+                    ReturnCode::SUCCESS
+                }
             }
             _ => ReturnCode::ENOSUPPORT
         }
@@ -292,6 +582,7 @@ impl<U: UART> Client for Console<'a, U> {
         // Either print more from the AppSlice or send a callback to the
         // application.
         self.tx_buffer.replace(buffer);
+        self.echo_in_progress.set(false);
         self.tx_in_progress.take().map(|appid| {
             self.apps.enter(appid, |app, _| {
                 match self.send_continue(appid, app) {
@@ -319,6 +610,12 @@ impl<U: UART> Client for Console<'a, U> {
             })
         });
 
+        // Echo output goes first, so typing stays responsive while processes
+        // write.
+        if self.send_echo() {
+            return;
+        }
+
         // If we are not printing more from the current AppSlice,
         // see if any other applications have pending messages.
         if self.tx_in_progress.is_none() {
@@ -352,6 +649,14 @@ impl<U: UART> Client for Console<'a, U> {
     }
 
     fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        if self.line.is_some() {
+            if rx_len > 0 && error == uart::Error::CommandComplete {
+                self.receive_byte(buffer[0]);
+            }
+            self.uart.receive(buffer, 1);
+            return;
+        }
+
         self.rx_in_progress
             .take()
             .map(|appid| {
@@ -361,28 +666,23 @@ impl<U: UART> Client for Console<'a, U> {
                             // An iterator over the returned buffer yielding only the first `rx_len`
                             // bytes
                             let rx_buffer = buffer.iter().take(rx_len);
-                            match error {
-                                uart::Error::CommandComplete | uart::Error::Aborted => {
-                                    // Receive some bytes, signal error type and return bytes to process buffer
-                                    if let Some(mut app_buffer) = app.read_buffer.take() {
-                                        for (a, b) in app_buffer.iter_mut().zip(rx_buffer) {
-                                            *a = *b;
-                                        }
-                                        let rettype = if error == uart::Error::CommandComplete {
-                                            ReturnCode::SUCCESS
-                                        } else {
-                                            ReturnCode::ECANCEL
-                                        };
-                                        cb.schedule(From::from(rettype), rx_len, 0);
-                                    } else {
-                                        // Oops, no app buffer
-                                        cb.schedule(From::from(ReturnCode::EINVAL), 0, 0);
-                                    }
+                            // Receive some bytes, signal error type and return bytes to
+                            // process buffer. Line errors still return the bytes received
+                            // before them.
+                            if let Some(mut app_buffer) = app.read_buffer.take() {
+                                for (a, b) in app_buffer.iter_mut().zip(rx_buffer) {
+                                    *a = *b;
                                 }
-                                _ => {
+                                let rettype = match error {
+                                    uart::Error::CommandComplete => ReturnCode::SUCCESS,
+                                    uart::Error::Aborted => ReturnCode::ECANCEL,
                                     // Some UART error occurred
-                                    cb.schedule(From::from(ReturnCode::FAIL), 0, 0);
-                                }
+                                    _ => ReturnCode::FAIL,
+                                };
+                                cb.schedule(From::from(rettype), rx_len, 0);
+                            } else {
+                                // Oops, no app buffer
+                                cb.schedule(From::from(ReturnCode::EINVAL), 0, 0);
                             }
                         });
                     })
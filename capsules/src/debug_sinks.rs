@@ -0,0 +1,292 @@
+//! Back ends for kernel debug output.
+//!
+//! `kernel::debug::DebugWriter` sends its output to a
+//! `kernel::debug::DebugSink`. Besides UARTs, through
+//! `kernel::debug::UartSink`, and `capsules::segger_rtt`, this module adds:
+//!
+//! - `Fanout`, which sends the output to several sinks in turn.
+//! - `RamRing`, which keeps the most recent output in RAM to be read back
+//!   later, for boards with no free UART.
+//! - `FlashLog`, which appends the output to a `hil::log::Log` on flash, so
+//!   it can be read back after a reset.
+//!
+//! `RamRing` can also write on panic, so a board can pass
+//! `kernel::debug::SinkWriter` to `kernel::debug::panic` as the panic writer.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let ring_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let ring = static_init!(
+//!     capsules::debug_sinks::RamRing<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::debug_sinks::RamRing::new(ring_alarm, &mut capsules::debug_sinks::RING_BUFFER)
+//! );
+//! ring_alarm.set_client(ring);
+//!
+//! // Send debug output to both the UART and the ring.
+//! let uart_sink = static_init!(
+//!     kernel::debug::UartSink<'static>,
+//!     kernel::debug::UartSink::new(debugger_uart)
+//! );
+//! hil::uart::UART::set_client(debugger_uart, uart_sink);
+//! let sinks = static_init!(
+//!     [&'static kernel::debug::DebugSink; 2],
+//!     [uart_sink, ring]
+//! );
+//! let fanout = static_init!(
+//!     capsules::debug_sinks::Fanout<'static>,
+//!     capsules::debug_sinks::Fanout::new(sinks)
+//! );
+//! DebugSink::set_client(uart_sink, fanout);
+//! DebugSink::set_client(ring, fanout);
+//!
+//! let debugger = static_init!(
+//!     kernel::debug::DebugWriter,
+//!     kernel::debug::DebugWriter::new(
+//!         fanout,
+//!         &mut kernel::debug::OUTPUT_BUF,
+//!         &mut kernel::debug::INTERNAL_BUF,
+//!     )
+//! );
+//! DebugSink::set_client(fanout, debugger);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::debug::{DebugSink, DebugSinkClient};
+use kernel::hil;
+use kernel::hil::time::Frequency;
+use kernel::ReturnCode;
+
+/// Storage for a `RamRing`.
+pub static mut RING_BUFFER: [u8; 1024] = [0; 1024];
+
+/// Sends everything it is given to several sinks, one after the other. The
+/// next chunk of output is accepted once every sink has sent the current one,
+/// so output goes no faster than the slowest sink.
+pub struct Fanout<'a> {
+    sinks: &'a [&'a DebugSink],
+    client: OptionalCell<&'static DebugSinkClient>,
+    // Sink sending the current chunk, and its length.
+    current: Cell<usize>,
+    length: Cell<usize>,
+}
+
+impl Fanout<'a> {
+    pub fn new(sinks: &'a [&'a DebugSink]) -> Fanout<'a> {
+        Fanout {
+            sinks: sinks,
+            client: OptionalCell::empty(),
+            current: Cell::new(0),
+            length: Cell::new(0),
+        }
+    }
+}
+
+impl DebugSink for Fanout<'a> {
+    fn set_client(&self, client: &'static DebugSinkClient) {
+        self.client.set(client);
+    }
+
+    fn transmit(&self, buffer: &'static mut [u8], len: usize) {
+        self.current.set(0);
+        self.length.set(len);
+        match self.sinks.first() {
+            Some(sink) => sink.transmit(buffer, len),
+            None => {
+                self.client.map(move |client| client.transmit_done(buffer));
+            }
+        }
+    }
+
+    fn write_now(&self, bytes: &[u8]) {
+        for sink in self.sinks.iter() {
+            sink.write_now(bytes);
+        }
+    }
+}
+
+impl DebugSinkClient for Fanout<'a> {
+    fn transmit_done(&self, buffer: &'static mut [u8]) {
+        let next = self.current.get() + 1;
+        if next < self.sinks.len() {
+            self.current.set(next);
+            self.sinks[next].transmit(buffer, self.length.get());
+        } else {
+            self.client.map(move |client| client.transmit_done(buffer));
+        }
+    }
+}
+
+/// Keeps the most recent output in a ring buffer in RAM, overwriting the
+/// oldest output when it is full. The output can be read back with `read`.
+///
+/// The alarm only defers the `transmit_done` callback until the next
+/// scheduler loop, as in `capsules::segger_rtt`.
+pub struct RamRing<'a, A: hil::time::Alarm> {
+    alarm: &'a A,
+    ring: TakeCell<'static, [u8]>,
+    // Index the next byte is stored at, and the number of bytes stored.
+    head: Cell<usize>,
+    length: Cell<usize>,
+    client: OptionalCell<&'static DebugSinkClient>,
+    client_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<A: hil::time::Alarm> RamRing<'a, A> {
+    pub fn new(alarm: &'a A, ring: &'static mut [u8]) -> RamRing<'a, A> {
+        RamRing {
+            alarm: alarm,
+            ring: TakeCell::new(ring),
+            head: Cell::new(0),
+            length: Cell::new(0),
+            client: OptionalCell::empty(),
+            client_buffer: TakeCell::empty(),
+        }
+    }
+
+    /// Store `bytes`, overwriting the oldest bytes if there is no room.
+    fn push(&self, bytes: &[u8]) {
+        self.ring.map(|ring| {
+            let size = ring.len();
+            if size == 0 {
+                return;
+            }
+            let mut head = self.head.get();
+            for &byte in bytes.iter() {
+                ring[head] = byte;
+                head = (head + 1) % size;
+            }
+            self.head.set(head);
+            self.length
+                .set(cmp::min(self.length.get() + bytes.len(), size));
+        });
+    }
+
+    /// Number of bytes stored.
+    pub fn len(&self) -> usize {
+        self.length.get()
+    }
+
+    /// Move the oldest stored bytes into `buffer`, and return how many were
+    /// moved.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        self.ring.map_or(0, |ring| {
+            let size = ring.len();
+            let length = self.length.get();
+            let count = cmp::min(length, buffer.len());
+            let oldest = (self.head.get() + size - length) % size;
+            for (i, byte) in buffer[..count].iter_mut().enumerate() {
+                *byte = ring[(oldest + i) % size];
+            }
+            self.length.set(length - count);
+            count
+        })
+    }
+}
+
+impl<A: hil::time::Alarm> DebugSink for RamRing<'a, A> {
+    fn set_client(&self, client: &'static DebugSinkClient) {
+        self.client.set(client);
+    }
+
+    fn transmit(&self, buffer: &'static mut [u8], len: usize) {
+        let length = cmp::min(len, buffer.len());
+        self.push(&buffer[..length]);
+
+        // Save the client buffer so we can pass it back with the callback.
+        self.client_buffer.replace(buffer);
+
+        // Start a short timer so that we get a callback and can issue the
+        // callback to the client.
+        let interval = (100 as u32) * <A::Frequency>::frequency() / 1000000;
+        let tics = self.alarm.now().wrapping_add(interval);
+        self.alarm.set_alarm(tics);
+    }
+
+    fn write_now(&self, bytes: &[u8]) {
+        self.push(bytes);
+    }
+}
+
+impl<A: hil::time::Alarm> hil::time::Client for RamRing<'a, A> {
+    fn fired(&self) {
+        self.client.map(|client| {
+            self.client_buffer
+                .take()
+                .map(|buffer| client.transmit_done(buffer));
+        });
+    }
+}
+
+/// Appends each chunk of output as an entry of a `hil::log::Log`. As the log
+/// is circular, the oldest output is dropped when the log is full.
+///
+/// The log can still be read through this sink: completions of reads and
+/// erases, and of appends not made by this sink, are passed on to the client
+/// set with `set_log_client`. The log serves one request at a time, so output
+/// that arrives while the log is busy is dropped.
+pub struct FlashLog<'a> {
+    log: &'a hil::log::Log<'a>,
+    client: OptionalCell<&'static DebugSinkClient>,
+    log_client: OptionalCell<&'a hil::log::Client>,
+    appending: Cell<bool>,
+}
+
+impl FlashLog<'a> {
+    pub fn new(log: &'a hil::log::Log<'a>) -> FlashLog<'a> {
+        FlashLog {
+            log: log,
+            client: OptionalCell::empty(),
+            log_client: OptionalCell::empty(),
+            appending: Cell::new(false),
+        }
+    }
+
+    pub fn set_log_client(&self, client: &'a hil::log::Client) {
+        self.log_client.set(client);
+    }
+}
+
+impl DebugSink for FlashLog<'a> {
+    fn set_client(&self, client: &'static DebugSinkClient) {
+        self.client.set(client);
+    }
+
+    fn transmit(&self, buffer: &'static mut [u8], len: usize) {
+        let length = cmp::min(len, self.log.max_entry_len());
+        let (res, buffer) = self.log.append(buffer, length);
+        if res == ReturnCode::SUCCESS {
+            self.appending.set(true);
+        }
+        buffer.map(|buffer| {
+            self.client.map(move |client| client.transmit_done(buffer));
+        });
+    }
+}
+
+impl hil::log::Client for FlashLog<'a> {
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize, entry: u32) {
+        self.log_client
+            .map(move |client| client.read_done(result, buffer, length, entry));
+    }
+
+    fn append_done(&self, result: ReturnCode, buffer: &'static mut [u8], entry: u32) {
+        if self.appending.get() {
+            self.appending.set(false);
+            self.client.map(move |client| client.transmit_done(buffer));
+        } else {
+            self.log_client
+                .map(move |client| client.append_done(result, buffer, entry));
+        }
+    }
+
+    fn erase_done(&self, result: ReturnCode) {
+        self.log_client.map(|client| client.erase_done(result));
+    }
+}
@@ -0,0 +1,294 @@
+//! Provides userspace with SHA-256 digests and HMAC-SHA256 tags.
+//!
+//! A process allows the driver a data buffer, a 32 byte buffer for the result
+//! and, for HMAC, a key, then starts a digest with a command. The driver
+//! serves one process at a time and queues requests from the others. The data
+//! is copied into the digest engine in chunks the size of the driver's kernel
+//! buffer, so it can be as long as the process's buffer.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let digest = static_init!(
+//!     capsules::digest::Digest<Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>>,
+//!     capsules::digest::Digest::new(
+//!         sha256,
+//!         &mut DIGEST_DATA_BUF,
+//!         &mut DIGEST_BUF,
+//!         kernel::Grant::create()
+//!     )
+//! );
+//! sha256.set_client(digest);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::TakeCell;
+use kernel::hil::digest;
+use kernel::hil::digest::SHA256_DIGEST_SIZE;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use util::{AppQueue, QueuedApp};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x40003;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request {
+    Sha256 { len: usize },
+    HmacSha256 { len: usize },
+}
+
+impl Request {
+    fn len(&self) -> usize {
+        match *self {
+            Request::Sha256 { len } | Request::HmacSha256 { len } => len,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    data: Option<AppSlice<Shared, u8>>,
+    digest: Option<AppSlice<Shared, u8>>,
+
+    // If Some, the process is waiting for the engine or being served.
+    waiting: Option<Request>,
+}
+
+impl QueuedApp for App {
+    type Request = Request;
+
+    fn waiting(&mut self) -> &mut Option<Request> {
+        &mut self.waiting
+    }
+
+    fn callback(&mut self) -> &mut Option<Callback> {
+        &mut self.callback
+    }
+}
+
+pub struct Digest<D: digest::Digest<'static> + 'static> {
+    engine: &'static D,
+    apps: Grant<App>,
+    queue: AppQueue,
+    data_buffer: TakeCell<'static, [u8]>,
+    digest_buffer: TakeCell<'static, [u8; SHA256_DIGEST_SIZE]>,
+
+    // Progress through the data of the request being served.
+    offset: Cell<usize>,
+    chunk_len: Cell<usize>,
+}
+
+impl<D: digest::Digest<'static>> Digest<D> {
+    pub fn new(
+        engine: &'static D,
+        data_buffer: &'static mut [u8],
+        digest_buffer: &'static mut [u8; SHA256_DIGEST_SIZE],
+        grant: Grant<App>,
+    ) -> Digest<D> {
+        Digest {
+            engine: engine,
+            apps: grant,
+            queue: AppQueue::new(),
+            data_buffer: TakeCell::new(data_buffer),
+            digest_buffer: TakeCell::new(digest_buffer),
+            offset: Cell::new(0),
+            chunk_len: Cell::new(0),
+        }
+    }
+
+    fn serve_waiting_apps(&self) {
+        self.queue.serve_next(&self.apps, |app, request| self.start_request(app, request));
+    }
+
+    fn start_request(&self, app: &mut App, request: Request) -> ReturnCode {
+        let len = request.len();
+        if len > app.data.as_ref().map_or(0, |data| data.len())
+            || app.digest.as_ref().map_or(0, |digest| digest.len()) < SHA256_DIGEST_SIZE
+        {
+            return ReturnCode::EINVAL;
+        }
+
+        let res = match request {
+            Request::Sha256 { .. } => self.engine.set_mode_sha256(),
+            Request::HmacSha256 { .. } => app.key.as_ref().map_or(ReturnCode::EINVAL, |key| {
+                self.engine.set_mode_hmacsha256(key.as_ref())
+            }),
+        };
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+
+        self.offset.set(0);
+        self.add_next_chunk(app, len)
+    }
+
+    /// Copy the next chunk of the process's data into the kernel buffer and
+    /// add it to the digest, or finish the digest if all data was added.
+    fn add_next_chunk(&self, app: &mut App, len: usize) -> ReturnCode {
+        let offset = self.offset.get();
+        if offset == len {
+            return self
+                .digest_buffer
+                .take()
+                .map_or(ReturnCode::ENOMEM, |digest| {
+                    let (res, digest) = self.engine.run(digest);
+                    digest.map(|digest| self.digest_buffer.replace(digest));
+                    res
+                });
+        }
+
+        self.data_buffer
+            .take()
+            .map_or(ReturnCode::ENOMEM, |buffer| {
+                let chunk_len = cmp::min(len - offset, buffer.len());
+                // The process may have allowed a shorter buffer since the
+                // request started.
+                if app.data.as_ref().map_or(0, |data| data.len()) < offset + chunk_len {
+                    self.data_buffer.replace(buffer);
+                    return ReturnCode::ESIZE;
+                }
+                app.data.as_ref().map(|data| {
+                    buffer[..chunk_len].copy_from_slice(&data.as_ref()[offset..offset + chunk_len]);
+                });
+                self.chunk_len.set(chunk_len);
+                let (res, buffer) = self.engine.add_data(buffer, chunk_len);
+                buffer.map(|buffer| self.data_buffer.replace(buffer));
+                res
+            })
+    }
+
+    /// Tell the process being served its request is over, and serve the next
+    /// one.
+    fn finish(&self, res: ReturnCode) {
+        self.queue.finish(&self.apps, |_, request| (res, request.len(), 0));
+        self.serve_waiting_apps();
+    }
+}
+
+impl<D: digest::Digest<'static>> Driver for Digest<D> {
+    /// Share buffers with the driver.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The HMAC key, of any length.
+    /// - `1`: The data to digest.
+    /// - `2`: Buffer of at least 32 bytes for the digest or tag.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.key = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.data = slice;
+                    ReturnCode::SUCCESS
+                }
+                2 => {
+                    app.digest = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to digest completion.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when a digest finishes, with its status and the number
+    ///        of bytes digested.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Start a digest.
+    ///
+    /// The key and buffers are read when the digest starts, which may be
+    /// after other processes' queued requests. A process may only have one
+    /// request outstanding and gets `EBUSY` otherwise. If the process allows
+    /// a buffer that is too short while its digest is in progress, the
+    /// request ends with `ESIZE`.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: SHA-256 digest of the first `data` bytes of the data buffer.
+    /// - `2`: HMAC-SHA256 tag of the first `data` bytes of the data buffer,
+    ///        using the allowed key.
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        let request = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Request::Sha256 { len: data },
+            2 => Request::HmacSha256 { len: data },
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        let res = self.queue.enqueue(&self.apps, appid, request);
+        if res == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        res
+    }
+}
+
+impl<D: digest::Digest<'static>> digest::Client<'static> for Digest<D> {
+    fn add_data_done(&self, result: ReturnCode, data: &'static mut [u8]) {
+        self.data_buffer.replace(data);
+        if result != ReturnCode::SUCCESS {
+            self.finish(result);
+            return;
+        }
+        self.offset.set(self.offset.get() + self.chunk_len.get());
+
+        let res = self
+            .queue
+            .with_serving(&self.apps, |app, request| self.add_next_chunk(app, request.len()))
+            .unwrap_or(ReturnCode::FAIL);
+        if res != ReturnCode::SUCCESS {
+            self.finish(res);
+        }
+    }
+
+    fn hash_done(&self, result: ReturnCode, digest: &'static mut [u8; SHA256_DIGEST_SIZE]) {
+        let mut res = result;
+        if res == ReturnCode::SUCCESS {
+            res = self
+                .queue
+                .with_serving(&self.apps, |app, _| match app.digest {
+                    Some(ref mut app_digest) if app_digest.len() >= SHA256_DIGEST_SIZE => {
+                        app_digest.as_mut()[..SHA256_DIGEST_SIZE].copy_from_slice(&digest[..]);
+                        ReturnCode::SUCCESS
+                    }
+                    _ => ReturnCode::ESIZE,
+                })
+                .unwrap_or(ReturnCode::FAIL);
+        }
+        self.digest_buffer.replace(digest);
+        self.finish(res);
+    }
+}
@@ -0,0 +1,1995 @@
+//! FAT16 and FAT32 file system on a block device.
+//!
+//! `FatFs` mounts the first FAT16 or FAT32 volume of a block device with 512
+//! byte blocks, such as an SD card through `sdcard::SDCardBlock`, either a
+//! partition listed in the master boot record or a device formatted without a
+//! partition table, and lets a single client open, read, write and list files
+//! and directories on it, so that data written by the kernel or processes can
+//! be read on a PC.
+//!
+//! The volume is mounted whenever the device becomes ready, such as when a
+//! card is inserted and initialized, and can also be mounted with `mount`
+//! once the device is ready.
+//!
+//! Only short (8.3) names are supported: long names written by other systems
+//! are skipped, and names are stored in upper case. Files are written through
+//! a single sector cache, and the directory entry of a file is updated and
+//! the cache written back at the end of every write, so that data is not lost
+//! if the device is removed or the board reset without closing the file.
+//! Directories and files are dated 2018-01-01, as there is no clock to date
+//! them with.
+//!
+//! Requests are served one at a time. A request that needs no device access,
+//! such as reading past the end of a file, completes before the call returns.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let sdcard_block = static_init!(
+//!     capsules::sdcard::SDCardBlock<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::sdcard::SDCardBlock::new(sdcard)
+//! );
+//! sdcard.set_client(sdcard_block);
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<'static>,
+//!     capsules::fat::FatFs::new(sdcard_block, &mut capsules::fat::BUFFER)
+//! );
+//! hil::block::BlockDevice::set_client(sdcard_block, fat);
+//! fat.set_client(client);
+//! sdcard.detect_changes();
+//! sdcard_block.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ReturnCode;
+use util::{read_u16, read_u32, write_u16, write_u32};
+
+/// Number of files that can be open at the same time.
+pub const MAX_FILES: usize = 8;
+
+/// Create the file or directory if it does not exist.
+pub const OPEN_CREATE: usize = 1;
+/// Discard the contents of an existing file.
+pub const OPEN_TRUNCATE: usize = 2;
+/// Write to the end of the file, wherever the file is positioned.
+pub const OPEN_APPEND: usize = 4;
+/// Open or create a hidden system file. `list` skips these files, and they
+/// can only be opened with this flag.
+pub const OPEN_SYSTEM: usize = 8;
+
+/// Sector cache, which must be at least 512 bytes long.
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+const SECTOR_SIZE: usize = 512;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xe5;
+
+/// 2018-01-01 in the FAT date format.
+const DATE: u16 = (38 << 9) | (1 << 5) | 1;
+
+/// A directory, which can be passed to `open` and `list`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Dir(u32);
+
+/// An open file.
+#[derive(Clone, Copy, PartialEq)]
+pub struct File(usize);
+
+/// A file or directory returned by `list`.
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    /// Name, with a dot before the extension if there is one.
+    pub name: [u8; 12],
+    pub name_len: usize,
+    pub size: u32,
+    pub is_dir: bool,
+    /// Position of the entry in its directory. Listing continues at the next
+    /// index.
+    pub index: usize,
+}
+
+/// Callbacks from `FatFs`.
+pub trait FatClient {
+    fn mounted(&self, result: ReturnCode);
+
+    /// The device was removed or stopped being usable. Directories and files
+    /// opened before can no longer be used, and the volume is mounted again
+    /// once the device is ready.
+    fn unmounted(&self);
+
+    fn dir_opened(&self, result: ReturnCode, dir: Dir);
+    fn file_opened(&self, result: ReturnCode, file: File);
+
+    /// `length` bytes were read into `buffer`. Fewer bytes than requested are
+    /// read at the end of the file.
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize);
+
+    /// `length` bytes were written from `buffer`.
+    fn write_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize);
+
+    /// The entry at or after the requested index, or `None` at the end of
+    /// the directory.
+    fn listed(&self, result: ReturnCode, entry: Option<DirEntry>);
+}
+
+/// Layout of the mounted volume, in absolute sectors.
+#[derive(Clone, Copy, Default)]
+struct Volume {
+    fat32: bool,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    num_fats: u32,
+    // Fixed root directory of FAT16 volumes.
+    root_start: u32,
+    root_sectors: u32,
+    // Root directory cluster of FAT32 volumes.
+    root_cluster: u32,
+    data_start: u32,
+    clusters: u32,
+}
+
+impl Volume {
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn cursor_sector(&self, cursor: Cursor) -> u32 {
+        if cursor.cluster == 0 {
+            self.root_start + cursor.sector
+        } else {
+            self.cluster_sector(cursor.cluster) + cursor.sector
+        }
+    }
+
+    /// Sector and offset of the FAT entry of `cluster`.
+    fn fat_location(&self, cluster: u32) -> (u32, usize) {
+        let offset = if self.fat32 { cluster * 4 } else { cluster * 2 };
+        (
+            self.fat_start + offset / SECTOR_SIZE as u32,
+            offset as usize % SECTOR_SIZE,
+        )
+    }
+
+    fn fat_entry_size(&self) -> usize {
+        if self.fat32 {
+            4
+        } else {
+            2
+        }
+    }
+
+    fn read_fat(&self, sector: &[u8], offset: usize) -> u32 {
+        if self.fat32 {
+            read_u32(&sector[offset..]) & 0x0fffffff
+        } else {
+            read_u16(&sector[offset..]) as u32
+        }
+    }
+
+    fn write_fat(&self, sector: &mut [u8], offset: usize, value: u32) {
+        if self.fat32 {
+            // The top four bits are reserved.
+            let value = (read_u32(&sector[offset..]) & 0xf0000000) | (value & 0x0fffffff);
+            write_u32(&mut sector[offset..], value);
+        } else {
+            write_u16(&mut sector[offset..], value as u16);
+        }
+    }
+
+    fn end_mark(&self) -> u32 {
+        if self.fat32 {
+            0x0fffffff
+        } else {
+            0xffff
+        }
+    }
+
+    /// Whether a FAT entry ends a cluster chain. Entries that are not valid
+    /// clusters end chains too.
+    fn is_end(&self, cluster: u32) -> bool {
+        cluster < 2 || cluster >= self.clusters + 2
+    }
+
+    fn is_fat_sector(&self, sector: u32) -> bool {
+        sector >= self.fat_start && sector < self.fat_start + self.fat_sectors
+    }
+
+    fn dir_start(&self, dir: Dir) -> Cursor {
+        match dir.0 {
+            0 if self.fat32 => Cursor {
+                cluster: self.root_cluster,
+                sector: 0,
+            },
+            cluster => Cursor {
+                cluster: cluster,
+                sector: 0,
+            },
+        }
+    }
+}
+
+/// A sector of a directory. Cluster 0 is the fixed root directory of FAT16
+/// volumes.
+#[derive(Clone, Copy, PartialEq)]
+struct Cursor {
+    cluster: u32,
+    sector: u32,
+}
+
+/// Location of a directory entry.
+#[derive(Clone, Copy, PartialEq)]
+struct Slot {
+    sector: u32,
+    offset: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Entry {
+    slot: Slot,
+    first_cluster: u32,
+    size: u32,
+    is_dir: bool,
+    is_system: bool,
+}
+
+#[derive(Clone, Copy)]
+struct FileState {
+    in_use: bool,
+    append: bool,
+    entry: Slot,
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    // Cluster holding `position`, or 0 if not known yet, and its index in
+    // the cluster chain.
+    cluster: u32,
+    cluster_index: u32,
+}
+
+impl FileState {
+    const fn new() -> FileState {
+        FileState {
+            in_use: false,
+            append: false,
+            entry: Slot {
+                sector: 0,
+                offset: 0,
+            },
+            first_cluster: 0,
+            size: 0,
+            position: 0,
+            cluster: 0,
+            cluster_index: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Request {
+    Idle,
+    Mount,
+    Open {
+        dir: Dir,
+        name: [u8; 11],
+        flags: usize,
+        want_dir: bool,
+    },
+    Read {
+        file: usize,
+        length: usize,
+    },
+    Write {
+        file: usize,
+        length: usize,
+    },
+    List {
+        dir: Dir,
+        index: usize,
+    },
+}
+
+/// Progress of the current request. Each phase accesses at most one sector
+/// before it moves on, so that a phase can simply be run again once a sector
+/// it needed has been read.
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Start,
+    ReadBoot,
+    ReadPartition,
+    Scan,
+    ScanNext,
+    Found,
+    Truncate,
+    TruncateEntry,
+    Create,
+    Extend,
+    ExtendZero,
+    ExtendLink,
+    NewDir,
+    NewDirZero,
+    NewDirDots,
+    WriteEntry,
+    Flush,
+    Opened,
+    Transfer,
+    UpdateEntry,
+    ListSkip,
+    ListScan,
+    ListNext,
+}
+
+/// Extending the cluster chain of a file.
+#[derive(Clone, Copy, PartialEq)]
+enum Link {
+    Idle,
+    Alloc,
+    Link(u32),
+}
+
+enum Poll<T> {
+    Ready(T),
+    Pending,
+    Failed(ReturnCode),
+}
+
+/// Evaluate to the value of a `Poll::Ready`, or return the `Poll` otherwise.
+macro_rules! ready {
+    ($poll:expr) => {
+        match $poll {
+            Poll::Ready(value) => value,
+            Poll::Pending => return Poll::Pending,
+            Poll::Failed(error) => return Poll::Failed(error),
+        }
+    };
+}
+
+enum Boot {
+    Volume(Volume),
+    Partition(u32),
+    Invalid,
+}
+
+enum Scan {
+    Found(Entry),
+    End,
+    More,
+}
+
+enum Listed {
+    Found(DirEntry, usize),
+    End,
+    More,
+}
+
+pub struct FatFs<'a> {
+    device: &'a hil::block::BlockDevice<'a>,
+    client: OptionalCell<&'a FatClient>,
+    mounted: Cell<bool>,
+    volume: Cell<Volume>,
+    files: Cell<[FileState; MAX_FILES]>,
+
+    // Sector cache.
+    buffer: TakeCell<'static, [u8]>,
+    cached: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    reading: Cell<Option<u32>>,
+    // FAT copy being written when a FAT sector is written back.
+    mirror: Cell<u32>,
+    io_error: Cell<bool>,
+
+    request: Cell<Request>,
+    phase: Cell<Phase>,
+    partition: Cell<u32>,
+    cursor: Cell<Cursor>,
+    found: Cell<Option<Entry>>,
+    free_slot: Cell<Option<Slot>>,
+    new_cluster: Cell<u32>,
+    opened: Cell<usize>,
+    skip: Cell<u32>,
+    list_index: Cell<usize>,
+    listed: Cell<Option<DirEntry>>,
+    data: TakeCell<'static, [u8]>,
+    transferred: Cell<usize>,
+
+    alloc_next: Cell<u32>,
+    alloc_scanned: Cell<u32>,
+    zeroed: Cell<u32>,
+    free_next: Cell<u32>,
+    link: Cell<Link>,
+}
+
+impl FatFs<'a> {
+    pub fn new(device: &'a hil::block::BlockDevice<'a>, buffer: &'static mut [u8]) -> FatFs<'a> {
+        FatFs {
+            device: device,
+            client: OptionalCell::empty(),
+            mounted: Cell::new(false),
+            volume: Cell::new(Volume::default()),
+            files: Cell::new([FileState::new(); MAX_FILES]),
+            buffer: TakeCell::new(buffer),
+            cached: Cell::new(None),
+            dirty: Cell::new(false),
+            reading: Cell::new(None),
+            mirror: Cell::new(0),
+            io_error: Cell::new(false),
+            request: Cell::new(Request::Idle),
+            phase: Cell::new(Phase::Start),
+            partition: Cell::new(0),
+            cursor: Cell::new(Cursor {
+                cluster: 0,
+                sector: 0,
+            }),
+            found: Cell::new(None),
+            free_slot: Cell::new(None),
+            new_cluster: Cell::new(0),
+            opened: Cell::new(0),
+            skip: Cell::new(0),
+            list_index: Cell::new(0),
+            listed: Cell::new(None),
+            data: TakeCell::empty(),
+            transferred: Cell::new(0),
+            alloc_next: Cell::new(2),
+            alloc_scanned: Cell::new(0),
+            zeroed: Cell::new(0),
+            free_next: Cell::new(0),
+            link: Cell::new(Link::Idle),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a FatClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.mounted.get()
+    }
+
+    /// Mount the first FAT volume of the device. The device has to be ready,
+    /// or else `ERESERVE` is returned and the volume is mounted once it is.
+    pub fn mount(&self) -> ReturnCode {
+        if self.mounted.get() {
+            return ReturnCode::EALREADY;
+        }
+        if self.device.num_blocks() == 0 {
+            return ReturnCode::ERESERVE;
+        }
+        self.start(Request::Mount)
+    }
+
+    /// The root directory of the volume.
+    pub fn root(&self) -> Dir {
+        Dir(0)
+    }
+
+    /// Open the directory `name` in `dir`, creating it if `create` is set.
+    pub fn open_dir(&self, dir: Dir, name: &[u8], create: bool) -> ReturnCode {
+        if !self.mounted.get() {
+            return ReturnCode::ERESERVE;
+        }
+        match short_name(name) {
+            Some(name) => self.start(Request::Open {
+                dir: dir,
+                name: name,
+                flags: if create { OPEN_CREATE } else { 0 },
+                want_dir: true,
+            }),
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Open the file `name` in `dir`. `flags` is a combination of
+    /// `OPEN_CREATE`, `OPEN_TRUNCATE`, `OPEN_APPEND` and `OPEN_SYSTEM`. Files
+    /// are positioned
+    /// at their start.
+    pub fn open(&self, dir: Dir, name: &[u8], flags: usize) -> ReturnCode {
+        if !self.mounted.get() {
+            return ReturnCode::ERESERVE;
+        }
+        if self.free_file().is_none() {
+            return ReturnCode::ENOMEM;
+        }
+        match short_name(name) {
+            Some(name) => self.start(Request::Open {
+                dir: dir,
+                name: name,
+                flags: flags,
+                want_dir: false,
+            }),
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Read up to `length` bytes from the position of `file` into `buffer`.
+    pub fn read(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.is_open(file) {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        let length = cmp::min(length, buffer.len());
+        self.start_transfer(
+            Request::Read {
+                file: file.0,
+                length: length,
+            },
+            buffer,
+        )
+    }
+
+    /// Write the first `length` bytes of `buffer` at the position of `file`.
+    pub fn write(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.is_open(file) {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        if length > buffer.len() {
+            return (ReturnCode::ESIZE, Some(buffer));
+        }
+        self.start_transfer(
+            Request::Write {
+                file: file.0,
+                length: length,
+            },
+            buffer,
+        )
+    }
+
+    /// Close `file`. Everything written to it is already on the device, so
+    /// this completes immediately.
+    pub fn close(&self, file: File) -> ReturnCode {
+        if !self.is_open(file) {
+            return ReturnCode::EINVAL;
+        }
+        let reading_or_writing = match self.request.get() {
+            Request::Read { file: f, .. } | Request::Write { file: f, .. } => f == file.0,
+            _ => false,
+        };
+        if reading_or_writing {
+            return ReturnCode::EBUSY;
+        }
+        self.set_file(file.0, FileState::new());
+        ReturnCode::SUCCESS
+    }
+
+    /// Size of `file` in bytes.
+    pub fn size(&self, file: File) -> Option<usize> {
+        if self.is_open(file) {
+            Some(self.file(file.0).size as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Find the first file or directory in `dir` at or after position
+    /// `index`. Start at index 0 and continue at the index after the one
+    /// returned.
+    pub fn list(&self, dir: Dir, index: usize) -> ReturnCode {
+        if !self.mounted.get() {
+            return ReturnCode::ERESERVE;
+        }
+        self.start(Request::List {
+            dir: dir,
+            index: index,
+        })
+    }
+
+    fn start(&self, request: Request) -> ReturnCode {
+        if self.request.get() != Request::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.request.set(request);
+        self.phase.set(Phase::Start);
+        self.run();
+        ReturnCode::SUCCESS
+    }
+
+    fn start_transfer(
+        &self,
+        request: Request,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.request.get() != Request::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        self.data.replace(buffer);
+        self.transferred.set(0);
+        (self.start(request), None)
+    }
+
+    fn file(&self, index: usize) -> FileState {
+        self.files.get()[index]
+    }
+
+    fn set_file(&self, index: usize, file: FileState) {
+        let mut files = self.files.get();
+        files[index] = file;
+        self.files.set(files);
+    }
+
+    fn is_open(&self, file: File) -> bool {
+        self.mounted.get() && file.0 < MAX_FILES && self.file(file.0).in_use
+    }
+
+    fn free_file(&self) -> Option<usize> {
+        self.files.get().iter().position(|file| !file.in_use)
+    }
+
+    fn unmount(&self) {
+        self.mounted.set(false);
+        self.files.set([FileState::new(); MAX_FILES]);
+        self.cached.set(None);
+        self.dirty.set(false);
+        self.alloc_next.set(2);
+    }
+
+    /// Make progress on the current request, and finish it if it is done.
+    fn run(&self) {
+        let poll = if self.io_error.get() {
+            self.io_error.set(false);
+            Poll::Failed(ReturnCode::FAIL)
+        } else {
+            match self.request.get() {
+                Request::Idle => return,
+                Request::Mount => self.poll_mount(),
+                Request::Open {
+                    dir,
+                    name,
+                    flags,
+                    want_dir,
+                } => self.poll_open(dir, name, flags, want_dir),
+                Request::Read { file, length } => self.poll_read(file, length),
+                Request::Write { file, length } => self.poll_write(file, length),
+                Request::List { dir, index } => self.poll_list(dir, index),
+            }
+        };
+        match poll {
+            Poll::Pending => {}
+            Poll::Ready(()) => self.complete(ReturnCode::SUCCESS),
+            Poll::Failed(error) => self.complete(error),
+        }
+    }
+
+    fn complete(&self, result: ReturnCode) {
+        let request = self.request.get();
+        self.request.set(Request::Idle);
+        self.alloc_scanned.set(0);
+        self.zeroed.set(0);
+        self.link.set(Link::Idle);
+
+        self.client.map(|client| match request {
+            Request::Idle => {}
+            Request::Mount => client.mounted(result),
+            Request::Open { want_dir: true, .. } => {
+                let cluster = self.found.get().map_or(0, |entry| entry.first_cluster);
+                client.dir_opened(result, Dir(cluster));
+            }
+            Request::Open { .. } => client.file_opened(result, File(self.opened.get())),
+            Request::Read { .. } => {
+                self.data.take().map(|buffer| {
+                    client.read_done(result, buffer, self.transferred.get());
+                });
+            }
+            Request::Write { .. } => {
+                self.data.take().map(|buffer| {
+                    client.write_done(result, buffer, self.transferred.get());
+                });
+            }
+            Request::List { .. } => client.listed(result, self.listed.get()),
+        });
+    }
+
+    fn poll_mount(&self) -> Poll<()> {
+        loop {
+            match self.phase.get() {
+                Phase::Start => {
+                    self.cached.set(None);
+                    self.dirty.set(false);
+                    if self.device.block_size() != SECTOR_SIZE {
+                        return Poll::Failed(ReturnCode::ENOSUPPORT);
+                    }
+                    self.phase.set(Phase::ReadBoot);
+                }
+                Phase::ReadBoot => match ready!(self.sector(0, |sector| boot_sector(sector))) {
+                    Boot::Volume(volume) => {
+                        self.volume.set(volume);
+                        self.mounted.set(true);
+                        return Poll::Ready(());
+                    }
+                    Boot::Partition(start) => {
+                        self.partition.set(start);
+                        self.phase.set(Phase::ReadPartition);
+                    }
+                    Boot::Invalid => return Poll::Failed(ReturnCode::ENOSUPPORT),
+                },
+                Phase::ReadPartition => {
+                    let start = self.partition.get();
+                    match ready!(self.sector(start, |sector| parse_volume(sector, start))) {
+                        Some(volume) => {
+                            self.volume.set(volume);
+                            self.mounted.set(true);
+                            return Poll::Ready(());
+                        }
+                        None => return Poll::Failed(ReturnCode::ENOSUPPORT),
+                    }
+                }
+                _ => return Poll::Failed(ReturnCode::FAIL),
+            }
+        }
+    }
+
+    fn poll_open(&self, dir: Dir, name: [u8; 11], flags: usize, want_dir: bool) -> Poll<()> {
+        let volume = self.volume.get();
+        loop {
+            match self.phase.get() {
+                Phase::Start => {
+                    self.cursor.set(volume.dir_start(dir));
+                    self.found.set(None);
+                    self.free_slot.set(None);
+                    self.phase.set(Phase::Scan);
+                }
+                Phase::Scan => {
+                    let sector = volume.cursor_sector(self.cursor.get());
+                    let (scan, free) = ready!(
+                        self.sector(sector, |buffer| { scan_sector(buffer, sector, &name) })
+                    );
+                    if self.free_slot.get().is_none() {
+                        self.free_slot.set(free);
+                    }
+                    match scan {
+                        Scan::Found(entry) => {
+                            self.found.set(Some(entry));
+                            self.phase.set(Phase::Found);
+                        }
+                        Scan::End => self.phase.set(Phase::Create),
+                        Scan::More => self.phase.set(Phase::ScanNext),
+                    }
+                }
+                Phase::ScanNext => match ready!(self.next_cursor(self.cursor.get())) {
+                    Some(cursor) => {
+                        self.cursor.set(cursor);
+                        self.phase.set(Phase::Scan);
+                    }
+                    None => self.phase.set(Phase::Create),
+                },
+                Phase::Found => {
+                    let entry = match self.found.get() {
+                        Some(entry) => entry,
+                        None => return Poll::Failed(ReturnCode::FAIL),
+                    };
+                    if entry.is_dir != want_dir {
+                        return Poll::Failed(ReturnCode::EINVAL);
+                    }
+                    if !want_dir && entry.is_system != (flags & OPEN_SYSTEM != 0) {
+                        return Poll::Failed(ReturnCode::ERESERVE);
+                    }
+                    if !want_dir && flags & OPEN_TRUNCATE != 0 && entry.size != 0 {
+                        self.free_next.set(entry.first_cluster);
+                        self.phase.set(Phase::Truncate);
+                    } else {
+                        self.phase.set(Phase::Opened);
+                    }
+                }
+                Phase::Truncate => {
+                    ready!(self.free_chain());
+                    self.phase.set(Phase::TruncateEntry);
+                }
+                Phase::TruncateEntry => {
+                    let mut entry = match self.found.get() {
+                        Some(entry) => entry,
+                        None => return Poll::Failed(ReturnCode::FAIL),
+                    };
+                    ready!(self.sector(entry.slot.sector, |buffer| {
+                        let raw = &mut buffer[entry.slot.offset..entry.slot.offset + ENTRY_SIZE];
+                        set_entry_cluster(raw, 0);
+                        write_u32(&mut raw[28..32], 0);
+                        self.dirty.set(true);
+                    }));
+                    entry.first_cluster = 0;
+                    entry.size = 0;
+                    self.found.set(Some(entry));
+                    self.phase.set(Phase::Flush);
+                }
+                Phase::Create => {
+                    if flags & OPEN_CREATE == 0 {
+                        return Poll::Failed(ReturnCode::ENOSUPPORT);
+                    }
+                    if self.free_slot.get().is_some() {
+                        self.phase.set(if want_dir {
+                            Phase::NewDir
+                        } else {
+                            Phase::WriteEntry
+                        });
+                    } else if self.cursor.get().cluster == 0 {
+                        // The FAT16 root directory cannot grow.
+                        return Poll::Failed(ReturnCode::ENOMEM);
+                    } else {
+                        self.phase.set(Phase::Extend);
+                    }
+                }
+                Phase::Extend => {
+                    self.new_cluster.set(ready!(self.alloc_cluster()));
+                    self.phase.set(Phase::ExtendZero);
+                }
+                Phase::ExtendZero => {
+                    ready!(self.zero_cluster(self.new_cluster.get()));
+                    self.phase.set(Phase::ExtendLink);
+                }
+                Phase::ExtendLink => {
+                    let cluster = self.new_cluster.get();
+                    ready!(self.fat_set(self.cursor.get().cluster, cluster));
+                    self.free_slot.set(Some(Slot {
+                        sector: volume.cluster_sector(cluster),
+                        offset: 0,
+                    }));
+                    self.phase.set(Phase::Create);
+                }
+                Phase::NewDir => {
+                    self.new_cluster.set(ready!(self.alloc_cluster()));
+                    self.phase.set(Phase::NewDirZero);
+                }
+                Phase::NewDirZero => {
+                    ready!(self.zero_cluster(self.new_cluster.get()));
+                    self.phase.set(Phase::NewDirDots);
+                }
+                Phase::NewDirDots => {
+                    let cluster = self.new_cluster.get();
+                    ready!(self.sector(volume.cluster_sector(cluster), |buffer| {
+                        write_entry(&mut buffer[0..32], b".          ", ATTR_DIRECTORY, cluster);
+                        write_entry(&mut buffer[32..64], b"..         ", ATTR_DIRECTORY, dir.0);
+                        self.dirty.set(true);
+                    }));
+                    self.phase.set(Phase::WriteEntry);
+                }
+                Phase::WriteEntry => {
+                    let slot = match self.free_slot.get() {
+                        Some(slot) => slot,
+                        None => return Poll::Failed(ReturnCode::FAIL),
+                    };
+                    let is_system = !want_dir && flags & OPEN_SYSTEM != 0;
+                    let (attr, cluster) = if want_dir {
+                        (ATTR_DIRECTORY, self.new_cluster.get())
+                    } else if is_system {
+                        (ATTR_ARCHIVE | ATTR_HIDDEN | ATTR_SYSTEM, 0)
+                    } else {
+                        (ATTR_ARCHIVE, 0)
+                    };
+                    ready!(self.sector(slot.sector, |buffer| {
+                        write_entry(
+                            &mut buffer[slot.offset..slot.offset + ENTRY_SIZE],
+                            &name,
+                            attr,
+                            cluster,
+                        );
+                        self.dirty.set(true);
+                    }));
+                    self.found.set(Some(Entry {
+                        slot: slot,
+                        first_cluster: cluster,
+                        size: 0,
+                        is_dir: want_dir,
+                        is_system: is_system,
+                    }));
+                    self.phase.set(Phase::Flush);
+                }
+                Phase::Flush => {
+                    ready!(self.flush());
+                    self.phase.set(Phase::Opened);
+                }
+                Phase::Opened => {
+                    if want_dir {
+                        return Poll::Ready(());
+                    }
+                    let (entry, index) = match (self.found.get(), self.free_file()) {
+                        (Some(entry), Some(index)) => (entry, index),
+                        _ => return Poll::Failed(ReturnCode::ENOMEM),
+                    };
+                    self.set_file(
+                        index,
+                        FileState {
+                            in_use: true,
+                            append: flags & OPEN_APPEND != 0,
+                            entry: entry.slot,
+                            first_cluster: entry.first_cluster,
+                            size: entry.size,
+                            position: 0,
+                            cluster: 0,
+                            cluster_index: 0,
+                        },
+                    );
+                    self.opened.set(index);
+                    return Poll::Ready(());
+                }
+                _ => return Poll::Failed(ReturnCode::FAIL),
+            }
+        }
+    }
+
+    fn poll_read(&self, index: usize, length: usize) -> Poll<()> {
+        let volume = self.volume.get();
+        loop {
+            let mut file = self.file(index);
+            let done = self.transferred.get();
+            if done >= length || file.position >= file.size {
+                return Poll::Ready(());
+            }
+            ready!(self.seek(index, false));
+            file = self.file(index);
+
+            let sector = volume.cluster_sector(file.cluster)
+                + (file.position % volume.cluster_bytes()) / SECTOR_SIZE as u32;
+            let offset = file.position as usize % SECTOR_SIZE;
+            let count = cmp::min(
+                cmp::min(SECTOR_SIZE - offset, length - done),
+                (file.size - file.position) as usize,
+            );
+            ready!(self.sector(sector, |buffer| {
+                self.data.map(|data| {
+                    data[done..done + count].copy_from_slice(&buffer[offset..offset + count]);
+                });
+            }));
+            file.position += count as u32;
+            self.set_file(index, file);
+            self.transferred.set(done + count);
+        }
+    }
+
+    fn poll_write(&self, index: usize, length: usize) -> Poll<()> {
+        let volume = self.volume.get();
+        loop {
+            match self.phase.get() {
+                Phase::Start => {
+                    let mut file = self.file(index);
+                    if file.append {
+                        file.position = file.size;
+                        self.set_file(index, file);
+                    }
+                    self.phase.set(Phase::Transfer);
+                }
+                Phase::Transfer => {
+                    let done = self.transferred.get();
+                    if done >= length {
+                        self.phase.set(Phase::UpdateEntry);
+                        continue;
+                    }
+                    ready!(self.seek(index, true));
+                    let mut file = self.file(index);
+
+                    let sector = volume.cluster_sector(file.cluster)
+                        + (file.position % volume.cluster_bytes()) / SECTOR_SIZE as u32;
+                    let offset = file.position as usize % SECTOR_SIZE;
+                    let count = cmp::min(SECTOR_SIZE - offset, length - done);
+                    if offset == 0 && (count == SECTOR_SIZE || file.position >= file.size) {
+                        // Nothing in the sector needs to be kept.
+                        ready!(self.sector_zeroed(sector));
+                    }
+                    ready!(self.sector(sector, |buffer| {
+                        self.data.map(|data| {
+                            buffer[offset..offset + count]
+                                .copy_from_slice(&data[done..done + count]);
+                        });
+                        self.dirty.set(true);
+                    }));
+                    file.position += count as u32;
+                    file.size = cmp::max(file.size, file.position);
+                    self.set_file(index, file);
+                    self.transferred.set(done + count);
+                }
+                Phase::UpdateEntry => {
+                    let file = self.file(index);
+                    ready!(self.sector(file.entry.sector, |buffer| {
+                        let raw = &mut buffer[file.entry.offset..file.entry.offset + ENTRY_SIZE];
+                        set_entry_cluster(raw, file.first_cluster);
+                        write_u32(&mut raw[28..32], file.size);
+                        write_u16(&mut raw[24..26], DATE);
+                        self.dirty.set(true);
+                    }));
+                    self.phase.set(Phase::Flush);
+                }
+                Phase::Flush => {
+                    ready!(self.flush());
+                    return Poll::Ready(());
+                }
+                _ => return Poll::Failed(ReturnCode::FAIL),
+            }
+        }
+    }
+
+    fn poll_list(&self, dir: Dir, index: usize) -> Poll<()> {
+        let volume = self.volume.get();
+        loop {
+            match self.phase.get() {
+                Phase::Start => {
+                    self.cursor.set(volume.dir_start(dir));
+                    self.skip.set((index / ENTRIES_PER_SECTOR) as u32);
+                    self.list_index.set(index);
+                    self.listed.set(None);
+                    self.phase.set(Phase::ListSkip);
+                }
+                Phase::ListSkip => {
+                    let skip = self.skip.get();
+                    let mut cursor = self.cursor.get();
+                    let sectors = if cursor.cluster == 0 {
+                        volume.root_sectors
+                    } else {
+                        volume.sectors_per_cluster
+                    };
+                    if cursor.sector + skip < sectors {
+                        cursor.sector += skip;
+                        self.cursor.set(cursor);
+                        self.phase.set(Phase::ListScan);
+                    } else if cursor.cluster == 0 {
+                        return Poll::Ready(());
+                    } else {
+                        let next = ready!(self.fat_get(cursor.cluster));
+                        if volume.is_end(next) {
+                            return Poll::Ready(());
+                        }
+                        self.skip.set(skip - (sectors - cursor.sector));
+                        self.cursor.set(Cursor {
+                            cluster: next,
+                            sector: 0,
+                        });
+                    }
+                }
+                Phase::ListScan => {
+                    let sector = volume.cursor_sector(self.cursor.get());
+                    let first = self.list_index.get() % ENTRIES_PER_SECTOR;
+                    let start = self.list_index.get() - first;
+                    match ready!(self.sector(sector, |buffer| list_sector(buffer, first))) {
+                        Listed::Found(mut entry, i) => {
+                            entry.index = start + i;
+                            self.listed.set(Some(entry));
+                            return Poll::Ready(());
+                        }
+                        Listed::End => return Poll::Ready(()),
+                        Listed::More => {
+                            self.list_index.set(start + ENTRIES_PER_SECTOR);
+                            self.phase.set(Phase::ListNext);
+                        }
+                    }
+                }
+                Phase::ListNext => match ready!(self.next_cursor(self.cursor.get())) {
+                    Some(cursor) => {
+                        self.cursor.set(cursor);
+                        self.phase.set(Phase::ListScan);
+                    }
+                    None => return Poll::Ready(()),
+                },
+                _ => return Poll::Failed(ReturnCode::FAIL),
+            }
+        }
+    }
+
+    /// Run `f` on the contents of `sector`, once it is in the cache.
+    fn sector<R, F: FnOnce(&mut [u8]) -> R>(&self, sector: u32, f: F) -> Poll<R> {
+        if self.cached.get() == Some(sector) {
+            return self
+                .buffer
+                .map_or(Poll::Failed(ReturnCode::FAIL), |buffer| {
+                    Poll::Ready(f(buffer))
+                });
+        }
+        pending(self.start_io(Some(sector)))
+    }
+
+    /// Make `sector` the cached sector and fill it with zeros, without
+    /// reading it.
+    fn sector_zeroed(&self, sector: u32) -> Poll<()> {
+        if self.cached.get() != Some(sector) {
+            if self.dirty.get() {
+                return pending(self.start_io(None));
+            }
+            self.cached.set(Some(sector));
+        }
+        self.buffer
+            .map_or(Poll::Failed(ReturnCode::FAIL), |buffer| {
+                for byte in buffer.iter_mut() {
+                    *byte = 0;
+                }
+                self.dirty.set(true);
+                Poll::Ready(())
+            })
+    }
+
+    /// Write the cached sector back to the device if it has been changed.
+    fn flush(&self) -> Poll<()> {
+        if self.dirty.get() {
+            pending(self.start_io(None))
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    /// Write back the cached sector if it has been changed, or else read
+    /// `read`.
+    fn start_io(&self, read: Option<u32>) -> ReturnCode {
+        if self.device.num_blocks() == 0 {
+            return ReturnCode::ERESERVE;
+        }
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let (res, buffer) = match (self.dirty.get(), self.cached.get(), read) {
+                (true, Some(sector), _) => {
+                    self.mirror.set(0);
+                    self.device.write_block(sector as usize, buffer)
+                }
+                (_, _, Some(sector)) => {
+                    self.cached.set(None);
+                    self.dirty.set(false);
+                    self.reading.set(Some(sector));
+                    self.device.read_block(sector as usize, buffer)
+                }
+                _ => (ReturnCode::EINVAL, Some(buffer)),
+            };
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            res
+        })
+    }
+
+    /// The cached sector cannot be trusted after a failed read or write.
+    fn io_failed(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.cached.set(None);
+        self.dirty.set(false);
+        self.reading.set(None);
+        if self.request.get() != Request::Idle {
+            self.io_error.set(true);
+            self.run();
+        }
+    }
+
+    fn fat_get(&self, cluster: u32) -> Poll<u32> {
+        let volume = self.volume.get();
+        let (sector, offset) = volume.fat_location(cluster);
+        self.sector(sector, |buffer| volume.read_fat(buffer, offset))
+    }
+
+    fn fat_set(&self, cluster: u32, value: u32) -> Poll<()> {
+        let volume = self.volume.get();
+        let (sector, offset) = volume.fat_location(cluster);
+        self.sector(sector, |buffer| {
+            volume.write_fat(buffer, offset, value);
+            self.dirty.set(true);
+        })
+    }
+
+    /// Find a free cluster and mark it as the end of a chain.
+    fn alloc_cluster(&self) -> Poll<u32> {
+        let volume = self.volume.get();
+        let entry_size = volume.fat_entry_size();
+        loop {
+            let scanned = self.alloc_scanned.get();
+            if scanned >= volume.clusters {
+                self.alloc_scanned.set(0);
+                return Poll::Failed(ReturnCode::ENOMEM);
+            }
+            let mut first = self.alloc_next.get();
+            if volume.is_end(first) {
+                first = 2;
+            }
+            let (sector, offset) = volume.fat_location(first);
+            let count = cmp::min(
+                ((SECTOR_SIZE - offset) / entry_size) as u32,
+                volume.clusters + 2 - first,
+            );
+            let found = ready!(self.sector(sector, |buffer| {
+                for i in 0..count {
+                    let entry = offset + i as usize * entry_size;
+                    if volume.read_fat(buffer, entry) == 0 {
+                        volume.write_fat(buffer, entry, volume.end_mark());
+                        self.dirty.set(true);
+                        return Some(first + i);
+                    }
+                }
+                None
+            }));
+            match found {
+                Some(cluster) => {
+                    self.alloc_next.set(cluster + 1);
+                    self.alloc_scanned.set(0);
+                    return Poll::Ready(cluster);
+                }
+                None => {
+                    self.alloc_next.set(first + count);
+                    self.alloc_scanned.set(scanned + count);
+                }
+            }
+        }
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Poll<()> {
+        let volume = self.volume.get();
+        while self.zeroed.get() < volume.sectors_per_cluster {
+            ready!(self.sector_zeroed(volume.cluster_sector(cluster) + self.zeroed.get()));
+            self.zeroed.set(self.zeroed.get() + 1);
+        }
+        self.zeroed.set(0);
+        Poll::Ready(())
+    }
+
+    /// Free the cluster chain starting at `free_next`.
+    fn free_chain(&self) -> Poll<()> {
+        let volume = self.volume.get();
+        loop {
+            let cluster = self.free_next.get();
+            if volume.is_end(cluster) {
+                return Poll::Ready(());
+            }
+            let (sector, offset) = volume.fat_location(cluster);
+            let next = ready!(self.sector(sector, |buffer| {
+                let next = volume.read_fat(buffer, offset);
+                volume.write_fat(buffer, offset, 0);
+                self.dirty.set(true);
+                next
+            }));
+            self.free_next.set(next);
+            if cluster < self.alloc_next.get() {
+                self.alloc_next.set(cluster);
+            }
+        }
+    }
+
+    /// Find the cluster holding the position of a file, extending the file
+    /// if `extend` is set.
+    fn seek(&self, index: usize, extend: bool) -> Poll<()> {
+        let volume = self.volume.get();
+        loop {
+            let mut file = self.file(index);
+            let target = file.position / volume.cluster_bytes();
+            if file.first_cluster == 0 {
+                if !extend {
+                    return Poll::Failed(ReturnCode::FAIL);
+                }
+                let cluster = ready!(self.alloc_cluster());
+                file.first_cluster = cluster;
+                file.cluster = cluster;
+                file.cluster_index = 0;
+            } else if file.cluster == 0 || file.cluster_index > target {
+                file.cluster = file.first_cluster;
+                file.cluster_index = 0;
+            } else if file.cluster_index == target {
+                return Poll::Ready(());
+            } else {
+                match self.link.get() {
+                    Link::Idle => {
+                        let next = ready!(self.fat_get(file.cluster));
+                        if !volume.is_end(next) {
+                            file.cluster = next;
+                            file.cluster_index += 1;
+                        } else if extend {
+                            self.link.set(Link::Alloc);
+                        } else {
+                            return Poll::Failed(ReturnCode::FAIL);
+                        }
+                    }
+                    Link::Alloc => {
+                        let cluster = ready!(self.alloc_cluster());
+                        self.link.set(Link::Link(cluster));
+                    }
+                    Link::Link(cluster) => {
+                        ready!(self.fat_set(file.cluster, cluster));
+                        file.cluster = cluster;
+                        file.cluster_index += 1;
+                        self.link.set(Link::Idle);
+                    }
+                }
+            }
+            self.set_file(index, file);
+        }
+    }
+
+    /// The next sector of a directory, or `None` at its end.
+    fn next_cursor(&self, cursor: Cursor) -> Poll<Option<Cursor>> {
+        let volume = self.volume.get();
+        let next = Cursor {
+            cluster: cursor.cluster,
+            sector: cursor.sector + 1,
+        };
+        if cursor.cluster == 0 {
+            return Poll::Ready(if next.sector < volume.root_sectors {
+                Some(next)
+            } else {
+                None
+            });
+        }
+        if next.sector < volume.sectors_per_cluster {
+            return Poll::Ready(Some(next));
+        }
+        let cluster = ready!(self.fat_get(cursor.cluster));
+        if volume.is_end(cluster) {
+            Poll::Ready(None)
+        } else {
+            Poll::Ready(Some(Cursor {
+                cluster: cluster,
+                sector: 0,
+            }))
+        }
+    }
+}
+
+impl hil::block::Client for FatFs<'a> {
+    fn ready(&self, result: ReturnCode) {
+        if result == ReturnCode::SUCCESS {
+            if !self.mounted.get() {
+                // Clients are told in `mounted`, and a client request in
+                // progress keeps the volume from being mounted now.
+                self.mount();
+            }
+        } else if self.mounted.get() {
+            self.unmount();
+            self.client.map(|client| client.unmounted());
+        }
+    }
+
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8]) {
+        if result != ReturnCode::SUCCESS {
+            self.io_failed(buffer);
+            return;
+        }
+        self.buffer.replace(buffer);
+        self.cached.set(self.reading.get());
+        self.reading.set(None);
+        self.run();
+    }
+
+    fn write_done(&self, result: ReturnCode, buffer: &'static mut [u8]) {
+        if result != ReturnCode::SUCCESS {
+            self.io_failed(buffer);
+            return;
+        }
+        let volume = self.volume.get();
+        if let Some(sector) = self.cached.get() {
+            // Keep every copy of the FAT up to date.
+            if volume.is_fat_sector(sector) && self.mirror.get() + 1 < volume.num_fats {
+                self.mirror.set(self.mirror.get() + 1);
+                let copy = sector + self.mirror.get() * volume.fat_sectors;
+                let (res, buffer) = self.device.write_block(copy as usize, buffer);
+                if res != ReturnCode::SUCCESS {
+                    buffer.map(|buffer| self.io_failed(buffer));
+                }
+                return;
+            }
+        }
+        self.dirty.set(false);
+        self.buffer.replace(buffer);
+        self.run();
+    }
+
+    fn erase_done(&self, _result: ReturnCode) {}
+
+    fn flush_done(&self, _result: ReturnCode) {}
+}
+
+fn pending<T>(result: ReturnCode) -> Poll<T> {
+    if result == ReturnCode::SUCCESS {
+        Poll::Pending
+    } else {
+        Poll::Failed(result)
+    }
+}
+
+/// Find the volume in the first sector of a device, which is either the boot
+/// sector of a volume or a master boot record.
+fn boot_sector(sector: &[u8]) -> Boot {
+    if sector[510] != 0x55 || sector[511] != 0xaa {
+        return Boot::Invalid;
+    }
+    if let Some(volume) = parse_volume(sector, 0) {
+        return Boot::Volume(volume);
+    }
+    for partition in sector[446..510].chunks(16) {
+        match partition[4] {
+            // FAT16 and FAT32 partition types.
+            0x04 | 0x06 | 0x0b | 0x0c | 0x0e => {
+                return Boot::Partition(read_u32(&partition[8..12]));
+            }
+            _ => {}
+        }
+    }
+    Boot::Invalid
+}
+
+/// Parse the boot sector of a volume starting at sector `start`. FAT12 and
+/// volumes with sectors other than 512 bytes are not supported.
+fn parse_volume(sector: &[u8], start: u32) -> Option<Volume> {
+    if sector[0] != 0xeb && sector[0] != 0xe9 {
+        return None;
+    }
+    if read_u16(&sector[11..]) as usize != SECTOR_SIZE {
+        return None;
+    }
+    let sectors_per_cluster = sector[13] as u32;
+    if sectors_per_cluster == 0 || sectors_per_cluster & (sectors_per_cluster - 1) != 0 {
+        return None;
+    }
+    let reserved = read_u16(&sector[14..]) as u32;
+    let num_fats = sector[16] as u32;
+    let root_entries = read_u16(&sector[17..]) as u32;
+    let total = match read_u16(&sector[19..]) {
+        0 => read_u32(&sector[32..]),
+        total => total as u32,
+    };
+    let fat_sectors = match read_u16(&sector[22..]) {
+        0 => read_u32(&sector[36..]),
+        sectors => sectors as u32,
+    };
+    if num_fats == 0 || fat_sectors == 0 {
+        return None;
+    }
+
+    let root_sectors =
+        (root_entries * ENTRY_SIZE as u32 + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
+    let metadata = reserved + num_fats * fat_sectors + root_sectors;
+    if total <= metadata {
+        return None;
+    }
+    let clusters = (total - metadata) / sectors_per_cluster;
+    if clusters < 4085 {
+        return None;
+    }
+    let fat32 = clusters >= 65525;
+    if !fat32 && root_sectors == 0 {
+        return None;
+    }
+
+    let fat_start = start + reserved;
+    let root_start = fat_start + num_fats * fat_sectors;
+    Some(Volume {
+        fat32: fat32,
+        sectors_per_cluster: sectors_per_cluster,
+        fat_start: fat_start,
+        fat_sectors: fat_sectors,
+        num_fats: num_fats,
+        root_start: root_start,
+        root_sectors: if fat32 { 0 } else { root_sectors },
+        root_cluster: if fat32 { read_u32(&sector[44..]) } else { 0 },
+        data_start: root_start + root_sectors,
+        clusters: clusters,
+    })
+}
+
+/// Convert a name like `log.txt` to the padded, upper case form stored in
+/// directory entries.
+fn short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, extension) = match name.iter().rposition(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &name[name.len()..]),
+    };
+    if base.len() == 0 || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    for (i, &c) in base.iter().enumerate() {
+        short[i] = short_name_char(c)?;
+    }
+    for (i, &c) in extension.iter().enumerate() {
+        short[8 + i] = short_name_char(c)?;
+    }
+    Some(short)
+}
+
+fn short_name_char(c: u8) -> Option<u8> {
+    match c {
+        b'A'...b'Z' | b'0'...b'9' => Some(c),
+        b'a'...b'z' => Some(c - b'a' + b'A'),
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_'
+        | b'`' | b'{' | b'}' | b'~' => Some(c),
+        _ => None,
+    }
+}
+
+fn entry_cluster(entry: &[u8]) -> u32 {
+    (read_u16(&entry[20..]) as u32) << 16 | read_u16(&entry[26..]) as u32
+}
+
+fn set_entry_cluster(entry: &mut [u8], cluster: u32) {
+    write_u16(&mut entry[20..22], (cluster >> 16) as u16);
+    write_u16(&mut entry[26..28], cluster as u16);
+}
+
+fn write_entry(entry: &mut [u8], name: &[u8; 11], attr: u8, cluster: u32) {
+    for byte in entry.iter_mut() {
+        *byte = 0;
+    }
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attr;
+    write_u16(&mut entry[16..18], DATE);
+    write_u16(&mut entry[18..20], DATE);
+    write_u16(&mut entry[24..26], DATE);
+    set_entry_cluster(entry, cluster);
+}
+
+/// Look for `name` in a directory sector, and for a free entry.
+fn scan_sector(buffer: &[u8], sector: u32, name: &[u8; 11]) -> (Scan, Option<Slot>) {
+    let mut free = None;
+    for (i, entry) in buffer[..SECTOR_SIZE].chunks(ENTRY_SIZE).enumerate() {
+        let slot = Slot {
+            sector: sector,
+            offset: i * ENTRY_SIZE,
+        };
+        match entry[0] {
+            ENTRY_END => return (Scan::End, free.or(Some(slot))),
+            ENTRY_FREE => {
+                free = free.or(Some(slot));
+                continue;
+            }
+            _ => {}
+        }
+        // Skip volume labels and long name entries.
+        if entry[11] & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        if &entry[0..11] == &name[..] {
+            let found = Entry {
+                slot: slot,
+                first_cluster: entry_cluster(entry),
+                size: read_u32(&entry[28..]),
+                is_dir: entry[11] & ATTR_DIRECTORY != 0,
+                is_system: entry[11] & ATTR_SYSTEM != 0,
+            };
+            return (Scan::Found(found), free);
+        }
+    }
+    (Scan::More, free)
+}
+
+/// Find the first file or directory that is not a system file in a directory
+/// sector, starting at entry `first`.
+fn list_sector(buffer: &[u8], first: usize) -> Listed {
+    for (i, entry) in buffer[..SECTOR_SIZE]
+        .chunks(ENTRY_SIZE)
+        .enumerate()
+        .skip(first)
+    {
+        match entry[0] {
+            ENTRY_END => return Listed::End,
+            ENTRY_FREE | b'.' => continue,
+            _ => {}
+        }
+        // Skip volume labels, long name entries and system files.
+        if entry[11] & (ATTR_VOLUME_ID | ATTR_SYSTEM) != 0 {
+            continue;
+        }
+
+        let mut name = [0; 12];
+        let mut name_len = 0;
+        for &c in entry[0..8].iter().take_while(|&&c| c != b' ') {
+            name[name_len] = c;
+            name_len += 1;
+        }
+        if entry[8] != b' ' {
+            name[name_len] = b'.';
+            name_len += 1;
+            for &c in entry[8..11].iter().take_while(|&&c| c != b' ') {
+                name[name_len] = c;
+                name_len += 1;
+            }
+        }
+        let listed = DirEntry {
+            name: name,
+            name_len: name_len,
+            size: read_u32(&entry[28..]),
+            is_dir: entry[11] & ATTR_DIRECTORY != 0,
+            index: 0,
+        };
+        return Listed::Found(listed, i);
+    }
+    Listed::More
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use self::std::vec::Vec;
+    use super::*;
+
+    const SECTORS: usize = 4200;
+    const FAT_SECTORS: usize = 17;
+
+    /// Block device on a RAM image that completes requests when `step` is
+    /// called, like a device completing them from an interrupt.
+    struct RamDisk {
+        image: TakeCell<'static, [u8]>,
+        pending: Cell<Option<(bool, usize)>>,
+        buffer: TakeCell<'static, [u8]>,
+        client: OptionalCell<&'static hil::block::Client>,
+    }
+
+    impl RamDisk {
+        fn step(&self) -> bool {
+            let (write, block) = match self.pending.take() {
+                Some(request) => request,
+                None => return false,
+            };
+            let buffer = self.buffer.take().unwrap();
+            self.image.map(|image| {
+                let sector = &mut image[block * SECTOR_SIZE..(block + 1) * SECTOR_SIZE];
+                if write {
+                    sector.copy_from_slice(&buffer[..SECTOR_SIZE]);
+                } else {
+                    buffer[..SECTOR_SIZE].copy_from_slice(sector);
+                }
+            });
+            self.client.map(move |client| {
+                if write {
+                    client.write_done(ReturnCode::SUCCESS, buffer);
+                } else {
+                    client.read_done(ReturnCode::SUCCESS, buffer);
+                }
+            });
+            true
+        }
+
+        fn start(
+            &self,
+            write: bool,
+            block: usize,
+            buffer: &'static mut [u8],
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            if block >= SECTORS || self.pending.get().is_some() {
+                return (ReturnCode::EINVAL, Some(buffer));
+            }
+            self.buffer.replace(buffer);
+            self.pending.set(Some((write, block)));
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    impl hil::block::BlockDevice<'static> for RamDisk {
+        fn set_client(&self, client: &'static hil::block::Client) {
+            self.client.set(client);
+        }
+
+        fn block_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn num_blocks(&self) -> usize {
+            SECTORS
+        }
+
+        fn read_block(
+            &self,
+            block: usize,
+            buffer: &'static mut [u8],
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            self.start(false, block, buffer)
+        }
+
+        fn write_block(
+            &self,
+            block: usize,
+            buffer: &'static mut [u8],
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            self.start(true, block, buffer)
+        }
+
+        fn erase_block(&self, _block: usize) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn flush(&self) -> ReturnCode {
+            ReturnCode::EALREADY
+        }
+    }
+
+    struct TestClient {
+        mounted: Cell<Option<ReturnCode>>,
+        unmounted: Cell<bool>,
+        dir: Cell<Option<(ReturnCode, Dir)>>,
+        file: Cell<Option<(ReturnCode, File)>>,
+        done: Cell<Option<(ReturnCode, usize)>>,
+        buffer: TakeCell<'static, [u8]>,
+        listed: Cell<Option<(ReturnCode, Option<DirEntry>)>>,
+    }
+
+    impl FatClient for TestClient {
+        fn mounted(&self, result: ReturnCode) {
+            self.mounted.set(Some(result));
+        }
+
+        fn unmounted(&self) {
+            self.unmounted.set(true);
+        }
+
+        fn dir_opened(&self, result: ReturnCode, dir: Dir) {
+            self.dir.set(Some((result, dir)));
+        }
+
+        fn file_opened(&self, result: ReturnCode, file: File) {
+            self.file.set(Some((result, file)));
+        }
+
+        fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.done.set(Some((result, length)));
+        }
+
+        fn write_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.done.set(Some((result, length)));
+        }
+
+        fn listed(&self, result: ReturnCode, entry: Option<DirEntry>) {
+            self.listed.set(Some((result, entry)));
+        }
+    }
+
+    struct Test {
+        disk: &'static RamDisk,
+        fs: &'static FatFs<'static>,
+        client: &'static TestClient,
+    }
+
+    impl Test {
+        /// A FAT16 volume without a partition table, with one sector per
+        /// cluster and two copies of the FAT.
+        fn new() -> Test {
+            let mut image: Vec<u8> = Vec::new();
+            image.resize(SECTORS * SECTOR_SIZE, 0);
+            image[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+            write_u16(&mut image[11..], SECTOR_SIZE as u16);
+            image[13] = 1;
+            write_u16(&mut image[14..], 1);
+            image[16] = 2;
+            write_u16(&mut image[17..], 512);
+            write_u16(&mut image[19..], SECTORS as u16);
+            image[21] = 0xf8;
+            write_u16(&mut image[22..], FAT_SECTORS as u16);
+            image[510] = 0x55;
+            image[511] = 0xaa;
+            for copy in 0..2 {
+                let fat = (1 + copy * FAT_SECTORS) * SECTOR_SIZE;
+                image[fat..fat + 4].copy_from_slice(&[0xf8, 0xff, 0xff, 0xff]);
+            }
+
+            let disk: &'static RamDisk = Box::leak(Box::new(RamDisk {
+                image: TakeCell::new(Box::leak(image.into_boxed_slice())),
+                pending: Cell::new(None),
+                buffer: TakeCell::empty(),
+                client: OptionalCell::empty(),
+            }));
+            let fs: &'static FatFs = Box::leak(Box::new(FatFs::new(
+                disk,
+                Box::leak(Box::new([0; SECTOR_SIZE])),
+            )));
+            let client: &'static TestClient = Box::leak(Box::new(TestClient {
+                mounted: Cell::new(None),
+                unmounted: Cell::new(false),
+                dir: Cell::new(None),
+                file: Cell::new(None),
+                done: Cell::new(None),
+                buffer: TakeCell::new(Box::leak(Box::new([0; 2048]))),
+                listed: Cell::new(None),
+            }));
+            hil::block::BlockDevice::set_client(disk, fs);
+            fs.set_client(client);
+
+            let test = Test {
+                disk: disk,
+                fs: fs,
+                client: client,
+            };
+            assert_eq!(test.fs.mount(), ReturnCode::SUCCESS);
+            test.run();
+            assert_eq!(test.client.mounted.take(), Some(ReturnCode::SUCCESS));
+            test
+        }
+
+        /// Complete requests until the device is idle.
+        fn run(&self) {
+            while self.disk.step() {}
+        }
+
+        fn open_dir(&self, dir: Dir, name: &[u8], create: bool) -> (ReturnCode, Dir) {
+            assert_eq!(self.fs.open_dir(dir, name, create), ReturnCode::SUCCESS);
+            self.run();
+            self.client.dir.take().unwrap()
+        }
+
+        fn open(&self, dir: Dir, name: &[u8], flags: usize) -> (ReturnCode, File) {
+            assert_eq!(self.fs.open(dir, name, flags), ReturnCode::SUCCESS);
+            self.run();
+            self.client.file.take().unwrap()
+        }
+
+        fn write(&self, file: File, data: &[u8]) -> (ReturnCode, usize) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            let (res, _) = self.fs.write(file, buffer, data.len());
+            assert_eq!(res, ReturnCode::SUCCESS);
+            self.run();
+            self.client.done.take().unwrap()
+        }
+
+        fn read(&self, file: File, length: usize) -> (ReturnCode, Vec<u8>) {
+            let buffer = self.client.buffer.take().unwrap();
+            let (res, _) = self.fs.read(file, buffer, length);
+            assert_eq!(res, ReturnCode::SUCCESS);
+            self.run();
+            let (res, length) = self.client.done.take().unwrap();
+            let data = self.client.buffer.map(|buffer| buffer[..length].to_vec());
+            (res, data.unwrap())
+        }
+
+        /// Names of the entries of `dir`, with a slash after directories.
+        fn list(&self, dir: Dir) -> Vec<Vec<u8>> {
+            let mut names = Vec::new();
+            let mut index = 0;
+            loop {
+                assert_eq!(self.fs.list(dir, index), ReturnCode::SUCCESS);
+                self.run();
+                match self.client.listed.take().unwrap() {
+                    (ReturnCode::SUCCESS, Some(entry)) => {
+                        let mut name = entry.name[..entry.name_len].to_vec();
+                        if entry.is_dir {
+                            name.push(b'/');
+                        }
+                        names.push(name);
+                        index = entry.index + 1;
+                    }
+                    (ReturnCode::SUCCESS, None) => return names,
+                    (res, _) => panic!("list failed: {:?}", res),
+                }
+            }
+        }
+
+        fn sector(&self, sector: usize) -> Vec<u8> {
+            self.disk
+                .image
+                .map(|image| image[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE].to_vec())
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn mount_empty_volume() {
+        let test = Test::new();
+        assert!(test.fs.is_mounted());
+        assert!(test.list(test.fs.root()).is_empty());
+        assert_eq!(test.fs.mount(), ReturnCode::EALREADY);
+    }
+
+    #[test]
+    fn open_missing_file() {
+        let test = Test::new();
+        let (res, _) = test.open(test.fs.root(), b"none.txt", 0);
+        assert_eq!(res, ReturnCode::ENOSUPPORT);
+        assert_eq!(test.fs.open(test.fs.root(), b"too_long_name", 0), ReturnCode::EINVAL);
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let test = Test::new();
+        let root = test.fs.root();
+        let data: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+
+        let (res, file) = test.open(root, b"log.txt", OPEN_CREATE);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert_eq!(test.write(file, &data), (ReturnCode::SUCCESS, data.len()));
+        assert_eq!(test.fs.close(file), ReturnCode::SUCCESS);
+
+        let (res, file) = test.open(root, b"LOG.TXT", 0);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert_eq!(test.fs.size(file), Some(data.len()));
+        assert_eq!(test.read(file, 2048), (ReturnCode::SUCCESS, data.clone()));
+        // Reading at the end of the file needs no access to the device.
+        assert_eq!(test.read(file, 2048), (ReturnCode::SUCCESS, Vec::new()));
+        assert_eq!(test.list(root), [b"LOG.TXT".to_vec()]);
+
+        // Both copies of the FAT are kept up to date.
+        for sector in 1..1 + FAT_SECTORS {
+            assert_eq!(test.sector(sector), test.sector(sector + FAT_SECTORS));
+        }
+    }
+
+    #[test]
+    fn append_and_truncate() {
+        let test = Test::new();
+        let root = test.fs.root();
+
+        let (_, file) = test.open(root, b"data", OPEN_CREATE | OPEN_APPEND);
+        assert_eq!(test.write(file, b"hello "), (ReturnCode::SUCCESS, 6));
+        assert_eq!(test.write(file, b"world"), (ReturnCode::SUCCESS, 5));
+        // Reads continue from the end of the last write.
+        assert_eq!(test.read(file, 100).1, Vec::new());
+        test.fs.close(file);
+
+        let (_, file) = test.open(root, b"data", 0);
+        assert_eq!(test.read(file, 100).1, b"hello world".to_vec());
+        test.fs.close(file);
+
+        let (res, file) = test.open(root, b"data", OPEN_TRUNCATE);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert_eq!(test.fs.size(file), Some(0));
+        assert_eq!(test.write(file, b"again"), (ReturnCode::SUCCESS, 5));
+        test.fs.close(file);
+
+        let (_, file) = test.open(root, b"data", 0);
+        assert_eq!(test.read(file, 100).1, b"again".to_vec());
+    }
+
+    #[test]
+    fn directories() {
+        let test = Test::new();
+        let root = test.fs.root();
+
+        let (res, _) = test.open_dir(root, b"apps", false);
+        assert_eq!(res, ReturnCode::ENOSUPPORT);
+        let (res, dir) = test.open_dir(root, b"apps", true);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        let (res, file) = test.open(dir, b"a.bin", OPEN_CREATE);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert_eq!(test.write(file, b"in a directory"), (ReturnCode::SUCCESS, 14));
+
+        let (res, again) = test.open_dir(root, b"APPS", false);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert!(again == dir);
+        assert_eq!(test.list(root), [b"APPS/".to_vec()]);
+        assert_eq!(test.list(dir), [b"A.BIN".to_vec()]);
+
+        // A directory is not a file, and the other way around.
+        assert_eq!(test.open(root, b"apps", 0).0, ReturnCode::EINVAL);
+        assert_eq!(test.open_dir(dir, b"a.bin", false).0, ReturnCode::EINVAL);
+    }
+
+    #[test]
+    fn system_files_are_hidden() {
+        let test = Test::new();
+        let root = test.fs.root();
+
+        let (res, file) = test.open(root, b"owner", OPEN_CREATE | OPEN_SYSTEM);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert_eq!(test.write(file, b"package"), (ReturnCode::SUCCESS, 7));
+        test.fs.close(file);
+
+        assert!(test.list(root).is_empty());
+        assert_eq!(test.open(root, b"owner", 0).0, ReturnCode::ERESERVE);
+        assert_eq!(test.open(root, b"owner", OPEN_CREATE).0, ReturnCode::ERESERVE);
+        let (res, file) = test.open(root, b"owner", OPEN_SYSTEM);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        assert_eq!(test.read(file, 100).1, b"package".to_vec());
+    }
+
+    #[test]
+    fn remount_when_ready() {
+        let test = Test::new();
+        let (_, file) = test.open(test.fs.root(), b"file", OPEN_CREATE);
+
+        hil::block::Client::ready(test.fs, ReturnCode::EUNINSTALLED);
+        assert!(test.client.unmounted.get());
+        assert!(!test.fs.is_mounted());
+        assert_eq!(test.fs.size(file), None);
+
+        hil::block::Client::ready(test.fs, ReturnCode::SUCCESS);
+        test.run();
+        assert_eq!(test.client.mounted.take(), Some(ReturnCode::SUCCESS));
+        assert_eq!(test.list(test.fs.root()), [b"FILE".to_vec()]);
+    }
+}
@@ -0,0 +1,635 @@
+//! Provides userspace access to files on a FAT formatted SD card.
+//!
+//! Each process gets its own directory in the root directory of the card,
+//! named after the package name in its TBF header, and can only open and
+//! list files in that directory. The directory name is the 32-bit hash of
+//! the package name as eight hex digits, with the first three letters or
+//! digits of the package name as its extension, so that it fits in a short
+//! FAT name. The full package name is stored in a hidden system file in the
+//! directory, and a process whose package name hashes to the directory of
+//! another package is refused with `ERESERVE`. Processes without a package
+//! name cannot use the driver.
+//!
+//! The directory of a process is created when the process first uses the
+//! driver. Requests fail with `ERESERVE` while no card is ready to be
+//! mounted. Requests from all processes are served one at a time. A process
+//! can keep up to four files open. Files are not closed when a process exits,
+//! so a process that is restarted without closing its files uses up handles
+//! of the file system.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let fat_driver = static_init!(
+//!     capsules::fat_driver::FatDriver<'static>,
+//!     capsules::fat_driver::FatDriver::new(
+//!         fat,
+//!         &mut capsules::fat_driver::BUFFER,
+//!         kernel::Grant::create()
+//!     )
+//! );
+//! fat.set_client(fat_driver);
+//! ```
+
+use core::cmp;
+use fat::{Dir, DirEntry, FatClient, FatFs, File};
+use fat::{OPEN_APPEND, OPEN_CREATE, OPEN_SYSTEM, OPEN_TRUNCATE};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use util::fnv1a;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x50004;
+
+/// Data is copied through this buffer, which limits the length of a read or
+/// write.
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+/// Number of files each process can keep open.
+const FILES_PER_APP: usize = 4;
+
+/// Longest file name, with a dot before the extension.
+const MAX_NAME_LEN: usize = 12;
+
+/// Hidden file holding the package name of the process owning a directory.
+const OWNER_FILE: &[u8] = b"owner";
+
+#[derive(Clone, Copy)]
+pub enum Request {
+    Open { name_len: usize, flags: usize },
+    Read { fd: usize, length: usize },
+    Write { fd: usize, length: usize },
+    List { index: usize },
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    name: Option<AppSlice<Shared, u8>>,
+    data: Option<AppSlice<Shared, u8>>,
+    dir: AppDir,
+    files: [Option<File>; FILES_PER_APP],
+    // The request this app is waiting for, if any.
+    waiting: Option<Request>,
+}
+
+/// Progress of opening the directory of an app.
+#[derive(Clone, Copy)]
+enum AppDir {
+    Closed,
+    /// Opened, but not yet known to belong to the app.
+    Opened(Dir),
+    /// The owner file of the directory is open, to be read or written.
+    Checking(Dir, File),
+    /// Opened and owned by the app.
+    Ready(Dir),
+}
+
+impl Default for AppDir {
+    fn default() -> AppDir {
+        AppDir::Closed
+    }
+}
+
+/// Next call to make to the file system for the app being served.
+#[derive(Clone, Copy)]
+enum Action {
+    Mount,
+    OpenDir([u8; MAX_NAME_LEN], usize),
+    OpenOwner(Dir),
+    Open(Dir, [u8; MAX_NAME_LEN], usize, usize),
+    Read(File, usize),
+    Write(File, usize),
+    List(Dir, usize),
+    Fail(ReturnCode),
+}
+
+pub struct FatDriver<'a> {
+    fs: &'a FatFs<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl FatDriver<'a> {
+    pub fn new(
+        fs: &'a FatFs<'a>,
+        buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> FatDriver<'a> {
+        FatDriver {
+            fs: fs,
+            buffer: TakeCell::new(buffer),
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the request of the next waiting app. Apps whose request cannot
+    /// be started are told so through their callback.
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            return;
+        }
+
+        let mut next = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if app.waiting.is_some() {
+                    next = Some(app.appid());
+                }
+            });
+            if next.is_some() {
+                break;
+            }
+        }
+        next.map(|appid| self.step(appid));
+    }
+
+    /// Make the next call to the file system for the request of `appid`.
+    fn step(&self, appid: AppId) {
+        let action = self
+            .apps
+            .enter(appid, |app, _| self.prepare(app, appid))
+            .unwrap_or_else(|err| Action::Fail(err.into()));
+
+        // The file system may call back before returning, so the app is
+        // marked as served first.
+        self.serving_app.set(appid);
+        let res = match action {
+            Action::Mount => self.fs.mount(),
+            Action::OpenDir(name, name_len) => {
+                self.fs.open_dir(self.fs.root(), &name[..name_len], true)
+            }
+            Action::OpenOwner(dir) => self.fs.open(dir, OWNER_FILE, OPEN_CREATE | OPEN_SYSTEM),
+            Action::Open(dir, name, name_len, flags) => self.fs.open(dir, &name[..name_len], flags),
+            Action::Read(file, length) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let (res, buffer) = self.fs.read(file, buffer, length);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                res
+            }),
+            Action::Write(file, length) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let (res, buffer) = self.fs.write(file, buffer, length);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                res
+            }),
+            Action::List(dir, index) => self.fs.list(dir, index),
+            Action::Fail(res) => res,
+        };
+        if res != ReturnCode::SUCCESS {
+            self.complete(res, 0, 0);
+        }
+    }
+
+    fn prepare(&self, app: &mut App, appid: AppId) -> Action {
+        let request = match app.waiting {
+            Some(request) => request,
+            None => return Action::Fail(ReturnCode::FAIL),
+        };
+        if !self.fs.is_mounted() {
+            return Action::Mount;
+        }
+        let dir = match app.dir {
+            AppDir::Closed => {
+                let (name, name_len) = app_dir_name(appid.get_package_name());
+                return Action::OpenDir(name, name_len);
+            }
+            AppDir::Opened(dir) => return Action::OpenOwner(dir),
+            AppDir::Checking(_, owner) => return self.check_owner(owner, appid),
+            AppDir::Ready(dir) => dir,
+        };
+
+        match request {
+            Request::Open { name_len, flags } => {
+                if app.files.iter().all(|file| file.is_some()) {
+                    return Action::Fail(ReturnCode::ENOMEM);
+                }
+                let mut name = [0; MAX_NAME_LEN];
+                match app.name {
+                    Some(ref slice) if name_len <= slice.len() => {
+                        name[..name_len].copy_from_slice(&slice.as_ref()[..name_len]);
+                        Action::Open(dir, name, name_len, flags)
+                    }
+                    _ => Action::Fail(ReturnCode::EINVAL),
+                }
+            }
+            Request::Read { fd, length } => match app.files[fd] {
+                Some(file) => {
+                    let max = app.data.as_ref().map_or(0, |slice| slice.len());
+                    Action::Read(file, cmp::min(length, max))
+                }
+                None => Action::Fail(ReturnCode::EINVAL),
+            },
+            Request::Write { fd, length } => {
+                let file = match app.files[fd] {
+                    Some(file) => file,
+                    None => return Action::Fail(ReturnCode::EINVAL),
+                };
+                self.buffer
+                    .map_or(Action::Fail(ReturnCode::EBUSY), |buffer| match app.data {
+                        Some(ref slice) if length <= slice.len() && length <= buffer.len() => {
+                            buffer[..length].copy_from_slice(&slice.as_ref()[..length]);
+                            Action::Write(file, length)
+                        }
+                        _ => Action::Fail(ReturnCode::EINVAL),
+                    })
+            }
+            Request::List { index } => Action::List(dir, index),
+        }
+    }
+
+    /// Read the owner file of the directory of `appid`, or claim the
+    /// directory if the file is empty because it was just created.
+    fn check_owner(&self, owner: File, appid: AppId) -> Action {
+        let package_name = appid.get_package_name().as_bytes();
+        match self.fs.size(owner) {
+            Some(0) => self
+                .buffer
+                .map_or(Action::Fail(ReturnCode::EBUSY), |buffer| {
+                    let len = cmp::min(package_name.len(), buffer.len());
+                    buffer[..len].copy_from_slice(&package_name[..len]);
+                    Action::Write(owner, len)
+                }),
+            Some(_) => Action::Read(owner, self.buffer.map_or(0, |buffer| buffer.len())),
+            None => Action::Fail(ReturnCode::FAIL),
+        }
+    }
+
+    /// The owner file being checked for the app being served, if any.
+    fn checking(&self) -> Option<(Dir, File)> {
+        self.serving_app.map_or(None, |appid| {
+            self.apps
+                .enter(*appid, |app, _| match app.dir {
+                    AppDir::Checking(dir, owner) => Some((dir, owner)),
+                    _ => None,
+                })
+                .unwrap_or(None)
+        })
+    }
+
+    /// Close the owner file of the directory of the app being served, and
+    /// carry on with its request if the directory belongs to it.
+    fn owner_checked(&self, dir: Dir, owner: File, result: ReturnCode) {
+        self.fs.close(owner);
+        self.serving_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.dir = if result == ReturnCode::SUCCESS {
+                    AppDir::Ready(dir)
+                } else {
+                    AppDir::Closed
+                };
+            });
+        });
+        self.resume(result);
+    }
+
+    /// Finish the request of the app being served, and start the next one.
+    fn complete(&self, result: ReturnCode, data1: usize, data2: usize) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                app.callback
+                    .map(|mut cb| cb.schedule(From::from(result), data1, data2));
+            });
+        });
+        self.serve_waiting_apps();
+    }
+
+    /// Carry on with the request of the app being served after the card was
+    /// mounted or its directory opened or checked.
+    fn resume(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.complete(result, 0, 0);
+        } else {
+            self.serving_app.take().map(|appid| self.step(appid));
+        }
+    }
+}
+
+impl FatClient for FatDriver<'a> {
+    fn mounted(&self, result: ReturnCode) {
+        self.resume(result);
+    }
+
+    fn unmounted(&self) {
+        self.apps.each(|app| {
+            app.dir = AppDir::Closed;
+            app.files = [None; FILES_PER_APP];
+        });
+    }
+
+    fn dir_opened(&self, result: ReturnCode, dir: Dir) {
+        if result == ReturnCode::SUCCESS {
+            self.serving_app.map(|appid| {
+                let _ = self.apps.enter(*appid, |app, _| app.dir = AppDir::Opened(dir));
+            });
+        }
+        self.resume(result);
+    }
+
+    fn file_opened(&self, result: ReturnCode, file: File) {
+        let mut owner = false;
+        self.serving_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                if let AppDir::Opened(dir) = app.dir {
+                    owner = true;
+                    if result == ReturnCode::SUCCESS {
+                        app.dir = AppDir::Checking(dir, file);
+                    }
+                }
+            });
+        });
+        if owner {
+            self.resume(result);
+            return;
+        }
+
+        let mut fd = 0;
+        let mut result = result;
+        if result == ReturnCode::SUCCESS {
+            self.serving_app.map(|appid| {
+                let _ = self.apps.enter(*appid, |app, _| {
+                    match app.files.iter().position(|file| file.is_none()) {
+                        Some(free) => {
+                            app.files[free] = Some(file);
+                            fd = free;
+                        }
+                        None => result = ReturnCode::ENOMEM,
+                    }
+                });
+            });
+            if result != ReturnCode::SUCCESS {
+                self.fs.close(file);
+            }
+        }
+        self.complete(result, fd, 0);
+    }
+
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+        if let Some((dir, owner)) = self.checking() {
+            let owned = self.serving_app.map_or(false, |appid| {
+                let package_name = appid.get_package_name().as_bytes();
+                let len = cmp::min(package_name.len(), buffer.len());
+                &buffer[..length] == &package_name[..len]
+            });
+            self.buffer.replace(buffer);
+            let result = match result {
+                ReturnCode::SUCCESS if !owned => ReturnCode::ERESERVE,
+                result => result,
+            };
+            self.owner_checked(dir, owner, result);
+            return;
+        }
+
+        self.serving_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.data.as_mut().map(|slice| {
+                    let len = cmp::min(slice.len(), length);
+                    slice.as_mut()[..len].copy_from_slice(&buffer[..len]);
+                });
+            });
+        });
+        self.buffer.replace(buffer);
+        self.complete(result, length, 0);
+    }
+
+    fn write_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+        if let Some((dir, owner)) = self.checking() {
+            let written = self.serving_app.map_or(false, |appid| {
+                length == cmp::min(appid.get_package_name().len(), buffer.len())
+            });
+            self.buffer.replace(buffer);
+            let result = match result {
+                ReturnCode::SUCCESS if !written => ReturnCode::FAIL,
+                result => result,
+            };
+            self.owner_checked(dir, owner, result);
+            return;
+        }
+
+        self.buffer.replace(buffer);
+        self.complete(result, length, 0);
+    }
+
+    fn listed(&self, result: ReturnCode, entry: Option<DirEntry>) {
+        match entry {
+            Some(entry) if result == ReturnCode::SUCCESS => {
+                self.serving_app.map(|appid| {
+                    let _ = self.apps.enter(*appid, |app, _| {
+                        app.data.as_mut().map(|slice| {
+                            let slice = slice.as_mut();
+                            let len = cmp::min(entry.name_len, slice.len().saturating_sub(1));
+                            slice[..len].copy_from_slice(&entry.name[..len]);
+                            if len < slice.len() {
+                                slice[len] = 0;
+                            }
+                        });
+                    });
+                });
+                self.complete(result, entry.index + 1, entry.size as usize);
+            }
+            Some(_) => self.complete(result, 0, 0),
+            None if result == ReturnCode::SUCCESS => self.complete(ReturnCode::ENOSUPPORT, 0, 0),
+            None => self.complete(result, 0, 0),
+        }
+    }
+}
+
+impl Driver for FatDriver<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: File name, such as `log.txt`.
+    /// - `1`: Data, read by write and written by read and list.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 | 1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if allow_num == 0 {
+                        app.name = slice;
+                    } else {
+                        app.data = slice;
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request complete. The callback is called with the status of
+    ///   the request and:
+    ///   - for an open, the file descriptor of the file.
+    ///   - for a read or write, the number of bytes read or written. Reads
+    ///     return fewer bytes than requested at the end of the file.
+    ///   - for a list, the index to continue listing at and the size of the
+    ///     file. The status is `ENOSUPPORT` at the end of the directory.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// Names are 8.3 names, without a path.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Open the file whose name is the first `data1` bytes of the name
+    ///   buffer. `data2` is a combination of 1 (create the file if it does
+    ///   not exist), 2 (truncate it) and 4 (append every write to its end).
+    ///   The status is `ENOSUPPORT` if the file does not exist and is not
+    ///   created.
+    /// - `2`: Read up to `data2` bytes from file `data1` into the data buffer.
+    /// - `3`: Write the first `data2` bytes of the data buffer to file
+    ///   `data1`.
+    /// - `4`: Close file `data1`.
+    /// - `5`: Copy the name of the first file at or after index `data1` in the
+    ///   directory of the app into the data buffer, NUL terminated. Start at
+    ///   index 0.
+    /// - `6`: Return the size of file `data1`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let request = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => {
+                if data1 == 0 || data1 > MAX_NAME_LEN {
+                    return ReturnCode::EINVAL;
+                }
+                Request::Open {
+                    name_len: data1,
+                    flags: data2 & (OPEN_CREATE | OPEN_TRUNCATE | OPEN_APPEND),
+                }
+            }
+            2 => Request::Read {
+                fd: data1,
+                length: data2,
+            },
+            3 => Request::Write {
+                fd: data1,
+                length: data2,
+            },
+            4 | 6 => {
+                if data1 >= FILES_PER_APP {
+                    return ReturnCode::EINVAL;
+                }
+                return self
+                    .apps
+                    .enter(appid, |app, _| match app.files[data1] {
+                        Some(file) if command_num == 4 => {
+                            let res = self.fs.close(file);
+                            if res == ReturnCode::SUCCESS {
+                                app.files[data1] = None;
+                            }
+                            res
+                        }
+                        Some(file) => self.fs.size(file).map_or(ReturnCode::EINVAL, |size| {
+                            ReturnCode::SuccessWithValue { value: size }
+                        }),
+                        None => ReturnCode::EINVAL,
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+            5 => Request::List { index: data1 },
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        if appid.get_package_name().len() == 0 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        match request {
+            Request::Read { fd, .. } | Request::Write { fd, .. } if fd >= FILES_PER_APP => {
+                return ReturnCode::EINVAL;
+            }
+            _ => {}
+        }
+
+        let res = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else if app.callback.is_none() {
+                    ReturnCode::EINVAL
+                } else {
+                    app.waiting = Some(request);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+
+        if res == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        res
+    }
+}
+
+/// Short name of the directory of a package: the eight hex digits of its
+/// 32-bit FNV-1a hash, with up to three letters or digits of the name as the
+/// extension.
+fn app_dir_name(package_name: &str) -> ([u8; MAX_NAME_LEN], usize) {
+    let hash = fnv1a(package_name.as_bytes());
+    let mut name = [b'.'; MAX_NAME_LEN];
+    for i in 0..8 {
+        let digit = (hash >> (28 - 4 * i)) as u8 & 0xf;
+        name[i] = match digit {
+            0...9 => b'0' + digit,
+            _ => b'A' + digit - 10,
+        };
+    }
+    let mut len = 9;
+    for byte in package_name.bytes().take(3) {
+        name[len] = match byte {
+            b'A'...b'Z' | b'0'...b'9' => byte,
+            b'a'...b'z' => byte - b'a' + b'A',
+            _ => b'_',
+        };
+        len += 1;
+    }
+    if len == 9 {
+        // No extension.
+        len = 8;
+    }
+    (name, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dir_names_use_the_full_hash() {
+        let (name, len) = app_dir_name("blink");
+        assert_eq!(&name[..len], &b"07617589.BLI"[..]);
+        let (name, len) = app_dir_name("org.tockos.x");
+        assert_eq!(&name[..len], &b"B350C642.ORG"[..]);
+        let (name, len) = app_dir_name("a-");
+        assert_eq!(&name[..len], &b"00248C93.A_"[..]);
+    }
+}
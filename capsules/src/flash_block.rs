@@ -0,0 +1,205 @@
+//! Use a range of flash pages as a block device.
+//!
+//! `FlashBlock` provides `hil::block::BlockDevice` on top of any
+//! `hil::flash::Flash`, such as internal flash or the MX25R6435F, with one
+//! block per flash page. Blocks are copied through a page buffer, and
+//! requests are served one at a time.
+//!
+//! ```plain
+//!     hil::block::BlockDevice
+//!         ┌─────────────┐
+//!         │             │
+//!         │ This module │
+//!         │             │
+//!         └─────────────┘
+//!        hil::flash::Flash
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! pub static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! let flash_block = static_init!(
+//!     capsules::flash_block::FlashBlock<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::flash_block::FlashBlock::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         0x60000 / 512, // First page
+//!         128,           // Number of pages
+//!         &mut PAGEBUFFER
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, flash_block);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ReturnCode;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    Write,
+    Erase,
+}
+
+pub struct FlashBlock<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    start_page: usize,
+    num_pages: usize,
+    page_size: usize,
+    pagebuffer: TakeCell<'static, F::Page>,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    client: OptionalCell<&'a hil::block::Client>,
+}
+
+impl<F: hil::flash::Flash> FlashBlock<'a, F> {
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        num_pages: usize,
+        pagebuffer: &'static mut F::Page,
+    ) -> FlashBlock<'a, F> {
+        let page_size = pagebuffer.as_mut().len();
+        FlashBlock {
+            flash: flash,
+            start_page: start_page,
+            num_pages: num_pages,
+            page_size: page_size,
+            pagebuffer: TakeCell::new(pagebuffer),
+            buffer: TakeCell::empty(),
+            state: Cell::new(State::Idle),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Check that a request for `block` with `buffer` can be started.
+    fn check(&self, block: usize, buffer: &[u8]) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            ReturnCode::EBUSY
+        } else if block >= self.num_pages || buffer.len() < self.page_size {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn result(error: hil::flash::Error) -> ReturnCode {
+        if error == hil::flash::Error::CommandComplete {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> hil::block::BlockDevice<'a> for FlashBlock<'a, F> {
+    fn set_client(&self, client: &'a hil::block::Client) {
+        self.client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.num_pages
+    }
+
+    fn read_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let res = self.check(block, buffer);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(buffer));
+        }
+        match self.pagebuffer.take() {
+            Some(pagebuffer) => {
+                let res = self.flash.read_page(self.start_page + block, pagebuffer);
+                if res != ReturnCode::SUCCESS {
+                    return (res, Some(buffer));
+                }
+                self.state.set(State::Read);
+                self.buffer.replace(buffer);
+                (ReturnCode::SUCCESS, None)
+            }
+            None => (ReturnCode::ERESERVE, Some(buffer)),
+        }
+    }
+
+    fn write_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let res = self.check(block, buffer);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(buffer));
+        }
+        match self.pagebuffer.take() {
+            Some(pagebuffer) => {
+                pagebuffer
+                    .as_mut()
+                    .copy_from_slice(&buffer[..self.page_size]);
+                let res = self.flash.write_page(self.start_page + block, pagebuffer);
+                if res != ReturnCode::SUCCESS {
+                    return (res, Some(buffer));
+                }
+                self.state.set(State::Write);
+                self.buffer.replace(buffer);
+                (ReturnCode::SUCCESS, None)
+            }
+            None => (ReturnCode::ERESERVE, Some(buffer)),
+        }
+    }
+
+    fn erase_block(&self, block: usize) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if block >= self.num_pages {
+            return ReturnCode::EINVAL;
+        }
+        let res = self.flash.erase_page(self.start_page + block);
+        if res == ReturnCode::SUCCESS {
+            self.state.set(State::Erase);
+        }
+        res
+    }
+
+    fn flush(&self) -> ReturnCode {
+        ReturnCode::EALREADY
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for FlashBlock<'a, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.state.set(State::Idle);
+        self.buffer.take().map(|buffer| {
+            buffer[..self.page_size].copy_from_slice(pagebuffer.as_mut());
+            self.client
+                .map(move |client| client.read_done(Self::result(error), buffer));
+        });
+        self.pagebuffer.replace(pagebuffer);
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.state.set(State::Idle);
+        self.pagebuffer.replace(pagebuffer);
+        self.buffer.take().map(|buffer| {
+            self.client
+                .map(move |client| client.write_done(Self::result(error), buffer));
+        });
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        self.state.set(State::Idle);
+        self.client
+            .map(|client| client.erase_done(Self::result(error)));
+    }
+}
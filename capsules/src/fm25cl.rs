@@ -28,15 +28,17 @@
 //! fm25cl_spi.set_client(fm25cl);
 //! ```
 //!
-//! This capsule provides two interfaces:
+//! This capsule provides three interfaces:
 //!
 //! - `hil::nonvolatile_storage::NonvolatileStorage`
+//! - `hil::block::BlockDevice`
 //! - `FM25CLCustom`
 //!
 //! The first is the generic interface for nonvolatile storage. This allows
 //! this driver to work with capsules like the `nonvolatile_storage_driver`
-//! that provide virtualization and a userspace interface. The second is a
-//! custom interface that exposes other chip-specific functions.
+//! that provide virtualization and a userspace interface. The second presents
+//! the chip as 256 byte blocks for capsules built on block devices. The third
+//! is a custom interface that exposes other chip-specific functions.
 
 use core::cell::Cell;
 use core::cmp;
@@ -52,6 +54,11 @@ pub static mut KERNEL_RXBUFFER: [u8; 512] = [0; 512];
 
 const SPI_SPEED: u32 = 4000000;
 
+/// Size of the blocks of the `BlockDevice` interface.
+const BLOCK_SIZE: usize = 256;
+/// Number of blocks in the 8 KB of the FM25CL64B.
+const NUM_BLOCKS: usize = 32;
+
 #[allow(dead_code)]
 enum Opcodes {
     WriteEnable = 0x06,
@@ -94,6 +101,10 @@ pub struct FM25CL<'a, S: hil::spi::SpiMasterDevice> {
     rxbuffer: TakeCell<'static, [u8]>,
     client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
     client_custom: OptionalCell<&'static FM25CLClient>,
+    client_block: OptionalCell<&'a hil::block::Client>,
+    // Whether the current read or write came through the `BlockDevice`
+    // interface.
+    block_operation: Cell<bool>,
     client_buffer: TakeCell<'static, [u8]>, // Store buffer and state for passing back to client
     client_write_address: Cell<u16>,
     client_write_len: Cell<u16>,
@@ -113,6 +124,8 @@ impl<S: hil::spi::SpiMasterDevice> FM25CL<'a, S> {
             rxbuffer: TakeCell::new(rxbuffer),
             client: OptionalCell::empty(),
             client_custom: OptionalCell::empty(),
+            client_block: OptionalCell::empty(),
+            block_operation: Cell::new(false),
             client_buffer: TakeCell::empty(),
             client_write_address: Cell::new(0),
             client_write_len: Cell::new(0),
@@ -234,8 +247,14 @@ impl<S: hil::spi::SpiMasterDevice> hil::spi::SpiMasterClient for FM25CL<'a, S> {
 
                 // Call done with the write() buffer
                 self.client_buffer.take().map(move |buffer| {
-                    self.client
-                        .map(move |client| client.write_done(buffer, write_len));
+                    if self.block_operation.get() {
+                        self.block_operation.set(false);
+                        self.client_block
+                            .map(move |client| client.write_done(ReturnCode::SUCCESS, buffer));
+                    } else {
+                        self.client
+                            .map(move |client| client.write_done(buffer, write_len));
+                    }
                 });
             }
             State::ReadMemory => {
@@ -246,16 +265,22 @@ impl<S: hil::spi::SpiMasterDevice> hil::spi::SpiMasterClient for FM25CL<'a, S> {
 
                 read_buffer.map(|read_buffer| {
                     self.client_buffer.take().map(move |buffer| {
-                        let read_len = cmp::min(buffer.len(), len);
+                        let read_len = cmp::min(buffer.len(), len - 3);
 
-                        for i in 0..(read_len - 3) {
+                        for i in 0..read_len {
                             buffer[i] = read_buffer[i + 3];
                         }
 
                         self.rxbuffer.replace(read_buffer);
 
-                        self.client
-                            .map(move |client| client.read_done(buffer, read_len - 3));
+                        if self.block_operation.get() {
+                            self.block_operation.set(false);
+                            self.client_block
+                                .map(move |client| client.read_done(ReturnCode::SUCCESS, buffer));
+                        } else {
+                            self.client
+                                .map(move |client| client.read_done(buffer, read_len));
+                        }
                     });
                 });
             }
@@ -302,3 +327,69 @@ impl<S: hil::spi::SpiMasterDevice> hil::nonvolatile_storage::NonvolatileStorage
         self.write(address as u16, buffer, length as u16)
     }
 }
+
+/// Implement the generic `BlockDevice` interface, with fixed size blocks
+/// that can be written without erasing them first.
+impl<S: hil::spi::SpiMasterDevice> hil::block::BlockDevice<'a> for FM25CL<'a, S> {
+    fn set_client(&self, client: &'a hil::block::Client) {
+        self.client_block.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> usize {
+        NUM_BLOCKS
+    }
+
+    fn read_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != State::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        if block >= NUM_BLOCKS || buffer.len() < BLOCK_SIZE {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        self.block_operation.set(true);
+        let res = self.read((block * BLOCK_SIZE) as u16, buffer, BLOCK_SIZE as u16);
+        if res != ReturnCode::SUCCESS {
+            self.block_operation.set(false);
+            return (res, self.client_buffer.take());
+        }
+        (res, None)
+    }
+
+    fn write_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != State::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        if block >= NUM_BLOCKS || buffer.len() < BLOCK_SIZE {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        self.block_operation.set(true);
+        let res = self.write((block * BLOCK_SIZE) as u16, buffer, BLOCK_SIZE as u16);
+        if res != ReturnCode::SUCCESS {
+            self.block_operation.set(false);
+            return (res, self.client_buffer.take());
+        }
+        (res, None)
+    }
+
+    fn erase_block(&self, _block: usize) -> ReturnCode {
+        // FRAM has no erased state.
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn flush(&self) -> ReturnCode {
+        // Writes are stored before `write_done` is called.
+        ReturnCode::EALREADY
+    }
+}
@@ -0,0 +1,427 @@
+//! HMAC_DRBG (NIST SP 800-90A) with SHA-256, seeded from an entropy source.
+//!
+//! `HmacDrbg` turns a raw entropy source such as a TRNG into a
+//! cryptographically secure random number generator. It gathers `SEED_WORDS`
+//! words of entropy to seed itself, and gathers more to reseed every
+//! `RESEED_INTERVAL` generate calls, producing output from the old state while
+//! it waits.
+//!
+//! It offers two interfaces:
+//!
+//! - `hil::rng::Random`, which returns values immediately, so any number of
+//!   kernel clients (802.15.4 sequence numbers, BLE addresses, TCP initial
+//!   sequence numbers) can share one instance.
+//! - `hil::rng::RNG`, so that `capsules::rng::SimpleRng` can serve
+//!   processes from it instead of from raw TRNG output.
+//!
+//! On chips without an entropy source, pass `None` and seed the generator
+//! with `Random::reseed()`. It reports itself seeded once it has been given
+//! `MIN_SEED_WORDS` words, its full 256 bit security strength, and its output
+//! is only as unpredictable as those seeds.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let drbg_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let drbg = static_init!(
+//!     capsules::hmac_drbg::HmacDrbg<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::hmac_drbg::HmacDrbg::new(Some(&sam4l::trng::TRNG), drbg_alarm)
+//! );
+//! sam4l::trng::TRNG.set_client(drbg);
+//! drbg_alarm.set_client(drbg);
+//! drbg.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::digest::SHA256_DIGEST_SIZE;
+use kernel::hil::rng;
+use kernel::hil::time::{self, Alarm, Frequency};
+use sha256::hmac_sha256;
+use util::MIN_DELAY_TICKS;
+
+/// Number of 32-bit words of entropy used to seed or reseed the generator:
+/// 256 bits of entropy plus a 128 bit nonce.
+pub const SEED_WORDS: usize = 12;
+
+/// Number of 32-bit words passed to `Random::reseed()` before a generator
+/// without an entropy source is seeded: 256 bits, the security strength of
+/// HMAC_DRBG with SHA-256.
+pub const MIN_SEED_WORDS: usize = 8;
+
+/// Number of generate calls, each producing 32 bytes, between reseeds.
+pub const RESEED_INTERVAL: usize = 1 << 16;
+
+/// Number of words handed to an `RNG` client at a time.
+const WORDS_PER_CALLBACK: usize = SHA256_DIGEST_SIZE / 4;
+
+pub struct HmacDrbg<'a, A: Alarm + 'a> {
+    entropy: Option<&'a rng::RNG>,
+    alarm: &'a A,
+    client: OptionalCell<&'a rng::Client>,
+    client_waiting: Cell<bool>,
+
+    key: Cell<[u8; SHA256_DIGEST_SIZE]>,
+    value: Cell<[u8; SHA256_DIGEST_SIZE]>,
+    generated: Cell<usize>,
+    seeded: Cell<bool>,
+
+    // Output of the last generate call, handed out four bytes at a time.
+    output: Cell<[u8; SHA256_DIGEST_SIZE]>,
+    output_used: Cell<usize>,
+
+    // Entropy gathered towards the next (re)seed.
+    gathering: Cell<bool>,
+    seed: Cell<[u8; SEED_WORDS * 4]>,
+    seed_words: Cell<usize>,
+}
+
+impl<A: Alarm> HmacDrbg<'a, A> {
+    pub fn new(entropy: Option<&'a rng::RNG>, alarm: &'a A) -> HmacDrbg<'a, A> {
+        HmacDrbg {
+            entropy: entropy,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            client_waiting: Cell::new(false),
+            key: Cell::new([0x00; SHA256_DIGEST_SIZE]),
+            value: Cell::new([0x01; SHA256_DIGEST_SIZE]),
+            generated: Cell::new(0),
+            seeded: Cell::new(false),
+            output: Cell::new([0; SHA256_DIGEST_SIZE]),
+            output_used: Cell::new(SHA256_DIGEST_SIZE),
+            gathering: Cell::new(false),
+            seed: Cell::new([0; SEED_WORDS * 4]),
+            seed_words: Cell::new(0),
+        }
+    }
+
+    /// Set the client that receives random numbers through the `RNG`
+    /// interface.
+    pub fn set_client(&self, client: &'a rng::Client) {
+        self.client.set(client);
+    }
+
+    /// The HMAC_DRBG update function: mix `provided` into the key and value.
+    fn update(&self, provided: &[u8]) {
+        let mut key = self.key.get();
+        let mut value = self.value.get();
+
+        key = hmac_sha256(&key, &[&value, &[0x00], provided]);
+        value = hmac_sha256(&key, &[&value]);
+        if provided.len() > 0 {
+            key = hmac_sha256(&key, &[&value, &[0x01], provided]);
+            value = hmac_sha256(&key, &[&value]);
+        }
+
+        self.key.set(key);
+        self.value.set(value);
+        // Output generated from the old state must not be handed out after
+        // new seed material has been mixed in.
+        self.output_used.set(SHA256_DIGEST_SIZE);
+    }
+
+    /// The HMAC_DRBG generate function, filling `output`.
+    fn generate(&self, output: &mut [u8]) {
+        let key = self.key.get();
+        let mut value = self.value.get();
+        for block in output.chunks_mut(SHA256_DIGEST_SIZE) {
+            value = hmac_sha256(&key, &[&value]);
+            let len = block.len();
+            block.copy_from_slice(&value[..len]);
+        }
+        self.value.set(value);
+        self.update(&[]);
+
+        self.generated.set(self.generated.get() + 1);
+        if self.generated.get() >= RESEED_INTERVAL {
+            self.gather_entropy();
+        }
+    }
+
+    fn gather_entropy(&self) {
+        if self.gathering.get() {
+            return;
+        }
+        self.entropy.map(|entropy| {
+            self.gathering.set(true);
+            self.seed_words.set(0);
+            entropy.get();
+        });
+    }
+
+    fn schedule_callback(&self) {
+        let delay = cmp::max(<A::Frequency>::frequency() / 10000, MIN_DELAY_TICKS);
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(delay));
+    }
+}
+
+impl<A: Alarm> rng::Random<'a> for HmacDrbg<'a, A> {
+    fn initialize(&'a self) {
+        self.gather_entropy();
+    }
+
+    fn reseed(&self, seed: u32) {
+        let seed = [
+            seed as u8,
+            (seed >> 8) as u8,
+            (seed >> 16) as u8,
+            (seed >> 24) as u8,
+        ];
+        self.update(&seed);
+        self.generated.set(0);
+
+        if self.entropy.is_none() && !self.seeded.get() {
+            // Without an entropy source these are the only seeds, so wait
+            // until they add up to the full security strength.
+            let words = self.seed_words.get() + 1;
+            self.seed_words.set(words);
+            if words >= MIN_SEED_WORDS {
+                self.seeded.set(true);
+                if self.client_waiting.get() {
+                    self.schedule_callback();
+                }
+            }
+        }
+    }
+
+    fn random(&self) -> u32 {
+        if self.output_used.get() == SHA256_DIGEST_SIZE {
+            let mut output = [0; SHA256_DIGEST_SIZE];
+            self.generate(&mut output);
+            self.output.set(output);
+            self.output_used.set(0);
+        }
+        let used = self.output_used.get();
+        let output = self.output.get();
+        self.output_used.set(used + 4);
+        (output[used] as u32)
+            | (output[used + 1] as u32) << 8
+            | (output[used + 2] as u32) << 16
+            | (output[used + 3] as u32) << 24
+    }
+
+    fn seeded(&self) -> bool {
+        self.seeded.get()
+    }
+}
+
+impl<A: Alarm> rng::RNG for HmacDrbg<'a, A> {
+    /// Hand random numbers to the client once the generator is seeded.
+    fn get(&self) {
+        self.client_waiting.set(true);
+        if self.seeded.get() {
+            self.schedule_callback();
+        } else {
+            self.gather_entropy();
+        }
+    }
+}
+
+/// Entropy for seeding arrives from the entropy source.
+impl<A: Alarm> rng::Client for HmacDrbg<'a, A> {
+    fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> rng::Continue {
+        let mut seed = self.seed.get();
+        let mut words = self.seed_words.get();
+        while words < SEED_WORDS {
+            match randomness.next() {
+                Some(word) => {
+                    seed[words * 4] = word as u8;
+                    seed[words * 4 + 1] = (word >> 8) as u8;
+                    seed[words * 4 + 2] = (word >> 16) as u8;
+                    seed[words * 4 + 3] = (word >> 24) as u8;
+                    words += 1;
+                }
+                None => break,
+            }
+        }
+        self.seed.set(seed);
+        self.seed_words.set(words);
+        if words < SEED_WORDS {
+            return rng::Continue::More;
+        }
+
+        if !self.seeded.get() {
+            // Instantiate: start from the initial key and value.
+            self.key.set([0x00; SHA256_DIGEST_SIZE]);
+            self.value.set([0x01; SHA256_DIGEST_SIZE]);
+        }
+        self.update(&seed);
+        self.seed.set([0; SEED_WORDS * 4]);
+        self.generated.set(0);
+        self.gathering.set(false);
+
+        if !self.seeded.get() {
+            self.seeded.set(true);
+            if self.client_waiting.get() {
+                self.schedule_callback();
+            }
+        }
+        rng::Continue::Done
+    }
+}
+
+/// Hands a bounded number of values to an `RNG` client.
+struct DrbgIter<'b, 'a: 'b, A: Alarm + 'a> {
+    drbg: &'b HmacDrbg<'a, A>,
+    remaining: usize,
+}
+
+impl<A: Alarm> Iterator for DrbgIter<'b, 'a, A> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(rng::Random::random(self.drbg))
+    }
+}
+
+impl<A: Alarm> time::Client for HmacDrbg<'a, A> {
+    fn fired(&self) {
+        if !self.client_waiting.get() {
+            return;
+        }
+        self.client_waiting.set(false);
+
+        let mut iter = DrbgIter {
+            drbg: self,
+            remaining: WORDS_PER_CALLBACK,
+        };
+        let more = self.client.map_or(rng::Continue::Done, |client| {
+            client.randomness_available(&mut iter)
+        });
+        if more == rng::Continue::More {
+            self.client_waiting.set(true);
+            self.schedule_callback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::vec::Vec;
+    use super::*;
+    use kernel::hil::rng::{Client, Random};
+
+    /// Alarm that never fires. The tests only use the `Random` interface.
+    struct NoAlarm;
+
+    impl time::Time for NoAlarm {
+        type Frequency = time::Freq32KHz;
+
+        fn disable(&self) {}
+
+        fn is_armed(&self) -> bool {
+            false
+        }
+    }
+
+    impl Alarm for NoAlarm {
+        fn now(&self) -> u32 {
+            0
+        }
+
+        fn set_alarm(&self, _tics: u32) {}
+
+        fn get_alarm(&self) -> u32 {
+            0
+        }
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Instantiate `drbg` as if the entropy source returned `seed`.
+    fn instantiate(drbg: &HmacDrbg<NoAlarm>, seed: &[u8]) {
+        let words: Vec<u32> = seed
+            .chunks(4)
+            .map(|word| {
+                word[0] as u32
+                    | (word[1] as u32) << 8
+                    | (word[2] as u32) << 16
+                    | (word[3] as u32) << 24
+            })
+            .collect();
+        let more = drbg.randomness_available(&mut words.into_iter());
+        assert!(more == rng::Continue::Done);
+        assert!(drbg.seeded());
+    }
+
+    /// NIST CAVP HMAC_DRBG.rsp, [SHA-256], no prediction resistance, no
+    /// personalization string or additional input, COUNT = 0: instantiate
+    /// with the entropy input and nonce, generate 1024 bits twice and check
+    /// the second output.
+    #[test]
+    fn sp800_90a_known_answer() {
+        let entropy = "ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488";
+        let nonce = "659ba96c601dc69fc902940805ec0ca8";
+        let expected = from_hex(
+            "e528e9abf2dece54d47c7e75e5fe302149f817ea9fb4bee6f4199697d04d5b89\
+             d54fbb978a15b5c443c9ec21036d2460b6f73ebad0dc2aba6e624abf07745bc1\
+             07694bb7547bb0995f70de25d6b29e2d3011bb19d27676c07162c8b5ccde0668\
+             961df86803482cb37ed6d5c0bb8d50cf1f50d476aa0458bdaba806f48be9dcb8",
+        );
+
+        let alarm = NoAlarm;
+        let drbg = HmacDrbg::new(None, &alarm);
+        let mut seed = from_hex(entropy);
+        seed.extend(from_hex(nonce));
+        assert_eq!(seed.len(), SEED_WORDS * 4);
+        instantiate(&drbg, &seed);
+
+        let mut output = [0; 128];
+        drbg.generate(&mut output);
+        drbg.generate(&mut output);
+        assert_eq!(&output[..], &expected[..]);
+    }
+
+    /// `random()` hands out the output of one generate call per 32 bytes.
+    #[test]
+    fn random_returns_generated_blocks() {
+        let seed = [0x5a; SEED_WORDS * 4];
+        let alarm = NoAlarm;
+        let drbg = HmacDrbg::new(None, &alarm);
+        let reference = HmacDrbg::new(None, &alarm);
+        instantiate(&drbg, &seed);
+        instantiate(&reference, &seed);
+
+        for _ in 0..2 {
+            let mut block = [0; SHA256_DIGEST_SIZE];
+            reference.generate(&mut block);
+            for word in block.chunks(4) {
+                let expected = word[0] as u32
+                    | (word[1] as u32) << 8
+                    | (word[2] as u32) << 16
+                    | (word[3] as u32) << 24;
+                assert_eq!(drbg.random(), expected);
+            }
+        }
+    }
+
+    /// Without an entropy source, a single 32-bit reseed does not seed the
+    /// generator; `MIN_SEED_WORDS` of them do.
+    #[test]
+    fn reseed_needs_full_security_strength() {
+        let alarm = NoAlarm;
+        let drbg = HmacDrbg::new(None, &alarm);
+        for seed in 1..MIN_SEED_WORDS as u32 {
+            drbg.reseed(seed);
+            assert!(!drbg.seeded());
+        }
+        drbg.reseed(MIN_SEED_WORDS as u32);
+        assert!(drbg.seeded());
+    }
+}
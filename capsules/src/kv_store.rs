@@ -0,0 +1,1136 @@
+//! Wear-levelled key-value store on flash.
+//!
+//! `KVStore` keeps a log of records in a range of flash pages, and
+//! `KVStoreUser` gives each of its users a `hil::kv_store::KVStore` with its
+//! own namespace. Requests from all users are served one at a time.
+//!
+//! Layout
+//! ------
+//!
+//! Every page in use starts with a 12 byte header: a magic number, a sequence
+//! number that grows with every page written, and a CRC-32 of the rest of the
+//! page. Records follow, each padded to a multiple of 4 bytes and protected
+//! by its own CRC-32:
+//!
+//! ```text
+//! 0         1         2         4               8               12
+//! +---------+---------+---------+---------------+---------------+-----+-------+
+//! | key len | flags   | val len | namespace     | CRC-32        | key | value |
+//! +---------+---------+---------+---------------+---------------+-----+-------+
+//! ```
+//!
+//! Bit 0 of the flags marks a deleted key, and the other seven bits hold the
+//! key's collision tag.
+//!
+//! Pages are only ever written whole. To add a record, the store writes a
+//! copy of the newest page with the record appended to an erased page, and
+//! only erases the old copy once that write has finished. A write cut short
+//! by a power loss leaves the old copy intact, and pages whose CRC does not
+//! match are ignored when the store is mounted. Erased pages are used in
+//! turn, so writes are spread over all pages of the store.
+//!
+//! Writing a key again turns its old record into garbage. When the store is
+//! down to its last erased page, it compacts the page with the most garbage
+//! into the erased page before adding new pages.
+//!
+//! The store keeps an index of the newest record of every key in RAM, which
+//! it builds when it is mounted. Index entries hold a 32-bit hash of the
+//! namespace and key rather than the key itself, so a request reads the
+//! records the hash points at and compares their namespace and key with its
+//! own. When a new key has the same hash as keys already in the store, it is
+//! given a collision tag none of them uses, and the hash and tag together
+//! identify its records from then on. Deleted keys keep a record and an
+//! index entry, so that their older values are not found again after a
+//! reboot.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let kv_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash)
+//! );
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStore<'static, capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::kv_store::KVStore::new(
+//!         kv_flash,
+//!         start_page,
+//!         num_pages,
+//!         &mut capsules::kv_store::PAGES,
+//!         &mut capsules::kv_store::INDEX,
+//!         &mut KV_PAGEBUFFER
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(kv_flash, kv_store);
+//! kv_store.initialize();
+//!
+//! let settings = static_init!(
+//!     capsules::kv_store::KVStoreUser<'static, capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::kv_store::KVStoreUser::new(kv_store, 1)
+//! );
+//! settings.set_client(client);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil;
+use kernel::ReturnCode;
+use util::{crc32_update, fnv1a, fnv1a_update, read_u32, write_u32};
+
+/// Longest key, in bytes.
+pub const MAX_KEY_LEN: usize = 32;
+
+const PAGE_MAGIC: u32 = 0x4b565331;
+const PAGE_HEADER_LEN: usize = 12;
+const RECORD_HEADER_LEN: usize = 12;
+const FLAG_DELETED: u8 = 1;
+const TAG_SHIFT: u8 = 1;
+const MAX_TAG: u8 = 0x7f;
+
+/// What the store knows about one of its pages.
+#[derive(Clone, Copy)]
+pub struct PageInfo {
+    seq: u32,
+    // Bytes used by the header and records, or 0 if the page is free.
+    used: usize,
+}
+
+impl PageInfo {
+    pub const fn new() -> PageInfo {
+        PageInfo { seq: 0, used: 0 }
+    }
+}
+
+/// Location of the newest record of a key.
+#[derive(Clone, Copy)]
+pub struct IndexEntry {
+    hash: u32,
+    tag: u8,
+    page: u8,
+    in_use: bool,
+    deleted: bool,
+    offset: u16,
+    length: u16,
+}
+
+impl IndexEntry {
+    pub const fn new() -> IndexEntry {
+        IndexEntry {
+            hash: 0,
+            tag: 0,
+            page: 0,
+            in_use: false,
+            deleted: false,
+            offset: 0,
+            length: 0,
+        }
+    }
+}
+
+/// Page state for stores of up to 16 pages.
+pub static mut PAGES: [PageInfo; 16] = [PageInfo::new(); 16];
+
+/// Index for up to 64 keys, including deleted ones.
+pub static mut INDEX: [IndexEntry; 64] = [IndexEntry::new(); 64];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Mount {
+        page: usize,
+    },
+    // Reading the page of the index entry `slot` to check whether its
+    // record belongs to the key of the request.
+    Probe {
+        slot: usize,
+    },
+    // Reading the newest page to append a record to it.
+    ReadTail {
+        page: usize,
+    },
+    // Reading a page to compact it.
+    ReadVictim {
+        page: usize,
+    },
+    // Erasing a page before writing the page buffer to it. `old` is the page
+    // the buffer was copied from, which is freed afterwards.
+    Erase {
+        target: usize,
+        old: Option<usize>,
+        compacting: bool,
+    },
+    Write {
+        target: usize,
+        old: Option<usize>,
+        compacting: bool,
+    },
+    // Freeing a page whose records are all garbage or have been copied.
+    EraseOld {
+        page: usize,
+        compacting: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Idle,
+    Get,
+    Set,
+    Delete,
+}
+
+pub struct KVStore<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    start_page: usize,
+    num_pages: usize,
+    page_len: usize,
+    pages: TakeCell<'static, [PageInfo]>,
+    index: TakeCell<'static, [IndexEntry]>,
+    buffer: TakeCell<'static, F::Page>,
+    state: Cell<State>,
+    mounted: Cell<bool>,
+    next_seq: Cell<u32>,
+    // Newest page, which new records are appended to.
+    tail: Cell<Option<usize>>,
+    // Where to start looking for a free page.
+    next_free: Cell<usize>,
+    // Offset of the record being written in the page buffer.
+    record_offset: Cell<usize>,
+    gc_rounds: Cell<usize>,
+    users: List<'a, KVStoreUser<'a, F>>,
+    inflight: OptionalCell<&'a KVStoreUser<'a, F>>,
+}
+
+impl<F: hil::flash::Flash> KVStore<'a, F> {
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        num_pages: usize,
+        pages: &'static mut [PageInfo],
+        index: &'static mut [IndexEntry],
+        buffer: &'static mut F::Page,
+    ) -> KVStore<'a, F> {
+        let num_pages = cmp::min(cmp::min(num_pages, pages.len()), 256);
+        let page_len = buffer.as_mut().len();
+        KVStore {
+            flash: flash,
+            start_page: start_page,
+            num_pages: num_pages,
+            page_len: page_len,
+            pages: TakeCell::new(pages),
+            index: TakeCell::new(index),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            mounted: Cell::new(false),
+            next_seq: Cell::new(0),
+            tail: Cell::new(None),
+            next_free: Cell::new(0),
+            record_offset: Cell::new(0),
+            gc_rounds: Cell::new(0),
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    /// Read every page of the store and build the index. Requests are queued
+    /// until this has finished.
+    pub fn initialize(&self) {
+        if !self.mounted.get() && self.state.get() == State::Idle && self.num_pages > 0 {
+            self.read_page(0, State::Mount { page: 0 });
+        }
+    }
+
+    /// Largest value that can be stored under a key of `key_len` bytes.
+    pub fn max_value_len(&self, key_len: usize) -> usize {
+        cmp::min(
+            self.page_len
+                .saturating_sub(PAGE_HEADER_LEN + RECORD_HEADER_LEN + key_len)
+                & !3,
+            0xffff,
+        )
+    }
+
+    fn read_page(&self, page: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.state.set(state);
+            let res = self.flash.read_page(self.start_page + page, buffer);
+            if res != ReturnCode::SUCCESS {
+                self.state.set(State::Idle);
+            }
+            res
+        })
+    }
+
+    fn erase_page(&self, page: usize, state: State) -> ReturnCode {
+        self.state.set(state);
+        let res = self.flash.erase_page(self.start_page + page);
+        if res != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        res
+    }
+
+    /// Start a request if nothing else is in progress. Otherwise the request
+    /// stays queued in its user.
+    fn start_or_queue(&self, user: &'a KVStoreUser<'a, F>) -> ReturnCode {
+        if !self.mounted.get() || self.state.get() != State::Idle || self.inflight.is_some() {
+            return ReturnCode::SUCCESS;
+        }
+        self.inflight.set(user);
+        let res = self.start_op(user);
+        if res != ReturnCode::SUCCESS {
+            self.inflight.clear();
+            user.op.set(Op::Idle);
+        }
+        res
+    }
+
+    /// Start queued requests until one of them uses the flash.
+    fn do_next_op(&self) {
+        while self.mounted.get() && self.state.get() == State::Idle && self.inflight.is_none() {
+            match self.users.iter().find(|user| user.op.get() != Op::Idle) {
+                Some(user) => {
+                    self.inflight.set(user);
+                    let res = self.start_op(user);
+                    if res != ReturnCode::SUCCESS {
+                        self.inflight.clear();
+                        user.complete(res, 0);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn start_op(&self, user: &KVStoreUser<'a, F>) -> ReturnCode {
+        if user.op.get() == Op::Idle {
+            return ReturnCode::FAIL;
+        }
+        self.probe_from(user, 0)
+    }
+
+    /// Read the record of the next index entry from `slot` on whose hash
+    /// matches the key of `user`, or go on without one if there is none.
+    fn probe_from(&self, user: &KVStoreUser<'a, F>, slot: usize) -> ReturnCode {
+        let hash = user.hash();
+        let candidate = self.index.map_or(None, |index| {
+            (slot..index.len())
+                .find(|&i| index[i].in_use && index[i].hash == hash)
+                .map(|i| (i, index[i].page as usize))
+        });
+        match candidate {
+            Some((slot, page)) => self.read_page(page, State::Probe { slot: slot }),
+            None => self.key_found(user, None),
+        }
+    }
+
+    /// Go on with a set or delete once the index entry of its key, if it has
+    /// one, is known.
+    fn key_found(&self, user: &KVStoreUser<'a, F>, slot: Option<usize>) -> ReturnCode {
+        let entry = slot.and_then(|slot| self.index.map(|index| index[slot]));
+        let exists = entry.map_or(false, |entry| !entry.deleted);
+        match user.op.get() {
+            Op::Set | Op::Delete => {
+                if user.op.get() == Op::Delete && !exists {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                let tag = match entry {
+                    Some(entry) => entry.tag,
+                    None => {
+                        if !self.index_has_room() {
+                            return ReturnCode::ENOMEM;
+                        }
+                        match self.free_tag(user.hash()) {
+                            Some(tag) => tag,
+                            None => return ReturnCode::ENOMEM,
+                        }
+                    }
+                };
+                user.tag.set(tag);
+                self.gc_rounds.set(0);
+                self.start_write(user)
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Add the record of `user`, by appending it to a copy of the newest
+    /// page, writing it to a new page, or first making room.
+    fn start_write(&self, user: &KVStoreUser<'a, F>) -> ReturnCode {
+        let record_len = user.record_len();
+        let free = self.free_pages();
+        let tail = self.tail.get();
+        let tail_used = tail.and_then(|tail| self.pages.map(|pages| pages[tail].used));
+
+        match (tail, tail_used) {
+            (Some(tail), Some(used)) if used + record_len <= self.page_len && free >= 1 => {
+                self.read_page(tail, State::ReadTail { page: tail })
+            }
+            // Keep one free page so there is always room to compact.
+            _ if free >= 2 => {
+                let target = self.alloc_page();
+                let built = self.buffer.map(|buffer| {
+                    let page = buffer.as_mut();
+                    for byte in page.iter_mut() {
+                        *byte = 0;
+                    }
+                    self.record_offset.set(PAGE_HEADER_LEN);
+                    user.write_record(&mut page[PAGE_HEADER_LEN..]);
+                });
+                if built.is_none() {
+                    return ReturnCode::EBUSY;
+                }
+                self.erase_page(
+                    target,
+                    State::Erase {
+                        target: target,
+                        old: None,
+                        compacting: false,
+                    },
+                )
+            }
+            _ => self.collect_garbage(),
+        }
+    }
+
+    /// Free a page that is all garbage, or compact the page with the most
+    /// garbage into a free page.
+    fn collect_garbage(&self) -> ReturnCode {
+        self.gc_rounds.set(self.gc_rounds.get() + 1);
+        if self.gc_rounds.get() > self.num_pages {
+            return ReturnCode::ENOMEM;
+        }
+
+        let mut victim = None;
+        let mut most_garbage = 0;
+        for page in 0..self.num_pages {
+            let used = self.pages.map_or(0, |pages| pages[page].used);
+            if used == 0 {
+                continue;
+            }
+            let garbage = used - PAGE_HEADER_LEN - self.live_bytes(page);
+            if garbage > most_garbage {
+                most_garbage = garbage;
+                victim = Some(page);
+            }
+        }
+
+        match victim {
+            Some(page) if self.live_bytes(page) == 0 => self.erase_page(
+                page,
+                State::EraseOld {
+                    page: page,
+                    compacting: true,
+                },
+            ),
+            Some(page) if self.free_pages() >= 1 => {
+                self.read_page(page, State::ReadVictim { page: page })
+            }
+            _ => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Finish the request being served and start the next one.
+    fn finish(&self, result: ReturnCode, length: usize) {
+        self.state.set(State::Idle);
+        self.inflight
+            .take()
+            .map(|user| user.complete(result, length));
+        self.do_next_op();
+    }
+
+    fn free_pages(&self) -> usize {
+        self.pages.map_or(0, |pages| {
+            pages[..self.num_pages]
+                .iter()
+                .filter(|info| info.used == 0)
+                .count()
+        })
+    }
+
+    /// Pick the next free page after the last one allocated.
+    fn alloc_page(&self) -> usize {
+        let start = self.next_free.get();
+        let page = self.pages.map_or(start, |pages| {
+            (0..self.num_pages)
+                .map(|i| (start + i) % self.num_pages)
+                .find(|&page| pages[page].used == 0)
+                .unwrap_or(start)
+        });
+        self.next_free.set((page + 1) % self.num_pages);
+        page
+    }
+
+    fn lookup(&self, hash: u32, tag: u8) -> Option<IndexEntry> {
+        self.index.map_or(None, |index| {
+            index
+                .iter()
+                .find(|entry| entry.in_use && entry.hash == hash && entry.tag == tag)
+                .map(|entry| *entry)
+        })
+    }
+
+    /// The lowest collision tag no key with this hash uses.
+    fn free_tag(&self, hash: u32) -> Option<u8> {
+        self.index.map_or(None, |index| {
+            (0..MAX_TAG + 1).find(|&tag| {
+                !index
+                    .iter()
+                    .any(|entry| entry.in_use && entry.hash == hash && entry.tag == tag)
+            })
+        })
+    }
+
+    fn index_has_room(&self) -> bool {
+        self.index
+            .map_or(false, |index| index.iter().any(|entry| !entry.in_use))
+    }
+
+    fn live_bytes(&self, page: usize) -> usize {
+        self.index.map_or(0, |index| {
+            index
+                .iter()
+                .filter(|entry| entry.in_use && entry.page as usize == page)
+                .map(|entry| entry.length as usize)
+                .sum()
+        })
+    }
+
+    /// Point the index at a record, unless it already points at a newer
+    /// record of the same key.
+    fn index_record(&self, page: usize, offset: usize, record: &Record) {
+        let seq = self.pages.map_or(0, |pages| pages[page].seq);
+        let newer = |entry: &IndexEntry| {
+            let entry_seq = self.pages.map_or(0, |pages| pages[entry.page as usize].seq);
+            entry_seq > seq || (entry_seq == seq && entry.offset as usize > offset)
+        };
+        let existing = self.lookup(record.hash, record.tag);
+        if existing.map_or(false, |entry| newer(&entry)) {
+            return;
+        }
+        self.index.map(|index| {
+            let slot = index
+                .iter()
+                .position(|entry| {
+                    entry.in_use && entry.hash == record.hash && entry.tag == record.tag
+                })
+                .or_else(|| index.iter().position(|entry| !entry.in_use));
+            slot.map(|i| {
+                index[i] = IndexEntry {
+                    hash: record.hash,
+                    tag: record.tag,
+                    page: page as u8,
+                    in_use: true,
+                    deleted: record.flags & FLAG_DELETED != 0,
+                    offset: offset as u16,
+                    length: record.length as u16,
+                };
+            });
+        });
+    }
+
+    /// Point index entries for records of `from` at the same offsets in `to`.
+    fn move_index(&self, from: usize, to: usize) {
+        self.index.map(|index| {
+            for entry in index.iter_mut() {
+                if entry.in_use && entry.page as usize == from {
+                    entry.page = to as u8;
+                }
+            }
+        });
+    }
+
+    /// Add the records of a page read while mounting to the index.
+    fn mount_page(&self, page: usize, data: &[u8]) {
+        if read_u32(&data[0..4]) != PAGE_MAGIC || read_u32(&data[8..12]) != page_crc(data) {
+            return;
+        }
+        let seq = read_u32(&data[4..8]);
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(record) = Record::parse(data, offset) {
+            offset += record.length;
+        }
+        self.pages.map(|pages| {
+            pages[page] = PageInfo {
+                seq: seq,
+                used: offset,
+            }
+        });
+        if seq >= self.next_seq.get() {
+            self.next_seq.set(seq.wrapping_add(1));
+            self.tail.set(Some(page));
+            self.next_free.set((page + 1) % self.num_pages);
+        }
+
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(record) = Record::parse(data, offset) {
+            self.index_record(page, offset, &record);
+            offset += record.length;
+        }
+    }
+
+    /// Drop records that are no longer the newest of their key from a page,
+    /// and return how many bytes are left. A record is kept only if an index
+    /// entry points at it.
+    fn compact(&self, page: usize, data: &mut [u8]) -> usize {
+        let mut read = PAGE_HEADER_LEN;
+        let mut write = PAGE_HEADER_LEN;
+        while let Some(record) = Record::parse(data, read) {
+            let live = self.index.map_or(false, |index| {
+                index.iter().any(|entry| {
+                    entry.in_use && entry.page as usize == page && entry.offset as usize == read
+                })
+            });
+            if live {
+                for i in 0..record.length {
+                    data[write + i] = data[read + i];
+                }
+                write += record.length;
+            }
+            read += record.length;
+        }
+        for byte in data[write..].iter_mut() {
+            *byte = 0;
+        }
+        write
+    }
+
+    /// Point index entries of the records in a compacted page at their new
+    /// location.
+    fn index_compacted(&self, old: usize, target: usize, data: &[u8]) {
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(record) = Record::parse(data, offset) {
+            self.index.map(|index| {
+                index
+                    .iter_mut()
+                    .find(|entry| {
+                        entry.in_use
+                            && entry.hash == record.hash
+                            && entry.tag == record.tag
+                            && entry.page as usize == old
+                    })
+                    .map(|entry| {
+                        entry.page = target as u8;
+                        entry.offset = offset as u16;
+                    });
+            });
+            offset += record.length;
+        }
+    }
+
+    /// Whether the record the index entry `slot` points at, in the page
+    /// `data` read for it, belongs to the key of `user`.
+    fn probe_matches(&self, user: &KVStoreUser<'a, F>, slot: usize, data: &[u8]) -> bool {
+        let offset = self.index.map_or(0, |index| index[slot].offset as usize);
+        match Record::parse(data, offset) {
+            Some(ref record) => user.matches(data, offset, record),
+            None => false,
+        }
+    }
+
+    /// Copy the value of the record the index entry `slot` points at to the
+    /// buffer of `user`.
+    fn get_done(&self, user: &KVStoreUser<'a, F>, slot: usize, data: &[u8]) -> (ReturnCode, usize) {
+        let entry = match self.index.map(|index| index[slot]) {
+            Some(entry) if !entry.deleted => entry,
+            _ => return (ReturnCode::ENOSUPPORT, 0),
+        };
+        let offset = entry.offset as usize;
+        match Record::parse(data, offset) {
+            Some(record) => {
+                let value_start = offset + RECORD_HEADER_LEN + record.key_len;
+                let value = &data[value_start..value_start + record.value_len];
+                user.value.map(|buffer| {
+                    let len = cmp::min(buffer.len(), value.len());
+                    buffer[..len].copy_from_slice(&value[..len]);
+                });
+                (ReturnCode::SUCCESS, record.value_len)
+            }
+            None => (ReturnCode::FAIL, 0),
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for KVStore<'a, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let ok = error == hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Mount { page } => {
+                if ok {
+                    self.mount_page(page, buffer.as_mut());
+                }
+                self.buffer.replace(buffer);
+                if page + 1 < self.num_pages {
+                    self.read_page(page + 1, State::Mount { page: page + 1 });
+                } else {
+                    self.state.set(State::Idle);
+                    self.mounted.set(true);
+                    self.do_next_op();
+                }
+            }
+            State::Probe { slot } => {
+                if !ok {
+                    self.buffer.replace(buffer);
+                    self.finish(ReturnCode::FAIL, 0);
+                    return;
+                }
+                let user = match self.inflight.map(|user| *user) {
+                    Some(user) => user,
+                    None => {
+                        self.buffer.replace(buffer);
+                        self.finish(ReturnCode::FAIL, 0);
+                        return;
+                    }
+                };
+                let matches = self.probe_matches(user, slot, buffer.as_mut());
+                if matches && user.op.get() == Op::Get {
+                    let (result, length) = self.get_done(user, slot, buffer.as_mut());
+                    self.buffer.replace(buffer);
+                    self.finish(result, length);
+                    return;
+                }
+                self.buffer.replace(buffer);
+                self.state.set(State::Idle);
+                let res = if matches {
+                    self.key_found(user, Some(slot))
+                } else {
+                    self.probe_from(user, slot + 1)
+                };
+                if res != ReturnCode::SUCCESS {
+                    self.finish(res, 0);
+                }
+            }
+            State::ReadTail { page } => {
+                if !ok {
+                    self.buffer.replace(buffer);
+                    self.finish(ReturnCode::FAIL, 0);
+                    return;
+                }
+                let used = self.pages.map_or(0, |pages| pages[page].used);
+                self.record_offset.set(used);
+                self.inflight.map(|user| {
+                    let data = buffer.as_mut();
+                    let end = used + user.write_record(&mut data[used..]);
+                    for byte in data[end..].iter_mut() {
+                        *byte = 0;
+                    }
+                });
+                self.buffer.replace(buffer);
+                let target = self.alloc_page();
+                self.erase_page(
+                    target,
+                    State::Erase {
+                        target: target,
+                        old: Some(page),
+                        compacting: false,
+                    },
+                );
+            }
+            State::ReadVictim { page } => {
+                if !ok {
+                    self.buffer.replace(buffer);
+                    self.finish(ReturnCode::FAIL, 0);
+                    return;
+                }
+                self.compact(page, buffer.as_mut());
+                self.buffer.replace(buffer);
+                let target = self.alloc_page();
+                self.erase_page(
+                    target,
+                    State::Erase {
+                        target: target,
+                        old: Some(page),
+                        compacting: true,
+                    },
+                );
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        if let State::Write {
+            target,
+            old,
+            compacting,
+        } = self.state.get()
+        {
+            if error != hil::flash::Error::CommandComplete {
+                self.buffer.replace(buffer);
+                self.finish(ReturnCode::FAIL, 0);
+                return;
+            }
+
+            // The page is committed: make it the newest page and point the
+            // index at it.
+            let seq = self.next_seq.get();
+            self.next_seq.set(seq.wrapping_add(1));
+            {
+                let data = buffer.as_mut();
+                let mut used = PAGE_HEADER_LEN;
+                while let Some(record) = Record::parse(data, used) {
+                    used += record.length;
+                }
+                self.pages.map(|pages| {
+                    pages[target] = PageInfo {
+                        seq: seq,
+                        used: used,
+                    }
+                });
+                self.tail.set(Some(target));
+
+                if compacting {
+                    old.map(|old| self.index_compacted(old, target, data));
+                } else {
+                    old.map(|old| self.move_index(old, target));
+                    let offset = self.record_offset.get();
+                    Record::parse(data, offset)
+                        .map(|record| self.index_record(target, offset, &record));
+                }
+            }
+            self.buffer.replace(buffer);
+
+            match old {
+                Some(old) => {
+                    let res = self.erase_page(
+                        old,
+                        State::EraseOld {
+                            page: old,
+                            compacting: compacting,
+                        },
+                    );
+                    if res != ReturnCode::SUCCESS {
+                        self.finish(res, 0);
+                    }
+                }
+                None => self.finish(ReturnCode::SUCCESS, 0),
+            }
+        } else {
+            self.buffer.replace(buffer);
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        match self.state.get() {
+            State::Erase {
+                target,
+                old,
+                compacting,
+            } => {
+                if error != hil::flash::Error::CommandComplete {
+                    self.finish(ReturnCode::FAIL, 0);
+                    return;
+                }
+                let seq = self.next_seq.get();
+                let res = self.buffer.take().map_or(ReturnCode::FAIL, |buffer| {
+                    seal_page(buffer.as_mut(), seq);
+                    self.state.set(State::Write {
+                        target: target,
+                        old: old,
+                        compacting: compacting,
+                    });
+                    self.flash.write_page(self.start_page + target, buffer)
+                });
+                if res != ReturnCode::SUCCESS {
+                    self.finish(res, 0);
+                }
+            }
+            State::EraseOld { page, compacting } => {
+                // Nothing in the index refers to the page any more, so it is
+                // free even if erasing it failed: it is erased again before
+                // it is written.
+                self.pages.map(|pages| pages[page] = PageInfo::new());
+                if self.tail.get() == Some(page) {
+                    self.tail.set(None);
+                }
+                if compacting {
+                    // Room has been made, so try adding the record again.
+                    self.state.set(State::Idle);
+                    let res = self
+                        .inflight
+                        .map_or(ReturnCode::FAIL, |user| self.start_write(user));
+                    if res != ReturnCode::SUCCESS {
+                        self.finish(res, 0);
+                    }
+                } else {
+                    self.finish(ReturnCode::SUCCESS, 0);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A user of the store, with its own namespace.
+pub struct KVStoreUser<'a, F: hil::flash::Flash + 'static> {
+    store: &'a KVStore<'a, F>,
+    namespace: Cell<u32>,
+    op: Cell<Op>,
+    key: Cell<[u8; MAX_KEY_LEN]>,
+    // Collision tag of the key of the record being written.
+    tag: Cell<u8>,
+    key_len: Cell<usize>,
+    value: TakeCell<'static, [u8]>,
+    value_len: Cell<usize>,
+    next: ListLink<'a, KVStoreUser<'a, F>>,
+    client: OptionalCell<&'a hil::kv_store::Client>,
+}
+
+impl<F: hil::flash::Flash> KVStoreUser<'a, F> {
+    pub const fn new(store: &'a KVStore<'a, F>, namespace: u32) -> KVStoreUser<'a, F> {
+        KVStoreUser {
+            store: store,
+            namespace: Cell::new(namespace),
+            op: Cell::new(Op::Idle),
+            key: Cell::new([0; MAX_KEY_LEN]),
+            tag: Cell::new(0),
+            key_len: Cell::new(0),
+            value: TakeCell::empty(),
+            value_len: Cell::new(0),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Change the namespace of the keys of this user's requests. Namespaces
+    /// with the top bit set are used for processes by
+    /// `capsules::kv_store_driver`.
+    pub fn set_namespace(&self, namespace: u32) {
+        self.namespace.set(namespace);
+    }
+
+    /// Largest value that can be stored under a key of `key_len` bytes.
+    pub fn max_value_len(&self, key_len: usize) -> usize {
+        self.store.max_value_len(key_len)
+    }
+
+    /// Check a request and remember its key.
+    fn prepare(&self, op: Op, key: &[u8]) -> ReturnCode {
+        if self.op.get() != Op::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if key.len() == 0 || key.len() > MAX_KEY_LEN {
+            return ReturnCode::EINVAL;
+        }
+        let mut stored_key = [0; MAX_KEY_LEN];
+        stored_key[..key.len()].copy_from_slice(key);
+        self.key.set(stored_key);
+        self.key_len.set(key.len());
+        self.op.set(op);
+        ReturnCode::SUCCESS
+    }
+
+    fn hash(&self) -> u32 {
+        key_hash(self.namespace.get(), &self.key.get()[..self.key_len.get()])
+    }
+
+    fn value_len(&self) -> usize {
+        match self.op.get() {
+            Op::Set => self.value_len.get(),
+            _ => 0,
+        }
+    }
+
+    fn record_len(&self) -> usize {
+        align4(RECORD_HEADER_LEN + self.key_len.get() + self.value_len())
+    }
+
+    /// Write this user's record to the start of `data` and return its length.
+    fn write_record(&self, data: &mut [u8]) -> usize {
+        let key_len = self.key_len.get();
+        let value_len = self.value_len();
+        let length = self.record_len();
+
+        data[0] = key_len as u8;
+        data[1] = self.tag.get() << TAG_SHIFT | if self.op.get() == Op::Delete {
+            FLAG_DELETED
+        } else {
+            0
+        };
+        data[2] = value_len as u8;
+        data[3] = (value_len >> 8) as u8;
+        write_u32(&mut data[4..8], self.namespace.get());
+        let key_start = RECORD_HEADER_LEN;
+        data[key_start..key_start + key_len].copy_from_slice(&self.key.get()[..key_len]);
+        let value_start = key_start + key_len;
+        self.value.map(|value| {
+            data[value_start..value_start + value_len].copy_from_slice(&value[..value_len]);
+        });
+        for byte in data[value_start + value_len..length].iter_mut() {
+            *byte = 0;
+        }
+        let crc = record_crc(&data[..length], key_len + value_len);
+        write_u32(&mut data[8..12], crc);
+        length
+    }
+
+    /// Whether the record at `offset` of `data` belongs to this user's key.
+    fn matches(&self, data: &[u8], offset: usize, record: &Record) -> bool {
+        let key_start = offset + RECORD_HEADER_LEN;
+        record.namespace == self.namespace.get()
+            && &data[key_start..key_start + record.key_len] == &self.key.get()[..self.key_len.get()]
+    }
+
+    fn complete(&self, result: ReturnCode, length: usize) {
+        let op = self.op.get();
+        self.op.set(Op::Idle);
+        match op {
+            Op::Get => {
+                self.value.take().map(|value| {
+                    self.client
+                        .map(move |client| client.get_complete(result, value, length));
+                });
+            }
+            Op::Set => {
+                self.value.take().map(|value| {
+                    self.client
+                        .map(move |client| client.set_complete(result, value));
+                });
+            }
+            Op::Delete => {
+                self.client.map(|client| client.delete_complete(result));
+            }
+            Op::Idle => {}
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> ListNode<'a, KVStoreUser<'a, F>> for KVStoreUser<'a, F> {
+    fn next(&'a self) -> &'a ListLink<'a, KVStoreUser<'a, F>> {
+        &self.next
+    }
+}
+
+impl<F: hil::flash::Flash> hil::kv_store::KVStore<'a> for KVStoreUser<'a, F> {
+    /// Set the client for this user. This also adds the user to the store,
+    /// so every user must set a client.
+    fn set_client(&'a self, client: &'a hil::kv_store::Client) {
+        self.store.users.push_head(self);
+        self.client.set(client);
+    }
+
+    fn get(
+        &'a self,
+        key: &[u8],
+        value: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let res = self.prepare(Op::Get, key);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(value));
+        }
+        self.value.replace(value);
+        let res = self.store.start_or_queue(self);
+        if res != ReturnCode::SUCCESS {
+            return (res, self.value.take());
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn set(
+        &'a self,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if length > value.len() || length > self.store.max_value_len(key.len()) {
+            return (ReturnCode::ESIZE, Some(value));
+        }
+        let res = self.prepare(Op::Set, key);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(value));
+        }
+        self.value.replace(value);
+        self.value_len.set(length);
+        let res = self.store.start_or_queue(self);
+        if res != ReturnCode::SUCCESS {
+            return (res, self.value.take());
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn delete(&'a self, key: &[u8]) -> ReturnCode {
+        let res = self.prepare(Op::Delete, key);
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+        self.store.start_or_queue(self)
+    }
+}
+
+/// Header of a record that passed its CRC check.
+struct Record {
+    hash: u32,
+    tag: u8,
+    namespace: u32,
+    flags: u8,
+    key_len: usize,
+    value_len: usize,
+    // Including the header and padding.
+    length: usize,
+}
+
+impl Record {
+    fn parse(data: &[u8], offset: usize) -> Option<Record> {
+        if offset + RECORD_HEADER_LEN > data.len() {
+            return None;
+        }
+        let header = &data[offset..offset + RECORD_HEADER_LEN];
+        let key_len = header[0] as usize;
+        let value_len = header[2] as usize | (header[3] as usize) << 8;
+        let length = align4(RECORD_HEADER_LEN + key_len + value_len);
+        if key_len == 0 || key_len > MAX_KEY_LEN || offset + length > data.len() {
+            return None;
+        }
+        let record = &data[offset..offset + length];
+        if read_u32(&header[8..12]) != record_crc(record, key_len + value_len) {
+            return None;
+        }
+        let namespace = read_u32(&header[4..8]);
+        let key = &record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key_len];
+        Some(Record {
+            hash: key_hash(namespace, key),
+            tag: header[1] >> TAG_SHIFT,
+            namespace: namespace,
+            flags: header[1],
+            key_len: key_len,
+            value_len: value_len,
+            length: length,
+        })
+    }
+}
+
+fn align4(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// 32-bit FNV-1a hash of a namespace and key.
+fn key_hash(namespace: u32, key: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    write_u32(&mut bytes, namespace);
+    fnv1a_update(fnv1a(&bytes), key)
+}
+
+/// CRC of a record's header, except the CRC itself, and its key and value.
+fn record_crc(record: &[u8], data_len: usize) -> u32 {
+    let crc = crc32_update(0xffffffff, &record[0..8]);
+    let end = RECORD_HEADER_LEN + data_len;
+    !crc32_update(crc, &record[RECORD_HEADER_LEN..end])
+}
+
+/// CRC of a page's header, except the CRC itself, and its contents.
+fn page_crc(page: &[u8]) -> u32 {
+    let crc = crc32_update(0xffffffff, &page[0..8]);
+    !crc32_update(crc, &page[PAGE_HEADER_LEN..])
+}
+
+fn seal_page(page: &mut [u8], seq: u32) {
+    write_u32(&mut page[0..4], PAGE_MAGIC);
+    write_u32(&mut page[4..8], seq);
+    let crc = page_crc(page);
+    write_u32(&mut page[8..12], crc);
+}
@@ -0,0 +1,319 @@
+//! Provides userspace access to a key-value store.
+//!
+//! Each process gets its own namespace, derived from the package name in its
+//! TBF header, so processes do not see each other's keys and a process finds
+//! its keys again after it is updated. Processes without a package name
+//! cannot use the store. Requests from all processes are served one at a
+//! time.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let kv_user = static_init!(
+//!     capsules::kv_store::KVStoreUser<'static, capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::kv_store::KVStoreUser::new(kv_store, 0)
+//! );
+//! let kv_store_driver = static_init!(
+//!     capsules::kv_store_driver::KVStoreDriver<'static, capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::kv_store_driver::KVStoreDriver::new(
+//!         kv_user,
+//!         &mut capsules::kv_store_driver::BUFFER,
+//!         kernel::Grant::create()
+//!     )
+//! );
+//! hil::kv_store::KVStore::set_client(kv_user, kv_store_driver);
+//! ```
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::kv_store::KVStore;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kv_store::{KVStoreUser, MAX_KEY_LEN};
+use util::fnv1a;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x50003;
+
+/// Namespaces of processes have the top bit set, so they never clash with
+/// namespaces used by the kernel.
+const APP_NAMESPACE: u32 = 0x8000_0000;
+
+/// Values are copied through this buffer, which limits their length.
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Get,
+    Set,
+    Delete,
+}
+
+#[derive(Clone, Copy)]
+pub struct Request {
+    op: Op,
+    key_len: usize,
+    value_len: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    // The request this app is waiting for, if any.
+    waiting: Option<Request>,
+}
+
+pub struct KVStoreDriver<'a, F: hil::flash::Flash + 'static> {
+    store: &'a KVStoreUser<'a, F>,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl<F: hil::flash::Flash> KVStoreDriver<'a, F> {
+    pub fn new(
+        store: &'a KVStoreUser<'a, F>,
+        buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> KVStoreDriver<'a, F> {
+        KVStoreDriver {
+            store: store,
+            buffer: TakeCell::new(buffer),
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the request of the next waiting app. Apps whose request cannot
+    /// be started are told so through their callback.
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            return;
+        }
+
+        let mut found = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                let appid = app.appid();
+                if let Some(request) = app.waiting {
+                    let res = self.start_request(app, appid, request);
+                    if res == ReturnCode::SUCCESS {
+                        self.serving_app.set(appid);
+                        found = true;
+                    } else {
+                        app.waiting = None;
+                        app.callback
+                            .map(|mut cb| cb.schedule(From::from(res), 0, 0));
+                    }
+                }
+            });
+            if found {
+                break;
+            }
+        }
+    }
+
+    fn start_request(&self, app: &mut App, appid: AppId, request: Request) -> ReturnCode {
+        let mut key = [0; MAX_KEY_LEN];
+        let key_len = match app.key {
+            Some(ref slice) if request.key_len <= slice.len() => {
+                key[..request.key_len].copy_from_slice(&slice.as_ref()[..request.key_len]);
+                request.key_len
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        self.store
+            .set_namespace(app_namespace(appid.get_package_name()));
+
+        match request.op {
+            Op::Delete => self.store.delete(&key[..key_len]),
+            Op::Get => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let (res, buffer) = self.store.get(&key[..key_len], buffer);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                res
+            }),
+            Op::Set => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let length = match app.value {
+                    Some(ref slice)
+                        if request.value_len <= slice.len()
+                            && request.value_len <= buffer.len() =>
+                    {
+                        buffer[..request.value_len]
+                            .copy_from_slice(&slice.as_ref()[..request.value_len]);
+                        request.value_len
+                    }
+                    _ => {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::EINVAL;
+                    }
+                };
+                let (res, buffer) = self.store.set(&key[..key_len], buffer, length);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                res
+            }),
+        }
+    }
+
+    /// Finish the request of the app being served, and start the next one.
+    /// For a get, `value` holds the value that is copied to the app.
+    fn complete(&self, result: ReturnCode, value: Option<&'static mut [u8]>, length: usize) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                if result == ReturnCode::SUCCESS {
+                    value.as_ref().map(|value| {
+                        app.value.as_mut().map(|slice| {
+                            let len = cmp::min(cmp::min(slice.len(), value.len()), length);
+                            slice.as_mut()[..len].copy_from_slice(&value[..len]);
+                        });
+                    });
+                }
+                app.callback
+                    .map(|mut cb| cb.schedule(From::from(result), length, 0));
+            });
+        });
+        value.map(|value| self.buffer.replace(value));
+        self.serve_waiting_apps();
+    }
+}
+
+impl<F: hil::flash::Flash> hil::kv_store::Client for KVStoreDriver<'a, F> {
+    fn get_complete(&self, result: ReturnCode, value: &'static mut [u8], length: usize) {
+        self.complete(result, Some(value), length);
+    }
+
+    fn set_complete(&self, result: ReturnCode, value: &'static mut [u8]) {
+        self.complete(result, Some(value), 0);
+    }
+
+    fn delete_complete(&self, result: ReturnCode) {
+        self.complete(result, None, 0);
+    }
+}
+
+impl<F: hil::flash::Flash> Driver for KVStoreDriver<'a, F> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Key.
+    /// - `1`: Value, read by set and written by get.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 | 1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if allow_num == 0 {
+                        app.key = slice;
+                    } else {
+                        app.value = slice;
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request complete. The callback is called with the status of
+    ///   the request and, for a get, the length of the stored value, of which
+    ///   as much as fits was copied into the value buffer. The status is
+    ///   `ENOSUPPORT` if the key has no value and `ENOMEM` if the store is
+    ///   full.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// Keys are the first `key_len` bytes of the key buffer, and may be up to
+    /// 32 bytes long.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Get the value of the key of length `data1`.
+    /// - `2`: Set the value of the key of length `data1` to the first `data2`
+    ///   bytes of the value buffer.
+    /// - `3`: Delete the key of length `data1`.
+    /// - `4`: Return the longest value that can be stored.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let op = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Op::Get,
+            2 => Op::Set,
+            3 => Op::Delete,
+            4 => {
+                return ReturnCode::SuccessWithValue {
+                    value: self.buffer.map_or(0, |buffer| {
+                        cmp::min(buffer.len(), self.store.max_value_len(MAX_KEY_LEN))
+                    }),
+                }
+            }
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        if appid.get_package_name().len() == 0 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if data1 == 0 || data1 > MAX_KEY_LEN {
+            return ReturnCode::EINVAL;
+        }
+
+        let res = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else if app.callback.is_none() || app.key.is_none() {
+                    ReturnCode::EINVAL
+                } else if op != Op::Delete && app.value.is_none() {
+                    ReturnCode::EINVAL
+                } else {
+                    app.waiting = Some(Request {
+                        op: op,
+                        key_len: data1,
+                        value_len: data2,
+                    });
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+
+        if res == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        res
+    }
+}
+
+/// 32-bit FNV-1a hash of a package name, with the top bit set.
+fn app_namespace(package_name: &str) -> u32 {
+    fnv1a(package_name.as_bytes()) | APP_NAMESPACE
+}
@@ -5,7 +5,7 @@
 #![no_std]
 
 #[allow(unused_imports)]
-#[macro_use(debug)]
+#[macro_use(debug, log, log_error, log_warn, log_info, log_debug, log_trace)]
 extern crate kernel;
 
 pub mod test;
@@ -14,6 +14,7 @@ pub mod test;
 pub mod net;
 
 pub mod adc;
+pub mod aes;
 pub mod aes_ccm;
 pub mod alarm;
 pub mod ambient_light;
@@ -23,15 +24,25 @@ pub mod button;
 pub mod console;
 pub mod crc;
 pub mod dac;
+pub mod debug_sinks;
+pub mod digest;
+pub mod fat;
+pub mod fat_driver;
+pub mod flash_block;
 pub mod fm25cl;
 pub mod fxos8700cq;
 pub mod gpio;
 pub mod gpio_async;
+pub mod hmac_drbg;
 pub mod humidity;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kv_store;
+pub mod kv_store_driver;
 pub mod led;
+pub mod log;
+pub mod log_driver;
 pub mod lps25hb;
 pub mod ltc294x;
 pub mod max17205;
@@ -39,15 +50,20 @@ pub mod mcp230xx;
 pub mod mx25r6435f;
 pub mod ninedof;
 pub mod nonvolatile_storage_driver;
+pub mod nonvolatile_to_blocks;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
 pub mod pca9544a;
+pub mod process_info;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
+pub mod rtc;
 pub mod sdcard;
 pub mod segger_rtt;
+pub mod sha256;
 pub mod si7021;
+pub mod software_rtc;
 pub mod spi;
 pub mod temperature;
 pub mod tmp006;
@@ -55,6 +71,9 @@ pub mod tsl2561;
 pub mod usb;
 pub mod usb_user;
 pub mod usbc_client;
+mod util;
+pub mod virtual_adc;
+pub mod virtual_aes;
 pub mod virtual_alarm;
 pub mod virtual_flash;
 pub mod virtual_i2c;
@@ -0,0 +1,654 @@
+//! Append-only circular log on flash.
+//!
+//! `Log` keeps a `hil::log::Log` in a range of flash pages, for data such as
+//! sensor readings that is recorded over time and read back later. Once all
+//! pages are full, the page with the oldest entries is dropped to make room
+//! for new ones. Requests are served one at a time.
+//!
+//! Layout
+//! ------
+//!
+//! Every page in use starts with a 16 byte header: a magic number, a sequence
+//! number that grows with every page started, the number of the first entry
+//! in the page, and a CRC-32 of the sequence and entry numbers. Entries
+//! follow in order, each with its length, the inverse of its length and a
+//! CRC-32 of both and of the data, and padded to a multiple of 4 bytes:
+//!
+//! ```text
+//! 0       4       8             12      16                    24
+//! +-------+-------+-------------+-------+-----+------+--------+------+-----+
+//! | magic | seq   | first entry | CRC   | len | !len | CRC    | data | ... |
+//! +-------+-------+-------------+-------+-----+------+--------+------+-----+
+//! ```
+//!
+//! Entries are programmed in place into the erased end of the newest page,
+//! without rewriting what is already there. When the newest page is full,
+//! the log erases the next page in turn, which is a free page or else the
+//! page with the oldest entries, and starts it with the header and the new
+//! entry. Each page is therefore erased once every time the log goes round
+//! all of its pages. An entry cut short by a power loss fails its CRC check
+//! when the log is mounted: the entries before it are kept, and new entries
+//! go to the next page.
+//!
+//! The log keeps a little information about each page in RAM, which it
+//! gathers when it is mounted.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let log_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash)
+//! );
+//! let log = static_init!(
+//!     capsules::log::Log<'static, capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::log::Log::new(
+//!         log_flash,
+//!         start_page,
+//!         num_pages,
+//!         &mut capsules::log::PAGES,
+//!         &mut LOG_PAGEBUFFER
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(log_flash, log);
+//! log.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ReturnCode;
+use util::{crc32_update, read_u16, read_u32, write_u16, write_u32};
+
+const MAGIC: u32 = 0x4c4f4732;
+const PAGE_HEADER_LEN: usize = 16;
+const ENTRY_HEADER_LEN: usize = 8;
+const END: u16 = 0xffff;
+
+/// What the log knows about one of its pages.
+#[derive(Clone, Copy)]
+pub struct PageInfo {
+    in_use: bool,
+    seq: u32,
+    first_entry: u32,
+    entries: u32,
+    // Bytes used by the header and entries. Pages that cannot take more
+    // entries count as full.
+    used: usize,
+}
+
+impl PageInfo {
+    pub const fn new() -> PageInfo {
+        PageInfo {
+            in_use: false,
+            seq: 0,
+            first_entry: 0,
+            entries: 0,
+            used: 0,
+        }
+    }
+}
+
+/// Page state for logs of up to 64 pages.
+pub static mut PAGES: [PageInfo; 64] = [PageInfo::new(); 64];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Mount {
+        page: usize,
+    },
+    Read {
+        entry: u32,
+    },
+    // Programming an entry into the erased end of the newest page.
+    Program {
+        page: usize,
+    },
+    // Erasing the next page in turn, before starting it with the entry
+    // being appended.
+    Erase {
+        target: usize,
+    },
+    Start {
+        target: usize,
+    },
+    EraseAll {
+        page: usize,
+    },
+}
+
+pub struct Log<'a, F: hil::flash::Flash + 'static> {
+    flash: &'a F,
+    start_page: usize,
+    num_pages: usize,
+    page_len: usize,
+    pages: TakeCell<'static, [PageInfo]>,
+    buffer: TakeCell<'static, F::Page>,
+    state: Cell<State>,
+    mounted: Cell<bool>,
+    client: OptionalCell<&'a hil::log::Client>,
+    // Buffer and length of the entry being read or appended.
+    data: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    next_seq: Cell<u32>,
+    next_entry: Cell<u32>,
+    read_entry: Cell<u32>,
+}
+
+impl<F: hil::flash::Flash> Log<'a, F> {
+    pub fn new(
+        flash: &'a F,
+        start_page: usize,
+        num_pages: usize,
+        pages: &'static mut [PageInfo],
+        buffer: &'static mut F::Page,
+    ) -> Log<'a, F> {
+        let num_pages = cmp::min(num_pages, pages.len());
+        let page_len = buffer.as_mut().len();
+        Log {
+            flash: flash,
+            start_page: start_page,
+            num_pages: num_pages,
+            page_len: page_len,
+            pages: TakeCell::new(pages),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            mounted: Cell::new(false),
+            client: OptionalCell::empty(),
+            data: TakeCell::empty(),
+            length: Cell::new(0),
+            next_seq: Cell::new(0),
+            next_entry: Cell::new(0),
+            read_entry: Cell::new(0),
+        }
+    }
+
+    /// Read every page of the log to find its entries. Requests fail with
+    /// `ERESERVE` until this has finished.
+    pub fn initialize(&self) {
+        // Dropping the oldest page must leave at least one page of entries.
+        if !self.mounted.get() && self.state.get() == State::Idle && self.num_pages >= 2 {
+            self.read_page(0, State::Mount { page: 0 });
+        }
+    }
+
+    fn read_page(&self, page: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.state.set(state);
+            let res = self.flash.read_page(self.start_page + page, buffer);
+            if res != ReturnCode::SUCCESS {
+                self.state.set(State::Idle);
+            }
+            res
+        })
+    }
+
+    fn erase_page(&self, page: usize, state: State) -> ReturnCode {
+        self.state.set(state);
+        let res = self.flash.erase_page(self.start_page + page);
+        if res != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        res
+    }
+
+    /// Program `length` bytes of the page buffer from `offset` on into a
+    /// page.
+    fn program_page(&self, page: usize, offset: usize, length: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.state.set(state);
+            let res = self
+                .flash
+                .program_page(self.start_page + page, buffer, offset, length);
+            if res != ReturnCode::SUCCESS {
+                self.state.set(State::Idle);
+            }
+            res
+        })
+    }
+
+    /// Newest page, which entries are appended to.
+    fn tail(&self) -> Option<usize> {
+        self.pages.map_or(None, |pages| {
+            (0..self.num_pages)
+                .filter(|&i| pages[i].in_use)
+                .max_by_key(|&i| pages[i].seq)
+        })
+    }
+
+    /// Page holding the oldest entries.
+    fn oldest(&self) -> Option<usize> {
+        self.pages.map_or(None, |pages| {
+            (0..self.num_pages)
+                .filter(|&i| pages[i].in_use)
+                .min_by_key(|&i| pages[i].seq)
+        })
+    }
+
+    /// A free page to start, after `after` so that pages are used in turn.
+    /// The oldest page is dropped if there is no free page.
+    fn alloc_page(&self, after: Option<usize>) -> usize {
+        let start = after.map_or(0, |page| page + 1);
+        let free = self.pages.map_or(None, |pages| {
+            (0..self.num_pages)
+                .map(|i| (start + i) % self.num_pages)
+                .find(|&i| !pages[i].in_use)
+        });
+        match free {
+            Some(page) => page,
+            None => {
+                let oldest = self.oldest().unwrap_or(0);
+                self.pages.map(|pages| pages[oldest].in_use = false);
+                oldest
+            }
+        }
+    }
+
+    /// Page holding `entry`, or else the first page with later entries.
+    fn find_entry(&self, entry: u32) -> Option<(usize, u32)> {
+        self.pages.map_or(None, |pages| {
+            let containing = (0..self.num_pages).find(|&i| {
+                pages[i].in_use
+                    && entry >= pages[i].first_entry
+                    && entry - pages[i].first_entry < pages[i].entries
+            });
+            match containing {
+                Some(page) => Some((page, entry)),
+                None => (0..self.num_pages)
+                    .filter(|&i| pages[i].in_use && pages[i].entries > 0)
+                    .filter(|&i| pages[i].first_entry > entry)
+                    .min_by_key(|&i| pages[i].first_entry)
+                    .map(|page| (page, pages[page].first_entry)),
+            }
+        })
+    }
+
+    /// Record what a page read while mounting holds, if it is valid.
+    fn mount_page(&self, page: usize, data: &[u8]) {
+        if read_u32(&data[0..4]) != MAGIC || read_u32(&data[12..16]) != header_crc(data) {
+            return;
+        }
+        let mut offset = PAGE_HEADER_LEN;
+        let mut entries = 0;
+        loop {
+            match parse_entry(data, offset) {
+                Entry::Valid(len) => {
+                    offset += ENTRY_HEADER_LEN + align(len);
+                    entries += 1;
+                }
+                Entry::End => break,
+                Entry::Corrupt => {
+                    // An append was cut short, and the rest of the page may
+                    // be partly programmed, so nothing more can go in it.
+                    offset = self.page_len;
+                    break;
+                }
+            }
+        }
+
+        self.pages.map(|pages| {
+            pages[page] = PageInfo {
+                in_use: true,
+                seq: read_u32(&data[4..8]),
+                first_entry: read_u32(&data[8..12]),
+                entries: entries,
+                used: offset,
+            };
+        });
+    }
+
+    fn mount_done(&self) {
+        let tail = self.tail();
+        self.pages.map(|pages| match tail {
+            Some(tail) => {
+                self.next_seq.set(pages[tail].seq.wrapping_add(1));
+                self.next_entry
+                    .set(pages[tail].first_entry + pages[tail].entries);
+            }
+            None => {
+                self.next_seq.set(0);
+                self.next_entry.set(0);
+            }
+        });
+        self.read_entry.set(hil::log::Log::oldest_entry(self));
+        self.state.set(State::Idle);
+        self.mounted.set(true);
+    }
+
+    /// Copy entry `entry` of a page whose first entry is `first_entry` into
+    /// the read buffer, and return its length.
+    fn read_done(&self, data: &[u8], first_entry: u32, entry: u32) -> (ReturnCode, usize) {
+        let mut offset = PAGE_HEADER_LEN;
+        for _ in first_entry..entry {
+            match parse_entry(data, offset) {
+                Entry::Valid(len) => offset += ENTRY_HEADER_LEN + align(len),
+                _ => return (ReturnCode::FAIL, 0),
+            }
+        }
+        let len = match parse_entry(data, offset) {
+            Entry::Valid(len) => len,
+            _ => return (ReturnCode::FAIL, 0),
+        };
+        let start = offset + ENTRY_HEADER_LEN;
+        self.data.map(|buffer| {
+            let copy = cmp::min(len, buffer.len());
+            buffer[..copy].copy_from_slice(&data[start..start + copy]);
+        });
+        (ReturnCode::SUCCESS, len)
+    }
+
+    /// Put the entry being appended into the page buffer at `offset`, and
+    /// return how many bytes it takes.
+    fn build_entry(&self, data: &mut [u8], offset: usize) -> usize {
+        let length = self.length.get();
+        let entry_len = ENTRY_HEADER_LEN + align(length);
+        write_u16(&mut data[offset..], length as u16);
+        write_u16(&mut data[offset + 2..], !(length as u16));
+        let start = offset + ENTRY_HEADER_LEN;
+        self.data.map(|buffer| {
+            data[start..start + length].copy_from_slice(&buffer[..length]);
+        });
+        // Padding is left erased.
+        for byte in data[start + length..offset + entry_len].iter_mut() {
+            *byte = 0xff;
+        }
+        let crc = entry_crc(&data[offset..], length);
+        write_u32(&mut data[offset + 4..], crc);
+        entry_len
+    }
+
+    /// Start appending the entry in `data`, to the newest page if it has
+    /// room or else to the next page in turn.
+    fn start_append(&self) -> ReturnCode {
+        let entry_len = ENTRY_HEADER_LEN + align(self.length.get());
+        let tail = self.tail();
+        let used = tail.map_or(0, |tail| self.pages.map_or(0, |pages| pages[tail].used));
+        match tail {
+            Some(tail) if used + entry_len <= self.page_len => {
+                let built = self
+                    .buffer
+                    .map(|buffer| self.build_entry(buffer.as_mut(), used));
+                if built.is_none() {
+                    return ReturnCode::EBUSY;
+                }
+                self.program_page(tail, used, entry_len, State::Program { page: tail })
+            }
+            _ => {
+                let target = self.alloc_page(tail);
+                self.erase_page(target, State::Erase { target: target })
+            }
+        }
+    }
+
+    fn finish_read(&self, result: ReturnCode, length: usize, entry: u32) {
+        self.state.set(State::Idle);
+        self.data.take().map(|buffer| {
+            self.client
+                .map(move |client| client.read_done(result, buffer, length, entry));
+        });
+    }
+
+    fn finish_append(&self, result: ReturnCode, entry: u32) {
+        self.state.set(State::Idle);
+        self.data.take().map(|buffer| {
+            self.client
+                .map(move |client| client.append_done(result, buffer, entry));
+        });
+    }
+}
+
+impl<F: hil::flash::Flash> hil::log::Log<'a> for Log<'a, F> {
+    fn set_client(&self, client: &'a hil::log::Client) {
+        self.client.set(client);
+    }
+
+    fn oldest_entry(&self) -> u32 {
+        match self.oldest() {
+            Some(oldest) => self.pages.map_or(0, |pages| pages[oldest].first_entry),
+            None => self.next_entry.get(),
+        }
+    }
+
+    fn next_entry(&self) -> u32 {
+        self.next_entry.get()
+    }
+
+    fn max_entry_len(&self) -> usize {
+        cmp::min(
+            self.page_len
+                .saturating_sub(PAGE_HEADER_LEN + ENTRY_HEADER_LEN),
+            END as usize - 1,
+        )
+    }
+
+    fn seek(&self, entry: u32) -> ReturnCode {
+        if !self.mounted.get() {
+            return ReturnCode::ERESERVE;
+        }
+        self.read_entry.set(entry);
+        ReturnCode::SUCCESS
+    }
+
+    fn read(&self, buffer: &'static mut [u8]) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.mounted.get() {
+            return (ReturnCode::ERESERVE, Some(buffer));
+        }
+        if self.state.get() != State::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        let entry = cmp::max(self.read_entry.get(), hil::log::Log::oldest_entry(self));
+        let (page, entry) = match self.find_entry(entry) {
+            Some(found) if entry < self.next_entry.get() => found,
+            _ => return (ReturnCode::ENOSUPPORT, Some(buffer)),
+        };
+        self.data.replace(buffer);
+        let res = self.read_page(page, State::Read { entry: entry });
+        if res != ReturnCode::SUCCESS {
+            return (res, self.data.take());
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn append(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.mounted.get() {
+            return (ReturnCode::ERESERVE, Some(buffer));
+        }
+        if self.state.get() != State::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        if length > buffer.len() || length > self.max_entry_len() {
+            return (ReturnCode::ESIZE, Some(buffer));
+        }
+        self.data.replace(buffer);
+        self.length.set(length);
+        let res = self.start_append();
+        if res != ReturnCode::SUCCESS {
+            return (res, self.data.take());
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn erase(&self) -> ReturnCode {
+        if !self.mounted.get() {
+            return ReturnCode::ERESERVE;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.erase_page(0, State::EraseAll { page: 0 })
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for Log<'a, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let ok = error == hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Mount { page } => {
+                if ok {
+                    self.mount_page(page, buffer.as_mut());
+                }
+                self.buffer.replace(buffer);
+                if page + 1 < self.num_pages {
+                    self.read_page(page + 1, State::Mount { page: page + 1 });
+                } else {
+                    self.mount_done();
+                }
+            }
+            State::Read { entry } => {
+                let first_entry = self.find_entry(entry).map_or(0, |(page, _)| {
+                    self.pages.map_or(0, |pages| pages[page].first_entry)
+                });
+                let (result, length) = if ok {
+                    self.read_done(buffer.as_mut(), first_entry, entry)
+                } else {
+                    (ReturnCode::FAIL, 0)
+                };
+                self.buffer.replace(buffer);
+                if result == ReturnCode::SUCCESS {
+                    self.read_entry.set(entry + 1);
+                }
+                self.finish_read(result, length, entry);
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.buffer.replace(buffer);
+        let ok = error == hil::flash::Error::CommandComplete;
+        let entry = self.next_entry.get();
+        let entry_len = ENTRY_HEADER_LEN + align(self.length.get());
+        match self.state.get() {
+            State::Program { page } => {
+                self.pages.map(|pages| {
+                    if ok {
+                        pages[page].entries += 1;
+                        pages[page].used += entry_len;
+                    } else {
+                        // The end of the page may be partly programmed.
+                        pages[page].used = self.page_len;
+                    }
+                });
+            }
+            State::Start { target } => {
+                if ok {
+                    self.pages.map(|pages| {
+                        pages[target] = PageInfo {
+                            in_use: true,
+                            seq: self.next_seq.get(),
+                            first_entry: entry,
+                            entries: 1,
+                            used: PAGE_HEADER_LEN + entry_len,
+                        };
+                    });
+                    self.next_seq.set(self.next_seq.get().wrapping_add(1));
+                }
+            }
+            _ => return,
+        }
+        if ok {
+            self.next_entry.set(entry + 1);
+            self.finish_append(ReturnCode::SUCCESS, entry);
+        } else {
+            self.finish_append(ReturnCode::FAIL, 0);
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        let ok = error == hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Erase { target } => {
+                if !ok {
+                    self.finish_append(ReturnCode::FAIL, 0);
+                    return;
+                }
+                let seq = self.next_seq.get();
+                let first_entry = self.next_entry.get();
+                let length = self.buffer.map_or(0, |buffer| {
+                    let data = buffer.as_mut();
+                    write_u32(&mut data[0..4], MAGIC);
+                    write_u32(&mut data[4..8], seq);
+                    write_u32(&mut data[8..12], first_entry);
+                    let crc = header_crc(data);
+                    write_u32(&mut data[12..16], crc);
+                    PAGE_HEADER_LEN + self.build_entry(data, PAGE_HEADER_LEN)
+                });
+                let res = self.program_page(target, 0, length, State::Start { target: target });
+                if res != ReturnCode::SUCCESS {
+                    self.finish_append(res, 0);
+                }
+            }
+            State::EraseAll { page } => {
+                self.pages.map(|pages| pages[page] = PageInfo::new());
+                let res = if !ok {
+                    ReturnCode::FAIL
+                } else if page + 1 < self.num_pages {
+                    self.erase_page(page + 1, State::EraseAll { page: page + 1 })
+                } else {
+                    self.next_seq.set(0);
+                    self.next_entry.set(0);
+                    self.read_entry.set(0);
+                    ReturnCode::SUCCESS
+                };
+                if res != ReturnCode::SUCCESS || page + 1 >= self.num_pages {
+                    self.state.set(State::Idle);
+                    self.client.map(|client| client.erase_done(res));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+enum Entry {
+    // A complete entry of this length.
+    Valid(usize),
+    // Erased flash, after the last entry.
+    End,
+    // An entry that was not completely programmed.
+    Corrupt,
+}
+
+/// Check the entry that starts at `offset` of a page.
+fn parse_entry(data: &[u8], offset: usize) -> Entry {
+    if offset + ENTRY_HEADER_LEN > data.len() {
+        return Entry::End;
+    }
+    let len = read_u16(&data[offset..]);
+    let inverse = read_u16(&data[offset + 2..]);
+    if len == END && inverse == END && read_u32(&data[offset + 4..]) == 0xffffffff {
+        return Entry::End;
+    }
+    let len = len as usize;
+    if inverse != !(len as u16) || offset + ENTRY_HEADER_LEN + align(len) > data.len() {
+        return Entry::Corrupt;
+    }
+    if read_u32(&data[offset + 4..]) != entry_crc(&data[offset..], len) {
+        return Entry::Corrupt;
+    }
+    Entry::Valid(len)
+}
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// CRC of a page header's sequence and first entry numbers.
+fn header_crc(data: &[u8]) -> u32 {
+    !crc32_update(0xffffffff, &data[4..12])
+}
+
+/// CRC of the length fields and data of the entry at the start of `entry`.
+fn entry_crc(entry: &[u8], len: usize) -> u32 {
+    let crc = crc32_update(0xffffffff, &entry[0..4]);
+    !crc32_update(crc, &entry[ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + len])
+}
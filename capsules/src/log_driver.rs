@@ -0,0 +1,291 @@
+//! Provides userspace access to an append-only log.
+//!
+//! All processes share one log, so that one process can record data that
+//! another reads back, but each process has its own read position. Entries
+//! are numbered by the log, and a process can seek to an entry by its
+//! number. Requests from all processes are served one at a time.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let log_driver = static_init!(
+//!     capsules::log_driver::LogDriver<'static>,
+//!     capsules::log_driver::LogDriver::new(
+//!         log,
+//!         &mut capsules::log_driver::BUFFER,
+//!         kernel::Grant::create()
+//!     )
+//! );
+//! hil::log::Log::set_client(log, log_driver);
+//! ```
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x50005;
+
+/// Entries are copied through this buffer, which limits their length.
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Request {
+    Read,
+    Append { length: usize },
+    Erase,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    buffer: Option<AppSlice<Shared, u8>>,
+    // Number of the next entry this app reads.
+    position: u32,
+    // The request this app is waiting for, if any.
+    waiting: Option<Request>,
+}
+
+pub struct LogDriver<'a> {
+    log: &'a hil::log::Log<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl LogDriver<'a> {
+    pub fn new(
+        log: &'a hil::log::Log<'a>,
+        buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> LogDriver<'a> {
+        LogDriver {
+            log: log,
+            buffer: TakeCell::new(buffer),
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the request of the next waiting app. Apps whose request cannot
+    /// be started are told so through their callback.
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            return;
+        }
+
+        let mut found = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                let appid = app.appid();
+                if let Some(request) = app.waiting {
+                    let res = self.start_request(app, request);
+                    if res == ReturnCode::SUCCESS {
+                        self.serving_app.set(appid);
+                        found = true;
+                    } else {
+                        app.waiting = None;
+                        app.callback
+                            .map(|mut cb| cb.schedule(From::from(res), 0, 0));
+                    }
+                }
+            });
+            if found {
+                break;
+            }
+        }
+    }
+
+    fn start_request(&self, app: &mut App, request: Request) -> ReturnCode {
+        match request {
+            Request::Erase => self.log.erase(),
+            Request::Read => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                self.log.seek(app.position);
+                let (res, buffer) = self.log.read(buffer);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                res
+            }),
+            Request::Append { length } => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                match app.buffer {
+                    Some(ref slice) if length <= slice.len() && length <= buffer.len() => {
+                        buffer[..length].copy_from_slice(&slice.as_ref()[..length]);
+                    }
+                    _ => {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::EINVAL;
+                    }
+                }
+                let (res, buffer) = self.log.append(buffer, length);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                res
+            }),
+        }
+    }
+
+    /// Finish the request of the app being served, and start the next one.
+    fn complete(&self, result: ReturnCode, data1: usize, data2: usize) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                app.callback
+                    .map(|mut cb| cb.schedule(From::from(result), data1, data2));
+            });
+        });
+        self.serve_waiting_apps();
+    }
+}
+
+impl hil::log::Client for LogDriver<'a> {
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize, entry: u32) {
+        self.serving_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                if result == ReturnCode::SUCCESS {
+                    app.position = entry.wrapping_add(1);
+                    app.buffer.as_mut().map(|slice| {
+                        let len = cmp::min(cmp::min(slice.len(), buffer.len()), length);
+                        slice.as_mut()[..len].copy_from_slice(&buffer[..len]);
+                    });
+                }
+            });
+        });
+        self.buffer.replace(buffer);
+        self.complete(result, length, entry as usize);
+    }
+
+    fn append_done(&self, result: ReturnCode, buffer: &'static mut [u8], entry: u32) {
+        self.buffer.replace(buffer);
+        self.complete(result, entry as usize, 0);
+    }
+
+    fn erase_done(&self, result: ReturnCode) {
+        self.apps.each(|app| app.position = 0);
+        self.complete(result, 0, 0);
+    }
+}
+
+impl Driver for LogDriver<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Entry buffer, read by append and written by read.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request complete. The callback is called with the status of
+    ///   the request and:
+    ///   - for a read, the length of the entry, of which as much as fits was
+    ///     copied into the entry buffer, and the number of the entry. The
+    ///     status is `ENOSUPPORT` if there are no more entries to read.
+    ///   - for an append, the number of the new entry.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Read the entry at the read position of the app, and move the
+    ///   read position to the entry after it.
+    /// - `2`: Append the first `data1` bytes of the entry buffer as a new
+    ///   entry.
+    /// - `3`: Move the read position of the app to entry `data1`. Reading
+    ///   starts at the oldest entry if that entry has been dropped.
+    /// - `4`: Remove all entries, for all apps.
+    /// - `5`: Return the number of the oldest entry.
+    /// - `6`: Return the number the next entry appended will get.
+    /// - `7`: Return the longest entry that can be appended.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        let request = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Request::Read,
+            2 => Request::Append { length: data1 },
+            3 => {
+                return self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.position = data1 as u32;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            4 => Request::Erase,
+            5 => {
+                return ReturnCode::SuccessWithValue {
+                    value: self.log.oldest_entry() as usize,
+                }
+            }
+            6 => {
+                return ReturnCode::SuccessWithValue {
+                    value: self.log.next_entry() as usize,
+                }
+            }
+            7 => {
+                return ReturnCode::SuccessWithValue {
+                    value: self
+                        .buffer
+                        .map_or(0, |buffer| cmp::min(buffer.len(), self.log.max_entry_len())),
+                }
+            }
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        let res = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else if app.callback.is_none() {
+                    ReturnCode::EINVAL
+                } else if request != Request::Erase && app.buffer.is_none() {
+                    ReturnCode::EINVAL
+                } else {
+                    app.waiting = Some(request);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+
+        if res == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        res
+    }
+}
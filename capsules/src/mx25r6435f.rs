@@ -170,6 +170,8 @@ pub struct MX25R6435F<
     rxbuffer: TakeCell<'static, [u8]>,
     client: OptionalCell<&'a hil::flash::Client<MX25R6435F<'a, S, P, A>>>,
     client_sector: TakeCell<'static, Mx25r6435fSector>,
+    // Start and end of the bytes of the sector being written.
+    write_range: Cell<(u32, u32)>,
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
@@ -193,6 +195,7 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
             rxbuffer: TakeCell::new(rxbuffer),
             client: OptionalCell::empty(),
             client_sector: TakeCell::empty(),
+            write_range: Cell::new((0, SECTOR_SIZE)),
         }
     }
 
@@ -279,6 +282,7 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
 
     fn write_sector(&self, sector_index: u32, sector: &'static mut Mx25r6435fSector) -> ReturnCode {
         self.client_sector.replace(sector);
+        self.write_range.set((0, SECTOR_SIZE));
         self.configure_spi();
         self.state.set(State::EraseSectorWriteEnable {
             sector_index,
@@ -286,6 +290,37 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         });
         self.enable_write()
     }
+
+    /// Program bytes `start..end` of a sector without erasing it first, by
+    /// programming the pages that hold them.
+    fn program_sector(
+        &self,
+        sector_index: u32,
+        sector: &'static mut Mx25r6435fSector,
+        start: u32,
+        end: u32,
+    ) -> ReturnCode {
+        if start >= end || end > SECTOR_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        self.write_protect_pin.map(|pin| {
+            pin.set();
+        });
+        self.txbuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, move |txbuffer| {
+                self.client_sector.replace(sector);
+                self.write_range.set((start, end));
+                self.configure_spi();
+                self.state.set(State::WriteSectorWrite {
+                    sector_index,
+                    page_index: start / PAGE_SIZE,
+                });
+                // Need to write enable before each PP
+                txbuffer[0] = Opcodes::WREN as u8;
+                self.spi.read_write_bytes(txbuffer, None, 1)
+            })
+    }
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
@@ -408,9 +443,9 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 sector_index,
                 page_index,
             } => {
-                // Check if we are done. This happens when we have written a
-                // sector's worth of data, one page at a time.
-                if page_index * PAGE_SIZE == SECTOR_SIZE {
+                // Check if we are done. This happens when we have written the
+                // range being written, one page at a time.
+                if page_index * PAGE_SIZE >= self.write_range.get().1 {
                     // No need to disable writes since it happens automatically.
                     self.state.set(State::Idle);
                     self.txbuffer.replace(write_buffer);
@@ -444,9 +479,17 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 write_buffer[2] = (address >> 8) as u8;
                 write_buffer[3] = (address >> 0) as u8;
 
+                // Bytes outside the range are sent as ones, which leaves them
+                // as they are.
+                let (start, end) = self.write_range.get();
                 self.client_sector.map(|sector| {
                     for i in 0..(PAGE_SIZE as usize) {
-                        write_buffer[i + 4] = sector[i + (page_index * PAGE_SIZE) as usize];
+                        let offset = i as u32 + page_index * PAGE_SIZE;
+                        write_buffer[i + 4] = if offset >= start && offset < end {
+                            sector[offset as usize]
+                        } else {
+                            0xff
+                        };
                     }
                 });
 
@@ -538,6 +581,21 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         self.write_sector(page_number as u32, buf)
     }
 
+    fn program_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        self.program_sector(
+            page_number as u32,
+            buf,
+            offset as u32,
+            (offset + length) as u32,
+        )
+    }
+
     fn erase_page(&self, page_number: usize) -> ReturnCode {
         self.erase_sector(page_number as u32)
     }
@@ -0,0 +1,127 @@
+//! Forwards IPv6 packets between the radio and a serial link.
+//!
+//! `BorderRouter` connects a 6LoWPAN mesh to a host, with one interface on
+//! the radio and one on a SLIP serial link (`net::slip`). It receives the
+//! packets of both interfaces. Packets whose destination is in the mesh
+//! prefix are sent on the radio, and all others on the serial link, so the
+//! host is the default route of the mesh. Packets for the router's own
+//! address and multicast packets are passed to an optional local client
+//! instead, for example the ICMPv6 layer so that the router answers pings.
+//!
+//! Packets are forwarded one at a time: a packet that arrives while the
+//! outgoing interface is still busy is dropped, as are packets whose hop
+//! limit runs out and packets the interface fails to send. Packets from the
+//! serial link can only be sent on the radio if they carry UDP or ICMPv6, as
+//! the 6LoWPAN sender needs to decode them. `dropped` counts the packets
+//! that were not forwarded.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let border_router = static_init!(
+//!     capsules::net::border_router::BorderRouter<'static>,
+//!     capsules::net::border_router::BorderRouter::new(
+//!         ip6_send_radio,
+//!         slip,
+//!         mesh_prefix,
+//!         64
+//!     )
+//! );
+//! border_router.set_addr(router_addr);
+//! ip6_send_radio.set_client(border_router);
+//! IP6Sender::set_client(slip, border_router);
+//! ip6_recv_radio.set_client(border_router);
+//! IP6Receiver::set_client(slip, border_router);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{NumericCellExt, OptionalCell};
+use kernel::ReturnCode;
+use net::ipv6::ip_utils::IPAddr;
+use net::ipv6::ipv6::IP6Header;
+use net::ipv6::ipv6_recv::IP6RecvClient;
+use net::ipv6::ipv6_send::{IP6Client, IP6Sender};
+
+pub struct BorderRouter<'a> {
+    radio: &'a IP6Sender<'a>,
+    serial: &'a IP6Sender<'a>,
+    mesh_prefix: IPAddr,
+    prefix_len: u8,
+    addr: Cell<IPAddr>,
+    local_client: OptionalCell<&'a IP6RecvClient>,
+    dropped: Cell<usize>,
+}
+
+impl BorderRouter<'a> {
+    pub fn new(
+        radio: &'a IP6Sender<'a>,
+        serial: &'a IP6Sender<'a>,
+        mesh_prefix: IPAddr,
+        prefix_len: u8,
+    ) -> BorderRouter<'a> {
+        BorderRouter {
+            radio: radio,
+            serial: serial,
+            mesh_prefix: mesh_prefix,
+            prefix_len: prefix_len,
+            addr: Cell::new(IPAddr::new()),
+            local_client: OptionalCell::empty(),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Sets the address of the router itself. Packets sent to it are passed
+    /// to the local client rather than forwarded.
+    pub fn set_addr(&self, addr: IPAddr) {
+        self.addr.set(addr);
+    }
+
+    /// Sets the client that receives the packets for the router itself.
+    pub fn set_local_client(&self, client: &'a IP6RecvClient) {
+        self.local_client.set(client);
+    }
+
+    /// Number of packets that were not forwarded, because their hop limit
+    /// ran out, the outgoing interface was busy, or sending failed.
+    pub fn dropped(&self) -> usize {
+        self.dropped.get()
+    }
+}
+
+impl IP6RecvClient for BorderRouter<'a> {
+    fn receive(&self, mut header: IP6Header, payload: &[u8]) {
+        let dst = header.dst_addr;
+        if dst.is_multicast() || dst.0 == self.addr.get().0 {
+            // Multicast is link scoped here, so it is not forwarded.
+            self.local_client
+                .map(|client| client.receive(header, payload));
+            return;
+        }
+
+        let hop_limit = header.get_hop_limit();
+        if hop_limit <= 1 {
+            self.dropped.increment();
+            return;
+        }
+        header.set_hop_limit(hop_limit - 1);
+
+        let interface = if dst.matches_prefix(&self.mesh_prefix, self.prefix_len) {
+            self.radio
+        } else {
+            self.serial
+        };
+        // There is no queue, so a packet that cannot be sent now is dropped.
+        if interface.send_packet(header, payload) != ReturnCode::SUCCESS {
+            self.dropped.increment();
+        }
+    }
+}
+
+impl IP6Client for BorderRouter<'a> {
+    fn send_done(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.dropped.increment();
+        }
+    }
+}
@@ -50,8 +50,8 @@
 // This layer is still in the early stages of implementation, and both the
 // interfaces and underlying code will change substantially. There are two main
 // areas of focus for additional work: 1) ensuring that the IP6Packet/IP6Header/
-// IPPayload design makes sense and is properly layered, and 2) figuring out
-// and implementing a receive path that uses this encapsulation.
+// IPPayload design makes sense and is properly layered, and 2) extending the
+// receive path, which can only decode UDP and ICMPv6 payloads.
 //
 // One of the primary problems with the current encapsulation design is that
 // it is impossible to encode recursive headers - any subsequent headers (IPv6
@@ -333,6 +333,48 @@ impl IPPayload<'a> {
         stream_done!(offset, offset)
     }
 
+    /// This function decodes a serialized transport header and payload into
+    /// the `IPPayload`. Only UDP and ICMPv6 payloads can be decoded.
+    ///
+    /// # Arguments
+    ///
+    /// `next_header` - The `ip6_nh` type of the serialized payload
+    /// `buf` - The serialized transport header and payload
+    ///
+    /// # Return Value
+    ///
+    /// `Result<usize, ()>` - The number of bytes decoded, or an error if the
+    /// payload is malformed, of another type, or does not fit the buffer
+    pub fn decode(&mut self, next_header: u8, buf: &[u8]) -> Result<usize, ()> {
+        let header = match next_header {
+            ip6_nh::UDP => {
+                let (_, udp_header) = UDPHeader::decode(buf).done().ok_or(())?;
+                if udp_header.get_len() as usize != buf.len() {
+                    return Err(());
+                }
+                TransportHeader::UDP(udp_header)
+            }
+            ip6_nh::ICMP => {
+                let (_, mut icmp_header) = ICMP6Header::decode(buf).done().ok_or(())?;
+                icmp_header.set_len(buf.len() as u16);
+                TransportHeader::ICMP(icmp_header)
+            }
+            _ => return Err(()),
+        };
+
+        let hdr_size = match header {
+            TransportHeader::UDP(udp_header) => udp_header.get_hdr_size(),
+            TransportHeader::ICMP(icmp_header) => icmp_header.get_hdr_size(),
+            _ => return Err(()),
+        };
+        if buf.len() < hdr_size || buf.len() - hdr_size > self.payload.len() {
+            return Err(());
+        }
+        self.payload[..buf.len() - hdr_size].copy_from_slice(&buf[hdr_size..]);
+        self.header = header;
+        Ok(buf.len())
+    }
+
     fn get_payload_length(&self) -> usize {
         match self.header {
             TransportHeader::UDP(udp_header) => {
@@ -442,14 +484,29 @@ impl IP6Packet<'a> {
         self.header.set_payload_len(payload_len);
     }
 
-    // TODO: Currently, the receive path is unimplemented, and this function
-    // should *not* be called
+    /// This function decodes a serialized IPv6 packet into `ip6_packet`.
+    /// Only packets without extension headers and with a UDP or ICMPv6
+    /// payload can be decoded.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - The serialized IPv6 packet
+    /// `ip6_packet` - The `IP6Packet` to decode the packet into
+    ///
+    /// # Return Value
+    ///
+    /// `Result<usize, ()>` - The length of the decoded packet, or an error
     pub fn decode(buf: &[u8], ip6_packet: &mut IP6Packet) -> Result<usize, ()> {
-        let (_offset, header) = IP6Header::decode(buf).done().ok_or(())?;
+        let (offset, header) = IP6Header::decode(buf).done().ok_or(())?;
+        let total_len = offset + header.get_payload_len() as usize;
+        if buf.len() < total_len {
+            return Err(());
+        }
+        ip6_packet
+            .payload
+            .decode(header.get_next_header(), &buf[offset..total_len])?;
         ip6_packet.header = header;
-        // TODO: Not sure how to convert an IP6Packet with a UDP payload to
-        // an IP6Packet with a TCP payload.
-        unimplemented!();
+        Ok(total_len)
     }
 
     pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
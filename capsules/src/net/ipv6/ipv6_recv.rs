@@ -0,0 +1,83 @@
+//! This file contains the interface definition for receiving IPv6 packets.
+//! The [IP6Receiver](trait.IP6Receiver.html) trait is implemented by network
+//! interfaces that receive IPv6 packets, while the
+//! [IP6RecvClient](trait.IP6RecvClient.html) trait must be implemented by
+//! upper layers to receive the packets.
+//!
+//! This file also includes an implementation of the `IP6Receiver` trait,
+//! which receives the IPv6 packets that 6LoWPAN has decompressed and
+//! reassembled.
+
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::ReturnCode;
+use net::ipv6::ipv6::IP6Header;
+use net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
+
+/// This trait must be implemented by upper layers in order to receive IPv6
+/// packets. The upper layer must then call `IP6Receiver.set_client` in order
+/// to receive them.
+pub trait IP6RecvClient {
+    /// Called for every IPv6 packet received.
+    ///
+    /// # Arguments
+    /// `header` - The decoded `IP6Header` of the packet
+    /// `payload` - The serialized payload of the packet, including the
+    /// transport header
+    fn receive(&self, header: IP6Header, payload: &[u8]);
+}
+
+/// This trait is implemented by network interfaces that receive IPv6
+/// packets.
+pub trait IP6Receiver<'a> {
+    /// This method sets the `IP6RecvClient` that receives the packets.
+    ///
+    /// # Arguments
+    /// `client` - Client that implements the `IP6RecvClient` trait to receive
+    /// the packets
+    fn set_client(&self, client: &'a IP6RecvClient);
+}
+
+/// Splits a serialized IPv6 packet into its decoded header and its payload.
+/// Returns `None` if the packet is malformed or truncated.
+pub fn decode_packet(buf: &[u8]) -> Option<(IP6Header, &[u8])> {
+    let (offset, header) = IP6Header::decode(buf).done()?;
+    let end = offset + header.get_payload_len() as usize;
+    if header.get_version() != 6 || end > buf.len() {
+        return None;
+    }
+    Some((header, &buf[offset..end]))
+}
+
+/// This struct is a specific implementation of the `IP6Receiver` trait. It
+/// must be set as the `SixlowpanRxClient` of a `Sixlowpan` instance, and
+/// passes the packets it reassembles on to its client.
+pub struct IP6RecvStruct<'a> {
+    client: OptionalCell<&'a IP6RecvClient>,
+}
+
+impl IP6Receiver<'a> for IP6RecvStruct<'a> {
+    fn set_client(&self, client: &'a IP6RecvClient) {
+        self.client.set(client);
+    }
+}
+
+impl IP6RecvStruct<'a> {
+    pub fn new() -> IP6RecvStruct<'a> {
+        IP6RecvStruct {
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl SixlowpanRxClient for IP6RecvStruct<'a> {
+    fn receive(&self, buf: &[u8], len: u16, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            return;
+        }
+        let buf = &buf[..cmp::min(len as usize, buf.len())];
+        decode_packet(buf).map(|(header, payload)| {
+            self.client.map(|client| client.receive(header, payload));
+        });
+    }
+}
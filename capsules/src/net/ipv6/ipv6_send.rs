@@ -82,6 +82,17 @@ pub trait IP6Sender<'a> {
     /// `payload` - The transport payload for the packet being sent
     fn send_to(&self, dst: IPAddr, transport_header: TransportHeader, payload: &[u8])
         -> ReturnCode;
+
+    /// This method sends a packet with the given header as is, for example
+    /// to forward a packet received on another interface. Returns EBUSY if a
+    /// packet is being sent, and ENOSUPPORT if the payload cannot be sent by
+    /// this instance.
+    ///
+    /// # Arguments
+    /// `header` - The `IP6Header` of the packet
+    /// `payload` - The serialized payload of the packet, including the
+    /// transport header
+    fn send_packet(&self, header: IP6Header, payload: &[u8]) -> ReturnCode;
 }
 
 /// This struct is a specific implementation of the `IP6Sender` trait. This
@@ -125,6 +136,25 @@ impl IP6Sender<'a> for IP6SendStruct<'a> {
         self.init_packet(dst, transport_header, payload);
         self.send_next_fragment()
     }
+
+    fn send_packet(&self, header: IP6Header, payload: &[u8]) -> ReturnCode {
+        if self.tx_buf.is_none() {
+            return ReturnCode::EBUSY;
+        }
+        // 6LoWPAN compresses the transport header, so the payload has to be
+        // decoded into the packet.
+        let decoded = self.ip6_packet.map_or(Err(()), |ip6_packet| {
+            ip6_packet
+                .payload
+                .decode(header.get_next_header(), payload)
+                .map(|_| ip6_packet.header = header)
+        });
+        if decoded.is_err() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.sixlowpan.init(SRC_MAC_ADDR, DST_MAC_ADDR, None);
+        self.send_next_fragment()
+    }
 }
 
 impl IP6SendStruct<'a> {
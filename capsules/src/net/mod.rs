@@ -11,3 +11,6 @@ pub mod ipv6;
 pub mod tcp;
 pub mod thread;
 pub mod udp;
+
+pub mod border_router;
+pub mod slip;
@@ -0,0 +1,371 @@
+//! SLIP (RFC 1055) link for IPv6 over a UART.
+//!
+//! `Slip` frames IPv6 packets with SLIP on a `hil::uart::UART`, so that a node
+//! can exchange IPv6 packets with a host over a serial line. It implements
+//! `IP6Sender` and `IP6Receiver`, so it is a second network interface next to
+//! 6LoWPAN, and `net::border_router` can forward packets between it and the
+//! radio. The link only forwards: it sends packets that are already built
+//! with `send_packet`, and `send_to` returns `ENOSUPPORT`, so the UDP and
+//! ICMPv6 layers cannot send through it.
+//!
+//! Every packet is sent between two `END` bytes, and `END` and `ESC` bytes in
+//! the packet are escaped. A received frame is passed to the client when it
+//! ends, if it holds a complete IPv6 packet; other frames are dropped.
+//!
+//! The UART can be shared with the console through `virtual_uart`. A
+//! `SlipRouter` installed on the `UartMux` sends the bytes of SLIP frames to
+//! the SLIP device only, so text and frames can be mixed on the line. This
+//! requires that the peer starts every frame with `END`, as RFC 1055
+//! recommends, and that the text does not contain the byte 0xC0.
+//!
+//! On a Linux host, the framing is that of the kernel `slip` driver attached
+//! with `slattach -p slip`. That driver only passes IPv4 up the stack though,
+//! so `slattach` cannot be used to test this link. To carry IPv6 the serial
+//! line is bridged to a tun interface instead, for example with Contiki's
+//! `tunslip6`:
+//!
+//! ```text
+//! sudo tunslip6 -s /dev/ttyUSB0 -B 115200 fd00::1/64
+//! ping6 fd00::2
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let slip_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
+//! slip_uart.setup();
+//! slip_uart.set_channel(capsules::net::slip::SLIP_CHANNEL);
+//! let slip_router = static_init!(
+//!     capsules::net::slip::SlipRouter,
+//!     capsules::net::slip::SlipRouter::new(capsules::net::slip::SLIP_CHANNEL)
+//! );
+//! uart_mux.set_router(slip_router);
+//!
+//! let slip = static_init!(
+//!     capsules::net::slip::Slip<'static>,
+//!     capsules::net::slip::Slip::new(
+//!         slip_uart,
+//!         &mut capsules::net::slip::TX_PACKET,
+//!         &mut capsules::net::slip::TX_BUF,
+//!         &mut capsules::net::slip::RX_BUF,
+//!         &mut capsules::net::slip::RX_PACKET
+//!     )
+//! );
+//! hil::uart::UART::set_client(slip_uart, slip);
+//! IP6Receiver::set_client(slip, border_router);
+//! slip.start();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+use net::ieee802154::MacAddress;
+use net::ipv6::ip_utils::IPAddr;
+use net::ipv6::ipv6::{IP6Header, TransportHeader};
+use net::ipv6::ipv6_recv::{decode_packet, IP6Receiver, IP6RecvClient};
+use net::ipv6::ipv6_send::{IP6Client, IP6Sender};
+use virtual_uart::RxRouter;
+
+/// Frame delimiter.
+const END: u8 = 0xC0;
+/// Escapes the next byte.
+const ESC: u8 = 0xDB;
+/// Escaped `END`.
+const ESC_END: u8 = 0xDC;
+/// Escaped `ESC`.
+const ESC_ESC: u8 = 0xDD;
+
+/// Largest packet sent or received, the minimum MTU of IPv6.
+pub const MTU: usize = 1280;
+
+/// Channel of the SLIP device on a `UartMux` with a `SlipRouter`.
+pub const SLIP_CHANNEL: u8 = 1;
+
+pub static mut TX_PACKET: [u8; MTU] = [0; MTU];
+pub static mut TX_BUF: [u8; 64] = [0; 64];
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+pub static mut RX_PACKET: [u8; MTU] = [0; MTU];
+
+pub struct Slip<'a> {
+    uart: &'a uart::UART,
+    // The packet being sent, which is escaped a part at a time into
+    // `tx_buf`.
+    tx_packet: TakeCell<'static, [u8]>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    // Position in the frame of the next byte to escape: the leading `END`,
+    // then the packet, then the trailing `END`.
+    tx_pos: Cell<usize>,
+    tx_busy: Cell<bool>,
+    rx_buf: TakeCell<'static, [u8]>,
+    rx_packet: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_escaped: Cell<bool>,
+    // Whether the frame being received is dropped, because it is too long
+    // or malformed.
+    rx_dropping: Cell<bool>,
+    client: OptionalCell<&'a IP6Client>,
+    recv_client: OptionalCell<&'a IP6RecvClient>,
+}
+
+impl Slip<'a> {
+    pub fn new(
+        uart: &'a uart::UART,
+        tx_packet: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        rx_packet: &'static mut [u8],
+    ) -> Slip<'a> {
+        Slip {
+            uart: uart,
+            tx_packet: TakeCell::new(tx_packet),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_len: Cell::new(0),
+            tx_pos: Cell::new(0),
+            tx_busy: Cell::new(false),
+            rx_buf: TakeCell::new(rx_buf),
+            rx_packet: TakeCell::new(rx_packet),
+            rx_len: Cell::new(0),
+            rx_escaped: Cell::new(false),
+            rx_dropping: Cell::new(false),
+            client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Start receiving packets.
+    pub fn start(&self) {
+        self.rx_buf
+            .take()
+            .map(|buffer| self.uart.receive(buffer, 1));
+    }
+
+    /// Send the first `len` bytes of `tx_packet`.
+    fn start_send(&self, len: usize) -> ReturnCode {
+        self.tx_len.set(len);
+        self.tx_pos.set(0);
+        self.tx_busy.set(true);
+        self.send_next_chunk();
+        ReturnCode::SUCCESS
+    }
+
+    /// Escapes as much of the rest of the frame as fits into `tx_buf` and
+    /// transmits it. Returns false if the whole frame has been sent.
+    fn send_next_chunk(&self) -> bool {
+        let len = self.tx_len.get();
+        let mut pos = self.tx_pos.get();
+        if pos == len + 2 {
+            return false;
+        }
+
+        self.tx_buf.take().map_or(false, |tx_buf| {
+            let mut n = 0;
+            self.tx_packet.map(|packet| {
+                // Every byte takes at most two bytes escaped.
+                while pos < len + 2 && n + 2 <= tx_buf.len() {
+                    if pos == 0 || pos == len + 1 {
+                        tx_buf[n] = END;
+                        n += 1;
+                    } else {
+                        match packet[pos - 1] {
+                            END => {
+                                tx_buf[n] = ESC;
+                                tx_buf[n + 1] = ESC_END;
+                                n += 2;
+                            }
+                            ESC => {
+                                tx_buf[n] = ESC;
+                                tx_buf[n + 1] = ESC_ESC;
+                                n += 2;
+                            }
+                            byte => {
+                                tx_buf[n] = byte;
+                                n += 1;
+                            }
+                        }
+                    }
+                    pos += 1;
+                }
+            });
+            self.tx_pos.set(pos);
+            self.uart.transmit(tx_buf, n);
+            true
+        })
+    }
+
+    fn send_completed(&self, result: ReturnCode) {
+        self.tx_busy.set(false);
+        self.client.map(move |client| client.send_done(result));
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        if byte == END {
+            let len = self.rx_len.get();
+            if len > 0 && !self.rx_dropping.get() {
+                self.rx_packet.map(|packet| {
+                    decode_packet(&packet[..len]).map(|(header, payload)| {
+                        self.recv_client
+                            .map(|client| client.receive(header, payload));
+                    });
+                });
+            }
+            self.rx_len.set(0);
+            self.rx_escaped.set(false);
+            self.rx_dropping.set(false);
+            return;
+        }
+        if self.rx_dropping.get() {
+            return;
+        }
+        if byte == ESC {
+            self.rx_escaped.set(true);
+            return;
+        }
+
+        let byte = if self.rx_escaped.get() {
+            self.rx_escaped.set(false);
+            match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                _ => {
+                    self.rx_dropping.set(true);
+                    return;
+                }
+            }
+        } else {
+            byte
+        };
+
+        let len = self.rx_len.get();
+        let stored = self.rx_packet.map_or(false, |packet| {
+            if len < packet.len() {
+                packet[len] = byte;
+                true
+            } else {
+                false
+            }
+        });
+        if stored {
+            self.rx_len.set(len + 1);
+        } else {
+            self.rx_dropping.set(true);
+        }
+    }
+}
+
+impl IP6Sender<'a> for Slip<'a> {
+    fn set_client(&self, client: &'a IP6Client) {
+        self.client.set(client);
+    }
+
+    fn set_addr(&self, _src_addr: IPAddr) {
+        // Forwarded packets keep their source address.
+    }
+
+    fn set_gateway(&self, _gateway: MacAddress) {
+        // A serial link has a single peer, which has no MAC address.
+    }
+
+    fn set_header(&mut self, _ip6_header: IP6Header) {
+        // Forwarded packets keep their header.
+    }
+
+    fn send_to(
+        &self,
+        _dst: IPAddr,
+        _transport_header: TransportHeader,
+        _payload: &[u8],
+    ) -> ReturnCode {
+        // The link only forwards packets built on another interface.
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn send_packet(&self, header: IP6Header, payload: &[u8]) -> ReturnCode {
+        if self.tx_busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let encoded = self.tx_packet.map_or(None, |tx_packet| {
+            let (offset, _) = header.encode(tx_packet).done()?;
+            if offset + payload.len() > tx_packet.len() {
+                return None;
+            }
+            tx_packet[offset..offset + payload.len()].copy_from_slice(payload);
+            Some(offset + payload.len())
+        });
+        match encoded {
+            Some(len) => self.start_send(len),
+            None => ReturnCode::ESIZE,
+        }
+    }
+}
+
+impl IP6Receiver<'a> for Slip<'a> {
+    fn set_client(&self, client: &'a IP6RecvClient) {
+        self.recv_client.set(client);
+    }
+}
+
+impl uart::Client for Slip<'a> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], error: uart::Error) {
+        self.tx_buf.replace(buffer);
+        if error != uart::Error::CommandComplete {
+            self.send_completed(ReturnCode::FAIL);
+        } else if !self.send_next_chunk() {
+            self.send_completed(ReturnCode::SUCCESS);
+        }
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        match error {
+            uart::Error::CommandComplete => {
+                if rx_len > 0 {
+                    self.receive_byte(buffer[0]);
+                }
+            }
+            uart::Error::Aborted => {}
+            _ => {
+                // The frame being received has lost bytes.
+                self.rx_dropping.set(true);
+            }
+        }
+        self.uart.receive(buffer, 1);
+    }
+}
+
+/// Routes the bytes of SLIP frames to one channel of a `UartMux`, and all
+/// other bytes to channel 0.
+pub struct SlipRouter {
+    channel: u8,
+    in_frame: Cell<bool>,
+    frame_empty: Cell<bool>,
+}
+
+impl SlipRouter {
+    pub const fn new(channel: u8) -> SlipRouter {
+        SlipRouter {
+            channel: channel,
+            in_frame: Cell::new(false),
+            frame_empty: Cell::new(true),
+        }
+    }
+}
+
+impl RxRouter for SlipRouter {
+    fn route(&self, byte: u8) -> u8 {
+        if byte == END {
+            // An `END` after frame data ends the frame. Any other `END`
+            // starts one, or is an empty frame that keeps the frame open.
+            let ends_frame = self.in_frame.get() && !self.frame_empty.get();
+            self.in_frame.set(!ends_frame);
+            self.frame_empty.set(true);
+            self.channel
+        } else if self.in_frame.get() {
+            self.frame_empty.set(false);
+            self.channel
+        } else {
+            0
+        }
+    }
+}
@@ -91,10 +91,12 @@ impl UDPHeader {
         stream_len_cond!(buf, self.get_hdr_size() + offset);
 
         let mut off = offset;
-        off = enc_consume!(buf, off; encode_u16, self.src_port);
-        off = enc_consume!(buf, off; encode_u16, self.dst_port);
-        off = enc_consume!(buf, off; encode_u16, self.len);
-        off = enc_consume!(buf, off; encode_u16, self.cksum);
+        // The fields are stored in network byte order, and `encode_u16`
+        // converts to it again, so encode the host byte order values.
+        off = enc_consume!(buf, off; encode_u16, self.get_src_port());
+        off = enc_consume!(buf, off; encode_u16, self.get_dst_port());
+        off = enc_consume!(buf, off; encode_u16, self.get_len());
+        off = enc_consume!(buf, off; encode_u16, self.get_cksum());
         stream_done!(off, off);
     }
 
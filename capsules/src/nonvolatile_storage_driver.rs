@@ -1,9 +1,18 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! Each application gets its own region of the memory space that has been
+//! provided to userland, and sees it as starting at address 0. Reads and
+//! writes are translated into the region and checked against its bounds, so
+//! one application cannot read or overwrite another application's data.
+//!
+//! Regions are allocated the first time an application uses this driver. An
+//! application can ask for a region size with the Nonvolatile Storage TLV in
+//! its TBF header; otherwise it gets the board's default size. Allocations
+//! are recorded in a table at the start of the userspace region, keyed by the
+//! application's package name, so an application keeps its data across
+//! upgrades. Applications without a package name cannot use the driver.
+//! Regions are never freed, and a region keeps the size it was allocated
+//! with.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
@@ -45,11 +54,14 @@
 //!         3000,                        // The byte start address for the userspace
 //!                                      // accessible memory region.
 //!         2000,                        // The length of the userspace region.
+//!         256,                         // The default length of each app's region.
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
-//!         &mut capsules::nonvolatile_storage_driver::BUFFER));
+//!         &mut capsules::nonvolatile_storage_driver::BUFFER,
+//!         &mut capsules::nonvolatile_storage_driver::REGION_TABLE));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
+//! nonvolatile_storage.initialize();
 //! ```
 
 use core::cell::Cell;
@@ -57,12 +69,29 @@ use core::cmp;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use util::{fnv1a, read_u32, write_u32};
 
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x50001;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// Buffer for the region table, which holds up to 16 regions.
+pub static mut REGION_TABLE: [u8; 16 * TABLE_ENTRY_SIZE] = [0; 16 * TABLE_ENTRY_SIZE];
+
+/// Size of one entry of the region table in storage.
+///
+/// Each entry is laid out as:
+///
+/// - `0..4`: Offset of the region from the end of the table.
+/// - `4..8`: Length of the region. `0` or `0xFFFFFFFF` marks an unused entry.
+/// - `8..12`: FNV-1a hash of the package name.
+/// - `12`: Length of the package name, saturated at 255.
+/// - `13..32`: The start of the package name, padded with zeros.
+pub const TABLE_ENTRY_SIZE: usize = 32;
+
+const TABLE_NAME_OFFSET: usize = 13;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -75,6 +104,26 @@ pub enum NonvolatileCommand {
 pub enum NonvolatileUser {
     App { app_id: AppId },
     Kernel,
+    RegionTable,
+}
+
+/// Whether the region table has been read from storage.
+#[derive(Clone, Copy, PartialEq)]
+enum TableState {
+    Unloaded,
+    Loading,
+    Loaded,
+    /// Reading or writing the table failed. The storage driver keeps the
+    /// buffer of a call that fails, so the table is gone until reboot.
+    Failed,
+}
+
+/// A region of the userspace storage owned by one app.
+#[derive(Clone, Copy)]
+struct Region {
+    // Physical address of the first byte.
+    start: usize,
+    length: usize,
 }
 
 pub struct App {
@@ -86,6 +135,7 @@ pub struct App {
     length: usize,
     buffer_read: Option<AppSlice<Shared, u8>>,
     buffer_write: Option<AppSlice<Shared, u8>>,
+    region: Option<Region>,
 }
 
 impl Default for App {
@@ -99,6 +149,7 @@ impl Default for App {
             length: 0,
             buffer_read: None,
             buffer_write: None,
+            region: None,
         }
     }
 }
@@ -123,6 +174,14 @@ pub struct NonvolatileStorage<'a> {
     // How many bytes allocated to kernel.
     kernel_length: usize,
 
+    // Copy of the region table stored at the start of the userspace region.
+    region_table: TakeCell<'static, [u8]>,
+    table_state: Cell<TableState>,
+    // Whether the copy has allocations that are not in storage yet.
+    table_dirty: Cell<bool>,
+    // Size of the regions of apps that do not ask for a size.
+    default_region_length: usize,
+
     // Optional client for the kernel. Only needed if the kernel intends to use
     // this nonvolatile storage.
     kernel_client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
@@ -144,9 +203,11 @@ impl NonvolatileStorage<'a> {
         grant: Grant<App>,
         userspace_start_address: usize,
         userspace_length: usize,
+        default_region_length: usize,
         kernel_start_address: usize,
         kernel_length: usize,
         buffer: &'static mut [u8],
+        region_table: &'static mut [u8],
     ) -> NonvolatileStorage<'a> {
         NonvolatileStorage {
             driver: driver,
@@ -157,6 +218,10 @@ impl NonvolatileStorage<'a> {
             userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
+            region_table: TakeCell::new(region_table),
+            table_state: Cell::new(TableState::Unloaded),
+            table_dirty: Cell::new(false),
+            default_region_length: default_region_length,
             kernel_client: OptionalCell::empty(),
             kernel_pending_command: Cell::new(false),
             kernel_command: Cell::new(NonvolatileCommand::KernelRead),
@@ -166,6 +231,18 @@ impl NonvolatileStorage<'a> {
         }
     }
 
+    /// Start reading the region table from storage. Apps that use the driver
+    /// before the table has been read have to wait for it, so boards should
+    /// call this once the driver's client has been set.
+    pub fn initialize(&self) {
+        if self.table_state.get() == TableState::Unloaded {
+            self.table_state.set(TableState::Loading);
+            if self.current_user.is_none() {
+                self.check_queue();
+            }
+        }
+    }
+
     // Check so see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending
     // command completes.
@@ -176,36 +253,11 @@ impl NonvolatileStorage<'a> {
         length: usize,
         app_id: Option<AppId>,
     ) -> ReturnCode {
-        // Do bounds check.
-        match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
-                {
-                    return ReturnCode::EINVAL;
-                }
-            }
-            NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
-                // Because the kernel uses the NonvolatileStorage interface,
-                // its calls are absolute addresses.
-                if offset < self.kernel_start_address
-                    || offset >= self.kernel_start_address + self.kernel_length
-                    || length > self.kernel_length
-                    || offset + length > self.kernel_start_address + self.kernel_length
-                {
-                    return ReturnCode::EINVAL;
-                }
-            }
-        }
-
         // Do very different actions if this is a call from userspace
         // or from the kernel.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                app_id.map_or(ReturnCode::FAIL, |appid| {
+                let res = app_id.map_or(ReturnCode::FAIL, |appid| {
                     self.apps
                         .enter(appid, |app, _| {
                             // Get the length of the correct allowed buffer.
@@ -228,54 +280,80 @@ impl NonvolatileStorage<'a> {
                             // put it.
                             let active_len = cmp::min(length, allow_buf_len);
 
-                            // First need to determine if we can execute this or must
-                            // queue it.
-                            if self.current_user.is_none() {
-                                // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user
-                                    .set(NonvolatileUser::App { app_id: appid });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    app.buffer_write.as_mut().map(|app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
-                                            // Check that the internal buffer and the buffer that was
-                                            // allowed are long enough.
-                                            let write_len =
-                                                cmp::min(active_len, kernel_buffer.len());
-
-                                            let d = &mut app_buffer.as_mut()[0..write_len];
-                                            for (i, c) in
-                                                kernel_buffer[0..write_len].iter_mut().enumerate()
-                                            {
-                                                *c = d[i];
-                                            }
-                                        });
-                                    });
+                            // Userspace sees its region as starting at address
+                            // 0. If the region is not known yet, the bounds
+                            // are checked when the command is started.
+                            let region = match self.app_region(app, appid) {
+                                Ok(region) => {
+                                    if offset >= region.length
+                                        || active_len > region.length
+                                        || offset + active_len > region.length
+                                    {
+                                        return ReturnCode::EINVAL;
+                                    }
+                                    Some(region)
                                 }
+                                Err(ReturnCode::EBUSY) => None,
+                                Err(err) => return err,
+                            };
 
-                                self.userspace_call_driver(command, offset, active_len)
-                            } else {
-                                // Some app is using the storage, we must wait.
-                                if app.pending_command == true {
-                                    // No more room in the queue, nowhere to store this
-                                    // request.
-                                    ReturnCode::ENOMEM
-                                } else {
-                                    // We can store this, so lets do it.
-                                    app.pending_command = true;
-                                    app.command = command;
-                                    app.offset = offset;
-                                    app.length = active_len;
-                                    ReturnCode::SUCCESS
+                            // First need to determine if we can execute this or must
+                            // queue it. Allocations are written to storage
+                            // before the new region is used.
+                            match region {
+                                Some(region)
+                                    if self.current_user.is_none() && !self.table_dirty.get() =>
+                                {
+                                    // No app is currently using the underlying storage.
+                                    // Mark this app as active, and then execute the command.
+                                    self.current_user
+                                        .set(NonvolatileUser::App { app_id: appid });
+                                    let res = self.userspace_call_driver(
+                                        app, command, region, offset, active_len,
+                                    );
+                                    if res != ReturnCode::SUCCESS {
+                                        self.current_user.clear();
+                                    }
+                                    res
+                                }
+                                _ => {
+                                    // Some app is using the storage, we must wait.
+                                    if app.pending_command == true {
+                                        // No more room in the queue, nowhere to store this
+                                        // request.
+                                        ReturnCode::ENOMEM
+                                    } else {
+                                        // We can store this, so lets do it.
+                                        app.pending_command = true;
+                                        app.command = command;
+                                        app.offset = offset;
+                                        app.length = active_len;
+                                        ReturnCode::SUCCESS
+                                    }
                                 }
                             }
                         })
                         .unwrap_or_else(|err| err.into())
-                })
+                });
+
+                // The region table may need to be read or written before the
+                // command can run.
+                if res == ReturnCode::SUCCESS && self.current_user.is_none() {
+                    self.check_queue();
+                }
+                res
             }
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
+                // Because the kernel uses the NonvolatileStorage interface,
+                // its calls are absolute addresses.
+                if offset < self.kernel_start_address
+                    || offset >= self.kernel_start_address + self.kernel_length
+                    || length > self.kernel_length
+                    || offset + length > self.kernel_start_address + self.kernel_length
+                {
+                    return ReturnCode::EINVAL;
+                }
+
                 self.kernel_buffer
                     .take()
                     .map_or(ReturnCode::ENOMEM, |kernel_buffer| {
@@ -312,27 +390,134 @@ impl NonvolatileStorage<'a> {
         }
     }
 
+    /// Find the region of an app, allocating one if the app does not have one
+    /// yet. Returns `EBUSY` if the region table is not available yet.
+    fn app_region(&self, app: &mut App, appid: AppId) -> Result<Region, ReturnCode> {
+        if let Some(region) = app.region {
+            return Ok(region);
+        }
+
+        match self.table_state.get() {
+            TableState::Loaded => {}
+            TableState::Unloaded => {
+                self.table_state.set(TableState::Loading);
+                return Err(ReturnCode::EBUSY);
+            }
+            TableState::Loading => return Err(ReturnCode::EBUSY),
+            TableState::Failed => return Err(ReturnCode::FAIL),
+        }
+
+        let name = appid.get_package_name();
+        if name.len() == 0 {
+            return Err(ReturnCode::ENOSUPPORT);
+        }
+        let length = appid
+            .get_nonvolatile_storage_size()
+            .unwrap_or(self.default_region_length);
+
+        let region = self.region_table.map_or(Err(ReturnCode::EBUSY), |table| {
+            self.find_region(table, name)
+                .map_or_else(|| self.allocate_region(table, name, length), Ok)
+        })?;
+        app.region = Some(region);
+        Ok(region)
+    }
+
+    fn find_region(&self, table: &[u8], name: &str) -> Option<Region> {
+        let data_start = self.userspace_start_address + table.len();
+        table
+            .chunks(TABLE_ENTRY_SIZE)
+            .filter(|entry| self.entry_in_use(table.len(), entry))
+            .find(|entry| entry_matches(entry, name))
+            .map(|entry| Region {
+                start: data_start + read_u32(&entry[0..4]) as usize,
+                length: read_u32(&entry[4..8]) as usize,
+            })
+    }
+
+    /// Allocate a region after all existing ones and record it in the first
+    /// unused table entry. The table is written to storage later.
+    fn allocate_region(
+        &self,
+        table: &mut [u8],
+        name: &str,
+        length: usize,
+    ) -> Result<Region, ReturnCode> {
+        if length == 0 {
+            return Err(ReturnCode::EINVAL);
+        }
+        let table_len = table.len();
+        let data_length = self.userspace_length.saturating_sub(table_len);
+
+        let offset = table
+            .chunks(TABLE_ENTRY_SIZE)
+            .filter(|entry| self.entry_in_use(table_len, entry))
+            .map(|entry| read_u32(&entry[0..4]) as usize + read_u32(&entry[4..8]) as usize)
+            .max()
+            .unwrap_or(0);
+        if length > data_length || offset > data_length - length {
+            return Err(ReturnCode::ENOMEM);
+        }
+
+        let entry = table
+            .chunks_mut(TABLE_ENTRY_SIZE)
+            .find(|entry| !self.entry_in_use(table_len, entry))
+            .ok_or(ReturnCode::ENOMEM)?;
+        write_u32(&mut entry[0..4], offset as u32);
+        write_u32(&mut entry[4..8], length as u32);
+        write_u32(&mut entry[8..12], fnv1a(name.as_bytes()));
+        entry[12] = cmp::min(name.len(), 255) as u8;
+        let name_len = cmp::min(name.len(), TABLE_ENTRY_SIZE - TABLE_NAME_OFFSET);
+        for (i, byte) in entry[TABLE_NAME_OFFSET..].iter_mut().enumerate() {
+            *byte = if i < name_len { name.as_bytes()[i] } else { 0 };
+        }
+        self.table_dirty.set(true);
+
+        Ok(Region {
+            start: self.userspace_start_address + table_len + offset,
+            length: length,
+        })
+    }
+
+    /// Whether a table entry describes a region. Entries of erased or
+    /// corrupted storage are treated as unused.
+    fn entry_in_use(&self, table_len: usize, entry: &[u8]) -> bool {
+        if entry.len() < TABLE_ENTRY_SIZE {
+            return false;
+        }
+        let offset = read_u32(&entry[0..4]) as usize;
+        let length = read_u32(&entry[4..8]) as usize;
+        let data_length = self.userspace_length.saturating_sub(table_len);
+        length != 0 && length <= data_length && offset <= data_length - length
+    }
+
     fn userspace_call_driver(
         &self,
+        app: &mut App,
         command: NonvolatileCommand,
+        region: Region,
         offset: usize,
         length: usize,
     ) -> ReturnCode {
         // Calculate where we want to actually read from in the physical
         // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let physical_address = region.start + offset;
 
         self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
             // Check that the internal buffer and the buffer that was
             // allowed are long enough.
             let active_len = cmp::min(length, buffer.len());
 
-            // self.current_app.set(Some(appid));
             match command {
                 NonvolatileCommand::UserspaceRead => {
                     self.driver.read(buffer, physical_address, active_len)
                 }
                 NonvolatileCommand::UserspaceWrite => {
+                    // Need to copy bytes if this is a write!
+                    app.buffer_write.as_ref().map(|app_buffer| {
+                        let write_len = cmp::min(active_len, app_buffer.len());
+                        buffer[0..write_len].copy_from_slice(&app_buffer.as_ref()[0..write_len]);
+                    });
                     self.driver.write(buffer, physical_address, active_len)
                 }
                 _ => ReturnCode::FAIL,
@@ -361,27 +546,118 @@ impl NonvolatileStorage<'a> {
                     _ => ReturnCode::FAIL,
                 }
             });
-        } else {
-            // If the kernel is not requesting anything, check all of the apps.
-            for cntr in self.apps.iter() {
-                let started_command = cntr.enter(|app, _| {
-                    if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user.set(NonvolatileUser::App {
-                            app_id: app.appid(),
-                        });
-                        self.userspace_call_driver(app.command, app.offset, app.length)
-                            == ReturnCode::SUCCESS
-                    } else {
-                        false
+            return;
+        }
+
+        // Then make sure the region table is loaded and up to date in storage.
+        if self.table_needs_io() {
+            let res = self.region_table.take().map_or(ReturnCode::FAIL, |table| {
+                self.current_user.set(NonvolatileUser::RegionTable);
+                let length = table.len();
+                if self.table_state.get() == TableState::Loading {
+                    self.driver
+                        .read(table, self.userspace_start_address, length)
+                } else {
+                    self.driver
+                        .write(table, self.userspace_start_address, length)
+                }
+            });
+            if res == ReturnCode::SUCCESS {
+                return;
+            }
+
+            // The table is lost with the buffer. Fail the app commands that
+            // wait for it below; `table_dirty` stays set, as the allocations
+            // never reached storage.
+            self.current_user.clear();
+            self.table_state.set(TableState::Failed);
+        }
+
+        // If the kernel is not requesting anything, check all of the apps.
+        for cntr in self.apps.iter() {
+            let started_command = cntr.enter(|app, _| {
+                if !app.pending_command {
+                    return false;
+                }
+
+                let appid = app.appid();
+                let res = match self.app_region(app, appid) {
+                    Ok(region) => {
+                        if app.offset >= region.length || app.offset + app.length > region.length {
+                            ReturnCode::EINVAL
+                        } else if self.table_state.get() == TableState::Failed
+                            && self.table_dirty.get()
+                        {
+                            // The new region can never be recorded in
+                            // storage.
+                            ReturnCode::FAIL
+                        } else if self.table_dirty.get() {
+                            // Leave the command queued until the new region
+                            // has been recorded in storage.
+                            return true;
+                        } else {
+                            app.pending_command = false;
+                            self.current_user
+                                .set(NonvolatileUser::App { app_id: appid });
+                            let (command, offset, length) = (app.command, app.offset, app.length);
+                            let res =
+                                self.userspace_call_driver(app, command, region, offset, length);
+                            if res != ReturnCode::SUCCESS {
+                                self.current_user.clear();
+                            }
+                            res
+                        }
                     }
-                });
-                if started_command {
-                    break;
+                    // Wait for the region table to be read.
+                    Err(ReturnCode::EBUSY) => return true,
+                    Err(err) => err,
+                };
+                if res == ReturnCode::SUCCESS {
+                    return true;
                 }
+
+                // The command cannot run. Tell the app that nothing was read
+                // or written.
+                app.pending_command = false;
+                match app.command {
+                    NonvolatileCommand::UserspaceRead => {
+                        app.callback_read.map(|mut cb| cb.schedule(0, 0, 0));
+                    }
+                    _ => {
+                        app.callback_write.map(|mut cb| cb.schedule(0, 0, 0));
+                    }
+                }
+                false
+            });
+            if started_command {
+                break;
             }
         }
+
+        // An app is waiting for the region table.
+        if self.current_user.is_none() && self.table_needs_io() {
+            self.check_queue();
+        }
     }
+
+    /// Whether the region table has to be read, or written back to storage.
+    fn table_needs_io(&self) -> bool {
+        match self.table_state.get() {
+            TableState::Loading => true,
+            TableState::Loaded => self.table_dirty.get(),
+            TableState::Unloaded | TableState::Failed => false,
+        }
+    }
+}
+
+/// Whether a table entry belongs to the package `name`. The hash tells apart
+/// package names that share the prefix stored in the table.
+fn entry_matches(entry: &[u8], name: &str) -> bool {
+    let stored_len = cmp::min(name.len(), TABLE_ENTRY_SIZE - TABLE_NAME_OFFSET);
+    read_u32(&entry[8..12]) == fnv1a(name.as_bytes())
+        && entry[12] == cmp::min(name.len(), 255) as u8
+        && &entry[TABLE_NAME_OFFSET..TABLE_NAME_OFFSET + stored_len]
+            == &name.as_bytes()[..stored_len]
 }
 
 /// This is the callback client for the underlying physical storage driver.
@@ -395,6 +671,10 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.read_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable => {
+                    self.region_table.replace(buffer);
+                    self.table_state.set(TableState::Loaded);
+                }
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app, _| {
                         // Need to copy in the contents of the buffer
@@ -429,6 +709,10 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.write_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable => {
+                    self.region_table.replace(buffer);
+                    self.table_dirty.set(false);
+                }
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app, _| {
                         // Replace the buffer we used to do this write.
@@ -519,17 +803,38 @@ impl Driver for NonvolatileStorage<'a> {
     /// ### `command_num`
     ///
     /// - `0`: Return SUCCESS if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes in this app's region. Returns `EBUSY`
+    ///        if the region table has not been read from storage yet, and
+    ///        `ENOMEM` if there is no room left for a new region.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
+    ///
+    /// Reads and writes queued behind other users are checked against the
+    /// app's region when they start. If they cannot run, their callback
+    /// reports that 0 bytes were read or written.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         let command_num = arg0 & 0xFF;
 
         match command_num {
             0 => /* This driver exists. */ ReturnCode::SUCCESS,
 
-            // How many bytes are accessible from userspace.
-            1 => ReturnCode::SuccessWithValue { value: self.userspace_length },
+            // How many bytes are accessible from this app.
+            1 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app, _| match self.app_region(app, appid) {
+                        Ok(region) => ReturnCode::SuccessWithValue {
+                            value: region.length,
+                        },
+                        Err(err) => err,
+                    })
+                    .unwrap_or_else(|err| err.into());
+                // The region table may need to be read or written.
+                if self.current_user.is_none() {
+                    self.check_queue();
+                }
+                res
+            }
 
             // Issue a read
             2 => {
@@ -0,0 +1,319 @@
+//! Map arbitrary nonvolatile reads and writes to block operations.
+//!
+//! This splits reads and writes that are not aligned to blocks into a series
+//! of block reads and writes, like `NonvolatileToPages` does for flash pages.
+//! While it is handling a read or write it returns `EBUSY` to all additional
+//! requests.
+//!
+//! ```plain
+//! hil::nonvolatile_storage::NonvolatileStorage
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │
+//!                │             │
+//!                └─────────────┘
+//!           hil::block::BlockDevice
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! pub static mut BLOCKBUFFER: [u8; 512] = [0; 512];
+//! let nv_to_blocks = static_init!(
+//!     capsules::nonvolatile_to_blocks::NonvolatileToBlocks<'static>,
+//!     capsules::nonvolatile_to_blocks::NonvolatileToBlocks::new(
+//!         sdcard_block,
+//!         &mut BLOCKBUFFER));
+//! hil::block::BlockDevice::set_client(sdcard_block, nv_to_blocks);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::NumericCellExt;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ReturnCode;
+
+/// This module is either waiting to do something, or handling a read/write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    Write,
+}
+
+pub struct NonvolatileToBlocks<'a> {
+    /// The module providing a `BlockDevice` interface.
+    driver: &'a hil::block::BlockDevice<'a>,
+    /// Callback to the user of this capsule.
+    client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
+    /// Buffer of at least one block.
+    blockbuffer: TakeCell<'static, [u8]>,
+    /// Current state of this capsule.
+    state: Cell<State>,
+    /// Temporary holding place for the user's buffer.
+    buffer: TakeCell<'static, [u8]>,
+    /// Absolute address of where we are reading or writing. This gets updated
+    /// as the operation proceeds across blocks.
+    address: Cell<usize>,
+    /// Total length to read or write. We need to store this to return it to the
+    /// client.
+    length: Cell<usize>,
+    /// How many bytes are left to read or write.
+    remaining_length: Cell<usize>,
+    /// Where we are in the user buffer.
+    buffer_index: Cell<usize>,
+    /// How many bytes of a write have been stored so far.
+    written: Cell<usize>,
+}
+
+impl NonvolatileToBlocks<'a> {
+    pub fn new(
+        driver: &'a hil::block::BlockDevice<'a>,
+        buffer: &'static mut [u8],
+    ) -> NonvolatileToBlocks<'a> {
+        NonvolatileToBlocks {
+            driver: driver,
+            client: OptionalCell::empty(),
+            blockbuffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+            remaining_length: Cell::new(0),
+            buffer_index: Cell::new(0),
+            written: Cell::new(0),
+        }
+    }
+
+    /// Check that a request fits in the device and the buffers.
+    fn check(&self, buffer: &[u8], address: usize, length: usize) -> ReturnCode {
+        let block_size = self.driver.block_size();
+        let device_size = self.driver.num_blocks() * block_size;
+        if self.state.get() != State::Idle {
+            ReturnCode::EBUSY
+        } else if self.blockbuffer.map_or(0, |b| b.len()) < block_size {
+            ReturnCode::ERESERVE
+        } else if length > buffer.len() || address > device_size || length > device_size - address {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn read_block(&self, block: usize, blockbuffer: &'static mut [u8]) -> ReturnCode {
+        let (res, blockbuffer) = self.driver.read_block(block, blockbuffer);
+        blockbuffer.map(|blockbuffer| self.blockbuffer.replace(blockbuffer));
+        res
+    }
+
+    fn write_block(&self, block: usize, blockbuffer: &'static mut [u8]) -> ReturnCode {
+        let (res, blockbuffer) = self.driver.write_block(block, blockbuffer);
+        blockbuffer.map(|blockbuffer| self.blockbuffer.replace(blockbuffer));
+        res
+    }
+
+    /// Give the user's buffer back. Errors after the first block cannot be
+    /// reported through `NonvolatileStorageClient`, so the callback is passed
+    /// the number of bytes handled before the error.
+    fn finish(&self, buffer: &'static mut [u8]) {
+        let length = match self.state.get() {
+            State::Write => self.written.get(),
+            _ => self.length.get() - self.remaining_length.get(),
+        };
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        self.client.map(move |client| match state {
+            State::Write => client.write_done(buffer, length),
+            _ => client.read_done(buffer, length),
+        });
+    }
+
+    /// Start the next block operation of a write, if there is one.
+    fn write_next(&self, buffer: &'static mut [u8], blockbuffer: &'static mut [u8]) {
+        let block_size = self.driver.block_size();
+        let res = if self.remaining_length.get() == 0 {
+            // Done!
+            self.blockbuffer.replace(blockbuffer);
+            self.state.set(State::Idle);
+            self.client
+                .map(move |client| client.write_done(buffer, self.length.get()));
+            return;
+        } else if self.address.get() % block_size == 0 && self.remaining_length.get() >= block_size
+        {
+            // Write an entire block!
+            let buffer_index = self.buffer_index.get();
+            let block = self.address.get() / block_size;
+            blockbuffer[..block_size]
+                .copy_from_slice(&buffer[buffer_index..buffer_index + block_size]);
+
+            self.buffer.replace(buffer);
+            self.remaining_length.subtract(block_size);
+            self.address.add(block_size);
+            self.buffer_index.set(buffer_index + block_size);
+            self.write_block(block, blockbuffer)
+        } else {
+            // Write a partial block, so read it first.
+            self.buffer.replace(buffer);
+            self.read_block(self.address.get() / block_size, blockbuffer)
+        };
+        if res != ReturnCode::SUCCESS {
+            self.buffer.take().map(|buffer| self.finish(buffer));
+        }
+    }
+}
+
+impl hil::nonvolatile_storage::NonvolatileStorage for NonvolatileToBlocks<'a> {
+    fn set_client(&self, client: &'static hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        let res = self.check(buffer, address, length);
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+
+        self.blockbuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, move |blockbuffer| {
+                // Just start reading. We'll worry about how much of the block
+                // we want later.
+                self.address.set(address);
+                self.length.set(length);
+                self.remaining_length.set(length);
+                self.buffer_index.set(0);
+                let res = self.read_block(address / self.driver.block_size(), blockbuffer);
+                if res == ReturnCode::SUCCESS {
+                    self.state.set(State::Read);
+                    self.buffer.replace(buffer);
+                }
+                res
+            })
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        let res = self.check(buffer, address, length);
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+
+        self.blockbuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, move |blockbuffer| {
+                let block_size = self.driver.block_size();
+                let block = address / block_size;
+                self.length.set(length);
+                self.written.set(0);
+
+                let res = if address % block_size == 0 && length >= block_size {
+                    // This write is aligned to a block and we are writing an
+                    // entire block or more.
+                    blockbuffer[..block_size].copy_from_slice(&buffer[..block_size]);
+                    self.address.set(address + block_size);
+                    self.remaining_length.set(length - block_size);
+                    self.buffer_index.set(block_size);
+                    self.write_block(block, blockbuffer)
+                } else {
+                    // Need to do a read first.
+                    self.address.set(address);
+                    self.remaining_length.set(length);
+                    self.buffer_index.set(0);
+                    self.read_block(block, blockbuffer)
+                };
+                if res == ReturnCode::SUCCESS {
+                    self.state.set(State::Write);
+                    self.buffer.replace(buffer);
+                }
+                res
+            })
+    }
+}
+
+impl hil::block::Client for NonvolatileToBlocks<'a> {
+    fn ready(&self, _result: ReturnCode) {}
+
+    fn read_done(&self, result: ReturnCode, blockbuffer: &'static mut [u8]) {
+        self.buffer.take().map(move |buffer| {
+            if result != ReturnCode::SUCCESS {
+                self.blockbuffer.replace(blockbuffer);
+                self.finish(buffer);
+                return;
+            }
+
+            let block_size = self.driver.block_size();
+            // This will get us our offset into the block.
+            let block_index = self.address.get() % block_size;
+            // Length is either the rest of the block or how much we have left.
+            let len = cmp::min(block_size - block_index, self.remaining_length.get());
+            // And where we left off in the user buffer.
+            let buffer_index = self.buffer_index.get();
+
+            match self.state.get() {
+                State::Read => {
+                    // Copy what we read from the block to the user buffer.
+                    buffer[buffer_index..buffer_index + len]
+                        .copy_from_slice(&blockbuffer[block_index..block_index + len]);
+                    self.remaining_length.subtract(len);
+                    self.address.add(len);
+                    self.buffer_index.set(buffer_index + len);
+
+                    if self.remaining_length.get() == 0 {
+                        // Nothing more to do. Put things back and issue callback.
+                        self.blockbuffer.replace(blockbuffer);
+                        self.state.set(State::Idle);
+                        self.client
+                            .map(move |client| client.read_done(buffer, self.length.get()));
+                    } else {
+                        // More to do!
+                        self.buffer.replace(buffer);
+                        let res = self.read_block(self.address.get() / block_size, blockbuffer);
+                        if res != ReturnCode::SUCCESS {
+                            self.buffer.take().map(|buffer| self.finish(buffer));
+                        }
+                    }
+                }
+                State::Write => {
+                    // We did a read because we're not block aligned on either
+                    // or both ends. Merge the user's data into the block and
+                    // write it back.
+                    let block = self.address.get() / block_size;
+                    blockbuffer[block_index..block_index + len]
+                        .copy_from_slice(&buffer[buffer_index..buffer_index + len]);
+
+                    self.buffer.replace(buffer);
+                    self.remaining_length.subtract(len);
+                    self.address.add(len);
+                    self.buffer_index.set(buffer_index + len);
+                    let res = self.write_block(block, blockbuffer);
+                    if res != ReturnCode::SUCCESS {
+                        self.buffer.take().map(|buffer| self.finish(buffer));
+                    }
+                }
+                State::Idle => {
+                    self.blockbuffer.replace(blockbuffer);
+                    self.buffer.replace(buffer);
+                }
+            }
+        });
+    }
+
+    fn write_done(&self, result: ReturnCode, blockbuffer: &'static mut [u8]) {
+        // After a write we could be done, need to do another write, or need to
+        // do a read.
+        self.buffer.take().map(move |buffer| {
+            if result != ReturnCode::SUCCESS {
+                self.blockbuffer.replace(blockbuffer);
+                self.finish(buffer);
+            } else {
+                self.written.set(self.buffer_index.get());
+                self.write_next(buffer, blockbuffer);
+            }
+        });
+    }
+
+    fn erase_done(&self, _result: ReturnCode) {}
+
+    fn flush_done(&self, _result: ReturnCode) {}
+}
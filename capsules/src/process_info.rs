@@ -0,0 +1,169 @@
+//! Provides userspace with read-only information about running processes.
+//!
+//! A process can use this driver to learn its own package name, app version,
+//! memory and flash bounds, how often it has been restarted and how many
+//! callbacks it has lost, and to enumerate the names and states of the other
+//! processes on the board.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! let process_info = static_init!(
+//!     capsules::process_info::ProcessInfo,
+//!     capsules::process_info::ProcessInfo::new(board_kernel, kernel::Grant::create()));
+//! ```
+
+use kernel::procs::State;
+use kernel::{AppId, AppSlice, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x10001;
+
+#[derive(Default)]
+pub struct App {
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct ProcessInfo {
+    kernel: &'static Kernel,
+    apps: Grant<App>,
+}
+
+impl ProcessInfo {
+    pub fn new(kernel: &'static Kernel, apps: Grant<App>) -> ProcessInfo {
+        ProcessInfo {
+            kernel: kernel,
+            apps: apps,
+        }
+    }
+
+    /// Run `fun` on the process in slot `index`, or return `EINVAL` if the
+    /// slot is empty.
+    fn with_process<F>(&self, index: usize, fun: F) -> ReturnCode
+    where
+        F: FnOnce(AppId) -> ReturnCode,
+    {
+        self.kernel
+            .lookup_app_by_index(index)
+            .map_or(ReturnCode::EINVAL, fun)
+    }
+
+    /// Copy the package name of `process` into the buffer `appid` allowed.
+    fn copy_name(&self, appid: AppId, process: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                app.buffer.as_mut().map_or(ReturnCode::ERESERVE, |buffer| {
+                    let name = process.get_package_name().as_bytes();
+                    if name.len() > buffer.len() {
+                        return ReturnCode::ESIZE;
+                    }
+                    buffer.as_mut()[..name.len()].copy_from_slice(name);
+                    ReturnCode::SuccessWithValue { value: name.len() }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl Driver for ProcessInfo {
+    /// Setup a buffer for process names to be copied into.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer that `command` 4 writes a package name into.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Query process information.
+    ///
+    /// Commands 3 through 7 take the index of a process slot in `data` and
+    /// return `EINVAL` if that slot does not hold a process.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Number of process slots on this board.
+    /// - `2`: Slot index of the calling process.
+    /// - `3`: State of a process: `0` running, `1` yielded, `2` faulted.
+    /// - `4`: Copy the package name of a process into the allowed buffer and
+    ///        return its length. Returns `ERESERVE` if no buffer was allowed
+    ///        and `ESIZE` if the name does not fit.
+    /// - `5`: App version of a process, from its TBF header, or `0` if the
+    ///        header does not have one.
+    /// - `6`: Number of times a process has been restarted.
+    /// - `7`: Number of callbacks to a process that were dropped.
+    /// - `8`: Start address of the calling process's memory.
+    /// - `9`: End address of the calling process's memory.
+    /// - `10`: Start address of the calling process's flash.
+    /// - `11`: End address of the calling process's flash.
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => ReturnCode::SuccessWithValue {
+                value: self.kernel.number_of_process_slots(),
+            },
+
+            2 => ReturnCode::SuccessWithValue { value: appid.idx() },
+
+            3 => self.with_process(data, |process| {
+                process.get_state().map_or(ReturnCode::EINVAL, |state| {
+                    ReturnCode::SuccessWithValue {
+                        value: match state {
+                            State::Running => 0,
+                            State::Yielded => 1,
+                            State::Fault => 2,
+                        },
+                    }
+                })
+            }),
+
+            4 => self.with_process(data, |process| self.copy_name(appid, process)),
+
+            5 => self.with_process(data, |process| ReturnCode::SuccessWithValue {
+                value: process.get_app_version() as usize,
+            }),
+
+            6 => self.with_process(data, |process| ReturnCode::SuccessWithValue {
+                value: process.get_restart_count(),
+            }),
+
+            7 => self.with_process(data, |process| ReturnCode::SuccessWithValue {
+                value: process.get_dropped_callback_count(),
+            }),
+
+            8 => ReturnCode::SuccessWithValue {
+                value: appid.get_memory_range().0,
+            },
+
+            9 => ReturnCode::SuccessWithValue {
+                value: appid.get_memory_range().1,
+            },
+
+            10 => ReturnCode::SuccessWithValue {
+                value: appid.get_flash_range().0,
+            },
+
+            11 => ReturnCode::SuccessWithValue {
+                value: appid.get_flash_range().1,
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Provides userspace with access to calendar time from a real-time clock.
+//!
+//! Any process can read the time, and set it from a source such as NTP or
+//! GPS. The time is kept by the clock, not by the processes, so it survives
+//! process restarts. Requests from all processes are served one at a time.
+//!
+//! Dates and times are passed to and from userspace packed into one word
+//! each:
+//!
+//! - date: `year << 9 | month << 5 | day`, with months and days from 1.
+//! - time: `hour << 12 | minute << 6 | second`.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let rtc_driver = static_init!(
+//!     capsules::rtc::RtcDriver<'static>,
+//!     capsules::rtc::RtcDriver::new(rtc, kernel::Grant::create())
+//! );
+//! hil::rtc::Rtc::set_client(rtc, rtc_driver);
+//! ```
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::rtc::{self, DateTime};
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x00007;
+
+#[derive(Clone, Copy)]
+enum Request {
+    Get,
+    Set(DateTime),
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    // The request this app is waiting for, if any.
+    waiting: Option<Request>,
+}
+
+pub struct RtcDriver<'a> {
+    rtc: &'a rtc::Rtc<'a>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl RtcDriver<'a> {
+    pub fn new(rtc: &'a rtc::Rtc<'a>, apps: Grant<App>) -> RtcDriver<'a> {
+        RtcDriver {
+            rtc: rtc,
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Start the request of the next waiting app. Apps whose request cannot
+    /// be started are told so through their callback.
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            return;
+        }
+
+        let mut found = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if let Some(request) = app.waiting {
+                    let res = match request {
+                        Request::Get => self.rtc.get_date_time(),
+                        Request::Set(date_time) => self.rtc.set_date_time(date_time),
+                    };
+                    if res == ReturnCode::SUCCESS {
+                        self.serving_app.set(app.appid());
+                        found = true;
+                    } else {
+                        app.waiting = None;
+                        app.callback
+                            .map(|mut cb| cb.schedule(From::from(res), 0, 0));
+                    }
+                }
+            });
+            if found {
+                break;
+            }
+        }
+    }
+
+    /// Finish the request of the app being served, and start the next one.
+    fn complete(&self, result: ReturnCode, date: usize, time: usize) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                app.callback
+                    .map(|mut cb| cb.schedule(From::from(result), date, time));
+            });
+        });
+        self.serve_waiting_apps();
+    }
+}
+
+fn pack(date_time: DateTime) -> (usize, usize) {
+    let date =
+        (date_time.year as usize) << 9 | (date_time.month as usize) << 5 | date_time.day as usize;
+    let time = (date_time.hour as usize) << 12
+        | (date_time.minute as usize) << 6
+        | date_time.second as usize;
+    (date, time)
+}
+
+fn unpack(date: usize, time: usize) -> DateTime {
+    DateTime {
+        year: (date >> 9) as u16,
+        month: (date >> 5 & 0xf) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 12 & 0x1f) as u8,
+        minute: (time >> 6 & 0x3f) as u8,
+        second: (time & 0x3f) as u8,
+    }
+}
+
+impl rtc::Client for RtcDriver<'a> {
+    fn get_date_time_done(&self, result: ReturnCode, date_time: DateTime) {
+        let (date, time) = pack(date_time);
+        self.complete(result, date, time);
+    }
+
+    fn set_date_time_done(&self, result: ReturnCode) {
+        self.complete(result, 0, 0);
+    }
+
+    fn alarm_fired(&self) {}
+}
+
+impl Driver for RtcDriver<'a> {
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request complete. The callback is called with the status of
+    ///   the request and, for a read, the packed date and time. The status is
+    ///   `ERESERVE` if the time has not been set.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Read the date and time.
+    /// - `2`: Set the date to `data1` and the time to `data2`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let request = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Request::Get,
+            2 => {
+                let date_time = unpack(data1, data2);
+                if !date_time.is_valid() || pack(date_time) != (data1, data2) {
+                    return ReturnCode::EINVAL;
+                }
+                Request::Set(date_time)
+            }
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        let res = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.waiting = Some(request);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+
+        if res == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        res
+    }
+}
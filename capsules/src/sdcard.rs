@@ -1,6 +1,8 @@
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
 //! This allows initialization and block reads or writes on top of SPI.
+//! `SDCardBlock` presents the card as a `hil::block::BlockDevice` for other
+//! kernel capsules.
 //!
 //! Usage
 //! -----
@@ -1326,6 +1328,12 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
             ReturnCode::EUNINSTALLED
         }
     }
+
+    /// Take back the buffer of a read or write that ended with an `error`
+    /// callback
+    pub fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        self.client_buffer.take()
+    }
 }
 
 /// Handle callbacks from the SPI peripheral
@@ -1380,6 +1388,176 @@ impl<A: hil::time::Alarm> hil::gpio::Client for SDCard<'a, A> {
     }
 }
 
+/// Block device adapter for the SD Card capsule
+/// This presents the card as a `hil::block::BlockDevice` with 512 byte blocks,
+/// so that storage capsules can use it like any other block device. The card
+/// is initialized with `initialize`, and again whenever a card is inserted.
+/// The client's `ready` is called once `num_blocks` is known, and with an
+/// error if initialization fails or the card is removed.
+pub struct SDCardBlock<'a, A: hil::time::Alarm> {
+    sdcard: &'a SDCard<'a, A>,
+    client: OptionalCell<&'a hil::block::Client>,
+    num_blocks: Cell<usize>,
+    operation: Cell<BlockOperation>,
+}
+
+/// Block operation in progress on the card
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockOperation {
+    Idle,
+    Read,
+    Write,
+}
+
+/// Size of SD card blocks in bytes
+const BLOCK_SIZE: usize = 512;
+
+/// Functions for SDCardBlock
+impl<A: hil::time::Alarm> SDCardBlock<'a, A> {
+    pub fn new(sdcard: &'a SDCard<'a, A>) -> SDCardBlock<'a, A> {
+        SDCardBlock {
+            sdcard: sdcard,
+            client: OptionalCell::empty(),
+            num_blocks: Cell::new(0),
+            operation: Cell::new(BlockOperation::Idle),
+        }
+    }
+
+    /// Initialize the card. The number of blocks is known once this
+    /// completes.
+    pub fn initialize(&self) -> ReturnCode {
+        self.num_blocks.set(0);
+        self.sdcard.initialize()
+    }
+
+    /// Check that a block request can be started now
+    fn check(&self, block: usize, buffer: &[u8]) -> ReturnCode {
+        if !self.sdcard.is_installed() {
+            ReturnCode::EUNINSTALLED
+        } else if !self.sdcard.is_initialized() {
+            ReturnCode::ERESERVE
+        } else if self.operation.get() != BlockOperation::Idle {
+            ReturnCode::EBUSY
+        } else if block >= self.num_blocks.get() || buffer.len() < BLOCK_SIZE {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+}
+
+/// Handle callbacks from SDCard
+impl<A: hil::time::Alarm> SDCardClient for SDCardBlock<'a, A> {
+    fn card_detection_changed(&self, installed: bool) {
+        // the card has to be initialized again before it can be used
+        self.num_blocks.set(0);
+        let res = if installed {
+            self.initialize()
+        } else {
+            ReturnCode::EUNINSTALLED
+        };
+        if res != ReturnCode::SUCCESS {
+            self.client.map(|client| client.ready(res));
+        }
+    }
+
+    fn init_done(&self, _block_size: u32, total_size: u64) {
+        self.num_blocks
+            .set((total_size / BLOCK_SIZE as u64) as usize);
+        self.client.map(|client| client.ready(ReturnCode::SUCCESS));
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        self.operation.set(BlockOperation::Idle);
+        self.client
+            .map(move |client| client.read_done(ReturnCode::SUCCESS, data));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.operation.set(BlockOperation::Idle);
+        self.client
+            .map(move |client| client.write_done(ReturnCode::SUCCESS, buffer));
+    }
+
+    fn error(&self, _error: u32) {
+        let operation = self.operation.get();
+        self.operation.set(BlockOperation::Idle);
+        if operation == BlockOperation::Idle {
+            // errors during initialization have no buffer to return
+            self.client.map(|client| client.ready(ReturnCode::FAIL));
+            return;
+        }
+        self.sdcard.take_buffer().map(|buffer| {
+            self.client.map(move |client| match operation {
+                BlockOperation::Read => client.read_done(ReturnCode::FAIL, buffer),
+                BlockOperation::Write => client.write_done(ReturnCode::FAIL, buffer),
+                BlockOperation::Idle => {}
+            });
+        });
+    }
+}
+
+/// Block device interface
+impl<A: hil::time::Alarm> hil::block::BlockDevice<'a> for SDCardBlock<'a, A> {
+    fn set_client(&self, client: &'a hil::block::Client) {
+        self.client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.num_blocks.get()
+    }
+
+    fn read_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let res = self.check(block, buffer);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(buffer));
+        }
+        let res = self.sdcard.read_blocks(buffer, block as u32, 1);
+        if res == ReturnCode::SUCCESS {
+            self.operation.set(BlockOperation::Read);
+            (res, None)
+        } else {
+            (res, self.sdcard.take_buffer())
+        }
+    }
+
+    fn write_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let res = self.check(block, buffer);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(buffer));
+        }
+        let res = self.sdcard.write_blocks(buffer, block as u32, 1);
+        if res == ReturnCode::SUCCESS {
+            self.operation.set(BlockOperation::Write);
+            (res, None)
+        } else {
+            (res, self.sdcard.take_buffer())
+        }
+    }
+
+    fn erase_block(&self, _block: usize) -> ReturnCode {
+        // the card manages erasing itself
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn flush(&self) -> ReturnCode {
+        // blocks are written to the card before `write_done`
+        ReturnCode::EALREADY
+    }
+}
+
 /// Application driver for SD Card capsule, layers on top of SD Card capsule
 /// This is used if the SDCard is going to be attached directly to userspace
 /// syscalls. SDCardDriver can be ignored if another capsule is going to build
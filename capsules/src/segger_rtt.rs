@@ -3,7 +3,8 @@
 //! RTT is a protocol for sending debugging messages to a connected host. The
 //! embedded platform configures a portion of memory in a special way, and then
 //! the host uses a JTAG connection to read the messages out of the chip's
-//! memory.
+//! memory. The host can also write messages into the chip's memory, which
+//! gives a console without a UART.
 //!
 //!	Receiving RTT Messages
 //!	----------------------
@@ -18,18 +19,26 @@
 //!
 //!	    $ JLinkRTTClient
 //!
+//!	Text typed into `JLinkRTTClient` is sent to down channel 0.
+//!
+//! Channels
+//! --------
+//!
+//! RTT has up channels, from the chip to the host, and down channels, from the
+//! host to the chip. `SeggerRttMemory` describes up to `MAX_CHANNELS` of
+//! each, and each `SeggerRttChannel` provides `hil::uart::UART` for one up
+//! and down channel pair. This way the console can use channel 0 while kernel
+//! debug output goes to channel 1.
+//!
 //! Notes
 //! -----
 //!
-//! This capsule requires a timer, but the timer is only there to defer the
+//! This capsule requires a timer. The host writes to the down channels without
+//! notifying the chip, so while a receive is pending the down channels are
+//! polled every `POLL_INTERVAL_MS` milliseconds. The timer also defers the
 //! `transmit_complete` callback until the next scheduler loop. In the future,
 //! if there is support for software interrupts or deferred calls in capsules,
-//! this timer should be removed.
-//!
-//! Todo
-//! ----
-//!
-//! - Implement receive functionality.
+//! that use of the timer should be removed.
 //!
 //! Usage
 //! -----
@@ -39,7 +48,7 @@
 //!     // Other fields omitted for clarity
 //!     console: &'static capsules::console::Console<
 //!         'static,
-//!         capsules::segger_rtt::SeggerRtt<
+//!         capsules::segger_rtt::SeggerRttChannel<
 //!             'static,
 //!             capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
 //!         >,
@@ -58,49 +67,83 @@
 //! let rtt_memory = static_init!(
 //!     capsules::segger_rtt::SeggerRttMemory,
 //!     capsules::segger_rtt::SeggerRttMemory::new(b"Terminal\0",
-//!         &mut capsules::segger_rtt::UP_BUFFER,
+//!         &capsules::segger_rtt::UP_BUFFER,
 //!         b"Terminal\0",
-//!         &mut capsules::segger_rtt::DOWN_BUFFER)
+//!         &capsules::segger_rtt::DOWN_BUFFER)
 //! );
+//! // A second up channel for kernel debug output, without a down channel.
+//! rtt_memory.set_channel(1, b"Debug\0", &capsules::segger_rtt::DEBUG_UP_BUFFER, b"\0", &[]);
 //!
 //! let rtt = static_init!(
 //!     capsules::segger_rtt::SeggerRtt<VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>>,
-//!     capsules::segger_rtt::SeggerRtt::new(virtual_alarm_rtt, rtt_memory,
+//!     capsules::segger_rtt::SeggerRtt::new(virtual_alarm_rtt, rtt_memory)
+//! );
+//! virtual_alarm_rtt.set_client(rtt);
+//!
+//! let rtt_console = static_init!(
+//!     capsules::segger_rtt::SeggerRttChannel<VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>>,
+//!     capsules::segger_rtt::SeggerRttChannel::new(rtt, 0,
 //!         &mut capsules::segger_rtt::UP_BUFFER,
 //!         &mut capsules::segger_rtt::DOWN_BUFFER)
 //! );
-//! virtual_alarm_rtt.set_client(rtt);
+//! rtt_console.setup(); // This is important!
 //!
 //! let console = static_init!(
 //!     capsules::console::Console<
 //!         'static,
-//!         capsules::segger_rtt::SeggerRtt<
+//!         capsules::segger_rtt::SeggerRttChannel<
 //!             capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
 //!         >,
 //!     >,
 //!     capsules::console::Console::new(
-//!         rtt,
+//!         rtt_console,
 //!         0, // Baud rate is meaningless with RTT
 //!         &mut capsules::console::WRITE_BUF,
 //!         &mut capsules::console::READ_BUF,
 //!         kernel::Grant::create()
 //!     )
 //! );
-//! kernel::hil::uart::UART::set_client(rtt, console);
+//! kernel::hil::uart::UART::set_client(rtt_console, console);
 //! console.initialize();
+//!
+//! let rtt_debug = static_init!(
+//!     capsules::segger_rtt::SeggerRttChannel<VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>>,
+//!     capsules::segger_rtt::SeggerRttChannel::new(rtt, 1,
+//!         &mut capsules::segger_rtt::DEBUG_UP_BUFFER,
+//!         &mut [])
+//! );
+//! rtt_debug.setup();
+//! // Pass `rtt_debug` to `kernel::debug::DebugWriter::new`, and the writer to
+//! // `DebugSink::set_client(rtt_debug, debugger)`.
 //! ```
 
+use core::cell::Cell;
+use core::cmp;
+use core::ptr;
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::debug::{DebugSink, DebugSinkClient};
 use kernel::hil;
 use kernel::hil::time::Frequency;
 use kernel::ReturnCode;
 
+/// Number of up channels and of down channels described to the host.
+pub const MAX_CHANNELS: usize = 2;
+
+/// How often the down channels are checked for data while a receive is
+/// pending.
+const POLL_INTERVAL_MS: u32 = 10;
+
 /// Buffer for transmitting to the host.
 pub static mut UP_BUFFER: [u8; 1024] = [0; 1024];
 
 /// Buffer for receiving messages from the host.
 pub static mut DOWN_BUFFER: [u8; 32] = [0; 32];
 
+/// Buffer for transmitting to the host on a second channel, such as for kernel
+/// debug output.
+pub static mut DEBUG_UP_BUFFER: [u8; 1024] = [0; 1024];
+
 /// This structure is defined by the segger RTT protocol. It must exist in
 /// memory in exactly this form so that the segger JTAG tool can find it in the
 /// chip's memory and read and write messages to the appropriate buffers.
@@ -109,8 +152,8 @@ pub struct SeggerRttMemory {
     id: [u8; 16],
     number_up_buffers: u32,
     number_down_buffers: u32,
-    up_buffer: SeggerRttBuffer,
-    down_buffer: SeggerRttBuffer,
+    up_buffers: [SeggerRttBuffer; MAX_CHANNELS],
+    down_buffers: [SeggerRttBuffer; MAX_CHANNELS],
 }
 
 #[repr(C)]
@@ -123,7 +166,27 @@ pub struct SeggerRttBuffer {
     flags: u32,
 }
 
+impl SeggerRttBuffer {
+    /// Describe a channel. A channel with an empty buffer is unused.
+    fn new(name: &'static [u8], buffer: &'static [u8]) -> SeggerRttBuffer {
+        SeggerRttBuffer {
+            name: name.as_ptr(),
+            buffer: if buffer.is_empty() {
+                ptr::null()
+            } else {
+                buffer.as_ptr()
+            },
+            length: buffer.len() as u32,
+            write_position: 0,
+            read_position: 0,
+            flags: 0,
+        }
+    }
+}
+
 impl SeggerRttMemory {
+    /// Describe channel 0. The other channels are unused until they are set
+    /// with `set_channel`.
     pub fn new(
         up_buffer_name: &'static [u8],
         up_buffer: &'static [u8],
@@ -133,56 +196,232 @@ impl SeggerRttMemory {
         SeggerRttMemory {
             // Must be "SEGGER RTT".
             id: *b"SEGGER RTT\0\0\0\0\0\0",
-            number_up_buffers: 1,
-            number_down_buffers: 1,
-            up_buffer: SeggerRttBuffer {
-                name: up_buffer_name.as_ptr(),
-                buffer: up_buffer.as_ptr(),
-                length: 1024,
-                write_position: 0,
-                read_position: 0,
-                flags: 0,
-            },
-            down_buffer: SeggerRttBuffer {
-                name: down_buffer_name.as_ptr(),
-                buffer: down_buffer.as_ptr(),
-                length: 32,
-                write_position: 0,
-                read_position: 0,
-                flags: 0,
-            },
+            number_up_buffers: MAX_CHANNELS as u32,
+            number_down_buffers: MAX_CHANNELS as u32,
+            up_buffers: [
+                SeggerRttBuffer::new(up_buffer_name, up_buffer),
+                SeggerRttBuffer::new(b"\0", &[]),
+            ],
+            down_buffers: [
+                SeggerRttBuffer::new(down_buffer_name, down_buffer),
+                SeggerRttBuffer::new(b"\0", &[]),
+            ],
         }
     }
+
+    /// Describe the up and down channels with number `channel`. Either buffer
+    /// can be empty to leave that direction unused.
+    pub fn set_channel(
+        &mut self,
+        channel: usize,
+        up_buffer_name: &'static [u8],
+        up_buffer: &'static [u8],
+        down_buffer_name: &'static [u8],
+        down_buffer: &'static [u8],
+    ) {
+        self.up_buffers[channel] = SeggerRttBuffer::new(up_buffer_name, up_buffer);
+        self.down_buffers[channel] = SeggerRttBuffer::new(down_buffer_name, down_buffer);
+    }
 }
 
+/// Shared state of all channels: the RTT memory and the alarm used to poll
+/// the down channels and to defer callbacks.
 pub struct SeggerRtt<'a, A: hil::time::Alarm> {
-    alarm: &'a A, // Dummy alarm so we can get a callback.
+    alarm: &'a A,
     config: TakeCell<'static, SeggerRttMemory>,
+    channels: List<'a, SeggerRttChannel<'a, A>>,
+}
+
+impl<A: hil::time::Alarm> SeggerRtt<'a, A> {
+    pub fn new(alarm: &'a A, config: &'static mut SeggerRttMemory) -> SeggerRtt<'a, A> {
+        SeggerRtt {
+            alarm: alarm,
+            config: TakeCell::new(config),
+            channels: List::new(),
+        }
+    }
+
+    /// Set the alarm for the next callback or poll that any channel needs.
+    fn schedule(&self) {
+        let transmitting = self
+            .channels
+            .iter()
+            .any(|channel| channel.tx_buffer.is_some() || channel.aborting.get());
+        let receiving = self
+            .channels
+            .iter()
+            .any(|channel| channel.rx_buffer.is_some());
+
+        let interval = if transmitting {
+            // Callbacks are issued after a short delay of about 100 us.
+            <A::Frequency>::frequency() / 10000
+        } else if receiving {
+            <A::Frequency>::frequency() / 1000 * POLL_INTERVAL_MS
+        } else {
+            return;
+        };
+        let tics = self.alarm.now().wrapping_add(cmp::max(interval, 1));
+        self.alarm.set_alarm(tics);
+    }
+}
+
+impl<A: hil::time::Alarm> hil::time::Client for SeggerRtt<'a, A> {
+    fn fired(&self) {
+        self.channels
+            .iter()
+            .for_each(|channel| channel.receive_pending());
+        self.channels
+            .iter()
+            .for_each(|channel| channel.transmit_pending());
+        self.schedule();
+    }
+}
+
+/// One up and down channel pair, used like a UART.
+pub struct SeggerRttChannel<'a, A: hil::time::Alarm> {
+    rtt: &'a SeggerRtt<'a, A>,
+    channel: usize,
     up_buffer: TakeCell<'static, [u8]>,
-    _down_buffer: TakeCell<'static, [u8]>,
+    down_buffer: TakeCell<'static, [u8]>,
     client: OptionalCell<&'static hil::uart::Client>,
-    client_buffer: TakeCell<'static, [u8]>,
+    debug_client: OptionalCell<&'static DebugSinkClient>,
+    // Buffer of a transmit that has been copied to the up buffer, waiting for
+    // its callback, and whether it came from `DebugSink::transmit`.
+    tx_buffer: TakeCell<'static, [u8]>,
+    debug_transmit: Cell<bool>,
+    // Buffer of a pending receive, how many bytes to receive, and how many
+    // have been received.
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_position: Cell<usize>,
+    aborting: Cell<bool>,
+    next: ListLink<'a, SeggerRttChannel<'a, A>>,
 }
 
-impl<A: hil::time::Alarm> SeggerRtt<'a, A> {
+impl<A: hil::time::Alarm> ListNode<'a, SeggerRttChannel<'a, A>> for SeggerRttChannel<'a, A> {
+    fn next(&'a self) -> &'a ListLink<'a, SeggerRttChannel<'a, A>> {
+        &self.next
+    }
+}
+
+impl<A: hil::time::Alarm> SeggerRttChannel<'a, A> {
+    /// Use channel `channel` of `rtt`. The buffers must be the ones described
+    /// for this channel in the `SeggerRttMemory`.
     pub fn new(
-        alarm: &'a A,
-        config: &'static mut SeggerRttMemory,
+        rtt: &'a SeggerRtt<'a, A>,
+        channel: usize,
         up_buffer: &'static mut [u8],
         down_buffer: &'static mut [u8],
-    ) -> SeggerRtt<'a, A> {
-        SeggerRtt {
-            alarm: alarm,
-            config: TakeCell::new(config),
+    ) -> SeggerRttChannel<'a, A> {
+        SeggerRttChannel {
+            rtt: rtt,
+            channel: channel,
             up_buffer: TakeCell::new(up_buffer),
-            _down_buffer: TakeCell::new(down_buffer),
+            down_buffer: TakeCell::new(down_buffer),
             client: OptionalCell::empty(),
-            client_buffer: TakeCell::empty(),
+            debug_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            debug_transmit: Cell::new(false),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_position: Cell::new(0),
+            aborting: Cell::new(false),
+            next: ListLink::empty(),
         }
     }
+
+    /// Must be called right after `static_init!()`.
+    pub fn setup(&'a self) {
+        self.rtt.channels.push_head(self);
+    }
+
+    /// Copy `bytes` into the up buffer.
+    fn write_up(&self, bytes: &[u8]) {
+        self.up_buffer.map(|buffer| {
+            self.rtt.config.map(|config| {
+                let up_buffer = &mut config.up_buffers[self.channel];
+
+                // Copy the incoming data into the buffer. Once we increment
+                // the `write_position` the RTT listener will go ahead and read
+                // the message from us.
+                let mut index = up_buffer.write_position as usize;
+                let buffer_len = up_buffer.length as usize;
+                if buffer_len == 0 {
+                    return;
+                }
+
+                for (i, &byte) in bytes.iter().enumerate() {
+                    buffer[(i + index) % buffer_len] = byte;
+                }
+
+                index = (index + bytes.len()) % buffer_len;
+                up_buffer.write_position = index as u32;
+            });
+        });
+    }
+
+    /// Issue the callback of a transmit, if one is waiting for it.
+    fn transmit_pending(&self) {
+        self.tx_buffer.take().map(|buffer| {
+            if self.debug_transmit.get() {
+                self.debug_transmit.set(false);
+                self.debug_client
+                    .map(move |client| client.transmit_done(buffer));
+            } else {
+                self.client.map(move |client| {
+                    client.transmit_complete(buffer, hil::uart::Error::CommandComplete);
+                });
+            }
+        });
+    }
+
+    /// Copy what the host wrote to the down buffer into the receive buffer,
+    /// and finish the receive if it is full or was aborted.
+    fn receive_pending(&self) {
+        self.rx_buffer.take().map(|rx_buffer| {
+            let rx_len = self.rx_len.get();
+            let mut position = self.rx_position.get();
+
+            self.down_buffer.map(|buffer| {
+                self.rtt.config.map(|config| {
+                    let down_buffer = &mut config.down_buffers[self.channel];
+                    let buffer_len = down_buffer.length as usize;
+                    if buffer_len == 0 {
+                        return;
+                    }
+
+                    // The host writes at `write_position`, and we consume
+                    // from `read_position`.
+                    let mut index = down_buffer.read_position as usize;
+                    let end = down_buffer.write_position as usize;
+                    while index != end && position < rx_len {
+                        rx_buffer[position] = buffer[index];
+                        position += 1;
+                        index = (index + 1) % buffer_len;
+                    }
+                    down_buffer.read_position = index as u32;
+                });
+            });
+            self.rx_position.set(position);
+
+            if position == rx_len || self.aborting.get() {
+                let error = if position == rx_len {
+                    hil::uart::Error::CommandComplete
+                } else {
+                    hil::uart::Error::Aborted
+                };
+                self.aborting.set(false);
+                self.client.map(move |client| {
+                    client.receive_complete(rx_buffer, position, error);
+                });
+            } else {
+                self.rx_buffer.replace(rx_buffer);
+            }
+        });
+    }
 }
 
-impl<A: hil::time::Alarm> hil::uart::UART for SeggerRtt<'a, A> {
+impl<A: hil::time::Alarm> hil::uart::UART for SeggerRttChannel<'a, A> {
     fn set_client(&self, client: &'static hil::uart::Client) {
         self.client.set(client);
     }
@@ -192,44 +431,69 @@ impl<A: hil::time::Alarm> hil::uart::UART for SeggerRtt<'a, A> {
     }
 
     fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
-        self.up_buffer.map(|buffer| {
-            self.config.map(|config| {
-                // Copy the incoming data into the buffer. Once we increment
-                // the `write_position` the RTT listener will go ahead and read
-                // the message from us.
-                let mut index = config.up_buffer.write_position as usize;
-                let buffer_len = config.up_buffer.length as usize;
+        if self.tx_buffer.is_some() {
+            self.client.map(move |client| {
+                client.transmit_complete(tx_data, hil::uart::Error::RepeatCallError);
+            });
+            return;
+        }
 
-                for i in 0..tx_len {
-                    buffer[(i + index) % buffer_len] = tx_data[i];
-                }
+        let length = cmp::min(tx_len, tx_data.len());
+        self.write_up(&tx_data[..length]);
+
+        // Save the client buffer so we can pass it back with the callback,
+        // which is issued from the alarm.
+        self.tx_buffer.replace(tx_data);
+        self.rtt.schedule();
+    }
 
-                index = (index + tx_len) % buffer_len;
-                config.up_buffer.write_position = index as u32;
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        if self.rx_buffer.is_some() {
+            self.client.map(move |client| {
+                client.receive_complete(rx_buffer, 0, hil::uart::Error::RepeatCallError);
             });
-        });
+            return;
+        }
+
+        self.rx_len.set(cmp::min(rx_len, rx_buffer.len()));
+        self.rx_position.set(0);
+        self.rx_buffer.replace(rx_buffer);
+        self.rtt.schedule();
+    }
 
-        // Save the client buffer so we can pass it back with the callback.
-        self.client_buffer.replace(tx_data);
+    fn abort_receive(&self) {
+        if self.rx_buffer.is_some() {
+            // The receive finishes with what has been received so far on
+            // the next alarm.
+            self.aborting.set(true);
+            self.rtt.schedule();
+        }
+    }
+}
 
-        // Start a short timer so that we get a callback and can issue the
-        // callback to the client.
-        let interval = (100 as u32) * <A::Frequency>::frequency() / 1000000;
-        let tics = self.alarm.now().wrapping_add(interval);
-        self.alarm.set_alarm(tics);
+/// Kernel debug output. The channel takes the panic output as well, when the
+/// board passes `kernel::debug::SinkWriter` to `kernel::debug::panic`.
+impl<A: hil::time::Alarm> DebugSink for SeggerRttChannel<'a, A> {
+    fn set_client(&self, client: &'static DebugSinkClient) {
+        self.debug_client.set(client);
     }
 
-    fn receive(&self, _rx_buf: &'static mut [u8], _rx_len: usize) {}
+    fn transmit(&self, buffer: &'static mut [u8], len: usize) {
+        if self.tx_buffer.is_some() {
+            self.debug_client
+                .map(move |client| client.transmit_done(buffer));
+            return;
+        }
 
-    fn abort_receive(&self) {}
-}
+        let length = cmp::min(len, buffer.len());
+        self.write_up(&buffer[..length]);
 
-impl<A: hil::time::Alarm> hil::time::Client for SeggerRtt<'a, A> {
-    fn fired(&self) {
-        self.client.map(|client| {
-            self.client_buffer.take().map(|buffer| {
-                client.transmit_complete(buffer, hil::uart::Error::CommandComplete);
-            });
-        });
+        self.debug_transmit.set(true);
+        self.tx_buffer.replace(buffer);
+        self.rtt.schedule();
+    }
+
+    fn write_now(&self, bytes: &[u8]) {
+        self.write_up(bytes);
     }
 }
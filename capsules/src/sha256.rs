@@ -0,0 +1,569 @@
+//! Software implementation of SHA-256 and HMAC-SHA256 (FIPS 180-4, RFC 2104).
+//!
+//! `Sha256Software` implements `hil::digest::Digest` on any chip. It hashes
+//! on the kernel's main loop rather than in an interrupt, so it uses an alarm
+//! to return from `add_data()` and `run()` and to break long messages into
+//! steps of `STEP_SIZE` bytes, letting other kernel work run in between.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let sha_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let sha256 = static_init!(
+//!     capsules::sha256::Sha256Software<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::sha256::Sha256Software::new(sha_alarm)
+//! );
+//! sha_alarm.set_client(sha256);
+//! sha256.set_client(client);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::digest;
+use kernel::hil::digest::{SHA256_BLOCK_SIZE, SHA256_DIGEST_SIZE};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+use util::MIN_DELAY_TICKS;
+
+/// Number of bytes hashed each time the alarm fires.
+pub const STEP_SIZE: usize = 8 * SHA256_BLOCK_SIZE;
+
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A synchronous SHA-256 computation, for kernel code that needs a digest of
+/// data it already holds.
+#[derive(Copy, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; SHA256_BLOCK_SIZE],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub const fn new() -> Sha256 {
+        Sha256 {
+            state: INITIAL_STATE,
+            block: [0; SHA256_BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = (self.block[i * 4] as u32) << 24
+                | (self.block[i * 4 + 1] as u32) << 16
+                | (self.block[i * 4 + 2] as u32) << 8
+                | (self.block[i * 4 + 3] as u32);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = self.state;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3].wrapping_add(t1);
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1.wrapping_add(t2);
+        }
+        for i in 0..8 {
+            self.state[i] = self.state[i].wrapping_add(v[i]);
+        }
+    }
+
+    /// Add `data` to the digest.
+    pub fn update(&mut self, data: &[u8]) {
+        self.total_len += data.len() as u64;
+        let mut data = data;
+        while data.len() > 0 {
+            let n = cmp::min(SHA256_BLOCK_SIZE - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len == SHA256_BLOCK_SIZE {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Pad the message and return its digest.
+    pub fn finish(mut self) -> [u8; SHA256_DIGEST_SIZE] {
+        let bit_len = self.total_len * 8;
+
+        // Append a one bit, pad with zeros to 8 bytes short of a block, and
+        // end with the message length in bits.
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > SHA256_BLOCK_SIZE - 8 {
+            self.block[self.block_len..].iter_mut().for_each(|b| *b = 0);
+            self.compress();
+            self.block_len = 0;
+        }
+        self.block[self.block_len..SHA256_BLOCK_SIZE - 8]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+        for i in 0..8 {
+            self.block[SHA256_BLOCK_SIZE - 1 - i] = (bit_len >> (i * 8)) as u8;
+        }
+        self.compress();
+
+        let mut digest = [0; SHA256_DIGEST_SIZE];
+        for (i, word) in self.state.iter().enumerate() {
+            digest[i * 4] = (word >> 24) as u8;
+            digest[i * 4 + 1] = (word >> 16) as u8;
+            digest[i * 4 + 2] = (word >> 8) as u8;
+            digest[i * 4 + 3] = *word as u8;
+        }
+        digest
+    }
+}
+
+const HMAC_IPAD: u8 = 0x36;
+const HMAC_OPAD: u8 = 0x5c;
+
+/// Pad `key` to a block, hashing it first if it is longer than a block.
+fn hmac_block_key(key: &[u8]) -> [u8; SHA256_BLOCK_SIZE] {
+    let mut block_key = [0; SHA256_BLOCK_SIZE];
+    if key.len() > SHA256_BLOCK_SIZE {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        block_key[..SHA256_DIGEST_SIZE].copy_from_slice(&hasher.finish());
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    block_key
+}
+
+/// Synchronously compute the HMAC-SHA256 tag of the concatenation of
+/// `message`'s parts.
+pub fn hmac_sha256(key: &[u8], message: &[&[u8]]) -> [u8; SHA256_DIGEST_SIZE] {
+    let block_key = hmac_block_key(key);
+    let mut pad = block_key;
+    pad.iter_mut().for_each(|b| *b ^= HMAC_IPAD);
+    let mut inner = Sha256::new();
+    inner.update(&pad);
+    for part in message {
+        inner.update(part);
+    }
+
+    let mut pad = block_key;
+    pad.iter_mut().for_each(|b| *b ^= HMAC_OPAD);
+    let mut outer = Sha256::new();
+    outer.update(&pad);
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    Sha256,
+    HmacSha256,
+}
+
+pub struct Sha256Software<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    client: OptionalCell<&'a digest::Client<'a>>,
+    mode: Cell<Option<Mode>>,
+    // The HMAC key, padded or hashed to a block.
+    hmac_key: Cell<[u8; SHA256_BLOCK_SIZE]>,
+    hasher: Cell<Sha256>,
+
+    data: TakeCell<'a, [u8]>,
+    data_len: Cell<usize>,
+    data_offset: Cell<usize>,
+    digest: TakeCell<'a, [u8; SHA256_DIGEST_SIZE]>,
+}
+
+impl<A: Alarm> Sha256Software<'a, A> {
+    pub fn new(alarm: &'a A) -> Sha256Software<'a, A> {
+        Sha256Software {
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            mode: Cell::new(None),
+            hmac_key: Cell::new([0; SHA256_BLOCK_SIZE]),
+            hasher: Cell::new(Sha256::new()),
+            data: TakeCell::empty(),
+            data_len: Cell::new(0),
+            data_offset: Cell::new(0),
+            digest: TakeCell::empty(),
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+
+    /// Start a new digest in the current mode.
+    fn reset(&self) {
+        let mut hasher = Sha256::new();
+        if self.mode.get() == Some(Mode::HmacSha256) {
+            let mut inner_pad = self.hmac_key.get();
+            inner_pad.iter_mut().for_each(|b| *b ^= HMAC_IPAD);
+            hasher.update(&inner_pad);
+        }
+        self.hasher.set(hasher);
+    }
+
+    /// Finish the digest, wrapping it in the outer hash for HMAC.
+    fn finish(&self) -> [u8; SHA256_DIGEST_SIZE] {
+        let inner = self.hasher.get().finish();
+        if self.mode.get() != Some(Mode::HmacSha256) {
+            return inner;
+        }
+        let mut outer_pad = self.hmac_key.get();
+        outer_pad.iter_mut().for_each(|b| *b ^= HMAC_OPAD);
+        let mut outer = Sha256::new();
+        outer.update(&outer_pad);
+        outer.update(&inner);
+        outer.finish()
+    }
+
+    fn schedule_step(&self) {
+        let delay = cmp::max(<A::Frequency>::frequency() / 10000, MIN_DELAY_TICKS);
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(delay));
+    }
+}
+
+impl<A: Alarm> digest::Digest<'a> for Sha256Software<'a, A> {
+    fn set_client(&'a self, client: &'a digest::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_mode_sha256(&self) -> ReturnCode {
+        if self.is_busy() {
+            return ReturnCode::EBUSY;
+        }
+        self.mode.set(Some(Mode::Sha256));
+        self.reset();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_mode_hmacsha256(&self, key: &[u8]) -> ReturnCode {
+        if self.is_busy() {
+            return ReturnCode::EBUSY;
+        }
+        self.hmac_key.set(hmac_block_key(key));
+        self.mode.set(Some(Mode::HmacSha256));
+        self.reset();
+        ReturnCode::SUCCESS
+    }
+
+    fn add_data(&self, data: &'a mut [u8], length: usize) -> (ReturnCode, Option<&'a mut [u8]>) {
+        if self.is_busy() {
+            return (ReturnCode::EBUSY, Some(data));
+        }
+        if self.mode.get().is_none() {
+            return (ReturnCode::EOFF, Some(data));
+        }
+        if length > data.len() {
+            return (ReturnCode::EINVAL, Some(data));
+        }
+        self.data.replace(data);
+        self.data_len.set(length);
+        self.data_offset.set(0);
+        self.schedule_step();
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn run(
+        &self,
+        digest: &'a mut [u8; SHA256_DIGEST_SIZE],
+    ) -> (ReturnCode, Option<&'a mut [u8; SHA256_DIGEST_SIZE]>) {
+        if self.is_busy() {
+            return (ReturnCode::EBUSY, Some(digest));
+        }
+        if self.mode.get().is_none() {
+            return (ReturnCode::EOFF, Some(digest));
+        }
+        self.digest.replace(digest);
+        self.schedule_step();
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn clear_data(&self) {
+        if !self.is_busy() && self.mode.get().is_some() {
+            self.reset();
+        }
+    }
+}
+
+impl<A: Alarm> time::Client for Sha256Software<'a, A> {
+    fn fired(&self) {
+        if self.data.is_some() {
+            let offset = self.data_offset.get();
+            let end = cmp::min(offset + STEP_SIZE, self.data_len.get());
+            self.data.map(|data| {
+                let mut hasher = self.hasher.get();
+                hasher.update(&data[offset..end]);
+                self.hasher.set(hasher);
+            });
+            self.data_offset.set(end);
+
+            if end < self.data_len.get() {
+                self.schedule_step();
+            } else {
+                self.data.take().map(|data| {
+                    self.client
+                        .map(move |client| client.add_data_done(ReturnCode::SUCCESS, data));
+                });
+            }
+        } else {
+            self.digest.take().map(|digest| {
+                digest.copy_from_slice(&self.finish());
+                self.reset();
+                self.client
+                    .map(move |client| client.hash_done(ReturnCode::SUCCESS, digest));
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use self::std::iter;
+    use self::std::vec::Vec;
+    use super::*;
+    use kernel::hil::digest::Digest;
+
+    /// Alarm that the test fires by hand.
+    struct TestAlarm {
+        armed: Cell<bool>,
+    }
+
+    impl time::Time for TestAlarm {
+        type Frequency = time::Freq32KHz;
+
+        fn disable(&self) {
+            self.armed.set(false);
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+    }
+
+    impl Alarm for TestAlarm {
+        fn now(&self) -> u32 {
+            0
+        }
+
+        fn set_alarm(&self, _tics: u32) {
+            self.armed.set(true);
+        }
+
+        fn get_alarm(&self) -> u32 {
+            0
+        }
+    }
+
+    /// Keeps the buffers handed back by the engine.
+    struct TestClient {
+        data: TakeCell<'static, [u8]>,
+        digest: TakeCell<'static, [u8; SHA256_DIGEST_SIZE]>,
+    }
+
+    impl digest::Client<'static> for TestClient {
+        fn add_data_done(&'static self, result: ReturnCode, data: &'static mut [u8]) {
+            assert_eq!(result, ReturnCode::SUCCESS);
+            self.data.replace(data);
+        }
+
+        fn hash_done(
+            &'static self,
+            result: ReturnCode,
+            digest: &'static mut [u8; SHA256_DIGEST_SIZE],
+        ) {
+            assert_eq!(result, ReturnCode::SUCCESS);
+            self.digest.replace(digest);
+        }
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn sha256(message: &[u8]) -> [u8; SHA256_DIGEST_SIZE] {
+        let mut hasher = Sha256::new();
+        hasher.update(message);
+        hasher.finish()
+    }
+
+    fn engine() -> (
+        &'static Sha256Software<'static, TestAlarm>,
+        &'static TestAlarm,
+        &'static TestClient,
+    ) {
+        let alarm = Box::leak(Box::new(TestAlarm {
+            armed: Cell::new(false),
+        }));
+        let sha = Box::leak(Box::new(Sha256Software::new(&*alarm)));
+        let client = Box::leak(Box::new(TestClient {
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+        }));
+        sha.set_client(&*client);
+        (sha, alarm, client)
+    }
+
+    /// Fire the alarm until the engine stops scheduling steps. Returns the
+    /// number of times it fired.
+    fn run_alarm(sha: &Sha256Software<TestAlarm>, alarm: &TestAlarm) -> usize {
+        let mut fired = 0;
+        while alarm.armed.get() {
+            alarm.armed.set(false);
+            time::Client::fired(sha);
+            fired += 1;
+        }
+        fired
+    }
+
+    /// Hash `message` with the engine, returning the digest and the number
+    /// of steps `add_data` took.
+    fn engine_digest(
+        sha: &'static Sha256Software<'static, TestAlarm>,
+        alarm: &TestAlarm,
+        client: &TestClient,
+        message: Vec<u8>,
+    ) -> ([u8; SHA256_DIGEST_SIZE], usize) {
+        let len = message.len();
+        let data = Box::leak(message.into_boxed_slice());
+        let (res, _) = sha.add_data(data, len);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        let steps = run_alarm(sha, alarm);
+        assert!(client.data.take().is_some());
+
+        let digest = Box::leak(Box::new([0; SHA256_DIGEST_SIZE]));
+        let (res, _) = sha.run(digest);
+        assert_eq!(res, ReturnCode::SUCCESS);
+        run_alarm(sha, alarm);
+        (*client.digest.take().unwrap(), steps)
+    }
+
+    /// FIPS 180-4 examples that fit in one or two blocks.
+    #[test]
+    fn fips_180_4_short_messages() {
+        assert_eq!(
+            &sha256(b"")[..],
+            &from_hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")[..]
+        );
+        assert_eq!(
+            &sha256(b"abc")[..],
+            &from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")[..]
+        );
+        assert_eq!(
+            &sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")[..],
+            &from_hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")[..]
+        );
+    }
+
+    /// The FIPS 180-4 one million "a" message, hashed by the engine in
+    /// steps of `STEP_SIZE` bytes.
+    #[test]
+    fn fips_180_4_long_message_in_steps() {
+        let (sha, alarm, client) = engine();
+        assert_eq!(sha.set_mode_sha256(), ReturnCode::SUCCESS);
+
+        let message = iter::repeat(b'a').take(1000000).collect();
+        let (digest, steps) = engine_digest(sha, alarm, client, message);
+        assert_eq!(steps, (1000000 + STEP_SIZE - 1) / STEP_SIZE);
+        assert_eq!(
+            &digest[..],
+            &from_hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")[..]
+        );
+    }
+
+    /// The empty message, which still needs a digest step.
+    #[test]
+    fn engine_empty_message() {
+        let (sha, alarm, client) = engine();
+        assert_eq!(sha.set_mode_sha256(), ReturnCode::SUCCESS);
+
+        let (digest, _) = engine_digest(sha, alarm, client, Vec::new());
+        assert_eq!(&digest[..], &sha256(b"")[..]);
+    }
+
+    /// RFC 4231 test cases 1, 2, 3 and 6.
+    #[test]
+    fn rfc_4231_hmac() {
+        let cases: [(Vec<u8>, Vec<u8>, &str); 4] = [
+            (
+                [0x0b; 20].to_vec(),
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                [0xaa; 20].to_vec(),
+                [0xdd; 50].to_vec(),
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                [0xaa; 131].to_vec(),
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+
+        for &(ref key, ref data, expected) in cases.iter() {
+            let expected = from_hex(expected);
+            assert_eq!(&hmac_sha256(key, &[data])[..], &expected[..]);
+
+            let (sha, alarm, client) = engine();
+            assert_eq!(sha.set_mode_hmacsha256(key), ReturnCode::SUCCESS);
+            let (digest, _) = engine_digest(sha, alarm, client, data.clone());
+            assert_eq!(&digest[..], &expected[..]);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Real-time clock on top of a monotonic clock.
+//!
+//! `SoftwareRtc` implements `hil::rtc::Rtc` for boards without an RTC. It
+//! stores the offset between calendar time and a `Monotonic` clock, so it
+//! keeps time for as long as the kernel runs, but it forgets the time on
+//! reset and has to be set again, for example by a process that gets the time
+//! from the network or a GPS receiver. Until then, reads return `ERESERVE`.
+//!
+//! The alarm is used for the RTC alarm, and to defer request callbacks until
+//! the next scheduler loop, as in `capsules::segger_rtt`. It must count the
+//! same ticks as the monotonic clock, which is the case for virtual alarms on
+//! the same `MuxAlarm`.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let rtc_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let rtc = static_init!(
+//!     capsules::software_rtc::SoftwareRtc<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::software_rtc::SoftwareRtc::new(rtc_alarm, clock)
+//! );
+//! rtc_alarm.set_client(rtc);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::rtc::{self, DateTime};
+use kernel::hil::time::{self, Alarm, Duration, Monotonic};
+use kernel::ReturnCode;
+
+/// Delay before callbacks of completed requests, in microseconds.
+const CALLBACK_DELAY_US: u64 = 100;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Request {
+    Get,
+    Set,
+}
+
+pub struct SoftwareRtc<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    clock: &'a Monotonic<Frequency = A::Frequency>,
+    // Microseconds since 1970 when the monotonic clock started, or `None` if
+    // the time has not been set.
+    offset: Cell<Option<u64>>,
+    // Seconds since 1970 at which the RTC alarm fires.
+    alarm_at: Cell<Option<u64>>,
+    // Request whose callback is waiting for the alarm.
+    request: Cell<Option<Request>>,
+    client: OptionalCell<&'a rtc::Client>,
+}
+
+impl<A: Alarm> SoftwareRtc<'a, A> {
+    pub fn new(alarm: &'a A, clock: &'a Monotonic<Frequency = A::Frequency>) -> SoftwareRtc<'a, A> {
+        SoftwareRtc {
+            alarm: alarm,
+            clock: clock,
+            offset: Cell::new(None),
+            alarm_at: Cell::new(None),
+            request: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Microseconds since 1970, if the time has been set.
+    fn now_micros(&self) -> Option<u64> {
+        let uptime = self.clock.now().since_start().as_micros();
+        self.offset.get().map(|offset| offset.wrapping_add(uptime))
+    }
+
+    /// Set the alarm for the next thing to do: a request callback, or the RTC
+    /// alarm. Alarms more than 2^30 ticks away are reached in steps, as the
+    /// counter wraps.
+    fn schedule(&self) {
+        let min_delay = Duration::from_micros(CALLBACK_DELAY_US);
+        let delay = if self.request.get().is_some() {
+            min_delay
+        } else {
+            match (self.alarm_at.get(), self.now_micros()) {
+                (Some(at), Some(now)) => {
+                    let remaining = at.saturating_mul(1_000_000).saturating_sub(now);
+                    cmp::max(Duration::from_micros(remaining), min_delay)
+                }
+                _ => {
+                    self.alarm.disable();
+                    return;
+                }
+            }
+        };
+        let ticks = cmp::min(delay.ticks_for::<A::Frequency>(), 1 << 30) as u32;
+        self.alarm
+            .set_alarm(self.alarm.now().wrapping_add(cmp::max(ticks, 1)));
+    }
+}
+
+impl<A: Alarm> rtc::Rtc<'a> for SoftwareRtc<'a, A> {
+    fn set_client(&self, client: &'a rtc::Client) {
+        self.client.set(client);
+    }
+
+    fn get_date_time(&self) -> ReturnCode {
+        if self.request.get().is_some() {
+            ReturnCode::EBUSY
+        } else if self.offset.get().is_none() {
+            ReturnCode::ERESERVE
+        } else {
+            self.request.set(Some(Request::Get));
+            self.schedule();
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn set_date_time(&self, date_time: DateTime) -> ReturnCode {
+        if self.request.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if !date_time.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        let uptime = self.clock.now().since_start().as_micros();
+        let micros = date_time.unix_secs() * 1_000_000;
+        self.offset.set(Some(micros.wrapping_sub(uptime)));
+        self.request.set(Some(Request::Set));
+        self.schedule();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_alarm(&self, date_time: DateTime) -> ReturnCode {
+        if !date_time.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        self.alarm_at.set(Some(date_time.unix_secs()));
+        self.schedule();
+        ReturnCode::SUCCESS
+    }
+
+    fn disable_alarm(&self) -> ReturnCode {
+        if self.alarm_at.get().is_none() {
+            return ReturnCode::EALREADY;
+        }
+        self.alarm_at.set(None);
+        self.schedule();
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<A: Alarm> time::Client for SoftwareRtc<'a, A> {
+    fn fired(&self) {
+        let now = self.now_micros();
+
+        if let Some(request) = self.request.get() {
+            self.request.set(None);
+            self.client.map(|client| match request {
+                Request::Get => match now {
+                    Some(now) => client.get_date_time_done(
+                        ReturnCode::SUCCESS,
+                        DateTime::from_unix_secs(now / 1_000_000),
+                    ),
+                    None => client.get_date_time_done(ReturnCode::ERESERVE, DateTime::EPOCH),
+                },
+                Request::Set => client.set_date_time_done(ReturnCode::SUCCESS),
+            });
+        }
+
+        if let (Some(at), Some(now)) = (self.alarm_at.get(), now) {
+            if now / 1_000_000 >= at {
+                self.alarm_at.set(None);
+                self.client.map(|client| client.alarm_fired());
+            }
+        }
+
+        self.schedule();
+    }
+}
@@ -0,0 +1,177 @@
+//! Helpers shared by several capsules.
+
+use kernel::common::cells::OptionalCell;
+use kernel::{AppId, Callback, Grant, ReturnCode};
+
+/// Offset basis of the 32-bit FNV-1a hash.
+pub const FNV1A_INIT: u32 = 0x811c9dc5;
+
+/// Add `data` to a 32-bit FNV-1a hash, which starts at `FNV1A_INIT`.
+pub fn fnv1a_update(hash: u32, data: &[u8]) -> u32 {
+    data.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// 32-bit FNV-1a hash of `data`.
+pub fn fnv1a(data: &[u8]) -> u32 {
+    fnv1a_update(FNV1A_INIT, data)
+}
+
+/// Read a little endian `u16` from the start of `bytes`.
+pub fn read_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+/// Read a little endian `u32` from the start of `bytes`.
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Write `value` to the start of `bytes`, little endian.
+pub fn write_u16(bytes: &mut [u8], value: u16) {
+    bytes[0] = value as u8;
+    bytes[1] = (value >> 8) as u8;
+}
+
+/// Write `value` to the start of `bytes`, little endian.
+pub fn write_u32(bytes: &mut [u8], value: u32) {
+    bytes[0] = value as u8;
+    bytes[1] = (value >> 8) as u8;
+    bytes[2] = (value >> 16) as u8;
+    bytes[3] = (value >> 24) as u8;
+}
+
+/// Fewest alarm ticks before a callback that a capsule defers with an alarm.
+/// Such callbacks are otherwise set about 100 us ahead: far enough that the
+/// alarm is not already in the past by the time it is set, which would delay
+/// it by a whole counter wrap.
+pub const MIN_DELAY_TICKS: u32 = 4;
+
+const CRC_TABLE: [u32; 16] = [
+    0x00000000, 0x1db71064, 0x3b6e20c8, 0x26d930ac, 0x76dc4190, 0x6b6b51f4, 0x4db26158, 0x5005713c,
+    0xedb88320, 0xf00f9344, 0xd6d6a3e8, 0xcb61b38c, 0x9b64c2b0, 0x86d3d2d4, 0xa00ae278, 0xbdbdf21c,
+];
+
+/// Update a CRC-32 (IEEE 802.3) with `data`, four bits at a time.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = (crc >> 4) ^ CRC_TABLE[((crc ^ *byte as u32) & 0xf) as usize];
+        crc = (crc >> 4) ^ CRC_TABLE[((crc ^ (*byte as u32 >> 4)) & 0xf) as usize];
+    }
+    crc
+}
+
+/// State of a process whose requests a driver serves one at a time, in turn
+/// with the requests of other processes.
+pub trait QueuedApp: Default {
+    type Request: Copy;
+
+    /// The request the process is waiting for or being served, if any.
+    fn waiting(&mut self) -> &mut Option<Self::Request>;
+
+    fn callback(&mut self) -> &mut Option<Callback>;
+}
+
+/// Serves the requests of processes one at a time.
+pub struct AppQueue {
+    serving_app: OptionalCell<AppId>,
+}
+
+impl AppQueue {
+    pub fn new() -> AppQueue {
+        AppQueue {
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Queue `request` for `appid`, unless it already has a request.
+    pub fn enqueue<T: QueuedApp>(
+        &self,
+        apps: &Grant<T>,
+        appid: AppId,
+        request: T::Request,
+    ) -> ReturnCode {
+        apps.enter(appid, |app, _| {
+            if app.waiting().is_some() {
+                ReturnCode::EBUSY
+            } else {
+                *app.waiting() = Some(request);
+                ReturnCode::SUCCESS
+            }
+        })
+        .unwrap_or_else(|err| err.into())
+    }
+
+    /// Start the request of the next waiting process with `start`, unless a
+    /// process is being served. Processes whose request cannot be started
+    /// are told so through their callback.
+    pub fn serve_next<T, F>(&self, apps: &Grant<T>, start: F)
+    where
+        T: QueuedApp,
+        F: Fn(&mut T, T::Request) -> ReturnCode,
+    {
+        if self.serving_app.is_some() {
+            return;
+        }
+
+        for cntr in apps.iter() {
+            let appid = cntr.enter(|app, _| {
+                let waiting = *app.waiting();
+                waiting.and_then(|request| {
+                    let res = start(app, request);
+                    if res == ReturnCode::SUCCESS {
+                        Some(app.appid())
+                    } else {
+                        complete::<T>(app, res, 0, 0);
+                        None
+                    }
+                })
+            });
+            if appid.is_some() {
+                self.serving_app.insert(appid);
+                break;
+            }
+        }
+    }
+
+    /// Run `f` on the process being served and its request.
+    pub fn with_serving<T, F, R>(&self, apps: &Grant<T>, f: F) -> Option<R>
+    where
+        T: QueuedApp,
+        F: FnOnce(&mut T, T::Request) -> R,
+        R: Copy,
+    {
+        let appid = self.serving_app.map(|appid| *appid)?;
+        apps.enter(appid, |app, _| {
+            let waiting = *app.waiting();
+            waiting.map(|request| f(app, request))
+        })
+        .unwrap_or(None)
+    }
+
+    /// End the request of the process being served, calling it back with the
+    /// status and values returned by `f`. The next request is not started.
+    pub fn finish<T, F>(&self, apps: &Grant<T>, f: F)
+    where
+        T: QueuedApp,
+        F: FnOnce(&mut T, T::Request) -> (ReturnCode, usize, usize),
+    {
+        self.serving_app.take().map(|appid| {
+            let _ = apps.enter(appid, |app, _| {
+                let waiting = *app.waiting();
+                waiting.map(|request| {
+                    let (res, data1, data2) = f(app, request);
+                    complete::<T>(app, res, data1, data2);
+                });
+            });
+        });
+    }
+}
+
+/// End the request of `app` and tell it so through its callback.
+fn complete<T: QueuedApp>(app: &mut T, res: ReturnCode, data1: usize, data2: usize) {
+    *app.waiting() = None;
+    app.callback()
+        .map(|mut cb| cb.schedule(From::from(res), data1, data2));
+}
@@ -0,0 +1,395 @@
+//! Virtualize an ADC.
+//!
+//! `MuxAdc` provides shared access to a single ADC for multiple users.
+//! `AdcDevice` gives each user its own `hil::adc::Adc` and
+//! `hil::adc::AdcHighSpeed` interface.
+//!
+//! Single samples requested by different devices are queued and taken one at
+//! a time, in turn: the next sample is taken for the first waiting device
+//! after the one served last, so a device that samples again from its
+//! callback waits behind the others. Continuous and high-speed sampling sessions need the ADC to
+//! themselves: a device can only start one while the ADC is idle, and while it
+//! runs every other device's requests fail with `EBUSY`.
+//!
+//! Queued samples keep a copy of their channel, so the ADC's channel type must
+//! be `Copy`.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let mux_adc = static_init!(
+//!     capsules::virtual_adc::MuxAdc<'static, sam4l::adc::Adc>,
+//!     capsules::virtual_adc::MuxAdc::new(&sam4l::adc::ADC0)
+//! );
+//! sam4l::adc::ADC0.set_client(mux_adc);
+//!
+//! let adc_device = static_init!(
+//!     capsules::virtual_adc::AdcDevice<'static, sam4l::adc::Adc>,
+//!     capsules::virtual_adc::AdcDevice::new(mux_adc)
+//! );
+//! adc_device.set_client(client);
+//! adc_device.set_highspeed_client(client);
+//! ```
+
+use core::cell::Cell;
+use core::ptr;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil;
+use kernel::ReturnCode;
+
+pub struct MuxAdc<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a>
+where
+    A::Channel: Copy,
+{
+    adc: &'a A,
+    devices: List<'a, AdcDevice<'a, A>>,
+    // Device whose single sample the ADC is currently taking.
+    inflight: OptionalCell<&'a AdcDevice<'a, A>>,
+    // Device whose single sample was started last.
+    last_served: OptionalCell<&'a AdcDevice<'a, A>>,
+    // Device that has the ADC to itself for a continuous or high-speed
+    // session.
+    session: OptionalCell<&'a AdcDevice<'a, A>>,
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> MuxAdc<'a, A>
+where
+    A::Channel: Copy,
+{
+    pub const fn new(adc: &'a A) -> MuxAdc<'a, A> {
+        MuxAdc {
+            adc: adc,
+            devices: List::new(),
+            inflight: OptionalCell::empty(),
+            last_served: OptionalCell::empty(),
+            session: OptionalCell::empty(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inflight.is_none() && self.session.is_none()
+    }
+
+    fn holds_session(&self, device: &AdcDevice<'a, A>) -> bool {
+        self.session
+            .map_or(false, |holder| ptr::eq(*holder, device))
+    }
+
+    fn is_inflight(&self, device: &AdcDevice<'a, A>) -> bool {
+        self.inflight
+            .map_or(false, |sampling| ptr::eq(*sampling, device))
+    }
+
+    /// Find `device` among the devices that set a client. This gives a
+    /// reference that lives long enough to be kept in the mux.
+    fn find(&self, device: &AdcDevice<'a, A>) -> Option<&'a AdcDevice<'a, A>> {
+        self.devices.iter().find(|node| ptr::eq(*node, device))
+    }
+
+    /// Start a single sample for `device`.
+    fn start_sample(&self, device: &'a AdcDevice<'a, A>, channel: &A::Channel) -> ReturnCode {
+        let res = self.adc.sample(channel);
+        if res == ReturnCode::SUCCESS {
+            self.inflight.set(device);
+            self.last_served.set(device);
+        }
+        res
+    }
+
+    /// Start the next queued single sample, if the ADC is free. Devices are
+    /// served in turn, starting after the one served last.
+    fn do_next_op(&self) {
+        while self.is_idle() {
+            let start = self.last_served.map_or(0, |last| {
+                self.devices
+                    .iter()
+                    .position(|node| ptr::eq(node, *last))
+                    .map_or(0, |pos| pos + 1)
+            });
+            let mnode = self
+                .devices
+                .iter()
+                .skip(start)
+                .chain(self.devices.iter().take(start))
+                .find(|node| node.channel.is_some());
+            match mnode {
+                Some(node) => {
+                    node.channel.take().map(|channel| {
+                        // A request that fails to start is dropped and the
+                        // next device gets its turn.
+                        self.start_sample(node, &channel);
+                    });
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for MuxAdc<'a, A>
+where
+    A::Channel: Copy,
+{
+    fn sample_ready(&self, sample: u16) {
+        if let Some(device) = self.session.map(|holder| *holder) {
+            // Continuous sampling session, which keeps the ADC.
+            device.client.map(|client| client.sample_ready(sample));
+        } else {
+            // Start the next queued sample before the callback, so that a
+            // client sampling again from its callback waits its turn.
+            let device = self.inflight.take();
+            self.do_next_op();
+            device.map(|device| {
+                device.client.map(|client| client.sample_ready(sample));
+            });
+        }
+    }
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::HighSpeedClient for MuxAdc<'a, A>
+where
+    A::Channel: Copy,
+{
+    fn samples_ready(&self, buf: &'static mut [u16], length: usize) {
+        match self.session.map(|holder| *holder) {
+            Some(device) => {
+                device
+                    .highspeed_client
+                    .map(move |client| client.samples_ready(buf, length));
+            }
+            None => {
+                // Stopping a session reclaims its buffers through
+                // `retrieve_buffers`, so the ADC has no buffer left to hand
+                // back once the session is gone.
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Session {
+    Continuous,
+    HighSpeed,
+}
+
+pub struct AdcDevice<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a>
+where
+    A::Channel: Copy,
+{
+    mux: &'a MuxAdc<'a, A>,
+    // Channel of a queued single sample.
+    channel: OptionalCell<A::Channel>,
+    session: Cell<Option<Session>>,
+    // Buffers reclaimed from the ADC when a high-speed session stopped.
+    stopped_buffer1: TakeCell<'static, [u16]>,
+    stopped_buffer2: TakeCell<'static, [u16]>,
+    next: ListLink<'a, AdcDevice<'a, A>>,
+    client: OptionalCell<&'a hil::adc::Client>,
+    highspeed_client: OptionalCell<&'a hil::adc::HighSpeedClient>,
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDevice<'a, A>
+where
+    A::Channel: Copy,
+{
+    pub const fn new(mux: &'a MuxAdc<'a, A>) -> AdcDevice<'a, A> {
+        AdcDevice {
+            mux: mux,
+            channel: OptionalCell::empty(),
+            session: Cell::new(None),
+            stopped_buffer1: TakeCell::empty(),
+            stopped_buffer2: TakeCell::empty(),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            highspeed_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the client for single and continuous samples. This also adds the
+    /// device to the mux, so every device must set a client.
+    pub fn set_client(&'a self, client: &'a hil::adc::Client) {
+        self.mux.devices.push_head(self);
+        self.client.set(client);
+    }
+
+    /// Set the client for high-speed buffered sessions.
+    pub fn set_highspeed_client(&self, client: &'a hil::adc::HighSpeedClient) {
+        self.highspeed_client.set(client);
+    }
+
+    /// Whether the ADC is free for this device to start a session.
+    fn can_start_session(&self) -> bool {
+        self.mux.is_idle() && self.channel.is_none()
+    }
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> ListNode<'a, AdcDevice<'a, A>> for AdcDevice<'a, A>
+where
+    A::Channel: Copy,
+{
+    fn next(&'a self) -> &'a ListLink<'a, AdcDevice<'a, A>> {
+        &self.next
+    }
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Adc for AdcDevice<'a, A>
+where
+    A::Channel: Copy,
+{
+    type Channel = <A as hil::adc::Adc>::Channel;
+
+    /// Queue a single sample. Returns `EBUSY` if this device already has a
+    /// sample outstanding or any device has a session.
+    fn sample(&self, channel: &Self::Channel) -> ReturnCode {
+        if self.channel.is_some() || self.mux.is_inflight(self) || self.mux.session.is_some() {
+            return ReturnCode::EBUSY;
+        }
+
+        self.mux.find(self).map_or(ReturnCode::FAIL, |device| {
+            if self.mux.is_idle() {
+                self.mux.start_sample(device, channel)
+            } else {
+                device.channel.set(*channel);
+                ReturnCode::SUCCESS
+            }
+        })
+    }
+
+    /// Start an exclusive continuous sampling session. Returns `EBUSY` unless
+    /// the ADC is idle.
+    fn sample_continuous(&self, channel: &Self::Channel, frequency: u32) -> ReturnCode {
+        if !self.can_start_session() {
+            return ReturnCode::EBUSY;
+        }
+
+        self.mux.find(self).map_or(ReturnCode::FAIL, |device| {
+            let res = self.mux.adc.sample_continuous(channel, frequency);
+            if res == ReturnCode::SUCCESS {
+                self.mux.session.set(device);
+                self.session.set(Some(Session::Continuous));
+            }
+            res
+        })
+    }
+
+    /// Stop this device's queued sample, sample in progress or session.
+    /// Returns `SUCCESS` if this device was not sampling.
+    fn stop_sampling(&self) -> ReturnCode {
+        if self.channel.take().is_some() {
+            return ReturnCode::SUCCESS;
+        }
+
+        if self.mux.is_inflight(self) {
+            self.mux.inflight.clear();
+            let res = self.mux.adc.stop_sampling();
+            self.mux.do_next_op();
+            return res;
+        }
+
+        if self.mux.holds_session(self) {
+            let res = self.mux.adc.stop_sampling();
+            if res != ReturnCode::SUCCESS {
+                return res;
+            }
+            if self.session.get() == Some(Session::HighSpeed) {
+                // Reclaim the buffers now, so the next session cannot
+                // overwrite them before this device retrieves them.
+                let (_, buf1, buf2) = self.mux.adc.retrieve_buffers();
+                self.stopped_buffer1.put(buf1);
+                self.stopped_buffer2.put(buf2);
+            }
+            self.mux.session.clear();
+            self.session.set(None);
+            self.mux.do_next_op();
+            return res;
+        }
+
+        ReturnCode::SUCCESS
+    }
+
+    fn get_resolution_bits(&self) -> usize {
+        self.mux.adc.get_resolution_bits()
+    }
+
+    fn get_voltage_reference_mv(&self) -> Option<usize> {
+        self.mux.adc.get_voltage_reference_mv()
+    }
+}
+
+impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::AdcHighSpeed for AdcDevice<'a, A>
+where
+    A::Channel: Copy,
+{
+    /// Start an exclusive high-speed session. Returns `EBUSY` and the buffers
+    /// unless the ADC is idle.
+    fn sample_highspeed(
+        &self,
+        channel: &Self::Channel,
+        frequency: u32,
+        buffer1: &'static mut [u16],
+        length1: usize,
+        buffer2: &'static mut [u16],
+        length2: usize,
+    ) -> (
+        ReturnCode,
+        Option<&'static mut [u16]>,
+        Option<&'static mut [u16]>,
+    ) {
+        // Buffers from an earlier session must be retrieved first, so that
+        // stopping this one does not overwrite them.
+        if !self.can_start_session()
+            || self.stopped_buffer1.is_some()
+            || self.stopped_buffer2.is_some()
+        {
+            return (ReturnCode::EBUSY, Some(buffer1), Some(buffer2));
+        }
+        let device = match self.mux.find(self) {
+            Some(device) => device,
+            None => return (ReturnCode::FAIL, Some(buffer1), Some(buffer2)),
+        };
+
+        let (res, buf1, buf2) = self
+            .mux
+            .adc
+            .sample_highspeed(channel, frequency, buffer1, length1, buffer2, length2);
+        if res == ReturnCode::SUCCESS {
+            self.mux.session.set(device);
+            self.session.set(Some(Session::HighSpeed));
+        }
+        (res, buf1, buf2)
+    }
+
+    /// Give the ADC another buffer for this device's high-speed session.
+    /// Returns `EBUSY` and the buffer if this device has no session.
+    fn provide_buffer(
+        &self,
+        buf: &'static mut [u16],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u16]>) {
+        if !self.mux.holds_session(self) || self.session.get() != Some(Session::HighSpeed) {
+            return (ReturnCode::EBUSY, Some(buf));
+        }
+        self.mux.adc.provide_buffer(buf, length)
+    }
+
+    /// Return the buffers reclaimed when this device's high-speed session
+    /// stopped. Returns `EBUSY` while the session is still running.
+    fn retrieve_buffers(
+        &self,
+    ) -> (
+        ReturnCode,
+        Option<&'static mut [u16]>,
+        Option<&'static mut [u16]>,
+    ) {
+        if self.mux.holds_session(self) {
+            return (ReturnCode::EBUSY, None, None);
+        }
+        (
+            ReturnCode::SUCCESS,
+            self.stopped_buffer1.take(),
+            self.stopped_buffer2.take(),
+        )
+    }
+}
@@ -0,0 +1,276 @@
+//! Virtualize an AES engine.
+//!
+//! `MuxAES128` shares a single AES engine among several users. Each user gets
+//! a `VirtualAES128Device` implementing `AES128`, `AES128Ctr` and
+//! `AES128CBC`, with its own key, IV and mode. The mux loads a device's
+//! settings into the engine just before running that device's `crypt()`, and
+//! requests made while the engine is busy are queued until it is free.
+//!
+//! The engine only carries its chaining state from one `crypt()` to the next
+//! if no other device ran in between. Users that share the engine should
+//! therefore call `start_message()` with a fresh IV before every request, as
+//! `capsules::aes_ccm` does.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let mux_aes = static_init!(
+//!     capsules::virtual_aes::MuxAES128<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::virtual_aes::MuxAES128::new(&sam4l::aes::AES)
+//! );
+//! sam4l::aes::AES.set_client(mux_aes);
+//!
+//! let aes_device = static_init!(
+//!     capsules::virtual_aes::VirtualAES128Device<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::virtual_aes::VirtualAES128Device::new(mux_aes)
+//! );
+//! aes_device.set_client(client);
+//! aes_device.enable();
+//! ```
+
+use core::cell::Cell;
+use core::ptr;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128, AES128CBC, AES128Ctr, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::ReturnCode;
+
+pub struct MuxAES128<'a, A: AES128<'a> + AES128Ctr + AES128CBC + 'a> {
+    aes: &'a A,
+    devices: List<'a, VirtualAES128Device<'a, A>>,
+    // Device whose request the engine is currently working on.
+    inflight: OptionalCell<&'a VirtualAES128Device<'a, A>>,
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> MuxAES128<'a, A> {
+    pub const fn new(aes: &'a A) -> MuxAES128<'a, A> {
+        MuxAES128 {
+            aes: aes,
+            devices: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    fn is_inflight(&self, device: &VirtualAES128Device<'a, A>) -> bool {
+        self.inflight
+            .map_or(false, |running| ptr::eq(*running, device))
+    }
+
+    /// Load the settings of `device` into the engine and start its request.
+    fn start(
+        &self,
+        device: &'a VirtualAES128Device<'a, A>,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        let res = self.aes.set_key(&device.key.get());
+        if res != ReturnCode::SUCCESS {
+            return Some((res, source, dest));
+        }
+        let res = self.aes.set_iv(&device.iv.get());
+        if res != ReturnCode::SUCCESS {
+            return Some((res, source, dest));
+        }
+        match device.mode.get() {
+            Mode::Ctr(encrypting) => self.aes.set_mode_aes128ctr(encrypting),
+            Mode::Cbc(encrypting) => self.aes.set_mode_aes128cbc(encrypting),
+        }
+        if device.new_message.get() {
+            self.aes.start_message();
+            device.new_message.set(false);
+        }
+
+        let res = self.aes.crypt(source, dest, start_index, stop_index);
+        if res.is_none() {
+            self.inflight.set(device);
+        }
+        res
+    }
+
+    /// Start queued requests until one of them keeps the engine busy.
+    fn do_next_op(&self) {
+        while self.inflight.is_none() {
+            let mnode = self.devices.iter().find(|node| node.dest.is_some());
+            match mnode {
+                Some(node) => {
+                    let (start_index, stop_index) = node.indices.get();
+                    node.dest.take().map(|dest| {
+                        let source = node.source.take();
+                        self.start(node, source, dest, start_index, stop_index).map(
+                            |(_, source, dest)| {
+                                // The request was checked when it was queued,
+                                // so this should not happen. There is no way
+                                // to report the error, so hand the buffers
+                                // back untouched rather than lose them.
+                                node.client
+                                    .map(move |client| client.crypt_done(source, dest));
+                            },
+                        );
+                    });
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a> for MuxAES128<'a, A> {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        self.inflight.take().map(move |device| {
+            device
+                .client
+                .map(move |client| client.crypt_done(source, dest));
+        });
+        self.do_next_op();
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    Ctr(bool),
+    Cbc(bool),
+}
+
+pub struct VirtualAES128Device<'a, A: AES128<'a> + AES128Ctr + AES128CBC + 'a> {
+    mux: &'a MuxAES128<'a, A>,
+    enabled: Cell<bool>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    mode: Cell<Mode>,
+    new_message: Cell<bool>,
+    // Buffers and indices of a queued request.
+    source: TakeCell<'a, [u8]>,
+    dest: TakeCell<'a, [u8]>,
+    indices: Cell<(usize, usize)>,
+    next: ListLink<'a, VirtualAES128Device<'a, A>>,
+    client: OptionalCell<&'a symmetric_encryption::Client<'a>>,
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> VirtualAES128Device<'a, A> {
+    pub const fn new(mux: &'a MuxAES128<'a, A>) -> VirtualAES128Device<'a, A> {
+        VirtualAES128Device {
+            mux: mux,
+            enabled: Cell::new(false),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            mode: Cell::new(Mode::Ctr(true)),
+            new_message: Cell::new(false),
+            source: TakeCell::empty(),
+            dest: TakeCell::empty(),
+            indices: Cell::new((0, 0)),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.dest.is_some() || self.mux.is_inflight(self)
+    }
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> ListNode<'a, VirtualAES128Device<'a, A>>
+    for VirtualAES128Device<'a, A>
+{
+    fn next(&'a self) -> &'a ListLink<'a, VirtualAES128Device<'a, A>> {
+        &self.next
+    }
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> AES128<'a> for VirtualAES128Device<'a, A> {
+    fn enable(&self) {
+        self.enabled.set(true);
+        self.mux.aes.enable();
+    }
+
+    /// Disable this device. The engine itself is only disabled once every
+    /// device has been disabled.
+    fn disable(&self) {
+        self.enabled.set(false);
+        if self.mux.devices.iter().all(|device| !device.enabled.get()) {
+            self.mux.aes.disable();
+        }
+    }
+
+    /// Set the client for this device. This also adds the device to the
+    /// mux, so every device must set a client.
+    fn set_client(&'a self, client: &'a symmetric_encryption::Client<'a>) {
+        self.mux.devices.push_head(self);
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_iv = [0; AES128_BLOCK_SIZE];
+        new_iv.copy_from_slice(iv);
+        self.iv.set(new_iv);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_message(&self) {
+        if !self.is_busy() {
+            self.new_message.set(true);
+        }
+    }
+
+    /// Run or queue a request. Requests are checked up front, because a
+    /// queued request has no way to report an error later.
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.is_busy() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        let valid = start_index <= stop_index
+            && stop_index <= dest.len()
+            && (stop_index - start_index) % AES128_BLOCK_SIZE == 0
+            && source
+                .as_ref()
+                .map_or(true, |source| source.len() == stop_index - start_index);
+        if !valid {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+
+        if self.mux.inflight.is_none() {
+            self.mux.start(self, source, dest, start_index, stop_index)
+        } else {
+            self.source.put(source);
+            self.dest.replace(dest);
+            self.indices.set((start_index, stop_index));
+            None
+        }
+    }
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> AES128Ctr for VirtualAES128Device<'a, A> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) {
+        self.mode.set(Mode::Ctr(encrypting));
+    }
+}
+
+impl<A: AES128<'a> + AES128Ctr + AES128CBC> AES128CBC for VirtualAES128Device<'a, A> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        self.mode.set(Mode::Cbc(encrypting));
+    }
+}
@@ -124,10 +124,6 @@ impl<Alrm: Alarm> MuxAlarm<'a, Alrm> {
     }
 }
 
-fn has_expired(alarm: u32, now: u32, prev: u32) -> bool {
-    now.wrapping_sub(prev) >= alarm.wrapping_sub(prev)
-}
-
 impl<Alrm: Alarm> time::Client for MuxAlarm<'a, Alrm> {
     fn fired(&self) {
         let now = self.alarm.now();
@@ -142,7 +138,7 @@ impl<Alrm: Alarm> time::Client for MuxAlarm<'a, Alrm> {
         // so a repeating client will set it again in the fired() callback.
         self.virtual_alarms
             .iter()
-            .filter(|cur| cur.armed.get() && has_expired(cur.when.get(), now, prev))
+            .filter(|cur| cur.armed.get() && time::has_expired(cur.when.get(), now, prev))
             .for_each(|cur| {
                 cur.armed.set(false);
                 self.enabled.set(self.enabled.get() - 1);
@@ -162,7 +158,7 @@ impl<Alrm: Alarm> time::Client for MuxAlarm<'a, Alrm> {
         // If there is an alarm to fire, set the underlying alarm to it
         if let Some(valrm) = next {
             self.alarm.set_alarm(valrm.when.get());
-            if has_expired(valrm.when.get(), self.alarm.now(), prev) {
+            if time::has_expired(valrm.when.get(), self.alarm.now(), prev) {
                 self.fired();
             }
         } else {
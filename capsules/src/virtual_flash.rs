@@ -95,6 +95,9 @@ impl<F: hil::flash::Flash> MuxFlash<'a, F> {
                             Op::Write(page_number) => {
                                 self.flash.write_page(page_number, buf);
                             }
+                            Op::Program(page_number, offset, length) => {
+                                self.flash.program_page(page_number, buf, offset, length);
+                            }
                             Op::Read(page_number) => {
                                 self.flash.read_page(page_number, buf);
                             }
@@ -116,6 +119,8 @@ impl<F: hil::flash::Flash> MuxFlash<'a, F> {
 enum Op {
     Idle,
     Write(usize),
+    // Page, offset and length.
+    Program(usize, usize, usize),
     Read(usize),
     Erase(usize),
 }
@@ -196,6 +201,19 @@ impl<F: hil::flash::Flash> hil::flash::Flash for FlashUser<'a, F> {
         ReturnCode::SUCCESS
     }
 
+    fn program_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        self.buffer.replace(buf);
+        self.operation.set(Op::Program(page_number, offset, length));
+        self.mux.do_next_op();
+        ReturnCode::SUCCESS
+    }
+
     fn erase_page(&self, page_number: usize) -> ReturnCode {
         self.operation.set(Op::Erase(page_number));
         self.mux.do_next_op();
@@ -4,8 +4,17 @@
 //! most useful for `printf()` like applications where multiple things want to
 //! write to the same UART channel.
 //!
-//! Clients can choose if they want to receive. Incoming messages will be sent
-//! to all clients that have enabled receiving.
+//! Clients can choose if they want to receive. Incoming bytes are kept in a
+//! receive ring that every receiving client reads from at its own pace, so
+//! each one gets its own copy of the input, starting with its first
+//! `receive`. Bytes that arrive while a client has no read pending wait in the
+//! ring for its next read. If more than `RX_RING_LEN` bytes arrive in the
+//! meantime, the oldest are lost and the next read ends with `OverrunError`.
+//! Aborting a read only ends the read of that client.
+//!
+//! A framing protocol that shares the UART with other clients, such as SLIP
+//! next to a console, can install an `RxRouter` that assigns each incoming
+//! byte to a channel. Clients then only receive the bytes of their channel.
 //!
 //! `UartMux` provides shared access to a single UART bus for multiple users.
 //! `UartDevice` provides access for a single client.
@@ -40,7 +49,7 @@
 use core::cell::Cell;
 use core::cmp;
 
-use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil;
 use kernel::hil::uart;
@@ -49,13 +58,34 @@ use kernel::ReturnCode;
 const RX_BUF_LEN: usize = 64;
 pub static mut RX_BUF: [u8; RX_BUF_LEN] = [0; RX_BUF_LEN];
 
+/// Number of received bytes kept for clients that have no read pending. This
+/// must be a power of two, so that byte sequence numbers can wrap.
+pub const RX_RING_LEN: usize = 128;
+
+/// Assigns incoming bytes to channels, for framing protocols that share a
+/// UART with other clients.
+pub trait RxRouter {
+    /// Returns the channel of `byte`. This is called for every received byte,
+    /// in order, so the router can follow the framing of the input.
+    fn route(&self, byte: u8) -> u8;
+}
+
 pub struct UartMux<'a> {
     uart: &'a hil::uart::UART,
     speed: u32,
     devices: List<'a, UartDevice<'a>>,
     inflight: OptionalCell<&'a UartDevice<'a>>,
     buffer: TakeCell<'static, [u8]>,
+    // Length of the receive in progress on the underlying UART.
+    rx_len: Cell<usize>,
     completing_read: Cell<bool>,
+    // Received bytes and their channels, at their sequence number modulo
+    // `RX_RING_LEN`.
+    ring: MapCell<[u8; RX_RING_LEN]>,
+    ring_channels: MapCell<[u8; RX_RING_LEN]>,
+    // Sequence number of the next byte to be received.
+    ring_end: Cell<usize>,
+    router: OptionalCell<&'a RxRouter>,
 }
 
 impl<'a> hil::uart::Client for UartMux<'a> {
@@ -68,75 +98,28 @@ impl<'a> hil::uart::Client for UartMux<'a> {
     }
 
     fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: hil::uart::Error) {
-        let mut next_read_len = RX_BUF_LEN;
-        let mut read_pending = false;
-        self.completing_read.set(true);
-        // Because clients may issue another read in their callback we need to first
-        // copy out all the data, then make the callbacks.
-        //
-        // Multiple client reads of different sizes can be pending. This code copies
-        // the underlying UART read into each of the client buffers; if the
-        // underlying read completes a client read, issue a callback to that
-        // client. In the meanwhile, compute the length of the next underlying
-        // UART read: if any client has more to read, issue another underlying
-        // read.
-        self.devices.iter().for_each(|device| {
-            if device.receiver {
-                device.rx_buffer.take().map(|rxbuf| {
-                    let state = device.state.get();
-                    // Copy the read into the buffer starting at rx_position
-                    let position = device.rx_position.get();
-                    let remaining = device.rx_len.get() - position;
-                    let len = cmp::min(rx_len, remaining);
-                    if state == UartDeviceReceiveState::Receiving
-                        || state == UartDeviceReceiveState::Aborting
-                    {
-                        //                        debug!("Have {} bytes, copying in bytes {}-{}, {} remain", rx_len, position, position + len, remaining);
-                        for i in 0..len {
-                            rxbuf[position + i] = buffer[i];
-                        }
-                    }
-                    device.rx_position.set(position + len);
-                    device.rx_buffer.replace(rxbuf);
-                });
-            }
-        });
+        for &byte in buffer.iter().take(rx_len) {
+            self.push_byte(byte);
+        }
         self.buffer.replace(buffer);
-        self.devices.iter().for_each(|device| {
-            if device.receiver {
-                device.rx_buffer.take().map(|rxbuf| {
-                    let state = device.state.get();
-                    let position = device.rx_position.get();
-                    let remaining = device.rx_len.get() - position;
-                    // If this finishes the read, signal to the caller,
-                    // otherwise update state so next read will fill in
-                    // more data.
-                    if remaining == 0 {
-                        device.state.set(UartDeviceReceiveState::Idle);
-                        device.receive_complete(rxbuf, position, error);
-                        // Need to check if receive was called in callback
-                        if device.state.get() == UartDeviceReceiveState::Receiving {
-                            read_pending = true;
-                        }
-                    } else if state == UartDeviceReceiveState::Aborting {
-                        device.state.set(UartDeviceReceiveState::Idle);
-                        device.receive_complete(rxbuf, position, hil::uart::Error::Aborted);
-                        // Need to check if receive was called in callback
-                        if device.state.get() == UartDeviceReceiveState::Receiving {
-                            read_pending = true;
-                        }
-                    } else {
-                        device.rx_buffer.replace(rxbuf);
-                        next_read_len = cmp::min(next_read_len, remaining);
-                        read_pending = true;
-                    }
-                });
-            }
-        });
-        self.completing_read.set(false);
-        if read_pending {
-            self.start_receive(next_read_len);
+
+        // A line error, such as a framing error or a break, ends the read of
+        // every device, as they all share the line. Aborts only come from the
+        // mux itself, to get at the bytes received so far.
+        let mut line_error = match error {
+            hil::uart::Error::CommandComplete | hil::uart::Error::Aborted => None,
+            _ => Some(error),
+        };
+
+        // Clients may issue another read in their callback, which bytes
+        // already in the ring can serve, so deliver until no device takes any
+        // more bytes.
+        self.completing_read.set(true);
+        while self.deliver(line_error) {
+            line_error = None;
         }
+        self.completing_read.set(false);
+        self.start_receive();
     }
 }
 
@@ -148,7 +131,12 @@ impl<'a> UartMux<'a> {
             devices: List::new(),
             inflight: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
+            rx_len: Cell::new(0),
             completing_read: Cell::new(false),
+            ring: MapCell::new([0; RX_RING_LEN]),
+            ring_channels: MapCell::new([0; RX_RING_LEN]),
+            ring_end: Cell::new(0),
+            router: OptionalCell::empty(),
         }
     }
 
@@ -161,6 +149,12 @@ impl<'a> UartMux<'a> {
         });
     }
 
+    /// Route incoming bytes to channels with `router`. Without a router, all
+    /// bytes are on channel 0.
+    pub fn set_router(&self, router: &'a RxRouter) {
+        self.router.set(router);
+    }
+
     fn do_next_op(&self) {
         if self.inflight.is_none() {
             let mnode = self.devices.iter().find(|node| node.operation.is_some());
@@ -176,34 +170,133 @@ impl<'a> UartMux<'a> {
         }
     }
 
-    /// Starts a new UART reception, return value denotes whether starting
-    /// the reception will issue a callback before the new read. A callback
-    /// needs to be issued before the new read if a read was ongoing; the
-    /// callback finishes the current read so the new one can start.
-    /// Three cases:
-    ///    1) We are in the midst of completing a read: let it restart the
-    ///       reads if needed (return false)
-    ///    2) We are in the midst of a read: abort so we can start a new
-    ///       read now (return true)
-    ///    3) We are idle: start reading (return false)
-    fn start_receive(&self, rx_len: usize) -> bool {
+    fn push_byte(&self, byte: u8) {
+        let channel = self.router.map_or(0, |router| router.route(byte));
+        let index = self.ring_end.get() % RX_RING_LEN;
+        self.ring.map(|ring| ring[index] = byte);
+        self.ring_channels.map(|channels| channels[index] = channel);
+        self.ring_end.set(self.ring_end.get().wrapping_add(1));
+    }
+
+    /// Copies the bytes of its channel that `device` has not seen yet into
+    /// its read buffer, until the buffer is full. Returns whether bytes were
+    /// lost because the device fell too far behind.
+    fn copy_to_device(&self, device: &UartDevice) -> bool {
+        let end = self.ring_end.get();
+        let mut pos = device.ring_pos.get();
+        let overrun = end.wrapping_sub(pos) > RX_RING_LEN;
+        if overrun {
+            pos = end.wrapping_sub(RX_RING_LEN);
+        }
+
+        let channel = device.channel.get();
+        let mut position = device.rx_position.get();
+        device.rx_buffer.map(|rxbuf| {
+            self.ring.map(|ring| {
+                self.ring_channels.map(|channels| {
+                    while pos != end && position < device.rx_len.get() {
+                        let index = pos % RX_RING_LEN;
+                        if channels[index] == channel {
+                            rxbuf[position] = ring[index];
+                            position += 1;
+                        }
+                        pos = pos.wrapping_add(1);
+                    }
+                });
+            });
+        });
+        device.rx_position.set(position);
+        device.ring_pos.set(pos);
+        overrun
+    }
+
+    /// Gives every device with a read pending the bytes it has not seen yet,
+    /// and completes the reads that are done. Reads that are not done end
+    /// with `error`, if there is one. Returns whether any device took bytes
+    /// from the ring.
+    fn deliver(&self, error: Option<hil::uart::Error>) -> bool {
+        let mut progress = false;
+        self.devices.iter().for_each(|device| {
+            let state = device.state.get();
+            if !device.receiver || state == UartDeviceReceiveState::Idle {
+                return;
+            }
+
+            let pos = device.ring_pos.get();
+            let overrun = self.copy_to_device(device);
+            progress |= device.ring_pos.get() != pos;
+
+            let result = if overrun {
+                Some(hil::uart::Error::OverrunError)
+            } else if device.rx_position.get() == device.rx_len.get() {
+                Some(hil::uart::Error::CommandComplete)
+            } else if state == UartDeviceReceiveState::Aborting {
+                Some(hil::uart::Error::Aborted)
+            } else {
+                error
+            };
+            result.map(|result| {
+                device.rx_buffer.take().map(|rxbuf| {
+                    device.state.set(UartDeviceReceiveState::Idle);
+                    let len = device.rx_position.get();
+                    hil::uart::Client::receive_complete(device, rxbuf, len, result);
+                });
+            });
+        });
+        progress
+    }
+
+    /// Keeps a receive going on the underlying UART once any device has
+    /// started receiving, so that the ring sees all input. The receive is as
+    /// long as the shortest remaining device read, so that it completes as
+    /// soon as a device read can. A receive in progress that is too long, or
+    /// that devices are waiting on while the bytes they need are already in
+    /// the ring, is aborted, and its completion starts the next one.
+    fn start_receive(&self) {
+        if self.completing_read.get() {
+            // The read completion starts the next receive once the devices
+            // have been served.
+            return;
+        }
+
+        let end = self.ring_end.get();
+        let mut listening = false;
+        let mut waiting = false;
+        let mut len = RX_BUF_LEN;
+        self.devices.iter().for_each(|device| {
+            if !device.receiver {
+                return;
+            }
+            listening |= device.listening.get();
+            match device.state.get() {
+                UartDeviceReceiveState::Idle => {}
+                UartDeviceReceiveState::Receiving => {
+                    let remaining = device.rx_len.get() - device.rx_position.get();
+                    if remaining > 0 {
+                        len = cmp::min(len, remaining);
+                        waiting |= device.ring_pos.get() != end;
+                    }
+                }
+                UartDeviceReceiveState::Aborting => waiting = true,
+            }
+        });
+        if !listening {
+            return;
+        }
+
         self.buffer.take().map_or_else(
             || {
-                // No rxbuf which means a read is ongoing
-                if self.completing_read.get() {
-                    // Do nothing, read completion will call start_receive when ready
-                    false
-                } else {
+                // A receive is in progress.
+                if waiting || len < self.rx_len.get() {
                     self.uart.abort_receive();
-                    true
                 }
             },
             |rxbuf| {
-                let len = cmp::min(rx_len, rxbuf.len());
+                let len = cmp::min(len, rxbuf.len());
+                self.rx_len.set(len);
                 self.uart.receive(rxbuf, len);
-                false
             },
-        )
+        );
     }
 }
 
@@ -227,6 +320,11 @@ pub struct UartDevice<'a> {
     rx_buffer: TakeCell<'static, [u8]>,
     rx_position: Cell<usize>,
     rx_len: Cell<usize>,
+    // Whether this device has started receiving, and so gets incoming bytes.
+    listening: Cell<bool>,
+    // Sequence number of the next byte in the mux ring for this device.
+    ring_pos: Cell<usize>,
+    channel: Cell<u8>,
     operation: OptionalCell<Operation>,
     next: ListLink<'a, UartDevice<'a>>,
     client: OptionalCell<&'a hil::uart::Client>,
@@ -242,6 +340,9 @@ impl<'a> UartDevice<'a> {
             rx_buffer: TakeCell::empty(),
             rx_position: Cell::new(0),
             rx_len: Cell::new(0),
+            listening: Cell::new(false),
+            ring_pos: Cell::new(0),
+            channel: Cell::new(0),
             operation: OptionalCell::empty(),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
@@ -252,6 +353,12 @@ impl<'a> UartDevice<'a> {
     pub fn setup(&'a self) {
         self.mux.devices.push_head(self);
     }
+
+    /// Only receive the bytes that the router of the mux assigns to
+    /// `channel`. Devices are on channel 0 by default.
+    pub fn set_channel(&self, channel: u8) {
+        self.channel.set(channel);
+    }
 }
 
 impl<'a> hil::uart::Client for UartDevice<'a> {
@@ -300,18 +407,24 @@ impl<'a> hil::uart::UART for UartDevice<'a> {
 
     /// Receive data until buffer is full.
     fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        if !self.listening.get() {
+            // Bytes received before the first read are not for this device.
+            self.listening.set(true);
+            self.ring_pos.set(self.mux.ring_end.get());
+        }
+        self.rx_len.set(cmp::min(rx_len, rx_buffer.len()));
         self.rx_buffer.replace(rx_buffer);
-        self.rx_len.set(rx_len);
         self.rx_position.set(0);
-        self.state.set(UartDeviceReceiveState::Idle);
-        self.mux.start_receive(rx_len);
         self.state.set(UartDeviceReceiveState::Receiving);
+        self.mux.start_receive();
     }
 
     // This virtualized device will abort its read: other devices
     // devices will continue with their reads.
     fn abort_receive(&self) {
-        self.state.set(UartDeviceReceiveState::Aborting);
-        self.mux.uart.abort_receive();
+        if self.state.get() == UartDeviceReceiveState::Receiving {
+            self.state.set(UartDeviceReceiveState::Aborting);
+            self.mux.start_receive();
+        }
     }
 }
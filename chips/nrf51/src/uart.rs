@@ -1,12 +1,18 @@
 use core::cell::Cell;
+use core::cmp;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
 use kernel::common::registers::{ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
+use kernel::hil::gpio::Pin;
 use kernel::hil::uart;
 use kernel::ReturnCode;
+use nrf5x::gpio;
 use nrf5x::pinmux::Pinmux;
 
+/// Value of a PSEL register that disconnects the signal from all pins.
+const PSEL_DISCONNECTED: u32 = 0xFFFFFFFF;
+
 pub static mut UART0: UART = UART::new();
 
 #[repr(C)]
@@ -155,6 +161,11 @@ pub struct UART {
     buffer: TakeCell<'static, [u8]>,
     len: Cell<usize>,
     index: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    // TXD pin, kept while it is disconnected to send a break.
+    break_pin: Cell<Option<u32>>,
 }
 
 #[derive(Copy, Clone)]
@@ -170,6 +181,10 @@ impl UART {
             buffer: TakeCell::empty(),
             len: Cell::new(0),
             index: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            break_pin: Cell::new(None),
         }
     }
 
@@ -219,7 +234,8 @@ impl UART {
 
     pub fn enable_rx_interrupts(&self) {
         let regs = &*self.registers;
-        regs.intenset.write(Interrupt::RXDRDY::SET);
+        regs.intenset
+            .write(Interrupt::RXDRDY::SET + Interrupt::ERROR::SET);
     }
 
     pub fn enable_tx_interrupts(&self) {
@@ -229,7 +245,8 @@ impl UART {
 
     pub fn disable_rx_interrupts(&self) {
         let regs = &*self.registers;
-        regs.intenclr.write(Interrupt::RXDRDY::SET);
+        regs.intenclr
+            .write(Interrupt::RXDRDY::SET + Interrupt::ERROR::SET);
     }
 
     pub fn disable_tx_interrupts(&self) {
@@ -253,19 +270,70 @@ impl UART {
                         client.transmit_complete(buffer, uart::Error::CommandComplete);
                     });
                 });
+            } else {
+                self.buffer.map(|buffer| {
+                    regs.event_txdrdy.write(Event::READY::CLEAR);
+                    regs.txd.set(buffer[self.index.get()] as u32);
+                    let next_index = self.index.get() + 1;
+                    self.index.set(next_index);
+                });
+            }
+        }
+
+        if self.rx_ready() {
+            regs.event_rxdrdy.write(Event::READY::CLEAR);
+            let byte = regs.rxd.get() as u8;
 
-                return;
+            let index = self.rx_index.get();
+            if self.rx_buffer.is_some() && index < self.rx_len.get() {
+                self.rx_buffer.map(|buffer| {
+                    buffer[index] = byte;
+                });
+                self.rx_index.set(index + 1);
+
+                if index + 1 == self.rx_len.get() {
+                    self.complete_receive(uart::Error::CommandComplete);
+                }
             }
+        }
 
-            self.buffer.map(|buffer| {
-                regs.event_txdrdy.write(Event::READY::CLEAR);
-                regs.txd.set(buffer[self.index.get()] as u32);
-                let next_index = self.index.get() + 1;
-                self.index.set(next_index);
-            });
+        if regs.event_error.is_set(Event::READY) {
+            regs.event_error.write(Event::READY::CLEAR);
+            let error = self.take_rx_error();
+            self.complete_receive(error);
         }
     }
 
+    /// Read and clear the cause of an ERROR event. A break also sets the
+    /// framing error flag, so it is checked first.
+    fn take_rx_error(&self) -> uart::Error {
+        let regs = &*self.registers;
+        let errorsrc = regs.errorsrc.extract();
+        regs.errorsrc.set(errorsrc.get());
+
+        if errorsrc.is_set(Errorsrc::BREAK) {
+            uart::Error::BreakError
+        } else if errorsrc.is_set(Errorsrc::FRAMING) {
+            uart::Error::FramingError
+        } else if errorsrc.is_set(Errorsrc::PARITY) {
+            uart::Error::ParityError
+        } else {
+            uart::Error::OverrunError
+        }
+    }
+
+    /// Stop receiving and pass the bytes received so far to the client.
+    fn complete_receive(&self, error: uart::Error) {
+        let regs = &*self.registers;
+        self.rx_buffer.take().map(|buffer| {
+            self.disable_rx_interrupts();
+            regs.task_stoprx.write(Task::ENABLE::SET);
+            self.client.map(move |client| {
+                client.receive_complete(buffer, self.rx_index.get(), error);
+            });
+        });
+    }
+
     pub unsafe fn send_byte(&self, byte: u8) {
         let regs = &*self.registers;
 
@@ -295,18 +363,22 @@ impl uart::UART for UART {
     }
 
     fn configure(&self, params: uart::UARTParameters) -> ReturnCode {
-        // These could probably be implemented, but are currently ignored, so
-        // throw an error.
+        // The UART only supports one stop bit and even parity.
         if params.stop_bits != uart::StopBits::One {
             return ReturnCode::ENOSUPPORT;
         }
-        if params.parity != uart::Parity::None {
-            return ReturnCode::ENOSUPPORT;
-        }
-        if params.hw_flow_control != false {
-            return ReturnCode::ENOSUPPORT;
-        }
+        let parity = match params.parity {
+            uart::Parity::None => Config::PARITY::ExcludeParity,
+            uart::Parity::Even => Config::PARITY::IncludeParity,
+            uart::Parity::Odd => return ReturnCode::ENOSUPPORT,
+        };
+        let hwfc = match params.hw_flow_control {
+            true => Config::HWFC::Enabled,
+            false => Config::HWFC::Disabled,
+        };
 
+        let regs = &*self.registers;
+        regs.config.write(parity + hwfc);
         self.set_baud_rate(params.baud_rate);
 
         ReturnCode::SUCCESS
@@ -329,19 +401,63 @@ impl uart::UART for UART {
         self.buffer.replace(tx_data);
     }
 
-    // Blocking implementation
     fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
         let regs = &*self.registers;
-        regs.task_startrx.set(1);
-        let mut i = 0;
-        while i < rx_len {
-            while !self.rx_ready() {}
-            rx_buffer[i] = regs.rxd.get() as u8;
-            i += 1;
+
+        let len = cmp::min(rx_len, rx_buffer.len());
+        if len == 0 {
+            return;
         }
+
+        self.rx_index.set(0);
+        self.rx_len.set(len);
+        self.rx_buffer.replace(rx_buffer);
+
+        // Drop bytes and errors left over from before this receive.
+        regs.event_rxdrdy.write(Event::READY::CLEAR);
+        regs.event_error.write(Event::READY::CLEAR);
+        self.take_rx_error();
+
+        self.enable_rx_interrupts();
+        regs.task_startrx.set(1);
     }
 
     fn abort_receive(&self) {
-        unimplemented!()
+        self.complete_receive(uart::Error::Aborted);
+    }
+}
+
+/// The UART cannot send a break itself, so the TXD pin is disconnected from
+/// it and driven low as a GPIO for the duration of the break.
+impl uart::UARTBreak for UART {
+    fn start_break(&self) -> ReturnCode {
+        if self.break_pin.get().is_some() {
+            return ReturnCode::EALREADY;
+        }
+        if self.buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+
+        let regs = &*self.registers;
+        let txd = regs.pseltxd.get();
+        let pin = unsafe { &gpio::PORT[txd as usize] };
+        pin.clear();
+        pin.make_output();
+        regs.pseltxd.set(PSEL_DISCONNECTED);
+        self.break_pin.set(Some(txd));
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_break(&self) -> ReturnCode {
+        let regs = &*self.registers;
+        match self.break_pin.get() {
+            None => ReturnCode::EALREADY,
+            Some(txd) => {
+                unsafe { gpio::PORT[txd as usize].set() };
+                regs.pseltxd.set(txd);
+                self.break_pin.set(None);
+                ReturnCode::SUCCESS
+            }
+        }
     }
 }
@@ -1,4 +1,6 @@
 use cortexm4::{generic_isr, hard_fault_handler, nvic, svc_handler, systick_handler};
+use nrf5x::peripheral_interrupts;
+use wdt;
 
 /*
  * Adapted from crt1.c which was relicensed by the original author from
@@ -77,9 +79,24 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     systick_handler,
 ];
 
+/// Peripheral interrupt vectors.
+///
+/// Every interrupt goes through `generic_isr` except the watchdog, whose
+/// early warning has to be handled even if the kernel loop is stuck.
+#[repr(C)]
+pub struct Irqs {
+    before_wdt: [unsafe extern "C" fn(); peripheral_interrupts::WDT as usize],
+    wdt: unsafe extern "C" fn(),
+    after_wdt: [unsafe extern "C" fn(); 79 - peripheral_interrupts::WDT as usize],
+}
+
 #[link_section = ".vectors"]
 #[used] // Ensures that the symbol is kept until the final binary
-pub static IRQS: [unsafe extern "C" fn(); 80] = [generic_isr; 80];
+pub static IRQS: Irqs = Irqs {
+    before_wdt: [generic_isr; peripheral_interrupts::WDT as usize],
+    wdt: wdt::wdt_handler,
+    after_wdt: [generic_isr; 79 - peripheral_interrupts::WDT as usize],
+};
 
 #[no_mangle]
 pub unsafe extern "C" fn init() {
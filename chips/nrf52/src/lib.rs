@@ -24,5 +24,6 @@ pub mod radio;
 pub mod spi;
 pub mod uart;
 pub mod uicr;
+pub mod wdt;
 
 pub use crt1::init;
@@ -337,6 +337,47 @@ impl Nvmc {
         ReturnCode::SUCCESS
     }
 
+    fn program_page(
+        &self,
+        page_number: usize,
+        data: &'static mut NrfPage,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        if offset + length > data.len() {
+            return ReturnCode::EINVAL;
+        }
+        let regs = &*self.registers;
+
+        // Put the NVMC in write mode.
+        regs.config.write(Configuration::WEN::Wen);
+
+        // Only write the words that hold the range, so that each word is
+        // written once between erases.
+        let start = offset & !3;
+        let end = (offset + length + 3) & !3;
+        for i in (start..end).step_by(4) {
+            let mut word: u32 = 0xffffffff;
+            for j in 0..4 {
+                if i + j >= offset && i + j < offset + length {
+                    word &= !(0xff << (8 * j)) | (data[i + j] as u32) << (8 * j);
+                }
+            }
+
+            let address = ((page_number * PAGE_SIZE) + i) as u32;
+            let location = unsafe { &*(address as *const VolatileCell<u32>) };
+            location.set(word);
+        }
+
+        while !regs.ready.is_set(Ready::READY) {}
+
+        self.buffer.replace(data);
+        self.state.set(FlashState::Write);
+        DEFERRED_CALL.set();
+
+        ReturnCode::SUCCESS
+    }
+
     fn erase_page(&self, page_number: usize) -> ReturnCode {
         // Do the basic erase.
         self.erase_page_helper(page_number);
@@ -367,6 +408,16 @@ impl hil::flash::Flash for Nvmc {
         self.write_page(page_number, buf)
     }
 
+    fn program_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        self.program_page(page_number, buf, offset, length)
+    }
+
     fn erase_page(&self, page_number: usize) -> ReturnCode {
         self.erase_page(page_number)
     }
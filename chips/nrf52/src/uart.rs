@@ -13,7 +13,10 @@ use kernel;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
+use kernel::hil::gpio::Pin;
+use kernel::hil::uart::Error;
 use kernel::ReturnCode;
+use nrf5x::gpio;
 use nrf5x::pinmux;
 
 const UARTE_MAX_BUFFER_SIZE: u32 = 0xff;
@@ -147,8 +150,14 @@ register_bitfields! [u32,
 
     /// Configuration of parity and flow control
     Config [
-        HWFC OFFSET(0) NUMBITS(1),
-        PARITY OFFSET(1) NUMBITS(3)
+        HWFC OFFSET(0) NUMBITS(1) [
+            DISABLED = 0,
+            ENABLED = 1
+        ],
+        PARITY OFFSET(1) NUMBITS(3) [
+            EXCLUDED = 0,
+            INCLUDED = 7
+        ]
     ]
 ];
 
@@ -163,7 +172,11 @@ pub struct Uarte {
     rx_buffer: kernel::common::cells::TakeCell<'static, [u8]>,
     rx_remaining_bytes: Cell<usize>,
     rx_abort_in_progress: Cell<bool>,
-    offset: Cell<usize>,
+    // Error to report when the aborted receive completes.
+    rx_abort_error: Cell<Error>,
+    tx_offset: Cell<usize>,
+    rx_offset: Cell<usize>,
+    sending_break: Cell<bool>,
 }
 
 #[derive(Copy, Clone)]
@@ -186,7 +199,10 @@ impl Uarte {
             rx_buffer: kernel::common::cells::TakeCell::empty(),
             rx_remaining_bytes: Cell::new(0),
             rx_abort_in_progress: Cell::new(false),
-            offset: Cell::new(0),
+            rx_abort_error: Cell::new(Error::Aborted),
+            tx_offset: Cell::new(0),
+            rx_offset: Cell::new(0),
+            sending_break: Cell::new(false),
         }
     }
 
@@ -244,7 +260,8 @@ impl Uarte {
 
     fn enable_rx_interrupts(&self) {
         let regs = &*self.registers;
-        regs.intenset.write(Interrupt::ENDRX::SET);
+        regs.intenset
+            .write(Interrupt::ENDRX::SET + Interrupt::ERROR::SET);
     }
 
     fn enable_tx_interrupts(&self) {
@@ -254,7 +271,8 @@ impl Uarte {
 
     fn disable_rx_interrupts(&self) {
         let regs = &*self.registers;
-        regs.intenclr.write(Interrupt::ENDRX::SET);
+        regs.intenclr
+            .write(Interrupt::ENDRX::SET + Interrupt::ERROR::SET);
     }
 
     fn disable_tx_interrupts(&self) {
@@ -267,6 +285,19 @@ impl Uarte {
     pub fn handle_interrupt(&mut self) {
         let regs = &*self.registers;
 
+        if regs.event_error.is_set(Event::READY) {
+            regs.event_error.write(Event::READY::CLEAR);
+
+            // Stop the receive at the error. The bytes received before it are
+            // returned when the ENDRX event that follows is handled.
+            let error = self.take_rx_error();
+            if self.rx_buffer.is_some() && !self.rx_abort_in_progress.get() {
+                self.rx_abort_in_progress.set(true);
+                self.rx_abort_error.set(error);
+                regs.task_stoprx.write(Task::ENABLE::SET);
+            }
+        }
+
         if self.tx_ready() {
             self.disable_tx_interrupts();
             let regs = &*self.registers;
@@ -299,7 +330,7 @@ impl Uarte {
                 });
             } else {
                 // Not all bytes have been transmitted then update offset and continue transmitting
-                self.offset.set(self.offset.get() + tx_bytes);
+                self.tx_offset.set(self.tx_offset.get() + tx_bytes);
                 self.tx_remaining_bytes.set(rem);
                 self.set_tx_dma_pointer_to_buffer();
                 regs.txd_maxcnt
@@ -326,8 +357,8 @@ impl Uarte {
                     self.rx_buffer.take().map(|rx_buffer| {
                         client.receive_complete(
                             rx_buffer,
-                            self.offset.get() + rx_bytes,
-                            kernel::hil::uart::Error::CommandComplete,
+                            self.rx_offset.get() + rx_bytes,
+                            self.rx_abort_error.get(),
                         );
                     });
                 });
@@ -339,7 +370,7 @@ impl Uarte {
                 // where we are storing in the buffer.
                 self.rx_remaining_bytes
                     .set(self.rx_remaining_bytes.get().saturating_sub(rx_bytes));
-                self.offset.set(self.offset.get() + rx_bytes);
+                self.rx_offset.set(self.rx_offset.get() + rx_bytes);
 
                 let rem = self.rx_remaining_bytes.get();
                 if rem == 0 {
@@ -348,7 +379,7 @@ impl Uarte {
                         self.rx_buffer.take().map(|rx_buffer| {
                             client.receive_complete(
                                 rx_buffer,
-                                self.offset.get(),
+                                self.rx_offset.get(),
                                 kernel::hil::uart::Error::CommandComplete,
                             );
                         });
@@ -368,6 +399,24 @@ impl Uarte {
         }
     }
 
+    /// Read and clear the cause of an ERROR event. A break also sets the
+    /// framing error flag, so it is checked first.
+    fn take_rx_error(&self) -> Error {
+        let regs = &*self.registers;
+        let errorsrc = regs.errorsrc.extract();
+        regs.errorsrc.set(errorsrc.get());
+
+        if errorsrc.is_set(ErrorSrc::BREAK) {
+            Error::BreakError
+        } else if errorsrc.is_set(ErrorSrc::FRAMING) {
+            Error::FramingError
+        } else if errorsrc.is_set(ErrorSrc::PARITY) {
+            Error::ParityError
+        } else {
+            Error::OverrunError
+        }
+    }
+
     /// Transmit one byte at the time and the client is responsible for polling
     /// This is used by the panic handler
     pub unsafe fn send_byte(&self, byte: u8) {
@@ -398,7 +447,7 @@ impl Uarte {
         let regs = &*self.registers;
         self.tx_buffer.map(|tx_buffer| {
             regs.txd_ptr
-                .set(tx_buffer[self.offset.get()..].as_ptr() as u32);
+                .set(tx_buffer[self.tx_offset.get()..].as_ptr() as u32);
         });
     }
 
@@ -406,7 +455,7 @@ impl Uarte {
         let regs = &*self.registers;
         self.rx_buffer.map(|rx_buffer| {
             regs.rxd_ptr
-                .set(rx_buffer[self.offset.get()..].as_ptr() as u32);
+                .set(rx_buffer[self.rx_offset.get()..].as_ptr() as u32);
         });
     }
 }
@@ -417,18 +466,22 @@ impl kernel::hil::uart::UART for Uarte {
     }
 
     fn configure(&self, params: kernel::hil::uart::UARTParameters) -> ReturnCode {
-        // These could probably be implemented, but are currently ignored, so
-        // throw an error.
+        // The UARTE only supports one stop bit and even parity.
         if params.stop_bits != kernel::hil::uart::StopBits::One {
             return ReturnCode::ENOSUPPORT;
         }
-        if params.parity != kernel::hil::uart::Parity::None {
-            return ReturnCode::ENOSUPPORT;
-        }
-        if params.hw_flow_control != false {
-            return ReturnCode::ENOSUPPORT;
-        }
+        let parity = match params.parity {
+            kernel::hil::uart::Parity::None => Config::PARITY::EXCLUDED,
+            kernel::hil::uart::Parity::Even => Config::PARITY::INCLUDED,
+            kernel::hil::uart::Parity::Odd => return ReturnCode::ENOSUPPORT,
+        };
+        let hwfc = match params.hw_flow_control {
+            true => Config::HWFC::ENABLED,
+            false => Config::HWFC::DISABLED,
+        };
 
+        let regs = &*self.registers;
+        regs.config.write(parity + hwfc);
         self.set_baud_rate(params.baud_rate);
 
         ReturnCode::SUCCESS
@@ -442,7 +495,7 @@ impl kernel::hil::uart::UART for Uarte {
         }
 
         self.tx_remaining_bytes.set(tx_len);
-        self.offset.set(0);
+        self.tx_offset.set(0);
         self.tx_buffer.replace(tx_data);
         self.set_tx_dma_pointer_to_buffer();
 
@@ -461,7 +514,7 @@ impl kernel::hil::uart::UART for Uarte {
         let truncated_length = core::cmp::min(rx_len, rx_buf.len());
 
         self.rx_remaining_bytes.set(truncated_length);
-        self.offset.set(0);
+        self.rx_offset.set(0);
         self.rx_buffer.replace(rx_buf);
         self.set_rx_dma_pointer_to_buffer();
 
@@ -470,15 +523,58 @@ impl kernel::hil::uart::UART for Uarte {
         regs.rxd_maxcnt
             .write(Counter::COUNTER.val(truncated_uart_max_length as u32));
         regs.task_stoprx.write(Task::ENABLE::SET);
+        regs.event_error.write(Event::READY::CLEAR);
+        self.take_rx_error();
         regs.task_startrx.write(Task::ENABLE::SET);
 
         self.enable_rx_interrupts();
     }
 
     fn abort_receive(&self) {
+        // Without a receive in progress there will be no ENDRX event to
+        // complete the abort.
+        if self.rx_buffer.is_none() || self.rx_abort_in_progress.get() {
+            return;
+        }
+
         // Trigger the STOPRX event to cancel the current receive call.
         let regs = &*self.registers;
         self.rx_abort_in_progress.set(true);
+        self.rx_abort_error.set(Error::Aborted);
         regs.task_stoprx.write(Task::ENABLE::SET);
     }
 }
+
+/// The UARTE cannot send a break itself, so the TXD pin is disconnected from
+/// it and driven low as a GPIO for the duration of the break.
+impl kernel::hil::uart::UARTBreak for Uarte {
+    fn start_break(&self) -> ReturnCode {
+        if self.sending_break.get() {
+            return ReturnCode::EALREADY;
+        }
+        if self.tx_buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+
+        let regs = &*self.registers;
+        let pin = unsafe { &gpio::PORT[regs.pseltxd.read(Psel::PIN) as usize] };
+        pin.clear();
+        pin.make_output();
+        regs.pseltxd.modify(Psel::CONNECT::SET);
+        self.sending_break.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_break(&self) -> ReturnCode {
+        if !self.sending_break.get() {
+            return ReturnCode::EALREADY;
+        }
+
+        let regs = &*self.registers;
+        let pin = unsafe { &gpio::PORT[regs.pseltxd.read(Psel::PIN) as usize] };
+        pin.set();
+        regs.pseltxd.modify(Psel::CONNECT::CLEAR);
+        self.sending_break.set(false);
+        ReturnCode::SUCCESS
+    }
+}
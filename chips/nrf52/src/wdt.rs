@@ -0,0 +1,172 @@
+//! Watchdog Timer (WDT)
+//!
+//! The nRF52 watchdog cannot be stopped once it has been started, it can only
+//! be reloaded. It keeps running while the CPU sleeps, so that a lost interrupt
+//! still resets the chip, but pauses while the CPU is halted by a debugger. The
+//! TIMEOUT interrupt fires two 32.768 kHz ticks before the chip resets and is
+//! handled directly in `wdt_handler` as an early warning.
+
+use core::cmp;
+use cortexm4::nvic;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil;
+
+const WDT_BASE: StaticRef<WdtRegisters> =
+    unsafe { StaticRef::new(0x40010000 as *const WdtRegisters) };
+
+/// Value to write to a reload request register to reload the watchdog.
+const RELOAD_VALUE: u32 = 0x6E524635;
+
+/// Frequency of the clock driving the watchdog counter.
+const WDT_CLOCK_HZ: u64 = 32768;
+
+#[repr(C)]
+struct WdtRegisters {
+    /// Start the watchdog
+    /// Address: 0x000 - 0x004
+    tasks_start: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 63],
+    /// Watchdog timeout
+    /// Address: 0x100 - 0x104
+    events_timeout: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 128],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved2: [u32; 61],
+    /// Run status
+    /// Address: 0x400 - 0x404
+    runstatus: ReadOnly<u32, RunStatus::Register>,
+    /// Request status
+    /// Address: 0x404 - 0x408
+    reqstatus: ReadOnly<u32>,
+    _reserved3: [u32; 63],
+    /// Counter reload value
+    /// Address: 0x504 - 0x508
+    crv: ReadWrite<u32>,
+    /// Enable register for reload request registers
+    /// Address: 0x508 - 0x50C
+    rren: ReadWrite<u32>,
+    /// Configuration register
+    /// Address: 0x50C - 0x510
+    config: ReadWrite<u32, Config::Register>,
+    _reserved4: [u32; 60],
+    /// Reload request registers
+    /// Address: 0x600 - 0x620
+    rr: [WriteOnly<u32>; 8],
+}
+
+register_bitfields! [u32,
+    /// Start task
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+    /// Timeout event
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+    /// Timeout interrupt
+    Interrupt [
+        TIMEOUT OFFSET(0) NUMBITS(1)
+    ],
+    /// Whether the watchdog is running
+    RunStatus [
+        RUNNING OFFSET(0) NUMBITS(1)
+    ],
+    /// Watchdog behaviour while the CPU sleeps or is halted
+    Config [
+        /// Keep the watchdog running while the CPU is sleeping
+        SLEEP OFFSET(0) NUMBITS(1) [
+            Pause = 0,
+            Run = 1
+        ],
+        /// Keep the watchdog running while the CPU is halted by the debugger
+        HALT OFFSET(3) NUMBITS(1) [
+            Pause = 0,
+            Run = 1
+        ]
+    ]
+];
+
+pub struct Wdt {
+    registers: StaticRef<WdtRegisters>,
+    client: OptionalCell<&'static hil::watchdog::Client>,
+}
+
+pub static mut WDT: Wdt = Wdt::new();
+
+impl Wdt {
+    const fn new() -> Wdt {
+        Wdt {
+            registers: WDT_BASE,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Called from `wdt_handler` when the watchdog times out. The chip resets
+    /// shortly afterwards regardless of what happens here.
+    fn handle_early_warning(&self) {
+        let regs = &*self.registers;
+        regs.events_timeout.write(Event::READY::CLEAR);
+
+        // The handler that was running when the watchdog fired has its
+        // interrupt disabled but still pending, so it is the lowest pending
+        // interrupt in the NVIC.
+        let interrupt = unsafe { nvic::next_pending() };
+        self.client.map(|client| client.early_warning(interrupt));
+    }
+}
+
+/// Top-half handler for the WDT interrupt.
+///
+/// This is installed directly in the vector table rather than going through
+/// `generic_isr` and the kernel loop, because a stuck kernel loop is the
+/// reason the watchdog fires in the first place.
+pub unsafe extern "C" fn wdt_handler() {
+    WDT.handle_early_warning();
+}
+
+impl hil::watchdog::Watchdog for Wdt {
+    fn start(&self, period: usize) {
+        let regs = &*self.registers;
+
+        // The configuration registers are locked while the watchdog runs.
+        if regs.runstatus.is_set(RunStatus::RUNNING) {
+            return;
+        }
+
+        // Timeout = (CRV + 1) / 32768 s, and CRV must be at least 0xF.
+        let ticks = (period as u64 * WDT_CLOCK_HZ) / 1000;
+        let crv = cmp::min(cmp::max(ticks, 0x10) - 1, 0xFFFFFFFF);
+        regs.crv.set(crv as u32);
+
+        regs.config.write(Config::SLEEP::Run + Config::HALT::Pause);
+        // Only reload request register 0 is used.
+        regs.rren.set(1);
+        regs.events_timeout.write(Event::READY::CLEAR);
+        regs.intenset.write(Interrupt::TIMEOUT::SET);
+
+        regs.tasks_start.write(Task::ENABLE::SET);
+    }
+
+    /// The nRF52 watchdog cannot be stopped. This only disables the early
+    /// warning interrupt; the watchdog must still be tickled.
+    fn stop(&self) {
+        let regs = &*self.registers;
+        regs.intenclr.write(Interrupt::TIMEOUT::SET);
+    }
+
+    fn tickle(&self) {
+        let regs = &*self.registers;
+        regs.rr[0].set(RELOAD_VALUE);
+    }
+
+    fn set_client(&self, client: &'static hil::watchdog::Client) {
+        self.client.set(client);
+    }
+}
@@ -29,6 +29,7 @@ use pm::{self, Clock, PBAClock};
 use scif;
 
 /// Representation of an ADC channel on the SAM4L.
+#[derive(Copy, Clone, Debug)]
 pub struct AdcChannel {
     chan_num: u32,
     internal: u32,
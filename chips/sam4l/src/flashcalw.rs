@@ -349,14 +349,15 @@ enum FlashCMD {
 /// FlashState is used to track the current state and command of the flash.
 #[derive(Clone, Copy, PartialEq)]
 enum FlashState {
-    Unconfigured,                 // Flash is unconfigured, call configure().
-    Ready,                        // Flash is ready to complete a command.
-    Read,                         // Performing a read operation.
-    WriteUnlocking { page: i32 }, // Started a write operation.
-    WriteErasing { page: i32 },   // Waiting on the page to erase.
-    WriteWriting,                 // Waiting on the page to actually be written.
-    EraseUnlocking { page: i32 }, // Started an erase operation.
-    EraseErasing,                 // Waiting on the erase to finish.
+    Unconfigured,                   // Flash is unconfigured, call configure().
+    Ready,                          // Flash is ready to complete a command.
+    Read,                           // Performing a read operation.
+    WriteUnlocking { page: i32 },   // Started a write operation.
+    ProgramUnlocking { page: i32 }, // Started a write without an erase.
+    WriteErasing { page: i32 },     // Waiting on the page to erase.
+    WriteWriting,                   // Waiting on the page to actually be written.
+    EraseUnlocking { page: i32 },   // Started an erase operation.
+    EraseErasing,                   // Waiting on the erase to finish.
 }
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
@@ -409,6 +410,8 @@ pub struct FLASHCALW {
     client: OptionalCell<&'static hil::flash::Client<FLASHCALW>>,
     current_state: Cell<FlashState>,
     buffer: TakeCell<'static, Sam4lPage>,
+    // Start and end of the bytes of the buffer to write.
+    write_range: Cell<(usize, usize)>,
 }
 
 // static instance for the board. Only one FLASHCALW on chip.
@@ -449,6 +452,7 @@ impl FLASHCALW {
             client: OptionalCell::empty(),
             current_state: Cell::new(FlashState::Unconfigured),
             buffer: TakeCell::empty(),
+            write_range: Cell::new((0, PAGE_SIZE as usize)),
         }
     }
 
@@ -508,6 +512,7 @@ impl FLASHCALW {
                     });
                 }
                 FlashState::WriteUnlocking { .. }
+                | FlashState::ProgramUnlocking { .. }
                 | FlashState::WriteErasing { .. }
                 | FlashState::WriteWriting => {
                     self.buffer.take().map(|buffer| {
@@ -537,7 +542,7 @@ impl FLASHCALW {
                     .set(FlashState::WriteErasing { page: page });
                 self.flashcalw_erase_page(page);
             }
-            FlashState::WriteErasing { page } => {
+            FlashState::WriteErasing { page } | FlashState::ProgramUnlocking { page } => {
                 //  Write page buffer isn't really a command, and
                 //  clear page buffer doesn't trigger an interrupt thus
                 //  I'm combining these with an actual command, write_page,
@@ -766,8 +771,12 @@ impl FLASHCALW {
 
     // Instead of having several memset/memcpy functions as Atmel's ASF
     // implementation will only have one to write to the page buffer.
+    /// Copy the bytes of `write_range` from the buffer to the page buffer.
+    /// The page buffer is all ones after it is cleared, so the rest of the
+    /// page is left as it is when the page is written.
     fn write_to_page_buffer(&self, pg_buff_addr: usize) {
         let mut page_buffer: *mut u8 = pg_buff_addr as *mut u8;
+        let (start, end) = self.write_range.get();
 
         // Errata 45.1.7 - Need to write a 64-bit all one word for every write
         // to the page buffer.
@@ -778,16 +787,24 @@ impl FLASHCALW {
             unsafe {
                 use core::ptr;
 
-                let mut start_buffer: *const u8 = &buffer[0] as *const u8;
-                let mut data_transfered: u32 = 0;
-                while data_transfered < PAGE_SIZE {
-                    // errata copy..
-                    ptr::copy(clr_ptr, page_buffer, 8);
-
-                    // real copy
-                    ptr::copy(start_buffer, page_buffer, 8);
+                let mut data_transfered: usize = 0;
+                while data_transfered < PAGE_SIZE as usize {
+                    if data_transfered + 8 > start && data_transfered < end {
+                        let mut double_word: [u8; 8] = [255; 8];
+                        for i in 0..8 {
+                            let offset = data_transfered + i;
+                            if offset >= start && offset < end {
+                                double_word[i] = buffer[offset];
+                            }
+                        }
+
+                        // errata copy..
+                        ptr::copy(clr_ptr, page_buffer, 8);
+
+                        // real copy
+                        ptr::copy(&double_word[0] as *const u8, page_buffer, 8);
+                    }
                     page_buffer = page_buffer.offset(8);
-                    start_buffer = start_buffer.offset(8);
                     data_transfered += 8;
                 }
             }
@@ -885,6 +902,7 @@ impl FLASHCALW {
 
         // Save the buffer for the future write.
         self.buffer.replace(data);
+        self.write_range.set((0, PAGE_SIZE as usize));
 
         self.current_state
             .set(FlashState::WriteUnlocking { page: page_num });
@@ -892,6 +910,34 @@ impl FLASHCALW {
         ReturnCode::SUCCESS
     }
 
+    fn program_page(
+        &self,
+        page_num: i32,
+        data: &'static mut Sam4lPage,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        pm::enable_clock(self.ahb_clock);
+
+        match self.current_state.get() {
+            FlashState::Unconfigured => return ReturnCode::FAIL,
+            FlashState::Ready => {}
+            _ => return ReturnCode::EBUSY,
+        }
+        if offset + length > PAGE_SIZE as usize {
+            return ReturnCode::EINVAL;
+        }
+
+        self.buffer.replace(data);
+        self.write_range.set((offset, offset + length));
+
+        // Unlock the page and then write it, skipping the erase.
+        self.current_state
+            .set(FlashState::ProgramUnlocking { page: page_num });
+        self.lock_page_region(page_num, false);
+        ReturnCode::SUCCESS
+    }
+
     fn erase_page(&self, page_num: i32) -> ReturnCode {
         // Enable AHB clock (in case it was off).
         pm::enable_clock(self.ahb_clock);
@@ -923,6 +969,16 @@ impl hil::flash::Flash for FLASHCALW {
         self.write_page(page_number as i32, buf)
     }
 
+    fn program_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        self.program_page(page_number as i32, buf, offset, length)
+    }
+
     fn erase_page(&self, page_number: usize) -> ReturnCode {
         self.erase_page(page_number as i32)
     }
@@ -92,9 +92,24 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
     systick_handler,     // SysTick
 ];
 
+/// Peripheral interrupt vectors.
+///
+/// Every interrupt goes through `generic_isr` except the watchdog, whose
+/// early warning has to be handled even if the kernel loop is stuck.
+#[repr(C)]
+pub struct Irqs {
+    before_wdt: [unsafe extern "C" fn(); nvic::WDT as usize],
+    wdt: unsafe extern "C" fn(),
+    after_wdt: [unsafe extern "C" fn(); 79 - nvic::WDT as usize],
+}
+
 #[link_section = ".vectors"]
 #[used] // Ensures that the symbol is kept until the final binary
-pub static IRQS: [unsafe extern "C" fn(); 80] = [generic_isr; 80];
+pub static IRQS: Irqs = Irqs {
+    before_wdt: [generic_isr; nvic::WDT as usize],
+    wdt: wdt::wdt_handler,
+    after_wdt: [generic_isr; 79 - nvic::WDT as usize],
+};
 
 pub unsafe fn init() {
     // Relocate data segment.
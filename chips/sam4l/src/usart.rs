@@ -285,6 +285,7 @@ pub struct USARTRegManager<'a> {
     clock: pm::Clock,
     rx_dma: Option<&'static dma::DMAChannel>,
     tx_dma: Option<&'static dma::DMAChannel>,
+    sending_break: &'a Cell<bool>,
 }
 
 static IS_PANICING: AtomicBool = AtomicBool::new(false);
@@ -300,6 +301,7 @@ impl USARTRegManager<'a> {
             clock: usart.clock,
             rx_dma: usart.rx_dma.get(),
             tx_dma: usart.tx_dma.get(),
+            sending_break: &usart.sending_break,
         }
     }
 
@@ -323,6 +325,7 @@ impl Drop for USARTRegManager<'a> {
                 + Interrupt::PARE::SET
                 + Interrupt::FRAME::SET
                 + Interrupt::OVRE::SET
+                + Interrupt::RXBRK::SET
                 + Interrupt::TXRDY::SET
                 + Interrupt::RXRDY::SET,
         );
@@ -335,7 +338,9 @@ impl Drop for USARTRegManager<'a> {
         // directly and we can't safely reason about what the custom panic
         // USART driver is doing / expects.
         let is_panic = IS_PANICING.load(Ordering::Relaxed);
-        if !(rx_active || tx_active || ints_active || is_panic) {
+        // The transmitter must stay clocked while it holds the line low.
+        let is_break = self.sending_break.get();
+        if !(rx_active || tx_active || ints_active || is_panic || is_break) {
             pm::disable_clock(self.clock);
         }
     }
@@ -384,6 +389,7 @@ pub struct USART {
     tx_dma: Cell<Option<&'static dma::DMAChannel>>,
     tx_dma_peripheral: dma::DMAPeripheral,
     tx_len: Cell<usize>,
+    sending_break: Cell<bool>,
 
     client: OptionalCell<UsartClient<'static>>,
 
@@ -439,6 +445,7 @@ impl USART {
             tx_dma: Cell::new(None),
             tx_dma_peripheral: tx_dma_peripheral,
             tx_len: Cell::new(0),
+            sending_break: Cell::new(false),
 
             // this gets defined later by `main.rs`
             client: OptionalCell::empty(),
@@ -527,9 +534,7 @@ impl USART {
             self.usart_tx_state.set(USARTStateTX::Idle);
 
             // get buffer
-            let mut length = 0;
             let buffer = self.tx_dma.get().map_or(None, |tx_dma| {
-                length = self.tx_len.get() - tx_dma.transfer_counter();
                 let buf = tx_dma.abort_transfer();
                 tx_dma.disable();
                 buf
@@ -540,7 +545,7 @@ impl USART {
             self.client.map(|usartclient| {
                 buffer.map(|buf| match usartclient {
                     UsartClient::Uart(client) => {
-                        client.receive_complete(buf, length, error);
+                        client.transmit_complete(buf, error);
                     }
                     UsartClient::SpiMaster(_) => {}
                 });
@@ -557,10 +562,15 @@ impl USART {
     }
 
     fn enable_rx_error_interrupts(&self, usart: &USARTRegManager) {
-        usart
-            .registers
-            .ier
-            .write(Interrupt::PARE::SET + Interrupt::FRAME::SET + Interrupt::OVRE::SET);
+        // Clear errors left over from before this receive, such as the end of
+        // a break that aborted the previous one.
+        usart.registers.cr.write(Control::RSTSTA::SET);
+        usart.registers.ier.write(
+            Interrupt::PARE::SET
+                + Interrupt::FRAME::SET
+                + Interrupt::OVRE::SET
+                + Interrupt::RXBRK::SET,
+        );
     }
 
     fn disable_rx_interrupts(&self, usart: &USARTRegManager) {
@@ -570,6 +580,7 @@ impl USART {
                 + Interrupt::PARE::SET
                 + Interrupt::FRAME::SET
                 + Interrupt::OVRE::SET
+                + Interrupt::RXBRK::SET
                 + Interrupt::RXRDY::SET,
         );
     }
@@ -660,6 +671,9 @@ impl USART {
                     }
                 });
             });
+        } else if status.is_set(ChannelStatus::RXBRK) && mask.is_set(Interrupt::RXBRK) {
+            // A break also looks like a framing error, so check it first.
+            self.abort_rx(usart, hil::uart::Error::BreakError);
         } else if status.is_set(ChannelStatus::PARE) {
             self.abort_rx(usart, hil::uart::Error::ParityError);
         } else if status.is_set(ChannelStatus::FRAME) {
@@ -908,6 +922,38 @@ impl hil::uart::UART for USART {
     }
 }
 
+impl hil::uart::UARTBreak for USART {
+    fn start_break(&self) -> ReturnCode {
+        if self.usart_mode.get() != UsartMode::Uart {
+            return ReturnCode::EOFF;
+        }
+        if self.sending_break.get() {
+            return ReturnCode::EALREADY;
+        }
+        if self.usart_tx_state.get() != USARTStateTX::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let usart = &USARTRegManager::new(&self);
+        self.enable_tx(usart);
+        self.sending_break.set(true);
+        usart.registers.cr.write(Control::STTBRK::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_break(&self) -> ReturnCode {
+        if !self.sending_break.get() {
+            return ReturnCode::EALREADY;
+        }
+        let usart = &USARTRegManager::new(&self);
+        usart.registers.cr.write(Control::STPBRK::SET);
+        self.sending_break.set(false);
+        if self.usart_tx_state.get() == USARTStateTX::Idle {
+            self.disable_tx(usart);
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
 impl hil::uart::UARTReceiveAdvanced for USART {
     fn receive_automatic(&self, rx_buffer: &'static mut [u8], interbyte_timeout: u8) {
         let usart = &USARTRegManager::new(&self);
@@ -1,7 +1,13 @@
 //! Implementation of the SAM4L hardware watchdog timer.
+//!
+//! The watchdog runs in interrupt mode: the first timeout raises the WDT
+//! interrupt, which is handled directly in `wdt_handler` as an early warning,
+//! and a second timeout without a tickle resets the chip.
 
 use core::cell::Cell;
+use cortexm4;
 use cortexm4::support;
+use kernel::common::cells::OptionalCell;
 use kernel::common::math::log_base_two_u64;
 use kernel::common::registers::{FieldValue, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
@@ -102,6 +108,7 @@ const WDT_REGS: StaticRef<WdtRegisters> =
 
 pub struct Wdt {
     enabled: Cell<bool>,
+    client: OptionalCell<&'static hil::watchdog::Client>,
 }
 
 pub static mut WDT: Wdt = Wdt::new();
@@ -125,6 +132,7 @@ impl Wdt {
     const fn new() -> Wdt {
         Wdt {
             enabled: Cell::new(false),
+            client: OptionalCell::empty(),
         }
     }
 
@@ -201,12 +209,17 @@ impl Wdt {
             + Control::PSEL.val(scaler)
             + Control::FCD::DoNotRedoCalibration
             + Control::DAR::DisableAfterReset
+            + Control::IM::InterruptModeEnabled
             + Control::EN::Enable;
         self.write_cr(control);
+
+        WDT_REGS.icr.write(Interrupt::WINT::SET);
+        WDT_REGS.ier.write(Interrupt::WINT::SET);
     }
 
     fn stop(&self) {
-        self.write_cr(Control::EN::CLEAR);
+        WDT_REGS.idr.write(Interrupt::WINT::SET);
+        self.write_cr(Control::EN::CLEAR + Control::IM::CLEAR);
 
         pm::disable_clock(Clock::PBD(PBDClock::WDT));
 
@@ -217,9 +230,38 @@ impl Wdt {
         // Need to write the WDTCLR bit twice for it to work
         WDT_REGS.clr.write(Clear::KEY::KEY1 + Clear::WDTCLR::SET);
         WDT_REGS.clr.write(Clear::KEY::KEY2 + Clear::WDTCLR::SET);
+
+        // Re-arm the early warning that `handle_early_warning` disarmed.
+        WDT_REGS.icr.write(Interrupt::WINT::SET);
+        WDT_REGS.ier.write(Interrupt::WINT::SET);
+    }
+
+    /// Called from `wdt_handler` when the first timeout expires. The chip
+    /// resets on the next timeout unless the watchdog is tickled first.
+    ///
+    /// WINT is left set, since in interrupt mode the chip only resets if WINT
+    /// is still set at the second timeout. Only the interrupt is disabled, so
+    /// the handler does not fire again until `tickle` re-arms it.
+    fn handle_early_warning(&self) {
+        WDT_REGS.idr.write(Interrupt::WINT::SET);
+
+        // The handler that was running when the watchdog fired has its
+        // interrupt disabled but still pending, so it is the lowest pending
+        // interrupt in the NVIC.
+        let interrupt = unsafe { cortexm4::nvic::next_pending() };
+        self.client.map(|client| client.early_warning(interrupt));
     }
 }
 
+/// Top-half handler for the WDT interrupt.
+///
+/// This is installed directly in the vector table rather than going through
+/// `generic_isr` and the kernel loop, because a stuck kernel loop is the
+/// reason the watchdog fires in the first place.
+pub unsafe extern "C" fn wdt_handler() {
+    WDT.handle_early_warning();
+}
+
 impl hil::watchdog::Watchdog for Wdt {
     fn start(&self, period: usize) {
         self.start(period);
@@ -232,4 +274,8 @@ impl hil::watchdog::Watchdog for Wdt {
     fn tickle(&self) {
         self.tickle();
     }
+
+    fn set_client(&self, client: &'static hil::watchdog::Client) {
+        self.client.set(client);
+    }
 }
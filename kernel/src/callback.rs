@@ -46,6 +46,62 @@ impl AppId {
             (start, end)
         })
     }
+
+    /// Returns the start and end address of the process's flash region,
+    /// including its TBF header.
+    pub fn get_flash_range(&self) -> (usize, usize) {
+        self.kernel.process_map_or((0, 0), self.idx, |process| {
+            (process.flash_start() as usize, process.flash_end() as usize)
+        })
+    }
+
+    /// Returns the start and end address of the process's RAM region.
+    pub fn get_memory_range(&self) -> (usize, usize) {
+        self.kernel.process_map_or((0, 0), self.idx, |process| {
+            (process.mem_start() as usize, process.mem_end() as usize)
+        })
+    }
+
+    /// Returns the package name from the process's TBF header.
+    pub fn get_package_name(&self) -> &'static str {
+        self.kernel
+            .process_map_or("", self.idx, |process| process.package_name)
+    }
+
+    /// Returns the size of the nonvolatile storage region the process asked
+    /// for in its TBF header, or `None` if it did not ask for one.
+    pub fn get_nonvolatile_storage_size(&self) -> Option<usize> {
+        self.kernel
+            .process_map_or(None, self.idx, |process| process.nonvolatile_storage_size())
+    }
+
+    /// Returns the app version from the process's TBF header, or 0 if the
+    /// header does not have one.
+    pub fn get_app_version(&self) -> u32 {
+        self.kernel
+            .process_map_or(0, self.idx, |process| process.app_version())
+    }
+
+    /// Returns the scheduling state of the process, or `None` if the process
+    /// does not exist.
+    pub fn get_state(&self) -> Option<process::State> {
+        self.kernel
+            .process_map_or(None, self.idx, |process| Some(process.current_state()))
+    }
+
+    /// Returns how many times the kernel has restarted the process after a
+    /// fault.
+    pub fn get_restart_count(&self) -> usize {
+        self.kernel
+            .process_map_or(0, self.idx, |process| process.restart_count())
+    }
+
+    /// Returns how many callbacks to the process were dropped because its
+    /// callback queue was full since it was last started.
+    pub fn get_dropped_callback_count(&self) -> usize {
+        self.kernel
+            .process_map_or(0, self.idx, |process| process.dropped_callback_count())
+    }
 }
 
 /// Wrapper around a function pointer.
@@ -12,6 +12,7 @@ pub use tock_registers::{macros, registers};
 pub mod deferred_call;
 pub mod list;
 pub mod math;
+pub mod monotonic;
 pub mod peripherals;
 pub mod utils;
 
@@ -0,0 +1,81 @@
+//! 64-bit monotonic clock on top of a 32-bit alarm.
+//!
+//! `MonotonicClock` extends the wrapping counter of an `Alarm` to 64 bits by
+//! counting how often it wraps. It uses its alarm to look at the counter at
+//! least twice per wrap period, so it never misses a wrap. The alarm should be
+//! a virtual alarm of its own, on the same counter as the alarms it is used
+//! with, so that the lower 32 bits of its ticks match their `now()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! let clock_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let clock = static_init!(
+//!     kernel::common::monotonic::MonotonicClock<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     kernel::common::monotonic::MonotonicClock::new(clock_alarm)
+//! );
+//! clock_alarm.set_client(clock);
+//! clock.start();
+//! ```
+
+use core::cell::Cell;
+
+use hil::time::{self, Alarm, Instant, Monotonic};
+
+pub struct MonotonicClock<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    // Counter value when it was last looked at, and how often it had wrapped.
+    last: Cell<u32>,
+    wraps: Cell<u32>,
+}
+
+impl<A: Alarm> MonotonicClock<'a, A> {
+    pub const fn new(alarm: &'a A) -> MonotonicClock<'a, A> {
+        MonotonicClock {
+            alarm: alarm,
+            last: Cell::new(0),
+            wraps: Cell::new(0),
+        }
+    }
+
+    /// Start tracking wraps of the counter. Ticks count from 0 when the
+    /// counter is at 0, so the clock starts at the current counter value.
+    pub fn start(&self) {
+        self.last.set(self.alarm.now());
+        self.set_alarm();
+    }
+
+    /// Look at the counter again in half of its wrap period.
+    fn set_alarm(&self) {
+        self.alarm.set_alarm(self.last.get().wrapping_add(1 << 31));
+    }
+
+    /// Read the counter, and count a wrap if it is lower than last time.
+    fn update(&self) -> u64 {
+        let now = self.alarm.now();
+        if now < self.last.get() {
+            self.wraps.set(self.wraps.get().wrapping_add(1));
+        }
+        self.last.set(now);
+        ((self.wraps.get() as u64) << 32) | now as u64
+    }
+}
+
+impl<A: Alarm> Monotonic for MonotonicClock<'a, A> {
+    type Frequency = A::Frequency;
+
+    fn now(&self) -> Instant<A::Frequency> {
+        Instant::from_ticks(self.update())
+    }
+}
+
+impl<A: Alarm> time::Client for MonotonicClock<'a, A> {
+    fn fired(&self) {
+        self.update();
+        self.set_alarm();
+    }
+}
@@ -16,6 +16,22 @@ impl<T: Copy> RingBuffer<'a, T> {
             ring: ring,
         }
     }
+
+    /// Return a mutable reference to the oldest element in the queue for
+    /// which `predicate` returns `true`, if any.
+    pub fn find_mut<F>(&mut self, predicate: F) -> Option<&mut T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut i = self.head;
+        while i != self.tail {
+            if predicate(&self.ring[i]) {
+                return Some(&mut self.ring[i]);
+            }
+            i = (i + 1) % self.ring.len();
+        }
+        None
+    }
 }
 
 impl<T: Copy> queue::Queue<T> for RingBuffer<'a, T> {
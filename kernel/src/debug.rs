@@ -1,8 +1,9 @@
 //! Support for in-kernel debugging.
 //!
 //! For printing, this module uses an internal buffer to write the strings into.
-//! If you are writing and the buffer fills up, you can make the size of
-//! `output_buffer` larger.
+//! Messages that do not fit in the buffer are dropped whole, and the number of
+//! dropped messages is printed once there is room again. If messages are
+//! dropped often, you can make the size of `INTERNAL_BUF` larger.
 //!
 //! Before debug interfaces can be used, the board file must assign them hardware:
 //!
@@ -19,6 +20,33 @@
 //! kernel::debug::assign_console_driver(Some(hail.console), kc);
 //! ```
 //!
+//! Output goes to the `DebugSink` given to `DebugWriter::new`. `UartSink`
+//! adapts a UART to this interface. Other sinks are `capsules::segger_rtt`
+//! and the RAM ring and flash log in `capsules::debug_sinks`, and
+//! `capsules::debug_sinks::Fanout` sends the output to several sinks at once.
+//! There is no USB sink yet, as the USB stack has no CDC class.
+//!
+//! ```ignore
+//! let debug_sink = static_init!(
+//!     kernel::debug::UartSink<'static>,
+//!     kernel::debug::UartSink::new(debugger_uart)
+//! );
+//! hil::uart::UART::set_client(debugger_uart, debug_sink);
+//! let debugger = static_init!(
+//!     kernel::debug::DebugWriter,
+//!     kernel::debug::DebugWriter::new(
+//!         debug_sink,
+//!         &mut kernel::debug::OUTPUT_BUF,
+//!         &mut kernel::debug::INTERNAL_BUF,
+//!     )
+//! );
+//! kernel::debug::DebugSink::set_client(debug_sink, debugger);
+//! ```
+//!
+//! Sinks that can write without interrupts, such as RTT and the RAM ring,
+//! can also take the panic output: pass `SinkWriter` to `panic` instead of a
+//! UART writer.
+//!
 //! Example
 //! -------
 //!
@@ -36,6 +64,31 @@
 //! Yes the code gets here with value 42
 //! TOCK_DEBUG(0): /tock/capsules/src/sensys.rs:24: got here
 //! ```
+//!
+//! Logging
+//! -------
+//!
+//! Log records have a level, and are printed with the level, a timestamp and
+//! the module they come from. Records less severe than the level selected
+//! with the `log_max_level_*` features of the kernel crate are compiled out.
+//! The remaining records are filtered at runtime, by a default level and by
+//! per-module levels that the board can set. Timestamps are read from the
+//! monotonic clock given to `set_log_clock`.
+//!
+//! ```ignore
+//! kernel::debug::set_log_clock(clock);
+//! kernel::debug::set_log_level(Some(kernel::debug::Level::Info));
+//! kernel::debug::set_log_filters(&[
+//!     ("capsules::rf233", Some(kernel::debug::Level::Warn)),
+//!     ("capsules::sdcard", Some(kernel::debug::Level::Trace)),
+//! ]);
+//!
+//! log_warn!("retrying transmission {}", attempt);
+//! ```
+//!
+//! ```text
+//! [   12.345] W capsules::rf233: retrying transmission 2
+//! ```
 
 use core::cell::Cell;
 use core::cmp::{self, min};
@@ -46,7 +99,7 @@ use core::slice;
 use core::str;
 
 use common::cells::NumericCellExt;
-use common::cells::{MapCell, TakeCell};
+use common::cells::{MapCell, OptionalCell, TakeCell};
 use hil;
 use process::Process;
 
@@ -162,6 +215,92 @@ pub fn panic_blink_forever<L: hil::led::Led>(leds: &mut [&mut L]) -> ! {
 // panic! support routines
 ///////////////////////////////////////////////////////////////////
 
+///////////////////////////////////////////////////////////////////
+// watchdog crash log support
+
+/// What the kernel was doing at a given point in time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Activity {
+    /// The kernel was asleep waiting for an interrupt.
+    Idle,
+    /// The kernel was servicing interrupts, and if known, which one.
+    Interrupt(Option<u32>),
+    /// The kernel was running the process at this index, or handling one of
+    /// its system calls.
+    Process(usize),
+}
+
+/// Record kept across resets describing what the kernel was doing when the
+/// watchdog was about to fire.
+#[repr(C)]
+struct CrashLog {
+    magic: u32,
+    kind: u32,
+    id: u32,
+}
+
+const CRASH_LOG_MAGIC: u32 = 0x7d0c_c4a5;
+
+const ACTIVITY_IDLE: u32 = 0;
+const ACTIVITY_INTERRUPT: u32 = 1;
+const ACTIVITY_UNKNOWN_INTERRUPT: u32 = 2;
+const ACTIVITY_PROCESS: u32 = 3;
+
+/// The `.crash_log` section is not zeroed on boot, so the record survives the
+/// watchdog reset. It is only trusted if `magic` is set.
+#[link_section = ".crash_log"]
+static mut CRASH_LOG: CrashLog = CrashLog {
+    magic: 0,
+    kind: 0,
+    id: 0,
+};
+
+/// Store `activity` in the crash log. This is meant to be called right before
+/// the board resets, so it writes the record with volatile stores.
+pub unsafe fn record_crash_log(activity: Activity) {
+    let (kind, id) = match activity {
+        Activity::Idle => (ACTIVITY_IDLE, 0),
+        Activity::Interrupt(Some(interrupt)) => (ACTIVITY_INTERRUPT, interrupt),
+        Activity::Interrupt(None) => (ACTIVITY_UNKNOWN_INTERRUPT, 0),
+        Activity::Process(index) => (ACTIVITY_PROCESS, index as u32),
+    };
+    ptr::write_volatile(&mut CRASH_LOG.kind, kind);
+    ptr::write_volatile(&mut CRASH_LOG.id, id);
+    ptr::write_volatile(&mut CRASH_LOG.magic, CRASH_LOG_MAGIC);
+}
+
+/// Return the activity stored by the last watchdog early warning, if any, and
+/// clear the crash log. `report_crash_log` prints it.
+pub unsafe fn take_crash_log() -> Option<Activity> {
+    if ptr::read_volatile(&CRASH_LOG.magic) != CRASH_LOG_MAGIC {
+        return None;
+    }
+    ptr::write_volatile(&mut CRASH_LOG.magic, 0);
+
+    let id = ptr::read_volatile(&CRASH_LOG.id);
+    match ptr::read_volatile(&CRASH_LOG.kind) {
+        ACTIVITY_IDLE => Some(Activity::Idle),
+        ACTIVITY_INTERRUPT => Some(Activity::Interrupt(Some(id))),
+        ACTIVITY_UNKNOWN_INTERRUPT => Some(Activity::Interrupt(None)),
+        ACTIVITY_PROCESS => Some(Activity::Process(id as usize)),
+        _ => None,
+    }
+}
+
+/// Print what the kernel was doing if the watchdog reset the board, and clear
+/// the crash log. Boards call this once the debug writer is set.
+pub unsafe fn report_crash_log() {
+    take_crash_log().map(|activity| {
+        begin_debug_fmt(format_args!(
+            "Watchdog reset. Last kernel activity: {:?}",
+            activity
+        ));
+    });
+}
+
+// watchdog crash log support
+///////////////////////////////////////////////////////////////////
+
 ///////////////////////////////////////////////////////////////////
 // debug_gpio! support
 
@@ -192,6 +331,80 @@ macro_rules! debug_gpio {
     }};
 }
 
+///////////////////////////////////////////////////////////////////
+// Output sinks
+
+/// Where `DebugWriter` sends its output.
+pub trait DebugSink {
+    fn set_client(&self, client: &'static DebugSinkClient);
+
+    /// Send the first `len` bytes of `buffer`, and return the buffer with
+    /// `transmit_done`. Output the sink cannot send is dropped.
+    fn transmit(&self, buffer: &'static mut [u8], len: usize);
+
+    /// Send `bytes` before returning, without relying on interrupts. This is
+    /// only used on panic. Sinks that cannot do this drop the bytes.
+    fn write_now(&self, _bytes: &[u8]) {}
+}
+
+pub trait DebugSinkClient {
+    fn transmit_done(&self, buffer: &'static mut [u8]);
+}
+
+/// Sends debug output to a UART.
+pub struct UartSink<'a> {
+    uart: &'a hil::uart::UART,
+    client: OptionalCell<&'static DebugSinkClient>,
+}
+
+impl UartSink<'a> {
+    pub fn new(uart: &'a hil::uart::UART) -> UartSink<'a> {
+        UartSink {
+            uart: uart,
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl DebugSink for UartSink<'a> {
+    fn set_client(&self, client: &'static DebugSinkClient) {
+        self.client.set(client);
+    }
+
+    fn transmit(&self, buffer: &'static mut [u8], len: usize) {
+        self.uart.transmit(buffer, len);
+    }
+}
+
+impl hil::uart::Client for UartSink<'a> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: hil::uart::Error) {
+        self.client.map(move |client| client.transmit_done(buffer));
+    }
+
+    fn receive_complete(
+        &self,
+        _buffer: &'static mut [u8],
+        _rx_len: usize,
+        _error: hil::uart::Error,
+    ) {
+    }
+}
+
+/// Panic writer that writes to the sink of the debug writer with
+/// `DebugSink::write_now`.
+pub struct SinkWriter;
+
+impl Write for SinkWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        unsafe {
+            if let Some(ref wrapper) = DEBUG_WRITER {
+                wrapper.dw.map(|dw| dw.sink.write_now(s.as_bytes()));
+            }
+        }
+        Ok(())
+    }
+}
+
 ///////////////////////////////////////////////////////////////////
 // debug! and debug_verbose! support
 
@@ -202,10 +415,10 @@ pub struct DebugWriterWrapper {
 }
 
 /// Main type that we need an immutable reference to so we can share it with
-/// the UART provider and this debug module.
+/// the output sink and this debug module.
 pub struct DebugWriter {
     // What provides the actual writing mechanism.
-    uart: &'static hil::uart::UART,
+    sink: &'static DebugSink,
     // The buffer that is passed to the writing mechanism.
     output_buffer: TakeCell<'static, [u8]>,
     // An internal buffer that is used to hold debug!() calls as they come in.
@@ -216,6 +429,11 @@ pub struct DebugWriter {
     active_len: Cell<usize>,
     // Number of debug!() calls.
     count: Cell<usize>,
+    // Where the message being written started, and whether it did not fit.
+    message_start: Cell<usize>,
+    overflowed: Cell<bool>,
+    // Number of messages dropped because the internal buffer was full.
+    dropped: Cell<usize>,
 }
 
 /// Static variable that holds the kernel's reference to the debug tool. This is
@@ -247,18 +465,21 @@ impl DebugWriterWrapper {
 
 impl DebugWriter {
     pub fn new(
-        uart: &'static hil::uart::UART,
+        sink: &'static DebugSink,
         out_buffer: &'static mut [u8],
         internal_buffer: &'static mut [u8],
     ) -> DebugWriter {
         DebugWriter {
-            uart: uart,
+            sink: sink,
             output_buffer: TakeCell::new(out_buffer),
             internal_buffer: TakeCell::new(internal_buffer),
             head: Cell::new(0),       // first valid index in output_buffer
             tail: Cell::new(0),       // one past last valid index (wraps to 0)
             active_len: Cell::new(0), // how big is the current transaction?
             count: Cell::new(0),      // how many debug! calls
+            message_start: Cell::new(0),
+            overflowed: Cell::new(false),
+            dropped: Cell::new(0),
         }
     }
 
@@ -270,6 +491,24 @@ impl DebugWriter {
         self.count.get()
     }
 
+    /// Start a message. If any part of it does not fit in the internal
+    /// buffer, the whole message is dropped by `end_message`.
+    fn begin_message(&self) {
+        self.message_start.set(self.head.get());
+        self.overflowed.set(false);
+    }
+
+    /// Finish a message, and return whether it fit in the internal buffer.
+    fn end_message(&self) -> bool {
+        if self.overflowed.get() {
+            self.head.set(self.message_start.get());
+            self.overflowed.set(false);
+            false
+        } else {
+            true
+        }
+    }
+
     /// Convenience method that writes (end-start) bytes from bytes into the
     /// internal debug buffer.
     fn write_buffer(&self, start: usize, end: usize, bytes: &[u8]) {
@@ -286,6 +525,11 @@ impl DebugWriter {
     /// Write as many of the bytes from the internal_buffer to the output
     /// mechanism as possible.
     fn publish_str(&self) {
+        // Nothing to publish if every message was dropped.
+        if self.head.get() == self.tail.get() {
+            return;
+        }
+
         // Can only publish if we have the output_buffer. If we don't that is
         // fine, we will do it when the transmit done callback happens.
         self.output_buffer.take().map(|out_buffer| {
@@ -331,7 +575,7 @@ impl DebugWriter {
             self.active_len.set(out_len);
 
             // Transmit the data in the output buffer.
-            self.uart.transmit(out_buffer, out_len);
+            self.sink.transmit(out_buffer, out_len);
         });
     }
 
@@ -342,8 +586,8 @@ impl DebugWriter {
     }
 }
 
-impl hil::uart::Client for DebugWriter {
-    fn transmit_complete(&self, buffer: &'static mut [u8], _error: hil::uart::Error) {
+impl DebugSinkClient for DebugWriter {
+    fn transmit_done(&self, buffer: &'static mut [u8]) {
         // Replace this buffer since we are done with it.
         self.output_buffer.replace(buffer);
 
@@ -373,14 +617,6 @@ impl hil::uart::Client for DebugWriter {
             self.publish_str();
         }
     }
-
-    fn receive_complete(
-        &self,
-        _buffer: &'static mut [u8],
-        _rx_len: usize,
-        _error: hil::uart::Error,
-    ) {
-    }
 }
 
 /// Pass through functions.
@@ -401,6 +637,22 @@ impl DebugWriterWrapper {
         });
     }
 
+    fn begin_message(&self) {
+        self.dw.map(|dw| dw.begin_message());
+    }
+
+    fn end_message(&self) -> bool {
+        self.dw.map_or(false, |dw| dw.end_message())
+    }
+
+    fn dropped(&self) -> usize {
+        self.dw.map_or(0, |dw| dw.dropped.get())
+    }
+
+    fn set_dropped(&self, dropped: usize) {
+        self.dw.map(|dw| dw.dropped.set(dropped));
+    }
+
     fn extract(&self) -> Option<(usize, usize, &mut [u8])> {
         self.dw.map_or(None, |dw| dw.extract())
     }
@@ -426,6 +678,20 @@ impl Write for DebugWriterWrapper {
             let tail = dw.tail.get();
             let len = dw.internal_buffer.map_or(0, |buffer| buffer.len());
 
+            // Once part of a message did not fit, drop the rest of it too.
+            if dw.overflowed.get() {
+                return;
+            }
+            let used = if head >= tail {
+                head - tail
+            } else {
+                len - tail + head
+            };
+            if len == 0 || s.len() > len - 1 - used {
+                dw.overflowed.set(true);
+                return;
+            }
+
             let remaining_bytes = if head >= tail {
                 let bytes = s.as_bytes();
 
@@ -460,33 +726,14 @@ impl Write for DebugWriterWrapper {
             //  o head < tail
             //  o head = len-1, tail = 0 (buffer full edge case)
             //  o there are no more bytes to write
+            //
+            // The check for free space above means that the remaining bytes
+            // fit between head and tail.
 
             if remaining_bytes.len() != 0 {
                 // Now write from the head up to tail
                 let start = head;
                 let end = tail;
-                if (tail == 0) && (head == len - 1) {
-                    let active = dw.active_len.get();
-                    panic!(
-                        "Debug buffer full. Head {} tail {} len {} active {} remaining {}",
-                        head,
-                        tail,
-                        len,
-                        active,
-                        remaining_bytes.len()
-                    );
-                }
-                if remaining_bytes.len() > end - start {
-                    let active = dw.active_len.get();
-                    panic!(
-                        "Debug buffer out of room. Head {} tail {} len {} active {} remaining {}",
-                        head,
-                        tail,
-                        len,
-                        active,
-                        remaining_bytes.len()
-                    );
-                }
                 dw.write_buffer(start, end, remaining_bytes);
                 let written = min(end - start, remaining_bytes.len());
 
@@ -501,27 +748,55 @@ impl Write for DebugWriterWrapper {
     }
 }
 
+/// Write one message to the debug buffer with `write_message`, and start
+/// printing it. Messages that do not fit are dropped and counted, and the
+/// count is printed before the next message that fits.
+unsafe fn debug_message<F: FnOnce(&mut DebugWriterWrapper)>(write_message: F) {
+    let writer = get_debug_writer();
+
+    let dropped = writer.dropped();
+    if dropped > 0 {
+        writer.begin_message();
+        let _ = writer.write_fmt(format_args!("TOCK_DEBUG: {} messages dropped\n", dropped));
+        if writer.end_message() {
+            writer.set_dropped(0);
+        }
+    }
+
+    writer.begin_message();
+    write_message(writer);
+    if !writer.end_message() {
+        writer.set_dropped(writer.dropped() + 1);
+    }
+    writer.publish_str();
+}
+
+/// Return the number of debug messages dropped because the debug buffer was
+/// full, and not yet reported.
+pub fn dropped_messages() -> usize {
+    unsafe { get_debug_writer().dropped() }
+}
+
 pub fn begin_debug_fmt(args: Arguments) {
     unsafe {
-        let writer = get_debug_writer();
-        let _ = write(writer, args);
-        let _ = writer.write_str("\n");
-        writer.publish_str();
+        debug_message(|writer| {
+            let _ = write(writer, args);
+            let _ = writer.write_str("\n");
+        });
     }
 }
 
 pub fn begin_debug_verbose_fmt(args: Arguments, file_line: &(&'static str, u32)) {
     unsafe {
-        let writer = get_debug_writer();
-
-        writer.increment_count();
-        let count = writer.get_count();
-
-        let (file, line) = *file_line;
-        let _ = writer.write_fmt(format_args!("TOCK_DEBUG({}): {}:{}: ", count, file, line));
-        let _ = write(writer, args);
-        let _ = writer.write_str("\n");
-        writer.publish_str();
+        debug_message(|writer| {
+            writer.increment_count();
+            let count = writer.get_count();
+
+            let (file, line) = *file_line;
+            let _ = writer.write_fmt(format_args!("TOCK_DEBUG({}): {}:{}: ", count, file, line));
+            let _ = write(writer, args);
+            let _ = writer.write_str("\n");
+        });
     }
 }
 
@@ -563,6 +838,187 @@ macro_rules! debug_verbose {
     });
 }
 
+///////////////////////////////////////////////////////////////////
+// log! support
+
+/// Severity of a log record, from most to least severe.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Level::Error => "E",
+            Level::Warn => "W",
+            Level::Info => "I",
+            Level::Debug => "D",
+            Level::Trace => "T",
+        }
+    }
+}
+
+/// Least severe level that is compiled in, or `None` if logging is compiled
+/// out. Selected with the `log_max_level_*` features of the kernel crate.
+#[cfg(feature = "log_max_level_off")]
+pub const STATIC_MAX_LEVEL: Option<Level> = None;
+#[cfg(all(feature = "log_max_level_error", not(feature = "log_max_level_off")))]
+pub const STATIC_MAX_LEVEL: Option<Level> = Some(Level::Error);
+#[cfg(all(
+    feature = "log_max_level_warn",
+    not(any(feature = "log_max_level_off", feature = "log_max_level_error"))
+))]
+pub const STATIC_MAX_LEVEL: Option<Level> = Some(Level::Warn);
+#[cfg(all(
+    feature = "log_max_level_info",
+    not(any(
+        feature = "log_max_level_off",
+        feature = "log_max_level_error",
+        feature = "log_max_level_warn"
+    ))
+))]
+pub const STATIC_MAX_LEVEL: Option<Level> = Some(Level::Info);
+#[cfg(all(
+    feature = "log_max_level_debug",
+    not(any(
+        feature = "log_max_level_off",
+        feature = "log_max_level_error",
+        feature = "log_max_level_warn",
+        feature = "log_max_level_info"
+    ))
+))]
+pub const STATIC_MAX_LEVEL: Option<Level> = Some(Level::Debug);
+#[cfg(not(any(
+    feature = "log_max_level_off",
+    feature = "log_max_level_error",
+    feature = "log_max_level_warn",
+    feature = "log_max_level_info",
+    feature = "log_max_level_debug"
+)))]
+pub const STATIC_MAX_LEVEL: Option<Level> = Some(Level::Trace);
+
+/// Source of log timestamps. Any `hil::time::Monotonic` clock is one.
+pub trait LogClock {
+    /// Time since the clock started.
+    fn since_start(&self) -> hil::time::Duration;
+}
+
+impl<M: hil::time::Monotonic> LogClock for M {
+    fn since_start(&self) -> hil::time::Duration {
+        self.now().since_start()
+    }
+}
+
+/// Least severe level printed by modules without a filter.
+static mut LOG_LEVEL: Option<Level> = Some(Level::Info);
+
+/// Module path prefixes with the least severe level printed for them.
+static mut LOG_FILTERS: &'static [(&'static str, Option<Level>)] = &[];
+
+static mut LOG_CLOCK: Option<&'static LogClock> = None;
+
+/// Set the least severe level printed by modules without a filter, or `None`
+/// to print nothing from them.
+pub unsafe fn set_log_level(level: Option<Level>) {
+    LOG_LEVEL = level;
+}
+
+/// Set per-module levels. Each filter applies to the module with the given
+/// path and the modules inside it, and the longest matching path is used.
+pub unsafe fn set_log_filters(filters: &'static [(&'static str, Option<Level>)]) {
+    LOG_FILTERS = filters;
+}
+
+/// Set the clock that log records are timestamped with, such as a
+/// `kernel::common::monotonic::MonotonicClock`.
+pub unsafe fn set_log_clock(clock: &'static LogClock) {
+    LOG_CLOCK = Some(clock);
+}
+
+/// Return whether `module` is logging records of `level`.
+#[inline]
+pub fn log_enabled(level: Level, module: &str) -> bool {
+    if STATIC_MAX_LEVEL.map_or(true, |max| level > max) {
+        return false;
+    }
+
+    let (mut matched, mut max) = (0, unsafe { LOG_LEVEL });
+    for &(path, filter) in unsafe { LOG_FILTERS }.iter() {
+        let matches = module.starts_with(path)
+            && (module.len() == path.len() || module[path.len()..].starts_with("::"));
+        if matches && path.len() >= matched {
+            matched = path.len();
+            max = filter;
+        }
+    }
+    max.map_or(false, |max| level <= max)
+}
+
+/// Milliseconds since the log clock started, if there is a log clock.
+unsafe fn log_timestamp() -> Option<u64> {
+    LOG_CLOCK.map(|clock| clock.since_start().as_millis())
+}
+
+pub fn begin_log_fmt(level: Level, module: &'static str, args: Arguments) {
+    unsafe {
+        let timestamp = log_timestamp();
+        debug_message(|writer| {
+            if let Some(ms) = timestamp {
+                let _ = writer.write_fmt(format_args!("[{:5}.{:03}] ", ms / 1000, ms % 1000));
+            }
+            let _ = writer.write_fmt(format_args!("{} {}: ", level.as_str(), module));
+            let _ = write(writer, args);
+            let _ = writer.write_str("\n");
+        });
+    }
+}
+
+/// In-kernel logging with a level.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => ({
+        let level: $crate::debug::Level = $level;
+        if $crate::debug::log_enabled(level, module_path!()) {
+            $crate::debug::begin_log_fmt(level, module_path!(), format_args!($($arg)+))
+        }
+    });
+}
+
+/// Log an error.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => (log!($crate::debug::Level::Error, $($arg)+));
+}
+
+/// Log a warning.
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => (log!($crate::debug::Level::Warn, $($arg)+));
+}
+
+/// Log information about normal operation.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => (log!($crate::debug::Level::Info, $($arg)+));
+}
+
+/// Log details useful when debugging.
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => (log!($crate::debug::Level::Debug, $($arg)+));
+}
+
+/// Log fine-grained tracing.
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => (log!($crate::debug::Level::Trace, $($arg)+));
+}
+
 pub trait Debug {
     fn write(&self, buf: &'static mut [u8], len: usize);
 }
@@ -17,6 +17,7 @@ pub struct Grant<T: Default> {
 
 pub struct AppliedGrant<T> {
     appid: AppId,
+    grant_num: usize,
     grant: *mut T,
     _phantom: PhantomData<T>,
 }
@@ -27,7 +28,10 @@ impl<T> AppliedGrant<T> {
         F: FnOnce(&mut Owned<T>, &mut Allocator) -> R,
         R: Copy,
     {
-        let mut allocator = Allocator { appid: self.appid };
+        let mut allocator = Allocator {
+            appid: self.appid,
+            grant_num: self.grant_num,
+        };
         let mut root = unsafe { Owned::new(self.grant, self.appid) };
         fun(&mut root, &mut allocator)
     }
@@ -35,6 +39,8 @@ impl<T> AppliedGrant<T> {
 
 pub struct Allocator {
     appid: AppId,
+    /// Allocations are accounted to this grant in the process.
+    grant_num: usize,
 }
 
 pub struct Owned<T: ?Sized> {
@@ -88,7 +94,7 @@ impl Allocator {
                 .kernel
                 .process_map_or(Err(Error::NoSuchApp), self.appid.idx(), |process| {
                     process
-                        .alloc(size_of::<T>())
+                        .alloc(size_of::<T>(), self.grant_num)
                         .map_or(Err(Error::OutOfMemory), |arr| {
                             let ptr = arr.as_mut_ptr() as *mut T;
                             // We use `ptr::write` to avoid `Drop`ping the uninitialized memory in
@@ -150,6 +156,7 @@ impl<T: Default> Grant<T> {
                 } else {
                     Some(AppliedGrant {
                         appid: appid,
+                        grant_num: self.grant_num,
                         grant: cntr,
                         _phantom: PhantomData,
                     })
@@ -171,7 +178,10 @@ impl<T: Default> Grant<T> {
                         Err(Error::OutOfMemory),
                         move |root_ptr| {
                             let mut root = Borrowed::new(&mut *root_ptr, appid);
-                            let mut allocator = Allocator { appid: appid };
+                            let mut allocator = Allocator {
+                                appid: appid,
+                                grant_num: self.grant_num,
+                            };
                             let res = fun(&mut root, &mut allocator);
                             Ok(res)
                         },
@@ -0,0 +1,77 @@
+//! Interface for block storage devices.
+//!
+//! A block device stores a fixed number of equally sized blocks, which are
+//! read and written whole. SD cards, SPI flash chips, FRAM and internal flash
+//! can all be used through this interface, so storage capsules such as file
+//! systems and logs can run on any of them.
+//!
+//! Blocks do not have to be erased before they are written: devices that need
+//! it erase a block as part of writing it. `erase_block` exists for devices
+//! with an erased state, where erasing blocks early can make later writes
+//! faster or clear data that is no longer needed.
+//!
+//! Devices such as removable cards are not ready when the board starts. Their
+//! `num_blocks` is 0 until they are, and they call `ready` on their client
+//! once they can be used, and again if they stop being usable.
+
+use returncode::ReturnCode;
+
+pub trait BlockDevice<'a> {
+    /// Set the client that is called when requests complete.
+    fn set_client(&self, client: &'a Client);
+
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device, or 0 while it is not known, such as
+    /// before a removable card has been initialized.
+    fn num_blocks(&self) -> usize;
+
+    /// Read block `block` into the start of `buffer`, which must be at least
+    /// `block_size` bytes long. The buffer is returned in `read_done`.
+    ///
+    /// If the request cannot be started, the error is returned along with
+    /// the buffer, and there is no callback.
+    fn read_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Write the first `block_size` bytes of `buffer` to block `block`. The
+    /// buffer is returned in `write_done`.
+    ///
+    /// If the request cannot be started, the error is returned along with
+    /// the buffer, and there is no callback.
+    fn write_block(
+        &self,
+        block: usize,
+        buffer: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Erase block `block`. Completion is signalled with `erase_done`, unless
+    /// an error is returned. Devices without an erased state return
+    /// `ENOSUPPORT`.
+    fn erase_block(&self, block: usize) -> ReturnCode;
+
+    /// Make sure that all blocks written so far are stored persistently.
+    /// Completion is signalled with `flush_done`. Devices that store blocks
+    /// before `write_done` is called return `EALREADY`, and there is no
+    /// callback.
+    fn flush(&self) -> ReturnCode;
+}
+
+/// Implement `Client` to receive the results of `BlockDevice` requests.
+pub trait Client {
+    /// The device became ready and `num_blocks` is known (`SUCCESS`), it
+    /// failed to become ready, or it can no longer be used, such as when a
+    /// card is removed (an error). Blocks read before an error may have
+    /// changed by the time the device is ready again. Devices that are ready
+    /// from the start never call this.
+    fn ready(&self, result: ReturnCode);
+
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8]);
+    fn write_done(&self, result: ReturnCode, buffer: &'static mut [u8]);
+    fn erase_done(&self, result: ReturnCode);
+    fn flush_done(&self, result: ReturnCode);
+}
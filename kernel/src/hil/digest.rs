@@ -0,0 +1,75 @@
+//! Interface for message digests: SHA-256 and HMAC-SHA256.
+//!
+//! A digest engine is put into a mode with `set_mode_sha256()` or
+//! `set_mode_hmacsha256()`, fed the message with any number of `add_data()`
+//! calls, and produces the digest with `run()`. `add_data()` and `run()` are
+//! split-phase: the engine keeps the buffer it is given until it hands it back
+//! through the matching `Client` callback.
+//!
+//! ```ignore
+//! engine.set_mode_sha256();
+//! engine.add_data(message, message.len());
+//! // ... Client::add_data_done(SUCCESS, message)
+//! engine.run(digest);
+//! // ... Client::hash_done(SUCCESS, digest)
+//! ```
+
+use returncode::ReturnCode;
+
+/// The length of a SHA-256 digest, and of an HMAC-SHA256 tag.
+pub const SHA256_DIGEST_SIZE: usize = 32;
+
+/// The number of bytes SHA-256 processes at a time. HMAC keys longer than
+/// this are hashed down to a digest before use.
+pub const SHA256_BLOCK_SIZE: usize = 64;
+
+/// Implement this trait and use `set_client()` in order to receive callbacks
+/// from a `Digest` instance.
+pub trait Client<'a> {
+    /// `add_data()` is done with `data`. If `result` is `SUCCESS` all of the
+    /// requested data was added to the digest.
+    fn add_data_done(&'a self, result: ReturnCode, data: &'a mut [u8]);
+
+    /// `run()` finished. If `result` is `SUCCESS`, `digest` holds the digest
+    /// of all data added since the mode was set.
+    fn hash_done(&'a self, result: ReturnCode, digest: &'a mut [u8; SHA256_DIGEST_SIZE]);
+}
+
+pub trait Digest<'a> {
+    /// Set the client instance which will receive callbacks.
+    fn set_client(&'a self, client: &'a Client<'a>);
+
+    /// Start a new SHA-256 digest, discarding any data added so far.
+    ///
+    /// Returns `EBUSY` if an `add_data()` or `run()` is outstanding.
+    fn set_mode_sha256(&self) -> ReturnCode;
+
+    /// Start a new HMAC-SHA256 tag with `key`, discarding any data added so
+    /// far. The key may be of any length.
+    ///
+    /// Returns `EBUSY` if an `add_data()` or `run()` is outstanding.
+    fn set_mode_hmacsha256(&self, key: &[u8]) -> ReturnCode;
+
+    /// Add the first `length` bytes of `data` to the digest.
+    ///
+    /// On `SUCCESS` the engine keeps `data` and returns it through
+    /// `Client::add_data_done()`. Otherwise the error is returned with
+    /// `data`: `EINVAL` if `length` is larger than `data`, `EBUSY` if an
+    /// operation is outstanding, and `EOFF` if no mode has been set.
+    fn add_data(&self, data: &'a mut [u8], length: usize) -> (ReturnCode, Option<&'a mut [u8]>);
+
+    /// Finish the digest of the data added so far and write it into
+    /// `digest`.
+    ///
+    /// On `SUCCESS` the engine keeps `digest` and returns it through
+    /// `Client::hash_done()`, after which it starts a new digest in the same
+    /// mode (and with the same key). Errors are reported as for `add_data()`.
+    fn run(
+        &self,
+        digest: &'a mut [u8; SHA256_DIGEST_SIZE],
+    ) -> (ReturnCode, Option<&'a mut [u8; SHA256_DIGEST_SIZE]>);
+
+    /// Discard the data added so far and start a new digest in the same
+    /// mode. Has no effect while an operation is outstanding.
+    fn clear_data(&self);
+}
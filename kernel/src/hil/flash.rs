@@ -58,6 +58,7 @@
 //!
 //!     fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode { ReturnCode::FAIL }
 //!     fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode { ReturnCode::FAIL }
+//!     fn program_page(&self, page_number: usize, buf: &'static mut Self::Page, offset: usize, length: usize) -> ReturnCode { ReturnCode::FAIL }
 //!     fn erase_page(&self, page_number: usize) -> ReturnCode { ReturnCode::FAIL }
 //! }
 //! ```
@@ -118,6 +119,18 @@ pub trait Flash {
     /// Write a page of flash from the buffer.
     fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode;
 
+    /// Program bytes `offset..offset + length` of a page from the same bytes
+    /// of the buffer, without erasing the page first. Programming can only
+    /// clear bits, so those bytes should still be erased. The rest of the
+    /// page is left as it is. Completion is signalled with `write_complete`.
+    fn program_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode;
+
     /// Erase a page of flash.
     fn erase_page(&self, page_number: usize) -> ReturnCode;
 }
@@ -0,0 +1,56 @@
+//! Interface for persistent key-value stores.
+//!
+//! Keys are short byte strings that the store copies when a request is made.
+//! Values are passed in buffers that are handed back in the completion
+//! callback. A store may have several users, each with its own namespace, so
+//! users do not see each other's keys.
+
+use returncode::ReturnCode;
+
+pub trait KVStore<'a> {
+    /// Set the client that is called when requests complete.
+    fn set_client(&'a self, client: &'a Client);
+
+    /// Copy the value stored under `key` into `value`. The value is returned
+    /// in `get_complete`.
+    ///
+    /// If the request cannot be started, the error is returned along with
+    /// the buffer, and there is no callback.
+    fn get(
+        &'a self,
+        key: &[u8],
+        value: &'static mut [u8],
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Store the first `length` bytes of `value` under `key`, replacing any
+    /// existing value. The buffer is returned in `set_complete`, after the
+    /// value has been committed to storage.
+    ///
+    /// If the request cannot be started, the error is returned along with
+    /// the buffer, and there is no callback.
+    fn set(
+        &'a self,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Remove `key` and its value. Completion is signalled with
+    /// `delete_complete`, unless an error is returned.
+    fn delete(&'a self, key: &[u8]) -> ReturnCode;
+}
+
+/// Implement `Client` to receive the results of `KVStore` requests.
+pub trait Client {
+    /// A get finished. `length` is the length of the stored value, which may
+    /// be larger than `value`, in which case only the start of the value was
+    /// copied. `result` is `ENOSUPPORT` if there is no value for the key.
+    fn get_complete(&self, result: ReturnCode, value: &'static mut [u8], length: usize);
+
+    /// A set finished. `result` is `ENOMEM` if the store is full.
+    fn set_complete(&self, result: ReturnCode, value: &'static mut [u8]);
+
+    /// A delete finished. `result` is `ENOSUPPORT` if there was no value for
+    /// the key.
+    fn delete_complete(&self, result: ReturnCode);
+}
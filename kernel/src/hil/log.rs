@@ -0,0 +1,69 @@
+//! Interface for append-only logs.
+//!
+//! A log holds a sequence of entries, each a short byte string numbered by
+//! the log when it is appended. Entry numbers grow by one with every entry
+//! appended. Logs have a fixed capacity, and a log that is full drops its
+//! oldest entries to make room for new ones, so the oldest entry still in
+//! the log can change with every append.
+//!
+//! A log has a read position, which is the number of the next entry to read.
+//! Reading an entry moves the position to the entry after it.
+
+use returncode::ReturnCode;
+
+pub trait Log<'a> {
+    /// Set the client that is called when requests complete.
+    fn set_client(&self, client: &'a Client);
+
+    /// Number of the oldest entry in the log.
+    fn oldest_entry(&self) -> u32;
+
+    /// Number the next entry appended will get. The log is empty if this is
+    /// the same as `oldest_entry`.
+    fn next_entry(&self) -> u32;
+
+    /// Longest entry that can be appended.
+    fn max_entry_len(&self) -> usize;
+
+    /// Move the read position to entry `entry`. If the entry has been
+    /// dropped, reading continues at the oldest entry.
+    fn seek(&self, entry: u32) -> ReturnCode;
+
+    /// Read the entry at the read position into `buffer`. The entry is
+    /// returned in `read_done`. Returns `ENOSUPPORT` if there are no more
+    /// entries to read.
+    ///
+    /// If the request cannot be started, the error is returned along with
+    /// the buffer, and there is no callback.
+    fn read(&self, buffer: &'static mut [u8]) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Append the first `length` bytes of `buffer` as a new entry. The buffer
+    /// is returned in `append_done`, after the entry has been committed to
+    /// storage.
+    ///
+    /// If the request cannot be started, the error is returned along with
+    /// the buffer, and there is no callback.
+    fn append(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Remove all entries. Completion is signalled with `erase_done`, unless
+    /// an error is returned.
+    fn erase(&self) -> ReturnCode;
+}
+
+/// Implement `Client` to receive the results of `Log` requests.
+pub trait Client {
+    /// Entry `entry` was read. `length` is the length of the entry, which may
+    /// be larger than `buffer`, in which case only the start of the entry was
+    /// copied.
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize, entry: u32);
+
+    /// An append finished, and the entry was given number `entry`.
+    fn append_done(&self, result: ReturnCode, buffer: &'static mut [u8], entry: u32);
+
+    /// An erase finished.
+    fn erase_done(&self, result: ReturnCode);
+}
@@ -2,16 +2,21 @@
 
 pub mod adc;
 pub mod ble_advertising;
+pub mod block;
 pub mod crc;
 pub mod dac;
+pub mod digest;
 pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod kv_store;
+pub mod log;
 pub mod led;
 pub mod nonvolatile_storage;
 pub mod radio;
 pub mod rng;
+pub mod rtc;
 pub mod sensors;
 pub mod spi;
 pub mod symmetric_encryption;
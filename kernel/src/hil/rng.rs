@@ -98,3 +98,27 @@ pub trait Client {
     /// more is available, or `Continue::Done`.
     fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> Continue;
 }
+
+/// Synchronous interface to a random number generator that is seeded once and
+/// then produces values on demand, such as a deterministic random bit
+/// generator.
+///
+/// Because `random()` returns immediately, any number of kernel clients can
+/// share one `Random` instance.
+pub trait Random<'a> {
+    /// Start seeding the generator from its entropy source, if it has one.
+    fn initialize(&'a self);
+
+    /// Mix `seed` into the generator's state. On a generator without an
+    /// entropy source this is the only way to seed it.
+    fn reseed(&self, seed: u32);
+
+    /// Return a random number.
+    ///
+    /// Values returned before the generator is seeded are not
+    /// unpredictable; clients that need them to be should check `seeded()`.
+    fn random(&self) -> u32;
+
+    /// Whether the generator has been seeded.
+    fn seeded(&self) -> bool;
+}
@@ -0,0 +1,153 @@
+//! Interface for real-time clocks, which keep calendar time.
+//!
+//! Times are in UTC. Hardware RTCs often sit on a bus, so reading and setting
+//! the time are split-phase like other bus operations, and complete with a
+//! callback to the [`Client`](trait.Client.html).
+//!
+//! `capsules::software_rtc::SoftwareRtc` implements this interface on top of a
+//! `Monotonic` clock, for boards without an RTC.
+
+use returncode::ReturnCode;
+
+/// A date and time in UTC, with one second resolution.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    /// 0 to 23.
+    pub hour: u8,
+    /// 0 to 59.
+    pub minute: u8,
+    /// 0 to 59. Leap seconds are not supported.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The earliest time supported, 1970-01-01 00:00:00.
+    pub const EPOCH: DateTime = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    /// Returns whether this is a real date and time, no earlier than
+    /// `EPOCH`.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// The time `secs` seconds after `EPOCH`, like a Unix timestamp.
+    pub fn from_unix_secs(secs: u64) -> DateTime {
+        // Convert days to a date counting years from March, so that the leap
+        // day is the last day of the year. See
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = secs / 86400 + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+        let secs_of_day = secs % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+    /// Seconds since `EPOCH`, like a Unix timestamp. The date and time must
+    /// be valid.
+    pub fn unix_secs(&self) -> u64 {
+        // The inverse of `from_unix_secs`.
+        let year = self.year as u64 - if self.month <= 2 { 1 } else { 0 };
+        let month = self.month as u64;
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_from_march + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Day of the week, from 0 for Sunday to 6 for Saturday.
+    pub fn day_of_week(&self) -> u8 {
+        // 1970-01-01 was a Thursday.
+        ((self.unix_secs() / 86400 + 4) % 7) as u8
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+pub trait Rtc<'a> {
+    /// Set the client that is called when requests complete and when the
+    /// alarm fires.
+    fn set_client(&self, client: &'a Client);
+
+    /// Read the current time. The time is passed to `get_date_time_done`.
+    /// Returns `ERESERVE` if the time has not been set since the clock lost
+    /// power, and `EBUSY` while another request is in progress.
+    fn get_date_time(&self) -> ReturnCode;
+
+    /// Set the current time, which must be valid. Completion is signalled
+    /// with `set_date_time_done`.
+    fn set_date_time(&self, date_time: DateTime) -> ReturnCode;
+
+    /// Call `alarm_fired` once the time reaches `date_time`, replacing any
+    /// alarm that is already set. Alarms follow changes to the time, so an
+    /// alarm fires at once if the time is set past it.
+    fn set_alarm(&self, date_time: DateTime) -> ReturnCode;
+
+    /// Cancel the alarm. Returns `EALREADY` if no alarm is set.
+    fn disable_alarm(&self) -> ReturnCode;
+}
+
+/// Implement `Client` to receive the results of `Rtc` requests.
+pub trait Client {
+    /// The time has been read. `date_time` is only meaningful if `result` is
+    /// `SUCCESS`.
+    fn get_date_time_done(&self, result: ReturnCode, date_time: DateTime);
+
+    /// The time has been set.
+    fn set_date_time_done(&self, result: ReturnCode);
+
+    /// The time set with `set_alarm` has been reached.
+    fn alarm_fired(&self);
+}
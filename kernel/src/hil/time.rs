@@ -1,4 +1,12 @@
 //! Hardware agnostic interfaces for counter-like resources.
+//!
+//! `Alarm` counters are 32 bits wide and wrap around. `Monotonic` clocks
+//! count ticks in 64 bits and do not wrap in practice, and `Instant` and
+//! `Duration` convert between their ticks and real time.
+
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+use core::ops::{Add, Sub};
 
 pub trait Time {
     type Frequency: Frequency;
@@ -98,3 +106,192 @@ pub trait Timer: Time {
     /// Sets repeating timer to fire every `interval` clock-tics.
     fn repeat(&self, interval: u32);
 }
+
+/// A span of time, with microsecond resolution.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    us: u64,
+}
+
+impl Duration {
+    pub fn from_secs(secs: u64) -> Duration {
+        Duration {
+            us: secs.saturating_mul(1_000_000),
+        }
+    }
+
+    pub fn from_millis(ms: u64) -> Duration {
+        Duration {
+            us: ms.saturating_mul(1000),
+        }
+    }
+
+    pub fn from_micros(us: u64) -> Duration {
+        Duration { us: us }
+    }
+
+    /// The length of `ticks` ticks of a clock running at `frequency` Hz.
+    pub fn from_ticks(ticks: u64, frequency: u32) -> Duration {
+        let frequency = frequency as u64;
+        if frequency == 0 {
+            return Duration::default();
+        }
+        // Split into whole seconds so that the intermediate values cannot
+        // overflow.
+        let secs = ticks / frequency;
+        let rest = ticks % frequency;
+        Duration {
+            us: secs.saturating_mul(1_000_000) + rest * 1_000_000 / frequency,
+        }
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.us / 1_000_000
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.us / 1000
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.us
+    }
+
+    /// The number of whole ticks of a clock running at `frequency` Hz in
+    /// this duration.
+    pub fn ticks(&self, frequency: u32) -> u64 {
+        let frequency = frequency as u64;
+        let secs = self.us / 1_000_000;
+        let rest = self.us % 1_000_000;
+        secs.saturating_mul(frequency) + rest * frequency / 1_000_000
+    }
+
+    /// The number of whole ticks of a clock with frequency `F`.
+    pub fn ticks_for<F: Frequency>(&self) -> u64 {
+        self.ticks(F::frequency())
+    }
+
+    pub fn saturating_sub(self, other: Duration) -> Duration {
+        Duration {
+            us: self.us.saturating_sub(other.us),
+        }
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration {
+        Duration {
+            us: self.us.saturating_add(other.us),
+        }
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, other: Duration) -> Duration {
+        self.saturating_sub(other)
+    }
+}
+
+/// A point in time, as the number of ticks of a `Monotonic` clock with
+/// frequency `F` since it started.
+pub struct Instant<F: Frequency> {
+    ticks: u64,
+    frequency: PhantomData<F>,
+}
+
+impl<F: Frequency> Instant<F> {
+    pub fn from_ticks(ticks: u64) -> Instant<F> {
+        Instant {
+            ticks: ticks,
+            frequency: PhantomData,
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// The lower 32 bits of the ticks, which is the value an `Alarm` on the
+    /// same counter uses for this time.
+    pub fn ticks32(&self) -> u32 {
+        self.ticks as u32
+    }
+
+    /// Time since the clock started.
+    pub fn since_start(&self) -> Duration {
+        Duration::from_ticks(self.ticks, F::frequency())
+    }
+
+    /// Time elapsed from `earlier` to this instant, or zero if `earlier` is
+    /// later.
+    pub fn duration_since(&self, earlier: Instant<F>) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_sub(earlier.ticks), F::frequency())
+    }
+}
+
+impl<F: Frequency> Clone for Instant<F> {
+    fn clone(&self) -> Instant<F> {
+        Instant::from_ticks(self.ticks)
+    }
+}
+
+impl<F: Frequency> Copy for Instant<F> {}
+
+impl<F: Frequency> PartialEq for Instant<F> {
+    fn eq(&self, other: &Instant<F>) -> bool {
+        self.ticks == other.ticks
+    }
+}
+
+impl<F: Frequency> Eq for Instant<F> {}
+
+impl<F: Frequency> PartialOrd for Instant<F> {
+    fn partial_cmp(&self, other: &Instant<F>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<F: Frequency> Ord for Instant<F> {
+    fn cmp(&self, other: &Instant<F>) -> Ordering {
+        self.ticks.cmp(&other.ticks)
+    }
+}
+
+impl<F: Frequency> Add<Duration> for Instant<F> {
+    type Output = Instant<F>;
+
+    fn add(self, duration: Duration) -> Instant<F> {
+        Instant::from_ticks(self.ticks.saturating_add(duration.ticks(F::frequency())))
+    }
+}
+
+impl<F: Frequency> Sub<Duration> for Instant<F> {
+    type Output = Instant<F>;
+
+    fn sub(self, duration: Duration) -> Instant<F> {
+        Instant::from_ticks(self.ticks.saturating_sub(duration.ticks(F::frequency())))
+    }
+}
+
+/// The `Monotonic` trait models a 64-bit clock that counts up from when it
+/// started and never wraps in practice.
+///
+/// `kernel::common::monotonic::MonotonicClock` provides this on top of any
+/// `Alarm` by counting how often the 32-bit counter wraps.
+pub trait Monotonic {
+    type Frequency: Frequency;
+
+    /// Returns the current time.
+    fn now(&self) -> Instant<Self::Frequency>;
+}
+
+/// Returns whether an alarm at `alarm` has expired at `now`, for an `Alarm`
+/// counter that read `reference` when the alarm was set, taking wrap-around
+/// into account.
+pub fn has_expired(alarm: u32, now: u32, reference: u32) -> bool {
+    now.wrapping_sub(reference) >= alarm.wrapping_sub(reference)
+}
@@ -1,4 +1,8 @@
 //! Interfaces for UART communications.
+//!
+//! Receives end early when the UART detects a line error, such as a framing
+//! error or a break, or when they are aborted. The bytes received before that
+//! are still passed to the client, along with the error, so no data is lost.
 
 use returncode::ReturnCode;
 
@@ -35,6 +39,9 @@ pub enum Error {
     /// Overrun error during receive
     OverrunError,
 
+    /// Break condition detected during receive
+    BreakError,
+
     /// Repeat call of transmit or receive before initial command complete
     RepeatCallError,
 
@@ -71,10 +78,27 @@ pub trait UART {
     fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize);
 
     /// Abort any ongoing receive transfers and return what is in the
-    /// receive buffer with the `receive_complete` callback.
+    /// receive buffer with the `receive_complete` callback, with the error
+    /// `Aborted`.
     fn abort_receive(&self);
 }
 
+/// Trait for UARTs that can send a break, which holds the line low for longer
+/// than a character. Breaks are commonly used to signal the start of a frame
+/// or to reset the other side.
+///
+/// Breaks sent by the other side end receives with `Error::BreakError`.
+pub trait UARTBreak: UART {
+    /// Start sending a break. The line is held low until `stop_break` is
+    /// called, which should be at least one character time later. Returns
+    /// `EBUSY` if a transmit is in progress, and `EALREADY` if a break is
+    /// already being sent.
+    fn start_break(&self) -> ReturnCode;
+
+    /// Stop sending a break. Returns `EALREADY` if no break is being sent.
+    fn stop_break(&self) -> ReturnCode;
+}
+
 /// Trait that isn't required for basic UART operation, but provides useful
 /// abstractions that capsules may want to be able to leverage.
 ///
@@ -106,6 +130,8 @@ pub trait Client {
     /// UART transmit complete.
     fn transmit_complete(&self, tx_buffer: &'static mut [u8], error: Error);
 
-    /// UART receive complete.
+    /// UART receive complete. `rx_len` bytes were received into the start of
+    /// `rx_buffer`, which may be fewer than requested if `error` is not
+    /// `CommandComplete`.
     fn receive_complete(&self, rx_buffer: &'static mut [u8], rx_len: usize, error: Error);
 }
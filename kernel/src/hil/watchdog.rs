@@ -1,4 +1,10 @@
 //! Interface for a watchdog timer.
+//!
+//! The kernel services the watchdog from its main loop (see
+//! `Kernel::kernel_loop`), so boards typically only need to `start()` it and
+//! hand it to the kernel. Watchdogs that can signal an interrupt shortly
+//! before resetting the chip report this to their [`Client`](trait.Client.html)
+//! so the kernel can record what it was doing into the crash log.
 
 pub trait Watchdog {
     /// Enable the watchdog timer. Period is the time in milliseconds
@@ -11,4 +17,23 @@ pub trait Watchdog {
     /// Service the watchdog to let the hardware know the application
     /// is still executing.
     fn tickle(&self);
+
+    /// Set the client to notify when the watchdog is about to expire.
+    fn set_client(&self, client: &'static Client);
+}
+
+/// A client of an implementor of the [`Watchdog`](trait.Watchdog.html) trait.
+pub trait Client {
+    /// Called when the watchdog is about to reset the chip.
+    ///
+    /// This is called directly from the watchdog's interrupt handler, not from
+    /// the kernel loop, because the kernel loop is what may be stuck. The
+    /// client must therefore do as little as possible and must not rely on
+    /// any other interrupt firing. `interrupt` is the interrupt the chip was
+    /// servicing at the time, if the chip can tell.
+    ///
+    /// The client must not call `tickle` from this callback. The warning only
+    /// means the kernel loop missed a tickle; clearing the watchdog here would
+    /// keep a hung board from ever being reset.
+    fn early_warning(&self, interrupt: Option<u32>);
 }
@@ -46,6 +46,7 @@ pub use platform::{mpu, Chip, Platform};
 pub use platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
 pub use returncode::ReturnCode;
 pub use sched::Kernel;
+pub use syscall::KERNEL_ABI_VERSION;
 
 // These symbols must be exported for the arch crate to access them.
 pub use process::APP_FAULT;
@@ -56,5 +57,5 @@ pub use process::SYSCALL_FIRED;
 // functions and types are used by board files to setup the platform and setup
 // processes.
 pub mod procs {
-    pub use process::{load_processes, FaultResponse, Process};
+    pub use process::{load_processes, CallbackOverflowPolicy, FaultResponse, Process, State};
 }
@@ -36,6 +36,8 @@ use returncode::ReturnCode;
 ///   where the app has put the start of its heap. This is not strictly
 ///   necessary for correct operation, but allows for better debugging if the
 ///   app crashes.
+/// - `12`: Get the number of callbacks the kernel dropped or coalesced because
+///   the process's callback queue was full, since the process last started.
 crate fn memop(process: &Process) -> ReturnCode {
     let op_type = process.r0();
     let r1 = process.r1();
@@ -111,6 +113,9 @@ crate fn memop(process: &Process) -> ReturnCode {
             ReturnCode::SUCCESS
         }
 
+        // Op Type 12: Number of callbacks lost to a full callback queue.
+        12 => ReturnCode::SuccessWithValue { value: process.dropped_callback_count() },
+
         _ => ReturnCode::ENOSUPPORT,
     }
 }
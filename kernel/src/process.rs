@@ -6,14 +6,14 @@ use common::{Queue, RingBuffer};
 use core::cell::Cell;
 use core::fmt::Write;
 use core::ptr::{read_volatile, write, write_volatile};
-use core::{mem, ptr, slice, str};
+use core::{cmp, mem, ptr, slice, str};
 
 use common::cells::MapCell;
 use common::math;
 use platform::mpu;
 use returncode::ReturnCode;
 use sched::Kernel;
-use syscall::Syscall;
+use syscall::{Syscall, KERNEL_ABI_VERSION};
 use tbfheader;
 
 /// This is used in the hardfault handler.
@@ -32,6 +32,10 @@ pub static mut APP_FAULT: usize = 0;
 #[used]
 static mut SCB_REGISTERS: [u32; 5] = [0; 5];
 
+/// Number of callbacks a process can have queued if its TBF header does not
+/// ask for a different number.
+const DEFAULT_CALLBACK_QUEUE_LENGTH: usize = 9;
+
 #[allow(improper_ctypes)]
 extern "C" {
     crate fn switch_to_user(user_stack: *const u8, process_regs: &[usize; 8]) -> *mut u8;
@@ -46,13 +50,16 @@ extern "C" {
 /// `app_memory` buffer until either the memory is exhausted or the allocated
 /// number of processes are created, with process structures placed in the
 /// provided array. How process faults are handled by the kernel is also
-/// selected.
+/// selected, as is how many bytes of memory are reserved for grants in each
+/// process (`min_grant_size`). They are added to the memory the process asks
+/// for, and the process cannot claim them with `brk` or `sbrk`.
 pub unsafe fn load_processes(
     kernel: &'static Kernel,
     start_of_flash: *const u8,
     app_memory: &mut [u8],
     procs: &mut [Option<&Process<'static>>],
     fault_response: FaultResponse,
+    min_grant_size: usize,
 ) {
     let mut apps_in_flash_ptr = start_of_flash;
     let mut app_memory_ptr = app_memory.as_mut_ptr();
@@ -64,6 +71,7 @@ pub unsafe fn load_processes(
             app_memory_ptr,
             app_memory_size,
             fault_response,
+            min_grant_size,
         );
 
         if process.is_none() {
@@ -101,13 +109,45 @@ impl From<Error> for ReturnCode {
     }
 }
 
+/// The scheduling state of a process.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-crate enum State {
+pub enum State {
+    /// The process is ready to run or is running.
     Running,
+    /// The process called `yield` and is waiting for a callback.
     Yielded,
+    /// The process faulted and will not be scheduled again.
     Fault,
 }
 
+/// What the kernel does with a new callback when the process's callback queue
+/// is full.
+///
+/// Apps select the policy in their TBF header. Every callback that is dropped
+/// or merged under any policy is counted, and the process can read the count
+/// with `memop`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CallbackOverflowPolicy {
+    /// Drop the new callback. This is the default.
+    DropNewest,
+    /// Drop the oldest queued callback to make room for the new one.
+    DropOldest,
+    /// If a callback to the same function with the same `appdata` is already
+    /// queued, replace its arguments with the new ones. Otherwise drop the new
+    /// callback.
+    Coalesce,
+}
+
+impl CallbackOverflowPolicy {
+    fn from_tbf(policy: u16) -> CallbackOverflowPolicy {
+        match policy {
+            1 => CallbackOverflowPolicy::DropOldest,
+            2 => CallbackOverflowPolicy::Coalesce,
+            _ => CallbackOverflowPolicy::DropNewest,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FaultResponse {
     Panic,
@@ -169,13 +209,21 @@ struct ProcessDebug {
     /// What was the most recent syscall.
     last_syscall: Option<Syscall>,
 
-    /// How many callbacks were dropped because the queue was insufficiently
-    /// long.
+    /// How many callbacks were dropped or coalesced because the queue was
+    /// insufficiently long.
     dropped_callback_count: usize,
 
     /// How many times this process has entered into a fault condition and the
     /// kernel has restarted it.
     restart_count: usize,
+
+    /// How many grant allocations failed because the process was out of
+    /// memory.
+    grant_alloc_failure_count: usize,
+
+    /// The grant number and size in bytes of the most recent grant allocation
+    /// that failed.
+    last_failed_grant_alloc: Option<(usize, usize)>,
 }
 
 pub struct Process<'a> {
@@ -220,6 +268,16 @@ pub struct Process<'a> {
     app_break: Cell<*const u8>,
     original_app_break: *const u8,
 
+    /// Bytes below `original_kernel_memory_break` that are reserved for grants.
+    /// The app break can never be moved into this region, so capsules can
+    /// always allocate at least this much grant memory for the process.
+    min_grant_size: usize,
+
+    /// How many bytes of grant memory have been allocated for each grant,
+    /// indexed by grant number. Grant memory is only reclaimed when the
+    /// process restarts, so these only ever grow until then.
+    grant_allocations: &'a [Cell<usize>],
+
     /// Saved when the app switches to the kernel.
     current_stack_pointer: Cell<*const u8>,
     original_stack_pointer: *const u8,
@@ -263,6 +321,9 @@ pub struct Process<'a> {
     /// process.
     tasks: MapCell<RingBuffer<'a, Task>>,
 
+    /// What to do with new callbacks when `tasks` is full.
+    callback_overflow_policy: CallbackOverflowPolicy,
+
     /// Name of the app. Public so that IPC can use it.
     pub package_name: &'static str,
 
@@ -278,37 +339,59 @@ impl Process<'a> {
             return false;
         }
 
-        self.kernel.increment_work();
-
-        let ret = self
-            .tasks
-            .map_or(false, |tasks| tasks.enqueue(Task::FunctionCall(callback)));
-
-        // Make a note that we lost this callback if the enqueue function
-        // fails.
-        if ret == false {
-            self.debug.map(|debug| {
-                debug.dropped_callback_count += 1;
-            });
-        }
-
-        ret
+        self.enqueue_task(Task::FunctionCall(callback))
     }
 
     crate fn schedule_ipc(&self, from: AppId, cb_type: IPCType) {
-        self.kernel.increment_work();
+        self.enqueue_task(Task::IPC((from, cb_type)));
+    }
 
-        let ret = self
-            .tasks
-            .map_or(false, |tasks| tasks.enqueue(Task::IPC((from, cb_type))));
+    /// Add a task to the process's queue, applying the process's overflow
+    /// policy if the queue is full. Returns `false` if the task was dropped.
+    fn enqueue_task(&self, task: Task) -> bool {
+        let policy = self.callback_overflow_policy;
+        let (queued, lost) = self.tasks.map_or((false, true), |tasks| {
+            if tasks.enqueue(task) {
+                self.kernel.increment_work();
+                return (true, false);
+            }
 
-        // Make a note that we lost this callback if the enqueue function
-        // fails.
-        if ret == false {
+            match policy {
+                CallbackOverflowPolicy::DropNewest => (false, true),
+                CallbackOverflowPolicy::DropOldest => {
+                    // The queue stays full, so the amount of work does not
+                    // change.
+                    tasks.dequeue();
+                    (tasks.enqueue(task), true)
+                }
+                CallbackOverflowPolicy::Coalesce => match task {
+                    Task::FunctionCall(new) => {
+                        let duplicate = tasks.find_mut(|queued| match *queued {
+                            Task::FunctionCall(old) => old.pc == new.pc && old.r3 == new.r3,
+                            Task::IPC(_) => false,
+                        });
+                        match duplicate {
+                            Some(queued) => {
+                                *queued = task;
+                                (true, true)
+                            }
+                            None => (false, true),
+                        }
+                    }
+                    // IPC notifications carry no arguments to merge.
+                    Task::IPC(_) => (false, true),
+                },
+            }
+        });
+
+        // Make a note that the process lost a callback.
+        if lost {
             self.debug.map(|debug| {
                 debug.dropped_callback_count += 1;
             });
         }
+
+        queued
     }
 
     /// Retrieve the current state of this process (i.e. is it running,
@@ -317,6 +400,26 @@ impl Process<'a> {
         self.state.get()
     }
 
+    /// How many times the kernel restarted this process after a fault.
+    crate fn restart_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.restart_count)
+    }
+
+    /// How many callbacks were dropped since the process last started.
+    crate fn dropped_callback_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.dropped_callback_count)
+    }
+
+    /// The app version from the TBF header, or 0 if it does not have one.
+    crate fn app_version(&self) -> u32 {
+        self.header.get_app_version()
+    }
+
+    /// Size of the nonvolatile storage region requested in the TBF header.
+    crate fn nonvolatile_storage_size(&self) -> Option<usize> {
+        self.header.get_nonvolatile_storage_size()
+    }
+
     /// Move this process from the running state to the yield state.
     crate fn yield_state(&self) {
         let current_state = self.state.get();
@@ -357,6 +460,8 @@ impl Process<'a> {
                     debug.syscall_count = 0;
                     debug.last_syscall = None;
                     debug.dropped_callback_count = 0;
+                    debug.grant_alloc_failure_count = 0;
+                    debug.last_failed_grant_alloc = None;
                 });
 
                 // We are going to start this process over again, so need
@@ -371,6 +476,9 @@ impl Process<'a> {
 
                 // Need to reset the grant region.
                 self.grant_ptrs_reset();
+                for allocated in self.grant_allocations.iter() {
+                    allocated.set(0);
+                }
                 self.kernel_memory_break
                     .set(self.original_kernel_memory_break);
 
@@ -575,6 +683,7 @@ impl Process<'a> {
         remaining_app_memory: *mut u8,
         remaining_app_memory_size: usize,
         fault_response: FaultResponse,
+        min_grant_size: usize,
     ) -> (Option<&'static Process<'a>>, usize, usize) {
         if let Some(tbf_header) = tbfheader::parse_and_validate_tbf_header(app_flash_address) {
             let app_flash_size = tbf_header.get_total_size() as usize;
@@ -585,9 +694,22 @@ impl Process<'a> {
                 return (None, app_flash_size, 0);
             }
 
+            let package_name = tbf_header.get_package_name(app_flash_address);
+
+            // Skip apps that were built for a syscall ABI this kernel does not
+            // implement.
+            if let Some((min_abi, max_abi)) = tbf_header.get_kernel_abi_range() {
+                if KERNEL_ABI_VERSION < min_abi || KERNEL_ABI_VERSION > max_abi {
+                    debug!(
+                        "{:?} not loaded. Requires kernel ABI {} to {}, kernel has {}",
+                        package_name, min_abi, max_abi, KERNEL_ABI_VERSION
+                    );
+                    return (None, app_flash_size, 0);
+                }
+            }
+
             // Otherwise, actually load the app.
             let mut min_app_ram_size = tbf_header.get_minimum_app_ram_size();
-            let package_name = tbf_header.get_package_name(app_flash_address);
             let init_fn =
                 app_flash_address.offset(tbf_header.get_init_function_offset() as isize) as usize;
 
@@ -604,9 +726,17 @@ impl Process<'a> {
             let grant_ptrs_num = kernel.get_grant_count_and_finalize();
             let grant_ptrs_offset = grant_ptrs_num * grant_ptr_size;
 
-            // Allocate memory for callback ring buffer.
+            // Make room for the per-grant allocation counters.
+            let grant_allocations_offset = grant_ptrs_num * mem::size_of::<Cell<usize>>();
+
+            // Allocate memory for callback ring buffer. The ring buffer keeps
+            // one slot empty, so it needs one more entry than the number of
+            // callbacks the app wants to be able to queue.
             let callback_size = mem::size_of::<Task>();
-            let callback_len = 10;
+            let callback_len = tbf_header
+                .get_callback_queue_length()
+                .unwrap_or(DEFAULT_CALLBACK_QUEUE_LENGTH)
+                + 1;
             let callbacks_offset = callback_len * callback_size;
 
             // Make room to store this process's metadata.
@@ -614,13 +744,19 @@ impl Process<'a> {
 
             // Need to make sure that the amount of memory we allocate for
             // this process at least covers this state.
-            if min_app_ram_size
-                < (grant_ptrs_offset + callbacks_offset + process_struct_offset) as u32
-            {
-                min_app_ram_size =
-                    (grant_ptrs_offset + callbacks_offset + process_struct_offset) as u32;
+            let kernel_state_size = grant_ptrs_offset
+                + grant_allocations_offset
+                + callbacks_offset
+                + process_struct_offset;
+            if min_app_ram_size < kernel_state_size as u32 {
+                min_app_ram_size = kernel_state_size as u32;
             }
 
+            // The grant memory reserved by the board comes on top of the
+            // memory the app asked for, so that the app can still use all of
+            // it.
+            min_app_ram_size += min_grant_size as u32;
+
             // TODO round app_ram_size up to a closer MPU unit.
             // This is a very conservative approach that rounds up to power of
             // two. We should be able to make this closer to what we actually need.
@@ -650,6 +786,14 @@ impl Process<'a> {
                 *opt = ptr::null()
             }
 
+            // Followed by the allocation counter for each grant.
+            kernel_memory_break = kernel_memory_break.offset(-(grant_allocations_offset as isize));
+            let grant_allocations =
+                slice::from_raw_parts_mut(kernel_memory_break as *mut Cell<usize>, grant_ptrs_num);
+            for allocated in grant_allocations.iter_mut() {
+                *allocated = Cell::new(0);
+            }
+
             // Now that we know we have the space we can setup the memory
             // for the callbacks.
             kernel_memory_break = kernel_memory_break.offset(-(callbacks_offset as isize));
@@ -680,6 +824,8 @@ impl Process<'a> {
             process.original_kernel_memory_break = kernel_memory_break;
             process.app_break = Cell::new(initial_sbrk_pointer);
             process.original_app_break = initial_sbrk_pointer;
+            process.min_grant_size = min_grant_size;
+            process.grant_allocations = grant_allocations;
             process.current_stack_pointer = Cell::new(initial_stack_pointer);
             process.original_stack_pointer = initial_stack_pointer;
 
@@ -701,6 +847,8 @@ impl Process<'a> {
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
             ];
             process.tasks = MapCell::new(tasks);
+            process.callback_overflow_policy =
+                CallbackOverflowPolicy::from_tbf(process.header.get_callback_overflow_policy());
             process.package_name = package_name;
 
             process.debug = MapCell::new(ProcessDebug {
@@ -711,6 +859,8 @@ impl Process<'a> {
                 last_syscall: None,
                 dropped_callback_count: 0,
                 restart_count: 0,
+                grant_alloc_failure_count: 0,
+                last_failed_grant_alloc: None,
             });
 
             if (init_fn & 0x1) != 1 {
@@ -749,7 +899,7 @@ impl Process<'a> {
     crate fn brk(&self, new_break: *const u8) -> Result<*const u8, Error> {
         if new_break < self.mem_start() || new_break >= self.mem_end() {
             Err(Error::AddressOutOfBounds)
-        } else if new_break > self.kernel_memory_break.get() {
+        } else if new_break > self.app_break_limit() {
             Err(Error::OutOfMemory)
         } else {
             let old_break = self.app_break.get();
@@ -771,16 +921,50 @@ impl Process<'a> {
             && buf_end_addr <= self.mem_break()
     }
 
-    crate unsafe fn alloc(&self, size: usize) -> Option<&mut [u8]> {
+    /// The highest address the app break may be moved to. This is the
+    /// current kernel memory break, or lower if the board reserved grant
+    /// memory that has not been allocated yet.
+    fn app_break_limit(&self) -> *const u8 {
+        let reserved_break = self
+            .original_kernel_memory_break
+            .wrapping_offset(-(self.min_grant_size as isize));
+        cmp::min(self.kernel_memory_break.get(), reserved_break)
+    }
+
+    /// Allocate `size` bytes of grant memory on behalf of grant `grant_num`.
+    crate unsafe fn alloc(&self, size: usize, grant_num: usize) -> Option<&mut [u8]> {
         let new_break = self.kernel_memory_break.get().offset(-(size as isize));
         if new_break < self.app_break.get() {
+            self.debug.map(|debug| {
+                debug.grant_alloc_failure_count += 1;
+                debug.last_failed_grant_alloc = Some((grant_num, size));
+            });
             None
         } else {
             self.kernel_memory_break.set(new_break);
+            self.grant_allocations
+                .get(grant_num)
+                .map(|allocated| allocated.set(allocated.get() + size));
             Some(slice::from_raw_parts_mut(new_break as *mut u8, size))
         }
     }
 
+    /// How many bytes of grant memory have been allocated for grant
+    /// `grant_num` in this process.
+    crate fn grant_allocated_bytes(&self, grant_num: usize) -> usize {
+        self.grant_allocations
+            .get(grant_num)
+            .map_or(0, |allocated| allocated.get())
+    }
+
+    /// How many bytes of grant memory have been allocated in this process
+    /// across all grants.
+    crate fn grant_allocated_bytes_total(&self) -> usize {
+        self.grant_allocations
+            .iter()
+            .fold(0, |total, allocated| total + allocated.get())
+    }
+
     crate unsafe fn free<T>(&self, _: *mut T) {}
 
     unsafe fn grant_ptr<T>(&self, grant_num: usize) -> *mut *mut T {
@@ -805,7 +989,7 @@ impl Process<'a> {
     crate unsafe fn grant_for_or_alloc<T: Default>(&self, grant_num: usize) -> Option<*mut T> {
         let ctr_ptr = self.grant_ptr::<T>(grant_num);
         if (*ctr_ptr).is_null() {
-            self.alloc(mem::size_of::<T>()).map(|root_arr| {
+            self.alloc(mem::size_of::<T>(), grant_num).map(|root_arr| {
                 let root_ptr = root_arr.as_mut_ptr() as *mut T;
                 // Initialize the grant contents using ptr::write, to
                 // ensure that we don't try to drop the contents of
@@ -1197,6 +1381,10 @@ impl Process<'a> {
         let last_syscall = self.debug.map(|debug| debug.last_syscall);
         let dropped_callback_count = self.debug.map_or(0, |debug| debug.dropped_callback_count);
         let restart_count = self.debug.map_or(0, |debug| debug.restart_count);
+        let grant_alloc_failure_count =
+            self.debug.map_or(0, |debug| debug.grant_alloc_failure_count);
+        let last_failed_grant_alloc =
+            self.debug.map_or(None, |debug| debug.last_failed_grant_alloc);
 
         // register values
         let (r0, r1, r2, r3, r12, sp, lr, pc, xpsr) = (
@@ -1213,10 +1401,11 @@ impl Process<'a> {
 
         let _ = writer.write_fmt(format_args!(
             "\
-             App: {}   -   [{:?}]\
+             App: {}   Version: {}   -   [{:?}]\
              \r\n Events Queued: {}   Syscall Count: {}   Dropped Callback Count: {}\
              \n Restart Count: {}\n",
             self.package_name,
+            self.header.get_app_version(),
             self.state,
             events_queued,
             syscall_count,
@@ -1224,6 +1413,28 @@ impl Process<'a> {
             restart_count,
         ));
 
+        let _ = writer.write_fmt(format_args!(
+            " Grant Allocated: {}   Grant Reserved: {}   Failed Grant Allocations: {}\r\n",
+            self.grant_allocated_bytes_total(),
+            self.min_grant_size,
+            grant_alloc_failure_count,
+        ));
+        for grant_num in 0..self.grant_allocations.len() {
+            let allocated = self.grant_allocated_bytes(grant_num);
+            if allocated > 0 {
+                let _ = writer.write_fmt(format_args!(
+                    "   Grant {:2}: {:6} bytes\r\n",
+                    grant_num, allocated
+                ));
+            }
+        }
+        if let Some((grant_num, size)) = last_failed_grant_alloc {
+            let _ = writer.write_fmt(format_args!(
+                " Last Failed Grant Allocation: grant {} requested {} bytes\r\n",
+                grant_num, size
+            ));
+        }
+
         let _ = match last_syscall {
             Some(syscall) => writer.write_fmt(format_args!(" Last Syscall: {:?}", syscall)),
             None => writer.write_fmt(format_args!(" Last Syscall: None")),
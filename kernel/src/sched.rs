@@ -7,7 +7,10 @@ use core::ptr::NonNull;
 use callback;
 use callback::{AppId, Callback};
 use common::cells::NumericCellExt;
+use debug;
+use debug::Activity;
 use grant::Grant;
+use hil;
 use ipc;
 use mem::AppSlice;
 use memop;
@@ -40,6 +43,11 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+    /// What the kernel loop is currently doing. This is recorded in the crash
+    /// log if the watchdog is about to reset the board.
+    last_activity: Cell<Activity>,
+    /// Whether the watchdog gave an early warning since it was last tickled.
+    warned: Cell<bool>,
 }
 
 impl Kernel {
@@ -49,6 +57,8 @@ impl Kernel {
             processes: processes,
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            last_activity: Cell::new(Activity::Idle),
+            warned: Cell::new(false),
         }
     }
 
@@ -77,7 +87,7 @@ impl Kernel {
     where
         F: FnOnce(&Process) -> R,
     {
-        if process_index > self.processes.len() {
+        if process_index >= self.processes.len() {
             return default;
         }
         self.processes[process_index]
@@ -124,10 +134,18 @@ impl Kernel {
     }
 
     /// Return how many processes this board supports.
-    crate fn number_of_process_slots(&self) -> usize {
+    pub fn number_of_process_slots(&self) -> usize {
         self.processes.len()
     }
 
+    /// Return the `AppId` for the process in slot `process_index`, or `None`
+    /// if that slot does not hold a process.
+    pub fn lookup_app_by_index(&'static self, process_index: usize) -> Option<AppId> {
+        self.process_map_or(None, process_index, |_| {
+            Some(AppId::new(self, process_index))
+        })
+    }
+
     /// Create a new grant. This is used in board initialization to setup grants
     /// that capsules use to interact with processes.
     ///
@@ -159,18 +177,42 @@ impl Kernel {
     }
 
     /// Main loop.
+    ///
+    /// If a `watchdog` is provided it is tickled on every pass through the
+    /// loop, after all pending interrupts have been serviced. A capsule that
+    /// never returns from an interrupt handler or system call therefore lets
+    /// the watchdog expire. The watchdog keeps running while the kernel
+    /// sleeps, so its period must be longer than the longest expected idle
+    /// time. Boards that want the early-warning record in the crash log should
+    /// also register the kernel as the watchdog's client.
     pub fn kernel_loop<P: Platform, C: Chip>(
         &'static self,
         platform: &P,
         chip: &mut C,
         ipc: Option<&ipc::IPC>,
+        watchdog: Option<&hil::watchdog::Watchdog>,
     ) {
         loop {
             unsafe {
+                self.last_activity.set(Activity::Interrupt(None));
                 chip.service_pending_interrupts();
 
+                // Every interrupt handler returned, so the kernel is still
+                // making progress.
+                watchdog.map(|watchdog| {
+                    watchdog.tickle();
+                    // Watchdogs that warn a whole period before resetting
+                    // also warn when the kernel sleeps for that long. The
+                    // board was not reset, so drop the record.
+                    if self.warned.get() {
+                        self.warned.set(false);
+                        debug::take_crash_log();
+                    }
+                });
+
                 for (i, p) in self.processes.iter().enumerate() {
                     p.as_ref().map(|process| {
+                        self.last_activity.set(Activity::Process(i));
                         self.do_process(
                             platform,
                             chip,
@@ -186,6 +228,7 @@ impl Kernel {
 
                 chip.atomic(|| {
                     if !chip.has_pending_interrupts() && self.processes_blocked() {
+                        self.last_activity.set(Activity::Idle);
                         chip.sleep();
                     }
                 });
@@ -330,3 +373,16 @@ impl Kernel {
         systick.reset();
     }
 }
+
+impl hil::watchdog::Client for Kernel {
+    fn early_warning(&self, interrupt: Option<u32>) {
+        let activity = match self.last_activity.get() {
+            Activity::Interrupt(_) => Activity::Interrupt(interrupt),
+            activity => activity,
+        };
+        self.warned.set(true);
+        unsafe {
+            debug::record_crash_log(activity);
+        }
+    }
+}
@@ -1,5 +1,13 @@
 //! Tock syscall number definitions.
 
+/// Version of the syscall ABI this kernel implements.
+///
+/// Apps can list the range of ABI versions they were built for in their TBF
+/// header, and the kernel refuses to load apps whose range excludes this
+/// version. Increment this whenever the syscall interface changes in a way
+/// that breaks existing apps.
+pub const KERNEL_ABI_VERSION: u32 = 1;
+
 /// The syscall number assignments.
 #[derive(Copy, Clone, Debug)]
 crate enum Syscall {
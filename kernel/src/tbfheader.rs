@@ -63,7 +63,11 @@ crate enum TbfHeaderTypes {
     TbfHeaderMain = 1,
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
-    Unused = 5,
+    TbfHeaderAppVersion = 5,
+    TbfHeaderKernelAbi = 6,
+    TbfHeaderCallbackQueue = 7,
+    TbfHeaderNonvolatileStorage = 8,
+    Unused = 9,
 }
 
 /// The TLV header (T and L).
@@ -97,6 +101,38 @@ crate struct TbfHeaderV2WriteableFlashRegion {
     writeable_flash_region_size: u32,
 }
 
+/// Version of the app, chosen by the app developer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2AppVersion {
+    version: u32,
+}
+
+/// Range of kernel syscall ABI versions the app was built to run on.
+///
+/// Both bounds are inclusive.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2KernelAbi {
+    minimum_version: u32,
+    maximum_version: u32,
+}
+
+/// Size of the process's callback queue and what to do when it is full.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2CallbackQueue {
+    length: u16,
+    overflow_policy: u16,
+}
+
+/// Size of the nonvolatile storage region the app wants.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2NonvolatileStorage {
+    size: u32,
+}
+
 /// PIC fields for kernel provided PIC fixup.
 ///
 /// If an app wants the kernel to do the PIC fixup for it, it must pass this
@@ -123,6 +159,10 @@ crate struct TbfHeaderV2 {
     main: Option<&'static TbfHeaderV2Main>,
     package_name: Option<&'static str>,
     writeable_regions: Option<&'static [TbfHeaderV2WriteableFlashRegion]>,
+    app_version: Option<&'static TbfHeaderV2AppVersion>,
+    kernel_abi: Option<&'static TbfHeaderV2KernelAbi>,
+    callback_queue: Option<&'static TbfHeaderV2CallbackQueue>,
+    nonvolatile_storage: Option<&'static TbfHeaderV2NonvolatileStorage>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -231,6 +271,54 @@ impl TbfHeader {
         }
     }
 
+    /// Get the version of the app, or 0 if the header does not specify one.
+    crate fn get_app_version(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.app_version.map_or(0, |v| v.version),
+            _ => 0,
+        }
+    }
+
+    /// Get the inclusive range of kernel ABI versions the app supports, or
+    /// `None` if the header does not restrict it.
+    crate fn get_kernel_abi_range(&self) -> Option<(u32, u32)> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .kernel_abi
+                .map(|abi| (abi.minimum_version, abi.maximum_version)),
+            _ => None,
+        }
+    }
+
+    /// Get the number of callbacks the app wants to be able to queue, or
+    /// `None` to use the kernel default.
+    crate fn get_callback_queue_length(&self) -> Option<usize> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .callback_queue
+                .and_then(|cq| if cq.length > 0 { Some(cq.length as usize) } else { None }),
+            _ => None,
+        }
+    }
+
+    /// Get the raw callback queue overflow policy the app asked for. Apps
+    /// that do not specify one get policy 0.
+    crate fn get_callback_overflow_policy(&self) -> u16 {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.callback_queue.map_or(0, |cq| cq.overflow_policy),
+            _ => 0,
+        }
+    }
+
+    /// Get the size of the nonvolatile storage region the app asked for, or
+    /// `None` if the header does not specify one.
+    crate fn get_nonvolatile_storage_size(&self) -> Option<usize> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.nonvolatile_storage.map(|nv| nv.size as usize),
+            _ => None,
+        }
+    }
+
     /// Get the number of flash regions this app has specified in its header.
     crate fn number_writeable_flash_regions(&self) -> usize {
         match *self {
@@ -358,6 +446,10 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
                     &'static [TbfHeaderV2WriteableFlashRegion],
                 > = None;
                 let mut app_name_str = "";
+                let mut app_version_pointer: Option<&TbfHeaderV2AppVersion> = None;
+                let mut kernel_abi_pointer: Option<&TbfHeaderV2KernelAbi> = None;
+                let mut callback_queue_pointer: Option<&TbfHeaderV2CallbackQueue> = None;
+                let mut nonvolatile_storage_pointer: Option<&TbfHeaderV2NonvolatileStorage> = None;
 
                 // Loop through the header looking for known options.
                 while remaining_length > mem::size_of::<TbfHeaderTlv>() {
@@ -396,6 +488,34 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
                                     let _ = str::from_utf8(package_name_byte_array).map(|name_str| { app_name_str = name_str; });
                                 }
                             }
+                            TbfHeaderTypes::TbfHeaderAppVersion => /* App Version */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2AppVersion>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2AppVersion>() {
+                                    let app_version = &*(address.offset(offset) as *const TbfHeaderV2AppVersion);
+                                    app_version_pointer = Some(app_version);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderKernelAbi => /* Kernel ABI */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2KernelAbi>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2KernelAbi>() {
+                                    let kernel_abi = &*(address.offset(offset) as *const TbfHeaderV2KernelAbi);
+                                    kernel_abi_pointer = Some(kernel_abi);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderCallbackQueue => /* Callback Queue */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2CallbackQueue>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2CallbackQueue>() {
+                                    let callback_queue = &*(address.offset(offset) as *const TbfHeaderV2CallbackQueue);
+                                    callback_queue_pointer = Some(callback_queue);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderNonvolatileStorage => /* Nonvolatile Storage */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2NonvolatileStorage>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2NonvolatileStorage>() {
+                                    let nonvolatile_storage = &*(address.offset(offset) as *const TbfHeaderV2NonvolatileStorage);
+                                    nonvolatile_storage_pointer = Some(nonvolatile_storage);
+                                }
+                            }
                             TbfHeaderTypes::Unused => {}
                         }
                     }
@@ -411,6 +531,10 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
                     main: main_pointer,
                     package_name: Some(app_name_str),
                     writeable_regions: wfr_pointer,
+                    app_version: app_version_pointer,
+                    kernel_abi: kernel_abi_pointer,
+                    callback_queue: callback_queue_pointer,
+                    nonvolatile_storage: nonvolatile_storage_pointer,
                 };
 
                 Some(TbfHeader::TbfHeaderV2(tbf_header))